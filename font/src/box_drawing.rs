@@ -0,0 +1,276 @@
+// Copyright 2016 Joe Wilm, The Alacritty Project Contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//! Box drawing and block element characters drawn without a font
+//!
+//! Glyphs are drawn at exactly the size of a cell, so lines always meet the
+//! lines in the cells around them. Dashed lines are drawn solid and arcs as
+//! square corners; where double lines cross, their strokes cross too.
+use std::cmp::{max, min};
+
+use super::{RasterizedGlyph, PixelFormat, is_box_drawing};
+
+/// Weight of the line from the middle of the cell to each edge for U+2500 to
+/// U+257F
+///
+/// Two bits per edge, from the top: up, right, down, left. 0 is no line, 1 a
+/// light line, 2 a heavy line and 3 a double line. The diagonals U+2571 to
+/// U+2573 are drawn separately.
+const LINES: [u8; 128] = [
+    0x11, 0x22, 0x44, 0x88, 0x11, 0x22, 0x44, 0x88,  // U+2500
+    0x11, 0x22, 0x44, 0x88, 0x14, 0x24, 0x18, 0x28,  // U+2508
+    0x05, 0x06, 0x09, 0x0a, 0x50, 0x60, 0x90, 0xa0,  // U+2510
+    0x41, 0x42, 0x81, 0x82, 0x54, 0x64, 0x94, 0x58,  // U+2518
+    0x98, 0xa4, 0x68, 0xa8, 0x45, 0x46, 0x85, 0x49,  // U+2520
+    0x89, 0x86, 0x4a, 0x8a, 0x15, 0x16, 0x25, 0x26,  // U+2528
+    0x19, 0x1a, 0x29, 0x2a, 0x51, 0x52, 0x61, 0x62,  // U+2530
+    0x91, 0x92, 0xa1, 0xa2, 0x55, 0x56, 0x65, 0x66,  // U+2538
+    0x95, 0x59, 0x99, 0x96, 0xa5, 0x5a, 0x69, 0xa6,  // U+2540
+    0x6a, 0x9a, 0xa9, 0xaa, 0x11, 0x22, 0x44, 0x88,  // U+2548
+    0x33, 0xcc, 0x34, 0x1c, 0x3c, 0x07, 0x0d, 0x0f,  // U+2550
+    0x70, 0xd0, 0xf0, 0x43, 0xc1, 0xc3, 0x74, 0xdc,  // U+2558
+    0xfc, 0x47, 0xcd, 0xcf, 0x37, 0x1d, 0x3f, 0x73,  // U+2560
+    0xd1, 0xf3, 0x77, 0xdd, 0xff, 0x14, 0x05, 0x41,  // U+2568
+    0x50, 0x00, 0x00, 0x00, 0x01, 0x40, 0x10, 0x04,  // U+2570
+    0x02, 0x80, 0x20, 0x08, 0x21, 0x48, 0x12, 0x84,  // U+2578
+];
+
+const LIGHT: u8 = 1;
+const HEAVY: u8 = 2;
+const DOUBLE: u8 = 3;
+
+/// Draw a box drawing or block element character filling a cell of `width`
+/// by `height` pixels
+///
+/// `descent` is how far the cell extends below the baseline; the glyph is
+/// positioned so that it covers the cell exactly. Returns `None` for any
+/// other character.
+pub fn rasterize(c: char, width: i32, height: i32, descent: i32) -> Option<RasterizedGlyph> {
+    if !is_box_drawing(c) || width <= 0 || height <= 0 {
+        return None;
+    }
+
+    let mut canvas = Canvas::new(width, height);
+    let index = c as usize - 0x2500;
+    match c {
+        '\u{2571}' => canvas.diagonal(false),
+        '\u{2572}' => canvas.diagonal(true),
+        '\u{2573}' => {
+            canvas.diagonal(false);
+            canvas.diagonal(true);
+        },
+        '\u{2500}'...'\u{257f}' => canvas.lines(LINES[index]),
+        _ => canvas.block(c),
+    }
+
+    Some(RasterizedGlyph {
+        c: c,
+        width: width,
+        height: height,
+        top: height - descent,
+        left: 0,
+        advance: (width, 0),
+        format: PixelFormat::Gray,
+        buf: canvas.buf,
+    })
+}
+
+/// Coverage of a cell, one byte per pixel from the top left
+struct Canvas {
+    width: i32,
+    height: i32,
+    buf: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: i32, height: i32) -> Canvas {
+        Canvas {
+            width: width,
+            height: height,
+            buf: vec![0; (width * height) as usize],
+        }
+    }
+
+    /// Thickness of a light line; heavy lines are twice as thick
+    fn light(&self) -> i32 {
+        max(1, (min(self.width, self.height) + 4) / 8)
+    }
+
+    /// Set the coverage of the pixels in `x0..x1` and `y0..y1`, clipped to the
+    /// cell
+    fn fill(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, value: u8) {
+        for y in max(y0, 0)..min(y1, self.height) {
+            for x in max(x0, 0)..min(x1, self.width) {
+                self.buf[(y * self.width + x) as usize] = value;
+            }
+        }
+    }
+
+    /// Draw lines from the middle of the cell to its edges, weighted as in
+    /// `LINES`
+    fn lines(&mut self, arms: u8) {
+        let light = self.light();
+        let thickness = |weight: u8| match weight {
+            LIGHT => light,
+            HEAVY => light * 2,
+            DOUBLE => light * 3,
+            _ => 0,
+        };
+
+        let weights = [arms >> 6 & 3, arms >> 4 & 3, arms >> 2 & 3, arms & 3];
+        let (cx, cy) = (self.width / 2, self.height / 2);
+
+        // Lines run past the middle far enough to cover the thickest line
+        // crossing them
+        let reach = weights.iter().map(|&w| thickness(w)).max().unwrap_or(0) / 2 + light;
+
+        for (edge, &weight) in weights.iter().enumerate() {
+            if weight == 0 {
+                continue;
+            }
+
+            // Each stroke as (offset of its start from the middle, thickness)
+            let strokes = if weight == DOUBLE {
+                vec![(-(light * 3) / 2, light), (-(light * 3) / 2 + light * 2, light)]
+            } else {
+                let t = thickness(weight);
+                vec![(-t / 2, t)]
+            };
+
+            for &(offset, t) in &strokes {
+                match edge {
+                    0 => self.fill(cx + offset, 0, cx + offset + t, cy + reach, 0xff),
+                    1 => self.fill(cx - reach, cy + offset, self.width, cy + offset + t, 0xff),
+                    2 => self.fill(cx + offset, cy - reach, cx + offset + t, self.height, 0xff),
+                    _ => self.fill(0, cy + offset, cx + reach, cy + offset + t, 0xff),
+                }
+            }
+        }
+    }
+
+    /// Draw a light diagonal line from the top left corner to the bottom
+    /// right, or from the top right to the bottom left
+    fn diagonal(&mut self, from_left: bool) {
+        let light = self.light();
+        for y in 0..self.height {
+            let x = y * self.width / self.height;
+            let x = if from_left { x } else { self.width - 1 - x };
+            self.fill(x - light / 2, y, x - light / 2 + light, y + 1, 0xff);
+        }
+    }
+
+    /// Draw one of the block elements U+2580 to U+259F
+    fn block(&mut self, c: char) {
+        let (w, h) = (self.width, self.height);
+        let eighths = |size: i32, n: i32| (size * n + 4) / 8;
+
+        match c {
+            '\u{2580}' => self.fill(0, 0, w, h / 2, 0xff),
+            '\u{2581}'...'\u{2588}' => {
+                let n = c as i32 - 0x2580;
+                self.fill(0, h - eighths(h, n), w, h, 0xff);
+            },
+            '\u{2589}'...'\u{258f}' => {
+                let n = 0x2590 - c as i32;
+                self.fill(0, 0, eighths(w, n), h, 0xff);
+            },
+            '\u{2590}' => self.fill(w / 2, 0, w, h, 0xff),
+            '\u{2591}' => self.fill(0, 0, w, h, 0x40),
+            '\u{2592}' => self.fill(0, 0, w, h, 0x80),
+            '\u{2593}' => self.fill(0, 0, w, h, 0xc0),
+            '\u{2594}' => self.fill(0, 0, w, eighths(h, 1), 0xff),
+            '\u{2595}' => self.fill(w - eighths(w, 1), 0, w, h, 0xff),
+            _ => {
+                // Quadrants; bits are upper left, upper right, lower left and
+                // lower right
+                let quadrants = match c {
+                    '\u{2596}' => 0b0100,
+                    '\u{2597}' => 0b1000,
+                    '\u{2598}' => 0b0001,
+                    '\u{2599}' => 0b1101,
+                    '\u{259a}' => 0b1001,
+                    '\u{259b}' => 0b0111,
+                    '\u{259c}' => 0b1011,
+                    '\u{259d}' => 0b0010,
+                    '\u{259e}' => 0b0110,
+                    _ => 0b1110,
+                };
+
+                let (cx, cy) = (w / 2, h / 2);
+                if quadrants & 0b0001 != 0 { self.fill(0, 0, cx, cy, 0xff); }
+                if quadrants & 0b0010 != 0 { self.fill(cx, 0, w, cy, 0xff); }
+                if quadrants & 0b0100 != 0 { self.fill(0, cy, cx, h, 0xff); }
+                if quadrants & 0b1000 != 0 { self.fill(cx, cy, w, h, 0xff); }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rasterize;
+
+    /// Get a row of a glyph's coverage
+    fn row(glyph: &::RasterizedGlyph, y: i32) -> Vec<u8> {
+        let start = (y * glyph.width) as usize;
+        glyph.buf[start..start + glyph.width as usize].to_vec()
+    }
+
+    #[test]
+    fn vertical_line_fills_center_column() {
+        let glyph = rasterize('\u{2502}', 8, 16, 4).unwrap();
+        assert_eq!((glyph.width, glyph.height, glyph.top, glyph.left), (8, 16, 12, 0));
+
+        for y in 0..16 {
+            let row = row(&glyph, y);
+            assert_eq!(row[4], 0xff);
+            assert_eq!(row[0], 0);
+            assert_eq!(row[7], 0);
+        }
+    }
+
+    #[test]
+    fn lines_reach_the_edges() {
+        // A light cross covers the middle row and column edge to edge
+        let glyph = rasterize('\u{253c}', 8, 16, 4).unwrap();
+        assert!(row(&glyph, 8).iter().all(|&c| c == 0xff));
+        assert!((0..16).all(|y| row(&glyph, y)[4] == 0xff));
+
+        // Double lines are two strokes with a gap between them
+        let glyph = rasterize('\u{2551}', 8, 16, 4).unwrap();
+        assert_eq!(row(&glyph, 0), vec![0, 0, 0, 0xff, 0, 0xff, 0, 0]);
+
+        // Nothing is drawn in the corner a corner piece turns away from
+        let glyph = rasterize('\u{250c}', 8, 16, 4).unwrap();
+        assert_eq!(row(&glyph, 0), vec![0; 8]);
+        assert_eq!(row(&glyph, 8)[7], 0xff);
+        assert_eq!(row(&glyph, 8)[0], 0);
+    }
+
+    #[test]
+    fn blocks() {
+        let full = rasterize('\u{2588}', 8, 16, 4).unwrap();
+        assert!(full.buf.iter().all(|&c| c == 0xff));
+
+        let lower_half = rasterize('\u{2584}', 8, 16, 4).unwrap();
+        assert_eq!(row(&lower_half, 7), vec![0; 8]);
+        assert_eq!(row(&lower_half, 8), vec![0xff; 8]);
+
+        let upper_left = rasterize('\u{2598}', 8, 16, 4).unwrap();
+        assert_eq!(row(&upper_left, 0), vec![0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        assert_eq!(row(&upper_left, 8), vec![0; 8]);
+
+        assert!(rasterize('a', 8, 16, 4).is_none());
+    }
+}
@@ -17,6 +17,9 @@
 //! TODO error handling... just search for unwrap.
 #![allow(improper_ctypes)]
 use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::ptr;
 
 use core_foundation::base::TCFType;
@@ -30,6 +33,7 @@ use core_graphics::context::{CGContext, CGContextRef};
 use core_graphics::font::{CGFont, CGFontRef, CGGlyph};
 use core_graphics::geometry::{CGPoint, CGRect, CGSize};
 use core_text::font::{CTFont, new_from_descriptor as ct_new_from_descriptor};
+use core_text::font::new_from_CGFont as ct_new_from_cg_font;
 use core_text::font_collection::create_for_family;
 use core_text::font_collection::get_family_names as ct_get_family_names;
 use core_text::font_descriptor::kCTFontDefaultOrientation;
@@ -37,13 +41,14 @@ use core_text::font_descriptor::kCTFontHorizontalOrientation;
 use core_text::font_descriptor::kCTFontVerticalOrientation;
 use core_text::font_descriptor::{CTFontDescriptor, CTFontDescriptorRef, CTFontOrientation};
 
-use libc::{size_t, c_int};
+use libc::{size_t, c_int, c_char, c_void};
 
 use euclid::point::Point2D;
 use euclid::rect::Rect;
 use euclid::size::Size2D;
 
-use super::{FontDesc, RasterizedGlyph, Metrics, FontKey, GlyphKey};
+use super::{FontDesc, RasterizedGlyph, Metrics, FontKey, GlyphKey, Error, RasterizerConfig};
+use super::{PixelFormat, Rasterize};
 
 pub mod cg_color;
 use self::cg_color::{CGColorRef, CGColor};
@@ -73,40 +78,69 @@ pub struct Descriptor {
 /// Rasterizer, the main type exported by this package
 ///
 /// Given a fontdesc, can rasterize fonts.
+///
+/// Hinting and LCD filtering are left to CoreText; only `antialias` from the
+/// `RasterizerConfig` is used.
 pub struct Rasterizer {
     fonts: HashMap<FontKey, Font>,
-    device_pixel_ratio: f32,
+    antialias: bool,
 }
 
 impl Rasterizer {
-    pub fn new(_dpi_x: f32, _dpi_y: f32, device_pixel_ratio: f32) -> Rasterizer {
-        println!("device_pixel_ratio: {}", device_pixel_ratio);
+    pub fn new(_dpi_x: f32, _dpi_y: f32, config: RasterizerConfig) -> Rasterizer {
         Rasterizer {
             fonts: HashMap::new(),
-            device_pixel_ratio: device_pixel_ratio,
+            antialias: config.antialias,
         }
     }
 
-    /// Get metrics for font specified by FontKey
+    /// Get the size of a terminal cell in pixels for the given font
+    ///
+    /// See `Metrics::cell_size` for how the metrics are rounded.
     ///
     /// # Panics
     ///
     /// If FontKey was not generated by `load_font`, this method will panic.
-    pub fn metrics(&self, key: FontKey, _size: Size) -> Metrics {
-        // NOTE size is not needed here since the font loaded already contains
-        // it. It's part of the API due to platform differences.
-        let font = self.fonts.get(&key).expect(FONT_LOAD_ERROR);
-        font.metrics()
+    pub fn cell_size(&self, key: FontKey, size: Size) -> (f32, f32) {
+        self.metrics(key, size).cell_size()
     }
 
-    pub fn load_font(&mut self, desc: &FontDesc, size: Size) -> Option<FontKey> {
-        self.get_font(desc, size)
-            .map(|font| {
-                let key = FontKey::next();
-                self.fonts.insert(key, font);
+    /// Load a font file, bypassing family lookup
+    ///
+    /// CoreText always uses the first face of a font collection, so `index` is
+    /// ignored.
+    pub fn load_font_from_file(
+        &mut self,
+        path: &Path,
+        _index: isize,
+        size: Size
+    ) -> Result<FontKey, Error> {
+        let invalid = || Error::InvalidFontFile(path.to_owned());
+        let filename = CString::new(path.as_os_str().as_bytes()).map_err(|_| invalid())?;
+
+        let cg_font: CGFont = unsafe {
+            let provider = CGDataProviderCreateWithFilename(filename.as_ptr());
+            if provider.is_null() {
+                return Err(invalid());
+            }
+
+            let font = CGFontCreateWithDataProvider(provider);
+            CGDataProviderRelease(provider);
+            if font.is_null() {
+                return Err(invalid());
+            }
 
-                key
-            })
+            TCFType::wrap_under_create_rule(font)
+        };
+
+        let ct_font = ct_new_from_cg_font(&cg_font, size.as_f32_px() as f64);
+        let key = FontKey::next();
+        self.fonts.insert(key, Font {
+            ct_font: ct_font,
+            cg_font: cg_font,
+        });
+
+        Ok(key)
     }
 
     fn get_font(&mut self, desc: &FontDesc, size: Size) -> Option<Font> {
@@ -114,7 +148,7 @@ impl Rasterizer {
         for descriptor in descriptors {
             if descriptor.style_name == desc.style {
                 // Found the font we want
-                let scaled_size = size.as_f32_pts() as f64 * self.device_pixel_ratio as f64;
+                let scaled_size = size.as_f32_px() as f64;
                 let font = descriptor.to_font(scaled_size);
                 return Some(font);
             }
@@ -122,19 +156,41 @@ impl Rasterizer {
 
         None
     }
+}
+
+impl Rasterize for Rasterizer {
+    /// Get metrics for font specified by FontKey
+    ///
+    /// # Panics
+    ///
+    /// If FontKey was not generated by `load_font`, this method will panic.
+    fn metrics(&self, key: FontKey, _size: Size) -> Metrics {
+        // NOTE size is not needed here since the font loaded already contains
+        // it. It's part of the API due to platform differences.
+        let font = self.fonts.get(&key).expect(FONT_LOAD_ERROR);
+        font.metrics()
+    }
+
+    fn load_font(&mut self, desc: &FontDesc, size: Size) -> Result<FontKey, Error> {
+        let font = self.get_font(desc, size).ok_or_else(|| Error::MissingFont(desc.clone()))?;
+        let key = FontKey::next();
+        self.fonts.insert(key, font);
+
+        Ok(key)
+    }
 
     /// Get rasterized glyph for given glyph key
     ///
     /// # Panics
     ///
     /// Panics if the FontKey specified in GlyphKey was not generated from `load_font`
-    pub fn get_glyph(&mut self, glyph: &GlyphKey) -> RasterizedGlyph {
-        let scaled_size = self.device_pixel_ratio * glyph.size.as_f32_pts();
+    fn get_glyph(&mut self, glyph: &GlyphKey) -> Result<RasterizedGlyph, Error> {
+        let scaled_size = glyph.size.as_f32_px();
 
         self.fonts
             .get(&glyph.font_key)
             .expect(FONT_LOAD_ERROR)
-            .get_glyph(glyph.c, scaled_size as _)
+            .get_glyph(glyph.c, scaled_size as _, self.antialias)
     }
 }
 
@@ -238,12 +294,19 @@ impl Font {
         Metrics {
             average_advance: average_advance,
             line_height: line_height,
+            ascent: ascent,
+            descent: descent,
+            underline_position: self.ct_font.underline_position() as f64,
+            underline_thickness: self.ct_font.underline_thickness() as f64,
         }
     }
 
     fn glyph_advance(&self, character: char) -> f64 {
         let index = self.glyph_index(character).unwrap();
+        self.advance_for_index(index)
+    }
 
+    fn advance_for_index(&self, index: u32) -> f64 {
         let indices = [index as CGGlyph];
 
         self.ct_font.get_advances_for_glyphs(FontOrientation::Default as _,
@@ -252,20 +315,15 @@ impl Font {
                                              1)
     }
 
-    pub fn get_glyph(&self, character: char, _size: f64) -> RasterizedGlyph {
+    pub fn get_glyph(
+        &self,
+        character: char,
+        _size: f64,
+        antialias: bool
+    ) -> Result<RasterizedGlyph, Error> {
         let glyph_index = match self.glyph_index(character) {
             Some(i) => i,
-            None => {
-                // TODO refactor this
-                return RasterizedGlyph {
-                    c: ' ',
-                    width: 0,
-                    height: 0,
-                    top: 0,
-                    left: 0,
-                    buf: Vec::new()
-                };
-            }
+            None => return Err(Error::MissingGlyph(character)),
         };
 
         let bounds = self.bounding_rect_for_glyph(Default::default(), glyph_index);
@@ -278,14 +336,16 @@ impl Font {
         let rasterized_height = (rasterized_descent + rasterized_ascent) as u32;
 
         if rasterized_width == 0 || rasterized_height == 0 {
-            return RasterizedGlyph {
+            return Ok(RasterizedGlyph {
                 c: ' ',
                 width: 0,
                 height: 0,
                 top: 0,
                 left: 0,
+                advance: (self.advance_for_index(glyph_index).round() as i32, 0),
+                format: PixelFormat::Rgb,
                 buf: Vec::new()
-            };
+            });
         }
 
         let mut cg_context = CGContext::create_bitmap_context(rasterized_width as usize,
@@ -313,8 +373,8 @@ impl Font {
         cg_context.set_should_subpixel_quantize_fonts(true);
         cg_context.set_allows_font_subpixel_positioning(true);
         cg_context.set_should_subpixel_position_fonts(true);
-        cg_context.set_allows_antialiasing(true);
-        cg_context.set_should_antialias(true);
+        cg_context.set_allows_antialiasing(antialias);
+        cg_context.set_should_antialias(antialias);
 
         // Set fill color to white for drawing the glyph
         cg_context.set_rgb_fill_color(1.0, 1.0, 1.0, 1.0);
@@ -331,14 +391,16 @@ impl Font {
 
         let buf = extract_rgb(rasterized_pixels);
 
-        RasterizedGlyph {
+        Ok(RasterizedGlyph {
             c: character,
             left: rasterized_left,
             top: (bounds.size.height + bounds.origin.y).ceil() as i32,
             width: rasterized_width as i32,
             height: rasterized_height as i32,
+            advance: (self.advance_for_index(glyph_index).round() as i32, 0),
+            format: PixelFormat::Rgb,
             buf: buf,
-        }
+        })
     }
 
     fn glyph_index(&self, character: char) -> Option<u32> {
@@ -467,8 +529,13 @@ extern {
     fn CGContextSetFont(c: CGContextRef, font: CGFontRef);
     fn CGContextSetFontSize(c: CGContextRef, size: CGFloat);
     fn CGContextSetFontSmoothingStyle(c: CGContextRef, style: c_int);
+    fn CGDataProviderCreateWithFilename(filename: *const c_char) -> CGDataProviderRef;
+    fn CGDataProviderRelease(provider: CGDataProviderRef);
+    fn CGFontCreateWithDataProvider(provider: CGDataProviderRef) -> CGFontRef;
 }
 
+type CGDataProviderRef = *const c_void;
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -495,7 +562,7 @@ mod tests {
 
             // Get a glyph
             for c in &['a', 'b', 'c', 'd'] {
-                let glyph = font.get_glyph(*c, 72.);
+                let glyph = font.get_glyph(*c, 72., true).unwrap();
 
                 // Debug the glyph.. sigh
                 for row in 0..glyph.height {
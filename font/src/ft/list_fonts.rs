@@ -15,13 +15,19 @@
 use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::fmt;
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::str::from_utf8;
+use std::sync::{Mutex, Once, ONCE_INIT};
 
 use libc::{c_char, c_int};
 
-use fontconfig::fontconfig::{FcConfigGetCurrent, FcConfigGetFonts, FcSetSystem};
+use ::FontDesc;
+
+use fontconfig::fontconfig::{FcConfig, FcConfigGetCurrent, FcConfigGetFonts, FcSetName};
+use fontconfig::fontconfig::{FcSetSystem, FcSetApplication};
+use fontconfig::fontconfig::{FcConfigCreate, FcConfigDestroy, FcConfigAppFontAddDir};
 use fontconfig::fontconfig::{FcPatternGetString, FcPatternCreate, FcPatternAddString};
 use fontconfig::fontconfig::{FcPatternGetInteger};
 use fontconfig::fontconfig::{FcObjectSetCreate, FcObjectSetAdd};
@@ -33,43 +39,48 @@ unsafe fn fc_char8_to_string(fc_str: *mut FcChar8) -> String {
     from_utf8(CStr::from_ptr(fc_str as *const c_char).to_bytes()).unwrap().to_owned()
 }
 
-fn list_families() -> Vec<String> {
+/// List the families of the fonts in one of the sets of `config`
+unsafe fn list_families(config: *mut FcConfig, set: FcSetName) -> Vec<String> {
     let mut families = Vec::new();
-    unsafe {
-        // https://www.freedesktop.org/software/fontconfig/fontconfig-devel/fcconfiggetcurrent.html
-        let config = FcConfigGetCurrent(); // *mut FcConfig
-
-        // https://www.freedesktop.org/software/fontconfig/fontconfig-devel/fcconfiggetfonts.html
-        let font_set = FcConfigGetFonts(config, FcSetSystem); // *mut FcFontSet
-
-        let nfont = (*font_set).nfont as isize;
-        for i in 0..nfont {
-            let font = (*font_set).fonts.offset(i); // *mut FcPattern
-            let id = 0 as c_int;
-            let mut family: *mut FcChar8 = ptr::null_mut();
-            let mut format: *mut FcChar8 = ptr::null_mut();
-
-            let result = FcPatternGetString(*font,
-                                            b"fontformat\0".as_ptr() as *mut c_char,
-                                            id,
-                                            &mut format);
-
-            if result != FcResultMatch {
-                continue;
-            }
 
-            let format = fc_char8_to_string(format);
+    // https://www.freedesktop.org/software/fontconfig/fontconfig-devel/fcconfiggetfonts.html
+    let font_set = FcConfigGetFonts(config, set); // *mut FcFontSet
 
-            if format != "TrueType" && format != "CFF" {
-                continue
-            }
+    // A set without any fonts may not exist
+    if font_set.is_null() {
+        return families;
+    }
 
-            let mut id = 0;
-            while FcPatternGetString(*font, b"family\0".as_ptr() as *mut c_char, id, &mut family) == FcResultMatch {
-                let safe_family = fc_char8_to_string(family);
-                id += 1;
-                families.push(safe_family);
-            }
+    let nfont = (*font_set).nfont as isize;
+    for i in 0..nfont {
+        let font = (*font_set).fonts.offset(i); // *mut FcPattern
+        let id = 0 as c_int;
+        let mut family: *mut FcChar8 = ptr::null_mut();
+        let mut format: *mut FcChar8 = ptr::null_mut();
+
+        let result = FcPatternGetString(*font,
+                                        b"fontformat\0".as_ptr() as *mut c_char,
+                                        id,
+                                        &mut format);
+
+        if result != FcResultMatch {
+            continue;
+        }
+
+        let format = fc_char8_to_string(format);
+
+        // Bitmap formats only have fixed sizes, which the rasterizer selects
+        // between rather than scaling
+        match &format[..] {
+            "TrueType" | "CFF" | "PCF" | "BDF" => (),
+            _ => continue,
+        }
+
+        let mut id = 0;
+        while FcPatternGetString(*font, b"family\0".as_ptr() as *mut c_char, id, &mut family) == FcResultMatch {
+            let safe_family = fc_char8_to_string(family);
+            id += 1;
+            families.push(safe_family);
         }
     }
 
@@ -78,11 +89,49 @@ fn list_families() -> Vec<String> {
     families
 }
 
-#[derive(Debug)]
+/// fontconfig weight of a regular face
+pub const WEIGHT_REGULAR: u16 = 80;
+
+/// fontconfig weight of a bold face
+pub const WEIGHT_BOLD: u16 = 200;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Slant {
+    Roman,
+    Italic,
+    Oblique,
+}
+
+impl Slant {
+    /// Convert fontconfig's `FC_SLANT` value
+    fn from_fc(slant: c_int) -> Slant {
+        match slant {
+            100 => Slant::Italic,
+            110 => Slant::Oblique,
+            _ => Slant::Roman,
+        }
+    }
+
+    /// How far apart two slants are when choosing a face
+    ///
+    /// Italic and oblique are interchangeable enough to prefer either one over
+    /// an upright face.
+    fn distance(self, other: Slant) -> u32 {
+        match (self, other) {
+            (a, b) if a == b => 0,
+            (Slant::Roman, _) | (_, Slant::Roman) => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Variant {
     style: String,
     file: PathBuf,
     index: isize,
+    weight: u16,
+    slant: Slant,
 }
 
 impl Variant {
@@ -95,9 +144,25 @@ impl Variant {
     pub fn index(&self) -> isize {
         self.index
     }
+
+    #[inline]
+    pub fn style(&self) -> &str {
+        &self.style
+    }
+
+    /// fontconfig weight; 80 is regular and 200 is bold
+    #[inline]
+    pub fn weight(&self) -> u16 {
+        self.weight
+    }
+
+    #[inline]
+    pub fn slant(&self) -> Slant {
+        self.slant
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Family {
     name: String,
     variants: HashMap<String, Variant>,
@@ -119,64 +184,208 @@ impl Family {
     pub fn variants(&self) -> &HashMap<String, Variant> {
         &self.variants
     }
+
+    /// Get the variant closest to the requested weight and slant
+    ///
+    /// Matching the slant takes priority over the weight. Returns `None` when
+    /// the family has no variants.
+    pub fn best_match(&self, weight: u16, slant: Slant) -> Option<&Variant> {
+        self.variants.values().min_by_key(|variant| {
+            let weight_distance = (variant.weight as i32 - weight as i32).abs() as u32;
+            let score = variant.slant.distance(slant) * 1000 + weight_distance;
+
+            // Style breaks ties so the choice doesn't depend on hash order
+            (score, variant.style.clone())
+        })
+    }
+
+    /// Get the variant with the style of `desc`
+    ///
+    /// Styles are compared ignoring case and whitespace, so "semibold" finds
+    /// "Semi Bold". When the family has no such style, the regular variant is
+    /// used instead. The name of `desc` isn't checked against the family.
+    pub fn variant_for(&self, desc: &FontDesc) -> Option<&Variant> {
+        self.exact_variant_for(desc).or_else(|| self.regular())
+    }
+
+    /// Get the variant with the style of `desc`, or `None` when the family has
+    /// no such style
+    ///
+    /// Styles are compared as in `variant_for`. Asking for "Regular" or
+    /// "Book" finds the regular variant whatever the family calls it.
+    pub fn exact_variant_for(&self, desc: &FontDesc) -> Option<&Variant> {
+        let wanted = normalize_style(&desc.style);
+
+        self.variants.values()
+            .find(|variant| normalize_style(&variant.style) == wanted)
+            .or_else(|| {
+                if wanted == "regular" || wanted == "book" {
+                    self.regular()
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// The upright variant of regular weight, if the family has one
+    fn regular(&self) -> Option<&Variant> {
+        self.variants.values()
+            .find(|variant| {
+                let style = normalize_style(&variant.style);
+                style == "regular" || style == "book"
+            })
+            .or_else(|| self.best_match(WEIGHT_REGULAR, Slant::Roman))
+    }
+}
+
+/// Lowercase a style and strip its whitespace for comparisons
+fn normalize_style(style: &str) -> String {
+    style.chars()
+         .filter(|c| !c.is_whitespace())
+         .flat_map(|c| c.to_lowercase())
+         .collect()
 }
 
 static FILE: &'static [u8] = b"file\0";
 static FAMILY: &'static [u8] = b"family\0";
 static INDEX: &'static [u8] = b"index\0";
 static STYLE: &'static [u8] = b"style\0";
+static WEIGHT: &'static [u8] = b"weight\0";
+static SLANT: &'static [u8] = b"slant\0";
+
+/// Families of the system fonts which have already been looked up
+///
+/// Listing a family's fonts is slow with many fonts installed.
+/// `get_family_info` shares one cache across the process; a `FamilyCache` of
+/// its own is unaffected by other users of that one.
+#[derive(Debug, Default)]
+pub struct FamilyCache {
+    families: HashMap<String, Family>,
+
+    /// Number of lookups which missed the cache
+    misses: usize,
+}
 
-pub fn get_family_info(family: String) -> Family {
+impl FamilyCache {
+    pub fn new() -> FamilyCache {
+        Default::default()
+    }
 
-    let mut members = Vec::new();
+    /// Get the variants of a system font family, looking them up if they
+    /// aren't cached
+    pub fn get(&mut self, family: String) -> Family {
+        if let Some(info) = self.families.get(&family) {
+            return info.clone();
+        }
+
+        self.misses += 1;
+        let info = unsafe {
+            // https://www.freedesktop.org/software/fontconfig/fontconfig-devel/fcconfiggetcurrent.html
+            let config = FcConfigGetCurrent(); // *mut FcConfig
+            family_info(config, FcSetSystem, family)
+        };
+
+        self.families.insert(info.name.clone(), info.clone());
+        info
+    }
+
+    /// Forget the cached families
+    ///
+    /// Call this when the installed fonts change so that the next lookup sees
+    /// the new variants.
+    pub fn clear(&mut self) {
+        self.families.clear();
+    }
+}
 
+static FONT_CACHE_INIT: Once = ONCE_INIT;
+static mut FONT_CACHE: *const Mutex<FamilyCache> = 0 as *const _;
+
+/// The cache shared by `get_family_info`
+fn font_cache() -> &'static Mutex<FamilyCache> {
     unsafe {
-        let config = FcConfigGetCurrent(); // *mut FcConfig
-        let mut font_set = FcConfigGetFonts(config, FcSetSystem); // *mut FcFontSet
-
-        let pattern = FcPatternCreate();
-        let family_name = CString::new(&family[..]).unwrap();
-        let family_name = family_name.as_ptr();
-
-        // Add family name to pattern. Use this for searching.
-        FcPatternAddString(pattern, FAMILY.as_ptr() as *mut c_char, family_name as *mut FcChar8);
-
-        // Request filename, style, and index for each variant in family
-        let object_set = FcObjectSetCreate(); // *mut FcObjectSet
-        FcObjectSetAdd(object_set, FILE.as_ptr() as *mut c_char);
-        FcObjectSetAdd(object_set, INDEX.as_ptr() as *mut c_char);
-        FcObjectSetAdd(object_set, STYLE.as_ptr() as *mut c_char);
-
-        let variants = FcFontSetList(config, &mut font_set, 1 /* nsets */, pattern, object_set);
-        let num_variant = (*variants).nfont as isize;
-
-        for i in 0..num_variant {
-            let font = (*variants).fonts.offset(i);
-            let mut file: *mut FcChar8 = ptr::null_mut();
-            assert_eq!(FcPatternGetString(*font, FILE.as_ptr() as *mut c_char, 0, &mut file),
-                       FcResultMatch);
-            let file = fc_char8_to_string(file);
-
-            let mut style: *mut FcChar8 = ptr::null_mut();
-            assert_eq!(FcPatternGetString(*font, STYLE.as_ptr() as *mut c_char, 0, &mut style),
-                       FcResultMatch);
-            let style = fc_char8_to_string(style);
-
-            let mut index = 0 as c_int;
-            assert_eq!(FcPatternGetInteger(*font, INDEX.as_ptr() as *mut c_char, 0, &mut index),
-                       FcResultMatch);
-
-            members.push(Variant {
-                style: style,
-                file: PathBuf::from(file),
-                index: index as isize,
-            });
-        }
+        FONT_CACHE_INIT.call_once(|| {
+            FONT_CACHE = Box::into_raw(Box::new(Mutex::new(FamilyCache::new())));
+        });
 
-        FcFontSetDestroy(variants);
-        FcPatternDestroy(pattern);
-        FcObjectSetDestroy(object_set);
+        &*FONT_CACHE
     }
+}
+
+/// Forget the families cached by `get_family_info`
+///
+/// Call this when the installed fonts change so that the next lookup sees the
+/// new variants.
+pub fn clear_font_cache() {
+    font_cache().lock().unwrap().clear();
+}
+
+/// Get the variants of a system font family
+///
+/// Results are cached for the whole process; see `FamilyCache`.
+pub fn get_family_info(family: String) -> Family {
+    font_cache().lock().unwrap().get(family)
+}
+
+/// Get the variants of a family from one of the sets of `config`
+unsafe fn family_info(config: *mut FcConfig, set: FcSetName, family: String) -> Family {
+    let mut members = Vec::new();
+    let mut font_set = FcConfigGetFonts(config, set); // *mut FcFontSet
+
+    let pattern = FcPatternCreate();
+    let family_name = CString::new(&family[..]).unwrap();
+    let family_name = family_name.as_ptr();
+
+    // Add family name to pattern. Use this for searching.
+    FcPatternAddString(pattern, FAMILY.as_ptr() as *mut c_char, family_name as *mut FcChar8);
+
+    // Request filename, style, index, weight, and slant for each variant in
+    // family
+    let object_set = FcObjectSetCreate(); // *mut FcObjectSet
+    FcObjectSetAdd(object_set, FILE.as_ptr() as *mut c_char);
+    FcObjectSetAdd(object_set, INDEX.as_ptr() as *mut c_char);
+    FcObjectSetAdd(object_set, STYLE.as_ptr() as *mut c_char);
+    FcObjectSetAdd(object_set, WEIGHT.as_ptr() as *mut c_char);
+    FcObjectSetAdd(object_set, SLANT.as_ptr() as *mut c_char);
+
+    let variants = FcFontSetList(config, &mut font_set, 1 /* nsets */, pattern, object_set);
+    let num_variant = (*variants).nfont as isize;
+
+    for i in 0..num_variant {
+        let font = (*variants).fonts.offset(i);
+        let mut file: *mut FcChar8 = ptr::null_mut();
+        assert_eq!(FcPatternGetString(*font, FILE.as_ptr() as *mut c_char, 0, &mut file),
+                   FcResultMatch);
+        let file = fc_char8_to_string(file);
+
+        let mut style: *mut FcChar8 = ptr::null_mut();
+        assert_eq!(FcPatternGetString(*font, STYLE.as_ptr() as *mut c_char, 0, &mut style),
+                   FcResultMatch);
+        let style = fc_char8_to_string(style);
+
+        let mut index = 0 as c_int;
+        assert_eq!(FcPatternGetInteger(*font, INDEX.as_ptr() as *mut c_char, 0, &mut index),
+                   FcResultMatch);
+
+        // Not every font declares these; assume a regular upright face
+        let mut weight = WEIGHT_REGULAR as c_int;
+        FcPatternGetInteger(*font, WEIGHT.as_ptr() as *mut c_char, 0, &mut weight);
+
+        let mut slant = 0 as c_int;
+        FcPatternGetInteger(*font, SLANT.as_ptr() as *mut c_char, 0, &mut slant);
+
+        members.push(Variant {
+            style: style,
+            file: PathBuf::from(file),
+            index: index as isize,
+            weight: weight as u16,
+            slant: Slant::from_fc(slant),
+        });
+    }
+
+    FcFontSetDestroy(variants);
+    FcPatternDestroy(pattern);
+    FcObjectSetDestroy(object_set);
 
     Family {
         name: family,
@@ -185,16 +394,195 @@ pub fn get_family_info(family: String) -> Family {
 }
 
 pub fn get_font_families() -> HashMap<String, Family> {
-    list_families().into_iter()
-                   .map(|family| (family.clone(), get_family_info(family)))
-                   .collect()
+    let families = unsafe { list_families(FcConfigGetCurrent(), FcSetSystem) };
+
+    families.into_iter()
+            .map(|family| (family.clone(), get_family_info(family)))
+            .collect()
+}
+
+/// Get the font families in a directory, ignoring the system fonts
+///
+/// Useful for bundled fonts and for tests which shouldn't depend on the fonts
+/// installed on the host.
+pub fn get_family_info_from_dir<P: AsRef<Path>>(dir: P) -> HashMap<String, Family> {
+    let dir = CString::new(dir.as_ref().as_os_str().as_bytes()).unwrap();
+
+    unsafe {
+        // A fresh config has no system fonts; the directory's fonts are added
+        // to its application set.
+        let config = FcConfigCreate();
+        if FcConfigAppFontAddDir(config, dir.as_ptr() as *const FcChar8) == 0 {
+            FcConfigDestroy(config);
+            return HashMap::new();
+        }
+
+        let families = families_in_set(config, FcSetApplication);
+        FcConfigDestroy(config);
+        families
+    }
+}
+
+unsafe fn families_in_set(config: *mut FcConfig, set: FcSetName) -> HashMap<String, Family> {
+    list_families(config, set).into_iter()
+                              .map(|family| (family.clone(), family_info(config, set, family)))
+                              .collect()
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+
+    use ::FontDesc;
+    use super::{Family, FamilyCache, Variant, Slant};
+
+    /// Directory of fonts bundled for tests
+    static FIXTURES: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+
     #[test]
     fn get_font_families() {
         let families = super::get_font_families();
         assert!(!families.is_empty());
     }
+
+    #[test]
+    fn get_family_info_from_dir() {
+        let families = super::get_family_info_from_dir(FIXTURES);
+        let family = families.get("DejaVu Sans Mono").expect("fixture family is found");
+
+        assert!(family.variants().contains_key("Book"));
+        assert!(family.variants().contains_key("Bold"));
+        assert!(family.variants()["Bold"].path().ends_with("DejaVuSansMono-Bold.ttf"));
+
+        // System fonts aren't included
+        for family in families.values() {
+            for variant in family.variants().values() {
+                assert!(variant.path().starts_with(FIXTURES));
+            }
+        }
+    }
+
+    #[test]
+    fn get_family_info_is_cached() {
+        let mut cache = FamilyCache::new();
+        let name = || String::from("DejaVu Sans Mono");
+
+        let first = cache.get(name());
+        assert_eq!(cache.misses, 1);
+        let second = cache.get(name());
+
+        assert_eq!(first, second);
+        assert_eq!(cache.misses, 1);
+
+        cache.clear();
+        cache.get(name());
+        assert_eq!(cache.misses, 2);
+    }
+
+    fn family(variants: &[(&str, u16, Slant)]) -> Family {
+        Family {
+            name: String::from("Test"),
+            variants: variants.iter().map(|&(style, weight, slant)| {
+                (String::from(style), Variant {
+                    style: String::from(style),
+                    file: PathBuf::from(format!("{}.ttf", style)),
+                    index: 0,
+                    weight: weight,
+                    slant: slant,
+                })
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn best_match_prefers_closest_weight() {
+        let family = family(&[
+            ("Light", 50, Slant::Roman),
+            ("Regular", 80, Slant::Roman),
+            ("Bold", 200, Slant::Roman),
+            ("Italic", 80, Slant::Italic),
+        ]);
+        let style = |weight, slant| family.best_match(weight, slant).unwrap().style();
+
+        assert_eq!(style(80, Slant::Roman), "Regular");
+        assert_eq!(style(100, Slant::Roman), "Regular");
+        assert_eq!(style(180, Slant::Roman), "Bold");
+        assert_eq!(style(40, Slant::Roman), "Light");
+        assert_eq!(style(200, Slant::Italic), "Italic");
+        assert_eq!(style(80, Slant::Oblique), "Italic");
+    }
+
+    #[test]
+    fn variants_have_weight_and_slant() {
+        let families = super::get_family_info_from_dir(FIXTURES);
+        let family = &families["DejaVu Sans Mono"];
+
+        let bold = family.best_match(super::WEIGHT_BOLD, Slant::Roman).unwrap();
+        assert_eq!(bold.style(), "Bold");
+        assert_eq!(bold.weight(), super::WEIGHT_BOLD);
+
+        let regular = family.best_match(super::WEIGHT_REGULAR, Slant::Roman).unwrap();
+        assert_eq!(regular.style(), "Book");
+        assert_eq!(regular.slant(), Slant::Roman);
+    }
+
+    #[test]
+    fn variant_for_exact_style() {
+        let family = family(&[
+            ("Regular", 80, Slant::Roman),
+            ("Bold", 200, Slant::Roman),
+            ("Semi Bold", 180, Slant::Roman),
+        ]);
+        let desc = FontDesc::new("Test", "Bold");
+
+        assert_eq!(family.variant_for(&desc).unwrap().style(), "Bold");
+    }
+
+    #[test]
+    fn variant_for_ignores_case_and_whitespace() {
+        let family = family(&[
+            ("Regular", 80, Slant::Roman),
+            ("Semi Bold", 180, Slant::Roman),
+        ]);
+
+        for style in &["semi bold", "SemiBold", " Semi  Bold "] {
+            let desc = FontDesc::new("Test", *style);
+            assert_eq!(family.variant_for(&desc).unwrap().style(), "Semi Bold");
+        }
+    }
+
+    #[test]
+    fn variant_for_missing_style_is_regular() {
+        let desc = FontDesc::new("Test", "Heavy");
+
+        let family = family(&[
+            ("Regular", 80, Slant::Roman),
+            ("Bold", 200, Slant::Roman),
+        ]);
+        assert_eq!(family.variant_for(&desc).unwrap().style(), "Regular");
+
+        // DejaVu calls its regular style "Book"
+        let families = super::get_family_info_from_dir(FIXTURES);
+        let dejavu = &families["DejaVu Sans Mono"];
+        assert_eq!(dejavu.variant_for(&desc).unwrap().style(), "Book");
+
+        let empty = family(&[]);
+        assert!(empty.variant_for(&desc).is_none());
+    }
+
+    #[test]
+    fn exact_variant_for_missing_style_is_none() {
+        let families = super::get_family_info_from_dir(FIXTURES);
+        let dejavu = &families["DejaVu Sans Mono"];
+
+        let bold_italic = FontDesc::new("DejaVu Sans Mono", "Bold Italic");
+        assert!(dejavu.exact_variant_for(&bold_italic).is_none());
+
+        let bold = FontDesc::new("DejaVu Sans Mono", "bold");
+        assert_eq!(dejavu.exact_variant_for(&bold).unwrap().style(), "Bold");
+
+        // The regular style is found by either name
+        let regular = FontDesc::new("DejaVu Sans Mono", "Regular");
+        assert_eq!(dejavu.exact_variant_for(&regular).unwrap().style(), "Book");
+    }
 }
@@ -14,15 +14,21 @@
 //
 //! Rasterization powered by FreeType and FontConfig
 use std::collections::HashMap;
+use std::path::Path;
+use std::slice;
 
 use freetype::Library;
 use freetype::Face;
+use freetype::bitmap::PixelMode;
+use freetype::face::LoadFlag;
+use freetype::render_mode::RenderMode;
 use freetype;
 
-mod list_fonts;
+pub mod list_fonts;
 
 use self::list_fonts::{Family, get_font_families};
-use super::{FontDesc, RasterizedGlyph, Metrics, Size, FontKey, GlyphKey};
+use super::{FontDesc, RasterizedGlyph, Metrics, Size, FontKey, GlyphKey, Error};
+use super::{RasterizerConfig, Hinting, LcdFilter, PixelFormat, Rasterize};
 
 /// Rasterizes glyphs for a single font face.
 pub struct Rasterizer {
@@ -31,7 +37,8 @@ pub struct Rasterizer {
     system_fonts: HashMap<String, Family>,
     dpi_x: u32,
     dpi_y: u32,
-    dpr: f32,
+    load_flags: LoadFlag,
+    render_mode: RenderMode,
 }
 
 #[inline]
@@ -45,93 +52,302 @@ fn to_freetype_26_6(f: f32) -> isize {
 // }
 
 impl Rasterizer {
-    pub fn new(dpi_x: f32, dpi_y: f32, device_pixel_ratio: f32) -> Rasterizer {
+    pub fn new(dpi_x: f32, dpi_y: f32, config: RasterizerConfig) -> Rasterizer {
         let library = Library::init().unwrap();
 
+        let lcd_filter = match config.lcd_filter {
+            LcdFilter::None => freetype::ffi::FT_LCD_FILTER_NONE,
+            LcdFilter::Default => freetype::ffi::FT_LCD_FILTER_DEFAULT,
+            LcdFilter::Light => freetype::ffi::FT_LCD_FILTER_LIGHT,
+            LcdFilter::Legacy => freetype::ffi::FT_LCD_FILTER_LEGACY,
+        };
+
+        unsafe {
+            freetype::ffi::FT_Library_SetLcdFilter(library.raw(), lcd_filter);
+        }
+
+        let hinting = match config.hinting {
+            Hinting::None => freetype::face::NO_HINTING,
+            Hinting::Light => freetype::face::TARGET_LIGHT,
+            Hinting::Full if !config.antialias => freetype::face::TARGET_MONO,
+            Hinting::Full if config.subpixel => freetype::face::TARGET_LCD,
+            Hinting::Full => freetype::face::TARGET_NORMAL,
+        };
+
+        let (load_flags, render_mode) = if !config.antialias {
+            (hinting | freetype::face::MONOCHROME, RenderMode::Mono)
+        } else if config.subpixel {
+            (hinting, RenderMode::Lcd)
+        } else {
+            (hinting, RenderMode::Normal)
+        };
+
         Rasterizer {
             system_fonts: get_font_families(),
             faces: HashMap::new(),
             library: library,
             dpi_x: dpi_x as u32,
             dpi_y: dpi_y as u32,
-            dpr: device_pixel_ratio,
+            load_flags: load_flags,
+            render_mode: render_mode,
         }
     }
 
-    pub fn metrics(&self, key: FontKey, size: Size) -> Metrics {
-        let face = self.faces.get(&key).unwrap();
-
-        let scale_size = self.dpr as f64 * size.as_f32_pts() as f64;
-
-        let em_size = face.em_size() as f64;
-        let w = face.max_advance_width() as f64;
-        let h = (face.ascender() - face.descender() + face.height()) as f64;
-
-        let w_scale = w * scale_size / em_size;
-        let h_scale = h * scale_size / em_size;
+    /// Get the size of a terminal cell in pixels for the given font
+    ///
+    /// See `Metrics::cell_size` for how the metrics are rounded.
+    pub fn cell_size(&self, key: FontKey, size: Size) -> (f32, f32) {
+        self.metrics(key, size).cell_size()
+    }
 
-        Metrics {
-            average_advance: w_scale,
-            line_height: h_scale,
-        }
+    /// Convert a font size to pixels on this rasterizer's display
+    fn pixel_size(&self, size: Size) -> f32 {
+        size.as_f32_px() * self.dpi_y as f32 / 72.
     }
 
-    pub fn load_font(&mut self, desc: &FontDesc, _size: Size) -> Option<FontKey> {
-        self.get_face(desc)
-            .map(|face| {
-                let key = FontKey::next();
-                self.faces.insert(key, face);
-                key
-            })
+    /// Load the face at `index` of a font file, bypassing family lookup
+    ///
+    /// The index selects a face in font collections such as `.ttc` files; use
+    /// 0 for other files. Returns `Error::InvalidFontFile` if FreeType can't
+    /// open the face.
+    pub fn load_font_from_file(
+        &mut self,
+        path: &Path,
+        index: isize,
+        _size: Size
+    ) -> Result<FontKey, Error> {
+        let face = self.library.new_face(path, index)
+                               .map_err(|_| Error::InvalidFontFile(path.to_owned()))?;
+        let key = FontKey::next();
+        self.faces.insert(key, face);
+        Ok(key)
     }
 
+    /// Open the face for `desc`, if its family has that exact style
+    ///
+    /// Callers fall back to other styles themselves, so a missing style must
+    /// not quietly load the regular face.
     fn get_face(&mut self, desc: &FontDesc) -> Option<Face<'static>> {
         self.system_fonts
             .get(&desc.name[..])
-            .and_then(|font| font.variants().get(&desc.style[..]))
+            .and_then(|font| font.exact_variant_for(desc))
             .map(|variant| {
                 self.library.new_face(variant.path(), variant.index())
                             .expect("TODO handle new_face error")
             })
     }
+}
+
+impl Rasterize for Rasterizer {
+    fn metrics(&self, key: FontKey, size: Size) -> Metrics {
+        let face = self.faces.get(&key).unwrap();
+
+        // Bitmap faces have no em square; use the strike's metrics instead
+        if is_bitmap_only(face) {
+            let pixel_size = self.pixel_size(size);
+            let selected = select_strike(face, pixel_size).ok().and_then(|_| face.size_metrics());
+
+            // Strikes carry no underline metrics; put a 1px line just below
+            // the baseline
+            return match selected {
+                Some(strike) => Metrics {
+                    average_advance: (strike.max_advance >> 6) as f64,
+                    line_height: (strike.height >> 6) as f64,
+                    ascent: (strike.ascender >> 6) as f64,
+                    descent: -(strike.descender >> 6) as f64,
+                    underline_position: -1.0,
+                    underline_thickness: 1.0,
+                },
+                None => nominal_strike_metrics(face, pixel_size),
+            };
+        }
 
-    pub fn get_glyph(&mut self, glyph_key: &GlyphKey) -> RasterizedGlyph {
+        let scale_size = size.as_f32_px() as f64;
+
+        let em_size = face.em_size() as f64;
+        let w = face.max_advance_width() as f64;
+        let h = (face.ascender() - face.descender() + face.height()) as f64;
+
+        let scale = |units: f64| units * scale_size / em_size;
+
+        Metrics {
+            average_advance: scale(w),
+            line_height: scale(h),
+            ascent: scale(face.ascender() as f64),
+            descent: -scale(face.descender() as f64),
+            underline_position: scale(face.underline_position() as f64),
+            underline_thickness: scale(face.underline_thickness() as f64),
+        }
+    }
+
+    fn load_font(&mut self, desc: &FontDesc, _size: Size) -> Result<FontKey, Error> {
+        let face = self.get_face(desc).ok_or_else(|| Error::MissingFont(desc.clone()))?;
+        let key = FontKey::next();
+        self.faces.insert(key, face);
+        Ok(key)
+    }
+
+    /// Get rasterized glyph for given glyph key
+    ///
+    /// Returns `Error::MissingGlyph` if the font has no glyph for the
+    /// character.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the FontKey specified in GlyphKey was not generated from `load_font`
+    fn get_glyph(&mut self, glyph_key: &GlyphKey) -> Result<RasterizedGlyph, Error> {
         let face = self.faces
             .get(&glyph_key.font_key)
             .expect("TODO handle get_face error");
 
-        let size = glyph_key.size.as_f32_pts() * self.dpr;
+        let size = glyph_key.size.as_f32_px();
         let c = glyph_key.c;
 
-        face.set_char_size(to_freetype_26_6(size), 0, self.dpi_x, self.dpi_y).unwrap();
-        face.load_char(c as usize, freetype::face::TARGET_LIGHT).unwrap();
-        let glyph = face.glyph();
-        glyph.render_glyph(freetype::render_mode::RenderMode::Lcd).unwrap();
+        // Index 0 is the "missing glyph" box; don't pretend it's the character
+        if face.get_char_index(c as usize) == 0 {
+            return Err(Error::MissingGlyph(c));
+        }
 
-        unsafe {
-            let ft_lib = self.library.raw();
-            freetype::ffi::FT_Library_SetLcdFilter(ft_lib, freetype::ffi::FT_LCD_FILTER_DEFAULT);
+        if is_bitmap_only(face) {
+            select_strike(face, self.pixel_size(glyph_key.size))?;
+        } else {
+            face.set_char_size(to_freetype_26_6(size), 0, self.dpi_x, self.dpi_y)?;
         }
 
+        face.load_char(c as usize, self.load_flags)?;
+        let glyph = face.glyph();
+        glyph.render_glyph(self.render_mode)?;
+
         let bitmap = glyph.bitmap();
-        let buf = bitmap.buffer();
-        let pitch = bitmap.pitch() as usize;
-
-        let mut packed = Vec::with_capacity((bitmap.rows() * bitmap.width()) as usize);
-        for i in 0..bitmap.rows() {
-            let start = (i as usize) * pitch;
-            let stop = start + bitmap.width() as usize;
-            packed.extend_from_slice(&buf[start..stop]);
-        }
+        let (format, width, packed) = pack_bitmap(&bitmap)?;
+
+        // The advance is in 26.6 fixed point
+        let advance = glyph.advance();
 
-        RasterizedGlyph {
+        Ok(RasterizedGlyph {
             c: c,
             top: glyph.bitmap_top(),
             left: glyph.bitmap_left(),
-            width: glyph.bitmap().width() / 3,
-            height: glyph.bitmap().rows(),
+            width: width,
+            height: bitmap.rows(),
+            advance: ((advance.x >> 6) as i32, (advance.y >> 6) as i32),
+            format: format,
             buf: packed,
-        }
+        })
+    }
+}
+
+/// Whether a face only has fixed size bitmaps, like most `.pcf` and `.bdf`
+/// fonts
+fn is_bitmap_only(face: &Face) -> bool {
+    let raw = face.raw();
+    let scalable = freetype::ffi::FT_FACE_FLAG_SCALABLE as freetype::ffi::FT_Long;
+
+    raw.face_flags & scalable == 0 && raw.num_fixed_sizes > 0
+}
+
+/// Get the fixed sizes of a bitmap face
+fn strikes<'a>(face: &'a Face) -> &'a [freetype::ffi::FT_Bitmap_Size] {
+    let raw = face.raw();
+    unsafe {
+        slice::from_raw_parts(raw.available_sizes, raw.num_fixed_sizes as usize)
+    }
+}
+
+/// Find the index of the strike of a bitmap face nearest to `pixel_size`
+fn nearest_strike(face: &Face, pixel_size: f32) -> usize {
+    let wanted = to_freetype_26_6(pixel_size) as freetype::ffi::FT_Pos;
+    strikes(face).iter()
+                 .enumerate()
+                 .min_by_key(|&(_, strike)| (strike.y_ppem - wanted).abs())
+                 .map(|(index, _)| index)
+                 .unwrap_or(0)
+}
+
+/// Estimate metrics from the nominal size of the strike nearest to
+/// `pixel_size`
+///
+/// Used when FreeType can't select the strike, so the font's own metrics for
+/// it aren't available. The ppem is taken to be above the baseline and the
+/// rest of the strike's height below it.
+fn nominal_strike_metrics(face: &Face, pixel_size: f32) -> Metrics {
+    let strike = &strikes(face)[nearest_strike(face, pixel_size)];
+    let height = strike.height as f64;
+    let ascent = ((strike.y_ppem >> 6) as f64).min(height);
+
+    Metrics {
+        average_advance: strike.width as f64,
+        line_height: height,
+        ascent: ascent,
+        descent: height - ascent,
+        underline_position: -1.0,
+        underline_thickness: 1.0,
+    }
+}
+
+/// Select the strike of a bitmap face nearest to `pixel_size`
+///
+/// Bitmap faces can't be scaled, so glyphs are rendered at the size of the
+/// strike rather than the requested size.
+fn select_strike(face: &Face, pixel_size: f32) -> Result<(), Error> {
+    let raw = face.raw();
+    let index = nearest_strike(face, pixel_size);
+
+    let err = unsafe {
+        freetype::ffi::FT_Select_Size(raw as *const _ as freetype::ffi::FT_Face,
+                                      index as freetype::ffi::FT_Int)
+    };
+
+    if err == 0 {
+        Ok(())
+    } else {
+        Err(freetype::Error::from(err).into())
+    }
+}
+
+/// Pack a rendered bitmap into coverage bytes with no row padding
+///
+/// LCD bitmaps keep a byte per subpixel. Grayscale bitmaps, which FreeType
+/// also produces for LCD rendering when built without subpixel support, and
+/// monochrome bitmaps get a byte per pixel. Returns the format and the width
+/// in pixels along with the buffer.
+fn pack_bitmap(bitmap: &freetype::Bitmap) -> Result<(PixelFormat, i32, Vec<u8>), Error> {
+    let buf = bitmap.buffer();
+    let pitch = bitmap.pitch().abs() as usize;
+    let rows = bitmap.rows() as usize;
+    let width = bitmap.width() as usize;
+
+    let mut packed = Vec::with_capacity(rows * width);
+
+    match bitmap.pixel_mode()? {
+        PixelMode::Lcd => {
+            for i in 0..rows {
+                let start = i * pitch;
+                packed.extend_from_slice(&buf[start..start + width]);
+            }
+
+            Ok((PixelFormat::Rgb, (width / 3) as i32, packed))
+        },
+        PixelMode::Gray => {
+            for i in 0..rows {
+                let start = i * pitch;
+                packed.extend_from_slice(&buf[start..start + width]);
+            }
+
+            Ok((PixelFormat::Gray, width as i32, packed))
+        },
+        PixelMode::Mono => {
+            for i in 0..rows {
+                let row = &buf[i * pitch..];
+                for j in 0..width {
+                    let set = row[j / 8] & (0x80 >> (j % 8)) != 0;
+                    packed.push(if set { 255 } else { 0 });
+                }
+            }
+
+            Ok((PixelFormat::Gray, width as i32, packed))
+        },
+        mode => Err(Error::UnsupportedPixelMode(format!("{:?}", mode))),
     }
 }
 
@@ -139,9 +355,232 @@ unsafe impl Send for Rasterizer {}
 
 #[cfg(test)]
 mod tests {
-    use ::FontDesc;
+    use ::{FontDesc, GlyphKey, Size, Error, RasterizerConfig, Hinting, LcdFilter, PixelFormat};
+    use ::Rasterize;
+    use std::path::Path;
+    use super::{Rasterizer, nominal_strike_metrics};
+    use super::list_fonts::get_family_info_from_dir;
 
     fn font_desc() -> FontDesc {
-        FontDesc::new("Ubuntu Mono", "Regular")
+        FontDesc::new("DejaVu Sans Mono", "Book")
+    }
+
+    #[test]
+    fn cell_size_is_stable() {
+        let mut rasterizer = Rasterizer::new(96., 96., RasterizerConfig::default());
+        let size = Size::new(11.);
+        let font_key = rasterizer.load_font(&font_desc(), size).unwrap();
+
+        let (width, height) = rasterizer.cell_size(font_key, size);
+        assert_eq!(width, width.round());
+        assert_eq!(height, height.round());
+
+        for _ in 0..10 {
+            assert_eq!(rasterizer.cell_size(font_key, size), (width, height));
+        }
+    }
+
+    #[test]
+    fn missing_glyph() {
+        let mut rasterizer = Rasterizer::new(96., 96., RasterizerConfig::default());
+        let size = Size::new(11.);
+        let font_key = rasterizer.load_font(&font_desc(), size).unwrap();
+
+        let glyph_key = GlyphKey {
+            c: 'a',
+            font_key: font_key,
+            size: size,
+        };
+        assert!(rasterizer.get_glyph(&glyph_key).is_ok());
+
+        // Private use codepoint no font is going to have
+        let glyph_key = GlyphKey {
+            c: '\u{10fffd}',
+            font_key: font_key,
+            size: size,
+        };
+        match rasterizer.get_glyph(&glyph_key) {
+            Err(Error::MissingGlyph(c)) => assert_eq!(c, '\u{10fffd}'),
+            other => panic!("expected missing glyph; got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hinting_changes_bitmap() {
+        let size = Size::new(11.);
+
+        let rasterize = |hinting| {
+            let config = RasterizerConfig { hinting: hinting, ..RasterizerConfig::default() };
+            let mut rasterizer = Rasterizer::new(96., 96., config);
+            let font_key = rasterizer.load_font(&font_desc(), size).unwrap();
+
+            rasterizer.get_glyph(&GlyphKey {
+                c: 'g',
+                font_key: font_key,
+                size: size,
+            }).unwrap()
+        };
+
+        let unhinted = rasterize(Hinting::None);
+        let light = rasterize(Hinting::Light);
+
+        assert!(unhinted.buf != light.buf);
+    }
+
+    #[test]
+    fn subpixel_glyph_is_three_times_as_wide() {
+        let size = Size::new(11.);
+
+        let rasterize = |subpixel| {
+            // Filtering pads LCD glyphs; leave it off so only the format differs
+            let config = RasterizerConfig {
+                subpixel: subpixel,
+                lcd_filter: LcdFilter::None,
+                ..RasterizerConfig::default()
+            };
+            let mut rasterizer = Rasterizer::new(96., 96., config);
+            let font_key = rasterizer.load_font(&font_desc(), size).unwrap();
+
+            rasterizer.get_glyph(&GlyphKey {
+                c: 'W',
+                font_key: font_key,
+                size: size,
+            }).unwrap()
+        };
+
+        let gray = rasterize(false);
+        let lcd = rasterize(true);
+
+        assert_eq!(gray.format, PixelFormat::Gray);
+        assert_eq!(lcd.format, PixelFormat::Rgb);
+        assert_eq!(lcd.width, gray.width);
+        assert_eq!(lcd.height, gray.height);
+        assert_eq!(lcd.buf.len(), gray.buf.len() * 3);
+    }
+
+    #[test]
+    fn bold_italic_falls_back_to_bold() {
+        let mut rasterizer = Rasterizer::new(96., 96., RasterizerConfig::default());
+        rasterizer.system_fonts =
+            get_family_info_from_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"));
+        let size = Size::new(11.);
+
+        let regular = rasterizer.load_font(&font_desc(), size).unwrap();
+        let bold_desc = FontDesc::new("DejaVu Sans Mono", "Bold");
+        let bold = rasterizer.load_font(&bold_desc, size).unwrap();
+
+        // The fixtures have no bold italic face; loading it must fail rather
+        // than quietly load the regular face, so the renderer uses bold
+        // instead.
+        let bold_italic_desc = FontDesc::new("DejaVu Sans Mono", "Bold Italic");
+        let bold_italic = match rasterizer.load_font(&bold_italic_desc, size) {
+            Err(Error::MissingFont(desc)) => {
+                assert_eq!(desc, bold_italic_desc);
+                bold
+            },
+            other => panic!("expected missing font; got {:?}", other),
+        };
+
+        let mut glyph = |font_key| {
+            rasterizer.get_glyph(&GlyphKey {
+                c: 'W',
+                font_key: font_key,
+                size: size,
+            }).unwrap().buf
+        };
+
+        let regular_glyph = glyph(regular);
+        let bold_italic_glyph = glyph(bold_italic);
+        assert!(regular_glyph != bold_italic_glyph);
+    }
+
+    #[test]
+    fn ascent_and_descent_fill_line() {
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/DejaVuSansMono.ttf"));
+        let mut rasterizer = Rasterizer::new(96., 96., RasterizerConfig::default());
+        let size = Size::new(11.);
+        let font_key = rasterizer.load_font_from_file(path, 0, size).unwrap();
+
+        // Lines may leave a gap, but glyphs must never overlap the next one
+        let metrics = rasterizer.metrics(font_key, size);
+        assert!(metrics.line_height > metrics.ascent + metrics.descent - 1.0);
+        assert!(metrics.underline_position < 0.0);
+        assert!(metrics.underline_position > -metrics.descent);
+        assert!(metrics.underline_thickness > 0.0);
+    }
+
+    #[test]
+    fn load_font_from_file() {
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/DejaVuSansMono.ttf"));
+        let mut rasterizer = Rasterizer::new(96., 96., RasterizerConfig::default());
+        let size = Size::new(11.);
+        let font_key = rasterizer.load_font_from_file(path, 0, size).unwrap();
+
+        let glyph = rasterizer.get_glyph(&GlyphKey {
+            c: 'a',
+            font_key: font_key,
+            size: size,
+        }).unwrap();
+        assert!(glyph.width > 0 && glyph.height > 0);
+        assert!(glyph.buf.iter().any(|&coverage| coverage != 0));
+
+        let missing = Path::new("/nonexistent.ttf");
+        match rasterizer.load_font_from_file(missing, 0, size) {
+            Err(Error::InvalidFontFile(path)) => assert_eq!(path, missing),
+            other => panic!("expected invalid font file; got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bitmap_font_uses_fixed_size() {
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/Fixed8x16.bdf"));
+        let mut rasterizer = Rasterizer::new(96., 96., RasterizerConfig::default());
+
+        // Much larger than the 16px strike, which is used regardless
+        let size = Size::new(30.);
+        let font_key = rasterizer.load_font_from_file(path, 0, size).unwrap();
+
+        assert_eq!(rasterizer.cell_size(font_key, size), (8., 16.));
+
+        let glyph = rasterizer.get_glyph(&GlyphKey {
+            c: 'a',
+            font_key: font_key,
+            size: size,
+        }).unwrap();
+
+        assert_eq!(glyph.format, PixelFormat::Gray);
+        assert_eq!((glyph.width, glyph.height), (8, 16));
+        assert_eq!(glyph.advance, (8, 0));
+        assert_eq!(glyph.buf.len(), 8 * 16);
+
+        // Bitmap pixels are either set or not
+        assert!(glyph.buf.iter().all(|&coverage| coverage == 0 || coverage == 255));
+        assert!(glyph.buf.iter().any(|&coverage| coverage == 255));
+
+        // The strike's nominal size gives the same cell
+        let face = &rasterizer.faces[&font_key];
+        assert_eq!(nominal_strike_metrics(face, 30.).cell_size(), (8., 16.));
+    }
+
+    #[test]
+    fn advance_of_monospace_glyph() {
+        let mut rasterizer = Rasterizer::new(96., 96., RasterizerConfig::default());
+        let size = Size::new(11.);
+        let font_key = rasterizer.load_font(&font_desc(), size).unwrap();
+        let (cell_width, _) = rasterizer.cell_size(font_key, size);
+
+        let glyph = rasterizer.get_glyph(&GlyphKey {
+            c: 'M',
+            font_key: font_key,
+            size: size,
+        }).unwrap();
+
+        let (x, y) = glyph.advance;
+        assert!(x > 0);
+        assert_eq!(y, 0);
+
+        // Glyphs of a monospace font fill about a cell
+        assert!((x - glyph.width).abs() <= 2);
+        assert!((x as f32 - cell_width).abs() <= 1.);
     }
 }
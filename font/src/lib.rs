@@ -39,6 +39,7 @@ extern crate euclid;
 extern crate libc;
 
 use std::fmt;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU32, ATOMIC_U32_INIT, Ordering};
 
 // If target isn't macos, reexport everything from ft
@@ -53,6 +54,8 @@ mod darwin;
 #[cfg(target_os = "macos")]
 pub use darwin::*;
 
+pub mod box_drawing;
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct FontDesc {
     name: String,
@@ -97,8 +100,17 @@ pub struct GlyphKey {
 }
 
 /// Font size stored as integer
+///
+/// Both the logical size in points and the size on the device are kept; the
+/// latter is what glyphs are rasterized at.
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
-pub struct Size(i32);
+pub struct Size {
+    /// Size in points multiplied by `factor()`
+    pts: i32,
+
+    /// Size in device pixels multiplied by `factor()`
+    px: i32,
+}
 
 impl Size {
     /// Scale factor between font "Size" type and point size
@@ -107,14 +119,48 @@ impl Size {
         2.0
     }
 
-    /// Create a new `Size` from a f32 size in points
+    /// Create a new `Size` from a f32 size in points on a 1x display
     pub fn new(size: f32) -> Size {
-        Size((size * Size::factor()) as i32)
+        Size::with_dpi(size, 1.0)
+    }
+
+    /// Create a new `Size` from a f32 size in points on a display with the
+    /// given scale factor
+    pub fn with_dpi(points: f32, dpi_factor: f32) -> Size {
+        Size {
+            pts: (points * Size::factor()) as i32,
+            px: (points * dpi_factor * Size::factor()) as i32,
+        }
     }
 
     /// Get the f32 size in points
     pub fn as_f32_pts(self) -> f32 {
-        self.0 as f32 / Size::factor()
+        self.pts as f32 / Size::factor()
+    }
+
+    /// Get the f32 size on the device; this is the size to rasterize at
+    pub fn as_f32_px(self) -> f32 {
+        self.px as f32 / Size::factor()
+    }
+}
+
+/// Layout of the coverage data in a `RasterizedGlyph`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PixelFormat {
+    /// One coverage byte per pixel
+    Gray,
+    /// One coverage byte per red, green and blue subpixel, in that order
+    Rgb,
+}
+
+impl PixelFormat {
+    /// Number of bytes used for each pixel
+    #[inline]
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Gray => 1,
+            PixelFormat::Rgb => 3,
+        }
     }
 }
 
@@ -124,9 +170,77 @@ pub struct RasterizedGlyph {
     pub height: i32,
     pub top: i32,
     pub left: i32,
+
+    /// Distance in whole pixels from this glyph's origin to the next one's
+    pub advance: (i32, i32),
+
+    pub format: PixelFormat,
     pub buf: Vec<u8>,
 }
 
+impl RasterizedGlyph {
+    /// Scale the glyph so the cell its font was designed for covers a cell of
+    /// `cell_width` by `cell_height` pixels
+    ///
+    /// The design cell is `metrics.average_advance` wide and spans from
+    /// `metrics.descent` below the baseline up to `metrics.ascent`. The result
+    /// covers the whole cell, with the baseline moved up from the bottom of
+    /// the cell by the scaled descent. Pixels are sampled nearest neighbor so
+    /// lines stay sharp.
+    pub fn stretch_to_cell(&self, metrics: &Metrics, cell_width: i32, cell_height: i32)
+        -> RasterizedGlyph
+    {
+        let bpp = self.format.bytes_per_pixel();
+        let design_width = metrics.average_advance.max(1.0);
+        let design_height = (metrics.ascent + metrics.descent).max(1.0);
+        let scale_x = cell_width as f64 / design_width;
+        let scale_y = cell_height as f64 / design_height;
+
+        let pixels = ::std::cmp::max(cell_width, 0) as usize *
+            ::std::cmp::max(cell_height, 0) as usize;
+        let mut buf = vec![0u8; pixels * bpp];
+        for y in 0..cell_height {
+            // Row of the glyph drawn at this row of the cell; the glyph's top
+            // row sits `ascent - top` below the top of the design cell
+            let design_y = ((y as f64 + 0.5) / scale_y).floor() as i32;
+            let row = design_y - (metrics.ascent.round() as i32 - self.top);
+            if row < 0 || row >= self.height {
+                continue;
+            }
+
+            for x in 0..cell_width {
+                let design_x = ((x as f64 + 0.5) / scale_x).floor() as i32;
+                let col = design_x - self.left;
+                if col < 0 || col >= self.width {
+                    continue;
+                }
+
+                let src = (row * self.width + col) as usize * bpp;
+                let dst = (y * cell_width + x) as usize * bpp;
+                buf[dst..(dst + bpp)].copy_from_slice(&self.buf[src..(src + bpp)]);
+            }
+        }
+
+        RasterizedGlyph {
+            c: self.c,
+            width: cell_width,
+            height: cell_height,
+            top: cell_height - (metrics.descent * scale_y).round() as i32,
+            left: 0,
+            advance: (cell_width, 0),
+            format: self.format,
+            buf: buf,
+        }
+    }
+}
+
+/// Whether `c` is a box drawing or block element character, which should join
+/// up with the same characters in the cells around it
+#[inline]
+pub fn is_box_drawing(c: char) -> bool {
+    c >= '\u{2500}' && c <= '\u{259f}'
+}
+
 struct BufDebugger<'a>(&'a [u8]);
 
 impl<'a> fmt::Debug for BufDebugger<'a> {
@@ -146,12 +260,301 @@ impl fmt::Debug for RasterizedGlyph {
             .field("height", &self.height)
             .field("top", &self.top)
             .field("left", &self.left)
+            .field("advance", &self.advance)
+            .field("format", &self.format)
             .field("buf", &BufDebugger(&self.buf[..]))
             .finish()
     }
 }
 
+/// Hinting applied to glyph outlines
+///
+/// Only used by the FreeType rasterizer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Hinting {
+    /// Use outlines as they are
+    None,
+    /// Only fit outlines vertically; preserves glyph shapes
+    Light,
+    /// Fit outlines to the pixel grid on both axes
+    Full,
+}
+
+impl Default for Hinting {
+    fn default() -> Hinting {
+        Hinting::Light
+    }
+}
+
+/// Filter reducing color fringes when rendering glyphs for LCD subpixels
+///
+/// Only used by the FreeType rasterizer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LcdFilter {
+    /// No filtering
+    None,
+    /// FreeType's default FIR filter
+    Default,
+    /// Lighter filter; sharper, but more color fringes
+    Light,
+    /// Filter used by older versions of FreeType
+    Legacy,
+}
+
+impl Default for LcdFilter {
+    fn default() -> LcdFilter {
+        LcdFilter::Default
+    }
+}
+
+/// Options controlling how glyphs are rasterized
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RasterizerConfig {
+    pub hinting: Hinting,
+    pub lcd_filter: LcdFilter,
+    pub antialias: bool,
+
+    /// Render antialiased glyphs with coverage per LCD subpixel
+    ///
+    /// Glyphs are then `PixelFormat::Rgb` rather than `PixelFormat::Gray`.
+    pub subpixel: bool,
+}
+
+impl Default for RasterizerConfig {
+    fn default() -> RasterizerConfig {
+        RasterizerConfig {
+            hinting: Hinting::default(),
+            lcd_filter: LcdFilter::default(),
+            antialias: true,
+            subpixel: true,
+        }
+    }
+}
+
+/// Errors occurring when rasterizing glyphs
+#[derive(Debug)]
+pub enum Error {
+    /// The font has no glyph for the requested character
+    MissingGlyph(char),
+
+    /// No font was found matching the description
+    MissingFont(FontDesc),
+
+    /// The file doesn't exist or doesn't contain a usable font
+    InvalidFontFile(PathBuf),
+
+    /// The glyph was rendered in a pixel format that can't be packed into a
+    /// `RasterizedGlyph`; holds the format's name
+    UnsupportedPixelMode(String),
+
+    /// Error from FreeType while loading or rendering a glyph
+    #[cfg(not(target_os = "macos"))]
+    FreeType(freetype::Error),
+}
+
+impl ::std::error::Error for Error {
+    fn cause(&self) -> Option<&::std::error::Error> {
+        match *self {
+            Error::MissingGlyph(_) |
+            Error::MissingFont(_) |
+            Error::InvalidFontFile(_) |
+            Error::UnsupportedPixelMode(_) => None,
+            #[cfg(not(target_os = "macos"))]
+            Error::FreeType(ref err) => Some(err),
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            Error::MissingGlyph(_) => "font has no glyph for character",
+            Error::MissingFont(_) => "no font matches description",
+            Error::InvalidFontFile(_) => "could not load font from file",
+            Error::UnsupportedPixelMode(_) => "glyph bitmap has an unsupported pixel mode",
+            #[cfg(not(target_os = "macos"))]
+            Error::FreeType(ref err) => ::std::error::Error::description(err),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::MissingGlyph(c) => write!(f, "font has no glyph for {:?}", c),
+            Error::MissingFont(ref desc) => {
+                write!(f, "no font matches family {:?} style {:?}", desc.name, desc.style)
+            },
+            Error::InvalidFontFile(ref path) => {
+                write!(f, "could not load font from {}", path.display())
+            },
+            Error::UnsupportedPixelMode(ref mode) => {
+                write!(f, "glyph bitmap has unsupported pixel mode {}", mode)
+            },
+            #[cfg(not(target_os = "macos"))]
+            Error::FreeType(ref err) => write!(f, "FreeType error: {}", err),
+        }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+impl From<freetype::Error> for Error {
+    fn from(val: freetype::Error) -> Error {
+        Error::FreeType(val)
+    }
+}
+
+/// Dimensions of a font in pixels
+///
+/// Vertical positions are relative to the baseline, positive upwards.
 pub struct Metrics {
     pub average_advance: f64,
+
+    /// Distance between baselines; at least the ascent and descent, with any
+    /// remainder left as a gap between lines
     pub line_height: f64,
+
+    /// Distance from the baseline to the top of the tallest glyphs
+    pub ascent: f64,
+
+    /// Distance from the baseline down to the bottom of the lowest glyphs;
+    /// positive when below the baseline
+    pub descent: f64,
+
+    /// Position of the middle of the underline, negative below the baseline
+    pub underline_position: f64,
+
+    /// Thickness of the underline
+    pub underline_thickness: f64,
+}
+
+impl Metrics {
+    /// Get the width and height of a terminal cell in pixels
+    ///
+    /// Both dimensions are rounded to the nearest whole pixel. Every column then
+    /// starts on a pixel boundary, so glyphs in the same column line up no matter
+    /// how far along the line they are.
+    pub fn cell_size(&self) -> (f32, f32) {
+        (self.average_advance.round() as f32, self.line_height.round() as f32)
+    }
+}
+
+/// Loading fonts and rasterizing their glyphs
+///
+/// Implemented by the `Rasterizer` of each platform and by `NullRasterizer`.
+pub trait Rasterize {
+    /// Load the font matching `desc` at the given size
+    fn load_font(&mut self, desc: &FontDesc, size: Size) -> Result<FontKey, Error>;
+
+    /// Get the rasterized glyph for the given key
+    fn get_glyph(&mut self, key: &GlyphKey) -> Result<RasterizedGlyph, Error>;
+
+    /// Get the metrics of a font generated from `load_font`
+    fn metrics(&self, key: FontKey, size: Size) -> Metrics;
+}
+
+/// Rasterizer which loads any font and draws nothing
+///
+/// Every glyph is empty and every font has 8x16 pixel cells. Useful for
+/// testing code which needs a rasterizer without depending on system fonts.
+#[derive(Debug, Default)]
+pub struct NullRasterizer;
+
+impl Rasterize for NullRasterizer {
+    fn load_font(&mut self, _desc: &FontDesc, _size: Size) -> Result<FontKey, Error> {
+        Ok(FontKey::next())
+    }
+
+    fn get_glyph(&mut self, key: &GlyphKey) -> Result<RasterizedGlyph, Error> {
+        Ok(RasterizedGlyph {
+            c: key.c,
+            width: 0,
+            height: 0,
+            top: 0,
+            left: 0,
+            advance: (0, 0),
+            format: PixelFormat::Gray,
+            buf: Vec::new(),
+        })
+    }
+
+    fn metrics(&self, _key: FontKey, _size: Size) -> Metrics {
+        Metrics {
+            average_advance: 8.0,
+            line_height: 16.0,
+            ascent: 12.0,
+            descent: 4.0,
+            underline_position: -2.0,
+            underline_thickness: 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Size, FontDesc, GlyphKey, Rasterize, NullRasterizer};
+    use super::{RasterizedGlyph, PixelFormat, is_box_drawing};
+
+    #[test]
+    fn size_with_dpi() {
+        let normal = Size::with_dpi(11.0, 1.0);
+        let hidpi = Size::with_dpi(11.0, 2.0);
+
+        assert_eq!(normal.as_f32_pts(), 11.0);
+        assert_eq!(hidpi.as_f32_pts(), 11.0);
+
+        assert_eq!(normal.as_f32_px(), 11.0);
+        assert_eq!(hidpi.as_f32_px(), 22.0);
+
+        assert_eq!(Size::new(11.0), normal);
+        assert!(normal != hidpi);
+    }
+
+    #[test]
+    fn null_rasterizer() {
+        let mut rasterizer = NullRasterizer;
+        let size = Size::new(11.0);
+
+        let regular = rasterizer.load_font(&FontDesc::new("Any", "Regular"), size).unwrap();
+        let bold = rasterizer.load_font(&FontDesc::new("Any", "Bold"), size).unwrap();
+        assert!(regular != bold);
+
+        let glyph = rasterizer.get_glyph(&GlyphKey { c: 'a', font_key: bold, size: size });
+        let glyph = glyph.unwrap();
+        assert_eq!(glyph.c, 'a');
+        assert!(glyph.buf.is_empty());
+
+        let metrics = rasterizer.metrics(regular, size);
+        assert_eq!(metrics.cell_size(), (8.0, 16.0));
+    }
+
+    #[test]
+    fn stretch_box_drawing_to_cell() {
+        assert!(is_box_drawing('\u{2502}'));
+        assert!(is_box_drawing('\u{2588}'));
+        assert!(!is_box_drawing('a'));
+
+        // A vertical line in the middle of an 8x16 design cell, 2 pixels short
+        // of its top and bottom
+        let metrics = NullRasterizer.metrics(super::FontKey::next(), Size::new(11.0));
+        let glyph = RasterizedGlyph {
+            c: '\u{2502}',
+            width: 2,
+            height: 12,
+            top: 10,
+            left: 3,
+            advance: (8, 0),
+            format: PixelFormat::Gray,
+            buf: vec![0xff; 2 * 12],
+        };
+
+        let stretched = glyph.stretch_to_cell(&metrics, 10, 20);
+        assert_eq!((stretched.width, stretched.height), (10, 20));
+        assert_eq!(stretched.left, 0);
+        assert_eq!(stretched.top, 15);
+        assert_eq!(stretched.buf.len(), 10 * 20);
+
+        // Still a line in the middle, scaled along with the cell
+        let row = |y: usize| stretched.buf[y * 10..(y + 1) * 10].to_vec();
+        assert_eq!(row(10), vec![0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 0]);
+        assert_eq!(row(0), vec![0; 10]);
+    }
 }
@@ -31,6 +31,7 @@
 //! should be, feel free to add it. Please try not to become overzealous and adding support for
 //! sequences only used by folks trapped in 1988.
 use std::ops::Range;
+use std::str;
 
 use vte;
 
@@ -45,14 +46,93 @@ pub struct Processor {
 }
 
 /// Internal state for VTE processor
-struct ProcessorState;
+struct ProcessorState {
+    /// Bytes of the OSC string currently being received
+    osc: Vec<u8>,
+
+    /// Where the processor is in a CSI sequence; see `scan_csi`
+    csi: CsiScan,
+
+    /// Parameter bytes of the current CSI sequence
+    csi_params: Vec<u8>,
+
+    /// Device control string currently being received, if it's one we handle,
+    /// and its data so far
+    dcs: Option<(Dcs, Vec<u8>)>,
+}
+
+/// Longest device control string collected; longer ones are dropped
+const MAX_DCS_LEN: usize = 4 * 1024 * 1024;
+
+/// Device control strings the processor collects
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Dcs {
+    /// `DCS q`; a sixel image
+    Sixel,
+
+    /// `DCS $ q`; DECRQSS
+    RequestStatus,
+}
+
+/// Position in a CSI sequence, tracked alongside vte
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum CsiScan {
+    Ground,
+    Escape,
+    Params,
+}
+
+impl ProcessorState {
+    /// Track CSI sequences which vte will ignore
+    ///
+    /// vte ignores any CSI sequence with a colon in its parameters. SGR uses
+    /// colons to separate sub-parameters (ISO 8613-6), as in `38:2::r:g:b`, so
+    /// the parameters of such sequences are collected here instead. Returns
+    /// true when an SGR sequence with colons ends; its parameters are then in
+    /// `csi_params`.
+    #[inline]
+    fn scan_csi(&mut self, byte: u8) -> bool {
+        match self.csi {
+            CsiScan::Ground => {
+                if byte == C0::ESC {
+                    self.csi = CsiScan::Escape;
+                }
+            },
+            CsiScan::Escape => {
+                if byte == b'[' {
+                    self.csi_params.clear();
+                    self.csi = CsiScan::Params;
+                } else if byte != C0::ESC {
+                    self.csi = CsiScan::Ground;
+                }
+            },
+            CsiScan::Params => {
+                match byte {
+                    C0::ESC => self.csi = CsiScan::Escape,
+                    0x30...0x3f => self.csi_params.push(byte),
+                    // Controls are executed without ending the sequence
+                    0x00...0x17 | 0x19 | 0x1c...0x1f | 0x7f => (),
+                    0x40...0x7e => {
+                        self.csi = CsiScan::Ground;
+                        return byte == b'm' && self.csi_params.contains(&b':');
+                    },
+                    // Intermediates, CAN, SUB, and anything else; none of
+                    // these can be part of an SGR sequence
+                    _ => self.csi = CsiScan::Ground,
+                }
+            },
+        }
+
+        false
+    }
+}
 
 /// Helper type that implements vte::Perform.
 ///
 /// Processor creates a Performer when running advance and passes the Performer
 /// to vte::Parser.
 struct Performer<'a, H: Handler + TermInfo + 'a> {
-    _state: &'a mut ProcessorState,
+    state: &'a mut ProcessorState,
     handler: &'a mut H
 }
 
@@ -61,7 +141,7 @@ impl<'a, H: Handler + TermInfo + 'a> Performer<'a, H> {
     #[inline]
     pub fn new<'b>(state: &'b mut ProcessorState, handler: &'b mut H) -> Performer<'b, H> {
         Performer {
-            _state: state,
+            state: state,
             handler: handler
         }
     }
@@ -70,13 +150,22 @@ impl<'a, H: Handler + TermInfo + 'a> Performer<'a, H> {
 impl Processor {
     pub fn new() -> Processor {
         Processor {
-            state: ProcessorState,
+            state: ProcessorState {
+                osc: Vec::new(),
+                csi: CsiScan::Ground,
+                csi_params: Vec::new(),
+                dcs: None,
+            },
             parser: vte::Parser::new(),
         }
     }
 
     #[inline]
     pub fn advance<H: Handler + TermInfo>(&mut self, handler: &mut H, byte: u8) {
+        if self.state.scan_csi(byte) {
+            dispatch_colon_sgr(handler, &self.state.csi_params);
+        }
+
         let mut performer = Performer::new(&mut self.state, handler);
         self.parser.advance(&mut performer, byte);
     }
@@ -118,6 +207,21 @@ pub trait Handler {
     /// Identify the terminal (should write back to the pty stream)
     fn identify_terminal(&mut self) {}
 
+    /// DA2 - Report the terminal type and version
+    fn secondary_device_attributes(&mut self) {}
+
+    /// XTVERSION - Report the terminal name and version
+    fn report_terminal_version(&mut self) {}
+
+    /// Sixel image data, from `DCS q` up to the string terminator
+    ///
+    /// See `sixel::decode`.
+    fn sixel(&mut self, _data: &[u8]) {}
+
+    /// DECRQSS - Report a setting, named by the final bytes of the control
+    /// sequence which sets it
+    fn report_setting(&mut self, _setting: &str) {}
+
     /// Move cursor forward `cols`
     fn move_forward(&mut self, Column) {}
 
@@ -197,6 +301,16 @@ pub trait Handler {
     /// Clear screen
     fn clear_screen(&mut self, _mode: ClearMode) {}
 
+    /// DECSEL - Clear current line, leaving cells protected by DECSCA
+    fn selective_clear_line(&mut self, _mode: LineClearMode) {}
+
+    /// DECSED - Clear screen, leaving cells protected by DECSCA
+    fn selective_clear_screen(&mut self, _mode: ClearMode) {}
+
+    /// DECSCA - Set whether characters written from now on are protected from
+    /// selective erase
+    fn set_protected_area(&mut self, _protected: bool) {}
+
     /// Clear tab stops
     fn clear_tabs(&mut self, _mode: TabulationClearMode) {}
 
@@ -226,6 +340,34 @@ pub trait Handler {
 
     /// DECKPNM - Set keypad to numeric mode (digits intead of ESCape seq)
     fn unset_keypad_application_mode(&mut self) {}
+
+    /// OSC 10/11 - Set the default foreground or background color
+    fn set_dynamic_color(&mut self, DynamicColor, Rgb) {}
+
+    /// OSC 10/11 - Report the default foreground or background color (should
+    /// write back to the pty stream)
+    fn report_dynamic_color(&mut self, DynamicColor) {}
+
+    /// Designate the character set for one of the G0-G3 slots
+    fn configure_charset(&mut self, CharsetIndex, StandardCharset) {}
+
+    /// SI/SO - Select the slot whose character set is used for input
+    fn set_active_charset(&mut self, CharsetIndex) {}
+
+    /// DECRQM - Report whether a mode is set (should write back to the pty
+    /// stream)
+    fn report_mode(&mut self, _private: bool, _mode: u16) {}
+
+    /// XTWINOPS - Manipulate the window or report its state; the first
+    /// parameter selects the operation
+    fn window_op(&mut self, _params: &[u16]) {}
+
+    /// DECSTR - Soft terminal reset; modes, margins and attributes are reset
+    /// but the screen and cursor are left alone
+    fn soft_reset(&mut self) {}
+
+    /// DECSCUSR - Set the cursor style; `None` restores the default style
+    fn set_cursor_style(&mut self, Option<CursorStyle>) {}
 }
 
 /// Terminal modes
@@ -233,14 +375,26 @@ pub trait Handler {
 pub enum Mode {
     /// ?1
     CursorKeys = 1,
+    /// ?5
+    ReverseScreen = 5,
     /// ?6
     Origin = 6,
     /// ?12
     BlinkingCursor = 12,
     /// ?25
     ShowCursor = 25,
+    /// ?45
+    ReverseWraparound = 45,
+    /// ?47
+    SwapScreen = 47,
+    /// ?1004
+    ReportFocusInOut = 1004,
+    /// ?1047
+    SwapScreenAndClear = 1047,
     /// ?1049
     SwapScreenAndSetRestoreCursor = 1049,
+    /// ?2026
+    SyncUpdate = 2026,
 }
 
 impl Mode {
@@ -251,10 +405,16 @@ impl Mode {
         if private {
             Some(match num {
                 1 => Mode::CursorKeys,
+                5 => Mode::ReverseScreen,
                 6 => Mode::Origin,
                 12 => Mode::BlinkingCursor,
                 25 => Mode::ShowCursor,
+                45 => Mode::ReverseWraparound,
+                47 => Mode::SwapScreen,
+                1004 => Mode::ReportFocusInOut,
+                1047 => Mode::SwapScreenAndClear,
                 1049 => Mode::SwapScreenAndSetRestoreCursor,
+                2026 => Mode::SyncUpdate,
                 _ => return None
             })
         } else {
@@ -288,6 +448,8 @@ pub enum ClearMode {
     Above,
     /// Clear entire terminal
     All,
+    /// Clear scrollback history
+    Saved,
 }
 
 /// Mode for clearing tab stops
@@ -299,6 +461,146 @@ pub enum TabulationClearMode {
     All,
 }
 
+/// Shape of the cursor
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CursorShape {
+    /// Fills the cell
+    Block,
+    /// Line along the bottom of the cell
+    Underline,
+    /// Line along the left edge of the cell
+    Beam,
+}
+
+/// How the cursor is drawn
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CursorStyle {
+    pub shape: CursorShape,
+    pub blinking: bool,
+}
+
+impl Default for CursorStyle {
+    fn default() -> CursorStyle {
+        CursorStyle { shape: CursorShape::Block, blinking: false }
+    }
+}
+
+impl CursorStyle {
+    /// Create a style from a DECSCUSR parameter
+    ///
+    /// 0 selects the default style, which gives `None` like unknown values.
+    pub fn from_primitive(num: i64) -> Option<CursorStyle> {
+        let shape = match num {
+            1 | 2 => CursorShape::Block,
+            3 | 4 => CursorShape::Underline,
+            5 | 6 => CursorShape::Beam,
+            _ => return None,
+        };
+
+        Some(CursorStyle { shape: shape, blinking: num % 2 == 1 })
+    }
+
+    /// Get the DECSCUSR parameter selecting this style
+    pub fn to_primitive(&self) -> i64 {
+        let base = match self.shape {
+            CursorShape::Block => 1,
+            CursorShape::Underline => 3,
+            CursorShape::Beam => 5,
+        };
+
+        if self.blinking { base } else { base + 1 }
+    }
+}
+
+/// Colors which may be queried or set with OSC 10 and 11
+///
+/// The discriminant is the OSC number used for the color.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DynamicColor {
+    /// Default foreground color
+    Foreground = 10,
+    /// Default background color
+    Background = 11,
+}
+
+/// Slots which character sets are designated to
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CharsetIndex {
+    /// Active by default; selected by SI
+    G0,
+    /// Selected by SO
+    G1,
+    G2,
+    G3,
+}
+
+impl Default for CharsetIndex {
+    fn default() -> CharsetIndex {
+        CharsetIndex::G0
+    }
+}
+
+/// Character sets which may be designated to a slot
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StandardCharset {
+    Ascii,
+    /// DEC Special Character and Line Drawing Set
+    SpecialCharacterAndLineDrawing,
+}
+
+impl Default for StandardCharset {
+    fn default() -> StandardCharset {
+        StandardCharset::Ascii
+    }
+}
+
+impl StandardCharset {
+    /// Map a character through the character set
+    #[inline]
+    pub fn map(&self, c: char) -> char {
+        match *self {
+            StandardCharset::Ascii => c,
+            StandardCharset::SpecialCharacterAndLineDrawing => {
+                match c {
+                    '_' => ' ',
+                    '`' => '◆',
+                    'a' => '▒',
+                    'b' => '␉',
+                    'c' => '␌',
+                    'd' => '␍',
+                    'e' => '␊',
+                    'f' => '°',
+                    'g' => '±',
+                    'h' => '␤',
+                    'i' => '␋',
+                    'j' => '┘',
+                    'k' => '┐',
+                    'l' => '┌',
+                    'm' => '└',
+                    'n' => '┼',
+                    'o' => '⎺',
+                    'p' => '⎻',
+                    'q' => '─',
+                    'r' => '⎼',
+                    's' => '⎽',
+                    't' => '├',
+                    'u' => '┤',
+                    'v' => '┴',
+                    'w' => '┬',
+                    'x' => '│',
+                    'y' => '≤',
+                    'z' => '≥',
+                    '{' => 'π',
+                    '|' => '≠',
+                    '}' => '£',
+                    '~' => '·',
+                    _ => c,
+                }
+            },
+        }
+    }
+}
+
 /// Standard colors
 ///
 /// The order here matters since the enum should be castable to a `usize` for
@@ -339,6 +641,26 @@ pub enum Color {
     BrightWhite,
 }
 
+/// Standard colors in palette order
+static NAMED_COLORS: [Color; 16] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::White,
+    Color::BrightBlack,
+    Color::BrightRed,
+    Color::BrightGreen,
+    Color::BrightYellow,
+    Color::BrightBlue,
+    Color::BrightMagenta,
+    Color::BrightCyan,
+    Color::BrightWhite,
+];
+
 /// Terminal character attributes
 #[derive(Debug, Eq, PartialEq)]
 pub enum Attr {
@@ -352,6 +674,10 @@ pub enum Attr {
     Italic,
     /// Underscore text
     Underscore,
+    /// Underscore text twice
+    DoubleUnderline,
+    /// Underscore text with a wavy line
+    Undercurl,
     /// Blink cursor slowly
     BlinkSlow,
     /// Blink cursor fast
@@ -376,6 +702,10 @@ pub enum Attr {
     CancelHidden,
     /// Cancel strike through
     CancelStrike,
+    /// Draw a line over text
+    Overline,
+    /// Cancel overline
+    CancelOverline,
     /// Set indexed foreground color
     Foreground(Color),
     /// Set specific foreground color
@@ -388,6 +718,12 @@ pub enum Attr {
     DefaultForeground,
     /// Set default background
     DefaultBackground,
+    /// Set indexed underline color
+    UnderlineColor(Color),
+    /// Set specific underline color
+    UnderlineColorSpec(Rgb),
+    /// Draw underlines in the foreground color
+    DefaultUnderlineColor,
 }
 
 impl<'a, H: Handler + TermInfo + 'a> vte::Perform for Performer<'a, H> {
@@ -405,42 +741,102 @@ impl<'a, H: Handler + TermInfo + 'a> vte::Perform for Performer<'a, H> {
             C0::LF | C0::VT | C0::FF => self.handler.linefeed(),
             C0::BEL => self.handler.bell(),
             C0::SUB => self.handler.substitute(),
+            C0::SI => self.handler.set_active_charset(CharsetIndex::G0),
+            C0::SO => self.handler.set_active_charset(CharsetIndex::G1),
             C1::NEL => self.handler.newline(),
             C1::HTS => self.handler.set_horizontal_tabstop(),
             C1::DECID => self.handler.identify_terminal(),
-            _ => err_println!("[unhandled] execute byte={:02x}", byte)
+            _ => debug!("[unhandled] execute byte={:02x}", byte)
         }
     }
 
     #[inline]
     fn hook(&mut self, params: &[i64], intermediates: &[u8], ignore: bool, byte: u8) {
-        err_println!("[unhandled hook] params={:?}, ints: {:?}, ignore: {:?}, byte={:?}",
-                     params, intermediates, ignore, byte as char);
+        let dcs = match (byte, intermediates.first()) {
+            (b'q', None) => Some(Dcs::Sixel),
+            (b'q', Some(&b'$')) if intermediates.len() == 1 => Some(Dcs::RequestStatus),
+            _ => {
+                debug!("[unhandled hook] params={:?}, ints: {:?}, ignore: {:?}, byte={:?}",
+                       params, intermediates, ignore, byte as char);
+                None
+            }
+        };
+
+        self.state.dcs = dcs.map(|dcs| (dcs, Vec::new()));
     }
 
     #[inline]
     fn put(&mut self, byte: u8) {
-        err_println!("[unhandled put] byte={:?}", byte);
+        let overflow = match self.state.dcs {
+            Some((_, ref mut data)) if data.len() < MAX_DCS_LEN => {
+                data.push(byte);
+                false
+            },
+            Some(_) => true,
+            None => {
+                debug!("[unhandled put] byte={:?}", byte);
+                false
+            },
+        };
+
+        if overflow {
+            debug!("[unhandled put] device control string longer than {} bytes", MAX_DCS_LEN);
+            self.state.dcs = None;
+        }
     }
 
     #[inline]
     fn unhook(&mut self, byte: u8) {
-        err_println!("[unhandled unhook] byte={:?}", byte);
+        match self.state.dcs.take() {
+            Some((Dcs::Sixel, data)) => self.handler.sixel(&data),
+            Some((Dcs::RequestStatus, data)) => {
+                self.handler.report_setting(&String::from_utf8_lossy(&data));
+            },
+            None => debug!("[unhandled unhook] byte={:?}", byte),
+        }
     }
 
     #[inline]
     fn osc_start(&mut self) {
-        err_println!("[unhandled osc_start]");
+        self.state.osc.clear();
     }
 
     #[inline]
     fn osc_put(&mut self, byte: u8) {
-        err_println!("[unhandled osc_put] byte={:?}", byte as char);
+        self.state.osc.push(byte);
     }
 
     #[inline]
-    fn osc_end(&mut self, byte: u8) {
-        err_println!("[unhandled osc_end] byte={:?}", byte);
+    fn osc_end(&mut self, _byte: u8) {
+        let handler = &mut self.handler;
+        let osc = &self.state.osc[..];
+
+        macro_rules! unhandled {
+            () => {{
+                debug!("[unhandled osc] {:?}", String::from_utf8_lossy(osc));
+                return;
+            }}
+        }
+
+        let mut params = osc.split(|b| *b == b';');
+        let color = match params.next() {
+            Some(param) if param == b"10" => DynamicColor::Foreground,
+            Some(param) if param == b"11" => DynamicColor::Background,
+            _ => unhandled!(),
+        };
+
+        // OSC 10 may continue with a spec for OSC 11 and so on; only the first
+        // spec is used.
+        match params.next() {
+            Some(param) if param == b"?" => handler.report_dynamic_color(color),
+            Some(spec) => {
+                match parse_color_spec(spec) {
+                    Some(rgb) => handler.set_dynamic_color(color, rgb),
+                    None => unhandled!(),
+                }
+            },
+            None => unhandled!(),
+        }
     }
 
     #[inline]
@@ -451,8 +847,8 @@ impl<'a, H: Handler + TermInfo + 'a> vte::Perform for Performer<'a, H> {
 
         macro_rules! unhandled {
             () => {{
-                err_println!("[Unhandled CSI] action={:?}, args={:?}, intermediates={:?}",
-                             action, args, intermediates);
+                debug!("[Unhandled CSI] action={:?}, args={:?}, intermediates={:?}",
+                       action, args, intermediates);
                 return;
             }}
         }
@@ -475,6 +871,12 @@ impl<'a, H: Handler + TermInfo + 'a> vte::Perform for Performer<'a, H> {
                 handler.move_up(Line(arg_or_default!(idx: 0, default: 1) as usize));
             },
             'B' | 'e' => handler.move_down(Line(arg_or_default!(idx: 0, default: 1) as usize)),
+            'c' if intermediates.first() == Some(&b'>') => {
+                match arg_or_default!(idx: 0, default: 0) {
+                    0 => handler.secondary_device_attributes(),
+                    _ => unhandled!(),
+                }
+            },
             'c' => handler.identify_terminal(),
             'C' | 'a' => handler.move_forward(Column(arg_or_default!(idx: 0, default: 1) as usize)),
             'D' => handler.move_backward(Column(arg_or_default!(idx: 0, default: 1) as usize)),
@@ -501,10 +903,15 @@ impl<'a, H: Handler + TermInfo + 'a> vte::Perform for Performer<'a, H> {
                     0 => ClearMode::Below,
                     1 => ClearMode::Above,
                     2 => ClearMode::All,
+                    3 if !private => ClearMode::Saved,
                     _ => unhandled!(),
                 };
 
-                handler.clear_screen(mode);
+                if private {
+                    handler.selective_clear_screen(mode);
+                } else {
+                    handler.clear_screen(mode);
+                }
             },
             'K' => {
                 let mode = match arg_or_default!(idx: 0, default: 0) {
@@ -514,7 +921,11 @@ impl<'a, H: Handler + TermInfo + 'a> vte::Perform for Performer<'a, H> {
                     _ => unhandled!(),
                 };
 
-                handler.clear_line(mode);
+                if private {
+                    handler.selective_clear_line(mode);
+                } else {
+                    handler.clear_line(mode);
+                }
             },
             'S' => handler.scroll_up(Line(arg_or_default!(idx: 0, default: 1) as usize)),
             'T' => handler.scroll_down(Line(arg_or_default!(idx: 0, default: 1) as usize)),
@@ -538,94 +949,12 @@ impl<'a, H: Handler + TermInfo + 'a> vte::Perform for Performer<'a, H> {
                     None => unhandled!(),
                 }
             },
-            'm' => {
-                // Sometimes a C-style for loop is just what you need
-                let mut i = 0; // C-for initializer
-                if args.len() == 0 {
-                    handler.terminal_attribute(Attr::Reset);
-                    return;
-                }
-                loop {
-                    // println!("args.len = {}; i={}", args.len(), i);
-                    if i >= args.len() { // C-for condition
-                        break;
-                    }
-
-                    let attr = match args[i] {
-                        0 => Attr::Reset,
-                        1 => Attr::Bold,
-                        2 => Attr::Dim,
-                        3 => Attr::Italic,
-                        4 => Attr::Underscore,
-                        5 => Attr::BlinkSlow,
-                        6 => Attr::BlinkFast,
-                        7 => Attr::Reverse,
-                        8 => Attr::Hidden,
-                        9 => Attr::Strike,
-                        22 => Attr::CancelBoldDim,
-                        23 => Attr::CancelItalic,
-                        24 => Attr::CancelUnderline,
-                        25 => Attr::CancelBlink,
-                        27 => Attr::CancelReverse,
-                        28 => Attr::CancelHidden,
-                        29 => Attr::CancelStrike,
-                        30 => Attr::Foreground(Color::Black),
-                        31 => Attr::Foreground(Color::Red),
-                        32 => Attr::Foreground(Color::Green),
-                        33 => Attr::Foreground(Color::Yellow),
-                        34 => Attr::Foreground(Color::Blue),
-                        35 => Attr::Foreground(Color::Magenta),
-                        36 => Attr::Foreground(Color::Cyan),
-                        37 => Attr::Foreground(Color::White),
-                        38 => {
-                            if let Some(spec) = parse_color(&args[i..], &mut i) {
-                                Attr::ForegroundSpec(spec)
-                            } else {
-                                break;
-                            }
-                        },
-                        39 => Attr::DefaultForeground,
-                        40 => Attr::Background(Color::Black),
-                        41 => Attr::Background(Color::Red),
-                        42 => Attr::Background(Color::Green),
-                        43 => Attr::Background(Color::Yellow),
-                        44 => Attr::Background(Color::Blue),
-                        45 => Attr::Background(Color::Magenta),
-                        46 => Attr::Background(Color::Cyan),
-                        47 => Attr::Background(Color::White),
-                        48 =>  {
-                            if let Some(spec) = parse_color(&args[i..], &mut i) {
-                                Attr::BackgroundSpec(spec)
-                            } else {
-                                break;
-                            }
-                        },
-                        49 => Attr::DefaultBackground,
-                        90 => Attr::Foreground(Color::BrightBlack),
-                        91 => Attr::Foreground(Color::BrightRed),
-                        92 => Attr::Foreground(Color::BrightGreen),
-                        93 => Attr::Foreground(Color::BrightYellow),
-                        94 => Attr::Foreground(Color::BrightBlue),
-                        95 => Attr::Foreground(Color::BrightMagenta),
-                        96 => Attr::Foreground(Color::BrightCyan),
-                        97 => Attr::Foreground(Color::BrightWhite),
-                        100 => Attr::Foreground(Color::BrightBlack),
-                        101 => Attr::Foreground(Color::BrightRed),
-                        102 => Attr::Foreground(Color::BrightGreen),
-                        103 => Attr::Foreground(Color::BrightYellow),
-                        104 => Attr::Foreground(Color::BrightBlue),
-                        105 => Attr::Foreground(Color::BrightMagenta),
-                        106 => Attr::Foreground(Color::BrightCyan),
-                        107 => Attr::Foreground(Color::BrightWhite),
-                        _ => unhandled!(),
-                    };
-
-                    handler.terminal_attribute(attr);
-
-                    i += 1; // C-for expr
-                }
-            }
+            'm' => dispatch_sgr(&mut **handler, args),
             'n' => handler.identify_terminal(),
+            'p' if intermediates.last() == Some(&b'!') => handler.soft_reset(),
+            'p' if intermediates.last() == Some(&b'$') => {
+                handler.report_mode(private, arg_or_default!(idx: 0, default: 0) as u16);
+            },
             'r' => {
                 if private {
                     unhandled!();
@@ -641,7 +970,28 @@ impl<'a, H: Handler + TermInfo + 'a> vte::Perform for Performer<'a, H> {
 
                 handler.set_scrolling_region(top..bottom);
             },
+            'q' if intermediates.first() == Some(&b'>') => {
+                match arg_or_default!(idx: 0, default: 0) {
+                    0 => handler.report_terminal_version(),
+                    _ => unhandled!(),
+                }
+            },
+            'q' if intermediates.last() == Some(&b'"') => {
+                match arg_or_default!(idx: 0, default: 0) {
+                    1 => handler.set_protected_area(true),
+                    0 | 2 => handler.set_protected_area(false),
+                    _ => unhandled!(),
+                }
+            },
+            'q' if intermediates.last() == Some(&b' ') => {
+                let style = CursorStyle::from_primitive(arg_or_default!(idx: 0, default: 0));
+                handler.set_cursor_style(style);
+            },
             's' => handler.save_cursor_position(),
+            't' if !private => {
+                let params = args.iter().map(|arg| *arg as u16).collect::<Vec<_>>();
+                handler.window_op(&params);
+            },
             'u' => handler.restore_cursor_position(),
             _ => unhandled!(),
         }
@@ -649,35 +999,282 @@ impl<'a, H: Handler + TermInfo + 'a> vte::Perform for Performer<'a, H> {
 
     #[inline]
     fn esc_dispatch(&mut self, params: &[i64], intermediates: &[u8], _ignore: bool, byte: u8) {
-        match byte {
-            b'D' => self.handler.linefeed(),
-            b'E' => self.handler.newline(),
-            b'H' => self.handler.set_horizontal_tabstop(),
-            b'M' => self.handler.reverse_index(),
-            b'Z' => self.handler.identify_terminal(),
-            b'c' => self.handler.reset_state(),
-            b'7' => self.handler.save_cursor_position(),
-            b'8' => self.handler.restore_cursor_position(),
-            b'=' => self.handler.set_keypad_application_mode(),
-            b'>' => self.handler.unset_keypad_application_mode(),
-            _ => err_println!("[unhandled] esc_dispatch params={:?}, ints={:?}, byte={:?} ({:02x})",
-                              params, intermediates, byte as char, byte),
+        macro_rules! unhandled {
+            () => {{
+                debug!("[unhandled] esc_dispatch params={:?}, ints={:?}, byte={:?} ({:02x})",
+                       params, intermediates, byte as char, byte);
+                return;
+            }}
+        }
+
+        macro_rules! configure_charset {
+            ($charset:path) => {{
+                let index = match intermediates[0] {
+                    b'(' => CharsetIndex::G0,
+                    b')' => CharsetIndex::G1,
+                    b'*' => CharsetIndex::G2,
+                    b'+' => CharsetIndex::G3,
+                    _ => unhandled!(),
+                };
+                self.handler.configure_charset(index, $charset)
+            }}
+        }
+
+        match (byte, intermediates.get(0)) {
+            (b'B', Some(_)) => configure_charset!(StandardCharset::Ascii),
+            (b'0', Some(_)) => configure_charset!(StandardCharset::SpecialCharacterAndLineDrawing),
+            (b'D', None) => self.handler.linefeed(),
+            (b'E', None) => self.handler.newline(),
+            (b'H', None) => self.handler.set_horizontal_tabstop(),
+            (b'M', None) => self.handler.reverse_index(),
+            (b'Z', None) => self.handler.identify_terminal(),
+            (b'c', None) => self.handler.reset_state(),
+            (b'7', None) => self.handler.save_cursor_position(),
+            (b'8', None) => self.handler.restore_cursor_position(),
+            (b'=', None) => self.handler.set_keypad_application_mode(),
+            (b'>', None) => self.handler.unset_keypad_application_mode(),
+            _ => unhandled!(),
+        }
+    }
+}
+
+
+/// Parse an X11 color specification
+///
+/// Both `rgb:R/G/B` with 1 to 4 hex digits per component and `#RGB` with 1 to
+/// 4 hex digits per component are accepted. Components are scaled to 8 bits.
+fn parse_color_spec(spec: &[u8]) -> Option<Rgb> {
+    let spec = match str::from_utf8(spec) {
+        Ok(spec) => spec,
+        Err(_) => return None,
+    };
+
+    /// Scale a component of 1 to 4 hex digits to 8 bits
+    fn component(hex: &str) -> Option<u8> {
+        if hex.len() == 0 || hex.len() > 4 || !hex.chars().all(|c| c.is_digit(16)) {
+            return None;
+        }
+
+        let value = match u32::from_str_radix(hex, 16) {
+            Ok(value) => value,
+            Err(_) => return None,
+        };
+
+        let max = (1u32 << (4 * hex.len())) - 1;
+        Some((value * 255 / max) as u8)
+    }
+
+    let components = if spec.starts_with("rgb:") {
+        let parts = spec[4..].split('/').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return None;
+        }
+
+        (parts[0], parts[1], parts[2])
+    } else if spec.starts_with('#') {
+        let hex = &spec[1..];
+        if hex.len() == 0 || hex.len() % 3 != 0 || !hex.chars().all(|c| c.is_digit(16)) {
+            return None;
+        }
+
+        let n = hex.len() / 3;
+        (&hex[..n], &hex[n..2 * n], &hex[2 * n..])
+    } else {
+        return None;
+    };
+
+    match (component(components.0), component(components.1), component(components.2)) {
+        (Some(r), Some(g), Some(b)) => Some(Rgb { r: r, g: g, b: b }),
+        _ => None,
+    }
+}
+
+/// Run the SGR (Select Graphic Rendition) sequence with the given parameters
+fn dispatch_sgr<H: Handler>(handler: &mut H, args: &[i64]) {
+    // Sometimes a C-style for loop is just what you need
+    let mut i = 0; // C-for initializer
+    if args.len() == 0 {
+        handler.terminal_attribute(Attr::Reset);
+        return;
+    }
+    loop {
+        // println!("args.len = {}; i={}", args.len(), i);
+        if i >= args.len() { // C-for condition
+            break;
+        }
+
+        let attr = match args[i] {
+            0 => Attr::Reset,
+            1 => Attr::Bold,
+            2 => Attr::Dim,
+            3 => Attr::Italic,
+            4 => Attr::Underscore,
+            5 => Attr::BlinkSlow,
+            6 => Attr::BlinkFast,
+            7 => Attr::Reverse,
+            8 => Attr::Hidden,
+            9 => Attr::Strike,
+            21 => Attr::DoubleUnderline,
+            22 => Attr::CancelBoldDim,
+            23 => Attr::CancelItalic,
+            24 => Attr::CancelUnderline,
+            25 => Attr::CancelBlink,
+            27 => Attr::CancelReverse,
+            28 => Attr::CancelHidden,
+            29 => Attr::CancelStrike,
+            30 => Attr::Foreground(Color::Black),
+            31 => Attr::Foreground(Color::Red),
+            32 => Attr::Foreground(Color::Green),
+            33 => Attr::Foreground(Color::Yellow),
+            34 => Attr::Foreground(Color::Blue),
+            35 => Attr::Foreground(Color::Magenta),
+            36 => Attr::Foreground(Color::Cyan),
+            37 => Attr::Foreground(Color::White),
+            38 => {
+                match parse_color(args, &mut i) {
+                    Some(ExtendedColor::Named(color)) => Attr::Foreground(color),
+                    Some(ExtendedColor::Spec(spec)) => Attr::ForegroundSpec(spec),
+                    None => break,
+                }
+            },
+            39 => Attr::DefaultForeground,
+            40 => Attr::Background(Color::Black),
+            41 => Attr::Background(Color::Red),
+            42 => Attr::Background(Color::Green),
+            43 => Attr::Background(Color::Yellow),
+            44 => Attr::Background(Color::Blue),
+            45 => Attr::Background(Color::Magenta),
+            46 => Attr::Background(Color::Cyan),
+            47 => Attr::Background(Color::White),
+            48 => {
+                match parse_color(args, &mut i) {
+                    Some(ExtendedColor::Named(color)) => Attr::Background(color),
+                    Some(ExtendedColor::Spec(spec)) => Attr::BackgroundSpec(spec),
+                    None => break,
+                }
+            },
+            49 => Attr::DefaultBackground,
+            53 => Attr::Overline,
+            55 => Attr::CancelOverline,
+            58 => {
+                match parse_color(args, &mut i) {
+                    Some(ExtendedColor::Named(color)) => Attr::UnderlineColor(color),
+                    Some(ExtendedColor::Spec(spec)) => Attr::UnderlineColorSpec(spec),
+                    None => break,
+                }
+            },
+            59 => Attr::DefaultUnderlineColor,
+            90 => Attr::Foreground(Color::BrightBlack),
+            91 => Attr::Foreground(Color::BrightRed),
+            92 => Attr::Foreground(Color::BrightGreen),
+            93 => Attr::Foreground(Color::BrightYellow),
+            94 => Attr::Foreground(Color::BrightBlue),
+            95 => Attr::Foreground(Color::BrightMagenta),
+            96 => Attr::Foreground(Color::BrightCyan),
+            97 => Attr::Foreground(Color::BrightWhite),
+            100 => Attr::Background(Color::BrightBlack),
+            101 => Attr::Background(Color::BrightRed),
+            102 => Attr::Background(Color::BrightGreen),
+            103 => Attr::Background(Color::BrightYellow),
+            104 => Attr::Background(Color::BrightBlue),
+            105 => Attr::Background(Color::BrightMagenta),
+            106 => Attr::Background(Color::BrightCyan),
+            107 => Attr::Background(Color::BrightWhite),
+            _ => {
+                debug!("[Unhandled CSI] action='m', args={:?}", args);
+                return;
+            },
+        };
+
+        handler.terminal_attribute(attr);
+
+        i += 1; // C-for expr
+    }
+}
+
+/// Run an SGR sequence which has colon separated sub-parameters
+///
+/// Extended colors are rewritten into the equivalent semicolon form; the
+/// color space ID in `38:2:<id>:r:g:b` is dropped. Underline styles (`4:n`)
+/// have no semicolon form and are dispatched directly. Other parameters with
+/// sub-parameters keep only their first value.
+fn dispatch_colon_sgr<H: Handler>(handler: &mut H, params: &[u8]) {
+    let mut args = Vec::new();
+    let mut dispatched = false;
+
+    for param in params.split(|b| *b == b';') {
+        let subparams = param.split(|b| *b == b':')
+            .map(|sub| {
+                str::from_utf8(sub).ok()
+                    .and_then(|sub| sub.parse::<i64>().ok())
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+
+        let first = subparams[0];
+        if first == 4 && subparams.len() > 1 {
+            // Keep attributes in order
+            if !args.is_empty() {
+                dispatch_sgr(handler, &args);
+                args.clear();
+            }
+
+            handler.terminal_attribute(match subparams[1] {
+                0 => Attr::CancelUnderline,
+                2 => Attr::DoubleUnderline,
+                3 => Attr::Undercurl,
+                // Dotted and dashed underlines are drawn as single ones
+                _ => Attr::Underscore,
+            });
+            dispatched = true;
+            continue;
+        }
+
+        if (first != 38 && first != 48 && first != 58) || subparams.len() == 1 {
+            args.push(first);
+            continue;
+        }
+
+        let color = &subparams[1..];
+        match (color[0], color.len()) {
+            // With color space ID
+            (2, 5) => args.extend_from_slice(&[first, 2, color[2], color[3], color[4]]),
+            // Without color space ID, as sent by some programs
+            (2, 4) => args.extend_from_slice(&[first, 2, color[1], color[2], color[3]]),
+            (5, 2) => args.extend_from_slice(&[first, 5, color[1]]),
+            _ => warn!("Unexpected color attr: {:?}", subparams),
         }
     }
+
+    // No args at all is a reset
+    if !args.is_empty() || !dispatched {
+        dispatch_sgr(handler, &args);
+    }
 }
 
+/// Color selected by an extended color parameter (38, 48 and 58)
+#[derive(Debug, Eq, PartialEq)]
+enum ExtendedColor {
+    /// One of the 16 colors from the configured palette
+    Named(Color),
+    /// Any other color
+    Spec(Rgb),
+}
 
 /// Parse a color specifier from list of attributes
-fn parse_color(attrs: &[i64], i: &mut usize) -> Option<Rgb> {
-    if attrs.len() < 2 {
+///
+/// `attrs[*i]` is the 38, 48 or 58 starting the specifier; `i` is advanced to
+/// the last attribute used.
+fn parse_color(attrs: &[i64], i: &mut usize) -> Option<ExtendedColor> {
+    if attrs.len() < *i + 2 {
         return None;
     }
 
     match attrs[*i+1] {
         2 => {
             // RGB color spec
-            if attrs.len() < 5 {
-                err_println!("Expected RGB color spec; got {:?}", attrs);
+            if attrs.len() < *i + 5 {
+                warn!("Expected RGB color spec; got {:?}", attrs);
                 return None;
             }
 
@@ -689,23 +1286,349 @@ fn parse_color(attrs: &[i64], i: &mut usize) -> Option<Rgb> {
 
             let range = 0...255;
             if !range.contains(r) || !range.contains(g) || !range.contains(b) {
-                err_println!("Invalid RGB color spec: ({}, {}, {})", r, g, b);
+                warn!("Invalid RGB color spec: ({}, {}, {})", r, g, b);
                 return None;
             }
 
-            Some(Rgb {
+            Some(ExtendedColor::Spec(Rgb {
                 r: r as u8,
                 g: g as u8,
                 b: b as u8
-            })
+            }))
+        },
+        5 => {
+            // Indexed color
+            if attrs.len() < *i + 3 {
+                warn!("Expected color index; got {:?}", attrs);
+                return None;
+            }
+
+            let index = attrs[*i+2];
+
+            *i = *i + 2;
+
+            match index {
+                0...15 => Some(ExtendedColor::Named(NAMED_COLORS[index as usize])),
+                16...255 => Some(ExtendedColor::Spec(indexed_color(index as u8))),
+                _ => {
+                    warn!("Invalid color index: {}", index);
+                    None
+                }
+            }
         },
         _ => {
-            err_println!("Unexpected color attr: {}", attrs[*i+1]);
+            warn!("Unexpected color attr: {}", attrs[*i+1]);
             None
         }
     }
 }
 
+/// Get a color from the 6x6x6 cube or the grayscale ramp of the 256 color
+/// palette
+fn indexed_color(index: u8) -> Rgb {
+    if index >= 232 {
+        let level = 8 + (index - 232) * 10;
+        return Rgb { r: level, g: level, b: level };
+    }
+
+    let index = index - 16;
+    let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+
+    Rgb {
+        r: level(index / 36),
+        g: level(index / 6 % 6),
+        b: level(index % 6),
+    }
+}
+
+/// Sixel graphics
+///
+/// Only the commands needed for plain images are handled: color
+/// introduction, repeat, graphics carriage return, next line and raster
+/// attributes. HLS color definitions are ignored.
+pub mod sixel {
+    use std::cmp::{max, min};
+
+    use ::Rgb;
+
+    /// Initial color registers, from the VT340
+    const DEFAULT_PALETTE: [(u8, u8, u8); 16] = [
+        (0, 0, 0), (20, 20, 80), (80, 13, 13), (20, 80, 20),
+        (80, 20, 80), (20, 80, 80), (80, 80, 20), (53, 53, 53),
+        (26, 26, 26), (33, 33, 60), (60, 26, 26), (33, 60, 33),
+        (60, 33, 60), (33, 60, 60), (60, 60, 33), (80, 80, 80),
+    ];
+
+    /// Number of color registers
+    const REGISTERS: usize = 256;
+
+    /// Most pixels an image may have in either direction; anything drawn past
+    /// this is dropped
+    const MAX_SIZE: usize = 4096;
+
+    /// Largest image the raster attributes may size up front, in pixels;
+    /// bigger images grow as they're drawn instead
+    const MAX_HINT_PIXELS: usize = 1024 * 1024;
+
+    /// A decoded sixel image
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    pub struct Image {
+        pub width: usize,
+        pub height: usize,
+
+        /// RGBA pixels, row by row; pixels never drawn are transparent
+        pub pixels: Vec<u8>,
+    }
+
+    impl Image {
+        /// Get the RGBA value of the pixel at `x`, `y`
+        #[inline]
+        pub fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+            let i = (y * self.width + x) * 4;
+            [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+        }
+    }
+
+    /// Decode the data of a sixel image, following the `q` of its DCS
+    pub fn decode(data: &[u8]) -> Image {
+        let mut decoder = Decoder::new();
+        let mut i = 0;
+
+        while i < data.len() {
+            let byte = data[i];
+            i += 1;
+
+            match byte {
+                b'"' => {
+                    // Raster attributes; the image size is taken as a hint,
+                    // unless it's too big to allocate before seeing any pixels
+                    let params = read_params(data, &mut i);
+                    if params.len() >= 4 {
+                        let (width, height) = (min(params[2], MAX_SIZE), min(params[3], MAX_SIZE));
+                        if width * height <= MAX_HINT_PIXELS {
+                            decoder.resize(width, height);
+                        }
+                    }
+                },
+                b'#' => {
+                    let params = read_params(data, &mut i);
+                    decoder.color(&params);
+                },
+                b'!' => {
+                    let count = read_params(data, &mut i).first().cloned().unwrap_or(1);
+                    if i < data.len() {
+                        decoder.put(data[i], max(count, 1));
+                        i += 1;
+                    }
+                },
+                b'$' => decoder.x = 0,
+                b'-' => {
+                    decoder.x = 0;
+                    decoder.y += 6;
+                },
+                0x3f...0x7e => decoder.put(byte, 1),
+                _ => (),
+            }
+        }
+
+        decoder.finish()
+    }
+
+    /// Read numeric parameters separated by `;` starting at `i`
+    ///
+    /// Empty parameters are 0.
+    fn read_params(data: &[u8], i: &mut usize) -> Vec<usize> {
+        let mut params = Vec::new();
+        let mut param = 0usize;
+        let mut any = false;
+
+        while *i < data.len() {
+            match data[*i] {
+                byte @ b'0'...b'9' => {
+                    param = param.saturating_mul(10).saturating_add((byte - b'0') as usize);
+                    any = true;
+                },
+                b';' => {
+                    params.push(param);
+                    param = 0;
+                    any = true;
+                },
+                _ => break,
+            }
+            *i += 1;
+        }
+
+        if any {
+            params.push(param);
+        }
+
+        params
+    }
+
+    /// Scale a sixel color component from 0-100 to 0-255
+    #[inline]
+    fn percent(value: usize) -> u8 {
+        ((::std::cmp::min(value, 100) * 255 + 50) / 100) as u8
+    }
+
+    struct Decoder {
+        palette: Vec<Rgb>,
+        color: usize,
+
+        /// Column of the next sixel
+        x: usize,
+
+        /// Top row of the current band of sixels
+        y: usize,
+
+        width: usize,
+        height: usize,
+
+        /// Allocated size of `pixels`, which grows ahead of the image so that
+        /// drawing band by band doesn't copy it every time
+        stride: usize,
+        rows: usize,
+        pixels: Vec<u8>,
+    }
+
+    impl Decoder {
+        fn new() -> Decoder {
+            let mut palette = vec![Rgb { r: 0, g: 0, b: 0 }; REGISTERS];
+            for (register, &(r, g, b)) in palette.iter_mut().zip(DEFAULT_PALETTE.iter()) {
+                *register = Rgb {
+                    r: percent(r as usize),
+                    g: percent(g as usize),
+                    b: percent(b as usize),
+                };
+            }
+
+            Decoder {
+                palette: palette,
+                color: 0,
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 0,
+                stride: 0,
+                rows: 0,
+                pixels: Vec::new(),
+            }
+        }
+
+        /// Get the image, trimming the pixels allocated past its edges
+        fn finish(self) -> Image {
+            let pixels = if self.stride == self.width && self.rows == self.height {
+                self.pixels
+            } else {
+                let mut pixels = Vec::with_capacity(self.width * self.height * 4);
+                for row in 0..self.height {
+                    let start = row * self.stride * 4;
+                    pixels.extend_from_slice(&self.pixels[start..(start + self.width * 4)]);
+                }
+                pixels
+            };
+
+            Image {
+                width: self.width,
+                height: self.height,
+                pixels: pixels,
+            }
+        }
+
+        /// Select a color register, defining it first when a color is given
+        fn color(&mut self, params: &[usize]) {
+            let register = match params.first() {
+                Some(&register) => register % REGISTERS,
+                None => return,
+            };
+
+            self.color = register;
+            if params.len() < 5 {
+                return;
+            }
+
+            match params[1] {
+                2 => {
+                    self.palette[register] = Rgb {
+                        r: percent(params[2]),
+                        g: percent(params[3]),
+                        b: percent(params[4]),
+                    };
+                },
+                _ => debug!("[unhandled sixel] color space {}", params[1]),
+            }
+        }
+
+        /// Draw a sixel `count` times with the current color
+        fn put(&mut self, byte: u8, count: usize) {
+            if byte < 0x3f || byte > 0x7e {
+                return;
+            }
+
+            let (x, y) = (self.x, self.y);
+            let count = min(count, MAX_SIZE.saturating_sub(x));
+            if count == 0 || y + 6 > MAX_SIZE {
+                return;
+            }
+
+            let bits = byte - 0x3f;
+            let rows = (0..6usize).filter(|&bit| bits & (1u8 << bit) != 0).last().map(|bit| bit + 1);
+            self.resize(x + count, y + rows.unwrap_or(0));
+
+            let color = self.palette[self.color];
+            for bit in 0..6usize {
+                if bits & (1u8 << bit) == 0 {
+                    continue;
+                }
+
+                for col in x..(x + count) {
+                    let i = ((y + bit) * self.stride + col) * 4;
+                    self.pixels[i] = color.r;
+                    self.pixels[i + 1] = color.g;
+                    self.pixels[i + 2] = color.b;
+                    self.pixels[i + 3] = 0xff;
+                }
+            }
+
+            self.x += count;
+        }
+
+        /// Grow the image to at least `width` by `height`, keeping its pixels
+        ///
+        /// The allocation at least doubles in each direction it grows in, up to
+        /// `MAX_SIZE`.
+        fn resize(&mut self, width: usize, height: usize) {
+            self.width = max(width, self.width);
+            self.height = max(height, self.height);
+            if self.width <= self.stride && self.height <= self.rows {
+                return;
+            }
+
+            let grow = |wanted: usize, allocated: usize| {
+                if wanted <= allocated {
+                    allocated
+                } else {
+                    max(wanted, min(allocated * 2, MAX_SIZE))
+                }
+            };
+            let stride = grow(self.width, self.stride);
+            let rows = grow(self.height, self.rows);
+
+            let mut pixels = vec![0; stride * rows * 4];
+            let len = self.stride * 4;
+            for row in 0..self.rows {
+                let src = row * len;
+                let dst = row * stride * 4;
+                pixels[dst..(dst + len)].copy_from_slice(&self.pixels[src..(src + len)]);
+            }
+
+            self.stride = stride;
+            self.rows = rows;
+            self.pixels = pixels;
+        }
+    }
+}
+
 /// C0 set of 7-bit control characters (from ANSI X3.4-1977).
 #[allow(non_snake_case)]
 pub mod C0 {
@@ -857,7 +1780,8 @@ pub mod C1 {
 #[cfg(test)]
 mod tests {
     use index::{Line, Column};
-    use super::{Processor, Handler, Attr, TermInfo};
+    use super::{Processor, Handler, Attr, TermInfo, DynamicColor, Color, parse_color_spec};
+    use super::sixel;
     use ::Rgb;
 
     #[derive(Default)]
@@ -920,6 +1844,112 @@ mod tests {
         assert_eq!(handler.attr, Some(Attr::ForegroundSpec(spec)));
     }
 
+    /// Handler which keeps every attribute it receives
+    #[derive(Default)]
+    struct AttrListHandler {
+        attrs: Vec<Attr>,
+    }
+
+    impl Handler for AttrListHandler {
+        fn terminal_attribute(&mut self, attr: Attr) {
+            self.attrs.push(attr);
+        }
+    }
+
+    impl TermInfo for AttrListHandler {
+        fn lines(&self) -> Line {
+            Line(24)
+        }
+
+        fn cols(&self) -> Column {
+            Column(80)
+        }
+    }
+
+    fn parse_attrs(bytes: &[u8]) -> Vec<Attr> {
+        let mut parser = Processor::new();
+        let mut handler = AttrListHandler::default();
+
+        for byte in bytes {
+            parser.advance(&mut handler, *byte);
+        }
+
+        handler.attrs
+    }
+
+    #[test]
+    fn parse_extended_colors_after_other_attrs() {
+        let spec = Rgb { r: 128, g: 66, b: 255 };
+
+        assert_eq!(parse_attrs(b"\x1b[1;38;2;128;66;255;4m"),
+                   vec![Attr::Bold, Attr::ForegroundSpec(spec), Attr::Underscore]);
+        assert_eq!(parse_attrs(b"\x1b[1;48;5;9m"),
+                   vec![Attr::Bold, Attr::Background(Color::BrightRed)]);
+    }
+
+    #[test]
+    fn parse_bright_colors() {
+        assert_eq!(parse_attrs(b"\x1b[91m"), vec![Attr::Foreground(Color::BrightRed)]);
+        assert_eq!(parse_attrs(b"\x1b[97m"), vec![Attr::Foreground(Color::BrightWhite)]);
+        assert_eq!(parse_attrs(b"\x1b[100m"), vec![Attr::Background(Color::BrightBlack)]);
+        assert_eq!(parse_attrs(b"\x1b[104m"), vec![Attr::Background(Color::BrightBlue)]);
+    }
+
+    #[test]
+    fn parse_indexed_colors() {
+        assert_eq!(parse_attrs(b"\x1b[38;5;3m"), vec![Attr::Foreground(Color::Yellow)]);
+        assert_eq!(parse_attrs(b"\x1b[38;5;16m"),
+                   vec![Attr::ForegroundSpec(Rgb { r: 0, g: 0, b: 0 })]);
+        assert_eq!(parse_attrs(b"\x1b[38;5;196m"),
+                   vec![Attr::ForegroundSpec(Rgb { r: 255, g: 0, b: 0 })]);
+        assert_eq!(parse_attrs(b"\x1b[48;5;244m"),
+                   vec![Attr::BackgroundSpec(Rgb { r: 128, g: 128, b: 128 })]);
+    }
+
+    #[test]
+    fn parse_colon_colors_match_semicolon_colors() {
+        let pairs: &[(&[u8], &[u8])] = &[
+            (b"\x1b[38;2;128;66;255m", b"\x1b[38:2::128:66:255m"),
+            (b"\x1b[38;2;128;66;255m", b"\x1b[38:2:128:66:255m"),
+            (b"\x1b[48;2;1;2;3m", b"\x1b[48:2::1:2:3m"),
+            (b"\x1b[38;5;196m", b"\x1b[38:5:196m"),
+            (b"\x1b[48;5;4m", b"\x1b[48:5:4m"),
+            (b"\x1b[1;38;2;1;2;3;48;5;100m", b"\x1b[1;38:2::1:2:3;48:5:100m"),
+        ];
+
+        for &(semicolon, colon) in pairs {
+            let expected = parse_attrs(semicolon);
+            assert!(!expected.is_empty());
+            assert_eq!(parse_attrs(colon), expected);
+        }
+    }
+
+    #[test]
+    fn parse_underline_color() {
+        assert_eq!(parse_attrs(b"\x1b[4;58;5;1m"),
+                   vec![Attr::Underscore, Attr::UnderlineColor(Color::Red)]);
+        assert_eq!(parse_attrs(b"\x1b[58;2;10;20;30m"),
+                   vec![Attr::UnderlineColorSpec(Rgb { r: 10, g: 20, b: 30 })]);
+        assert_eq!(parse_attrs(b"\x1b[58:2::10:20:30m"),
+                   vec![Attr::UnderlineColorSpec(Rgb { r: 10, g: 20, b: 30 })]);
+        assert_eq!(parse_attrs(b"\x1b[59m"), vec![Attr::DefaultUnderlineColor]);
+    }
+
+    #[test]
+    fn parse_underline_styles() {
+        assert_eq!(parse_attrs(b"\x1b[21m"), vec![Attr::DoubleUnderline]);
+        assert_eq!(parse_attrs(b"\x1b[4:3m"), vec![Attr::Undercurl]);
+        assert_eq!(parse_attrs(b"\x1b[1;4:2;3m"),
+                   vec![Attr::Bold, Attr::DoubleUnderline, Attr::Italic]);
+        assert_eq!(parse_attrs(b"\x1b[4:1m"), vec![Attr::Underscore]);
+        assert_eq!(parse_attrs(b"\x1b[4:0m"), vec![Attr::CancelUnderline]);
+    }
+
+    #[test]
+    fn parse_overline() {
+        assert_eq!(parse_attrs(b"\x1b[53;55m"), vec![Attr::Overline, Attr::CancelOverline]);
+    }
+
     /// No exactly a test; useful for debugging
     #[test]
     fn parse_zsh_startup() {
@@ -946,4 +1976,147 @@ mod tests {
             parser.advance(&mut handler, *byte);
         }
     }
+
+    #[derive(Default)]
+    struct DynamicColorHandler {
+        set: Option<(DynamicColor, Rgb)>,
+        reported: Option<DynamicColor>,
+    }
+
+    impl Handler for DynamicColorHandler {
+        fn set_dynamic_color(&mut self, color: DynamicColor, rgb: Rgb) {
+            self.set = Some((color, rgb));
+        }
+
+        fn report_dynamic_color(&mut self, color: DynamicColor) {
+            self.reported = Some(color);
+        }
+    }
+
+    impl TermInfo for DynamicColorHandler {
+        fn lines(&self) -> Line {
+            Line(24)
+        }
+
+        fn cols(&self) -> Column {
+            Column(80)
+        }
+    }
+
+    #[test]
+    fn parse_dynamic_color_query() {
+        let mut parser = Processor::new();
+        let mut handler = DynamicColorHandler::default();
+
+        for byte in &b"\x1b]11;?\x07"[..] {
+            parser.advance(&mut handler, *byte);
+        }
+
+        assert_eq!(handler.reported, Some(DynamicColor::Background));
+        assert_eq!(handler.set, None);
+    }
+
+    #[test]
+    fn parse_dynamic_color_set() {
+        let mut parser = Processor::new();
+        let mut handler = DynamicColorHandler::default();
+
+        for byte in &b"\x1b]10;rgb:ffff/8080/00\x1b\\"[..] {
+            parser.advance(&mut handler, *byte);
+        }
+
+        let spec = Rgb { r: 255, g: 128, b: 0 };
+        assert_eq!(handler.set, Some((DynamicColor::Foreground, spec)));
+        assert_eq!(handler.reported, None);
+    }
+
+    #[derive(Default)]
+    struct SixelHandler {
+        images: Vec<usize>,
+    }
+
+    impl Handler for SixelHandler {
+        fn sixel(&mut self, data: &[u8]) {
+            self.images.push(data.len());
+        }
+    }
+
+    impl TermInfo for SixelHandler {
+        fn lines(&self) -> Line {
+            Line(24)
+        }
+
+        fn cols(&self) -> Column {
+            Column(80)
+        }
+    }
+
+    #[test]
+    fn oversized_dcs_is_dropped() {
+        let mut parser = Processor::new();
+        let mut handler = SixelHandler::default();
+
+        let mut input = b"\x1bPq".to_vec();
+        input.extend(b"~".iter().cycle().take(super::MAX_DCS_LEN + 1));
+        input.extend(b"\x1b\\\x1bPq~~\x1b\\");
+        for byte in &input {
+            parser.advance(&mut handler, *byte);
+        }
+
+        assert_eq!(handler.images, vec![2]);
+    }
+
+    #[test]
+    fn decode_sixel() {
+        let red = [0xff, 0x00, 0x00, 0xff];
+        let blue = [0x00, 0x00, 0xff, 0xff];
+        let clear = [0x00, 0x00, 0x00, 0x00];
+
+        // Two full red columns, then a blue pixel at the top of the next band
+        let image = sixel::decode(b"#1;2;100;0;0#1!2~-#2;2;0;0;100#2@");
+        assert_eq!((image.width, image.height), (2, 7));
+        assert_eq!(image.pixels.len(), 2 * 7 * 4);
+
+        for y in 0..6 {
+            assert_eq!(image.pixel(0, y), red);
+            assert_eq!(image.pixel(1, y), red);
+        }
+        assert_eq!(image.pixel(0, 6), blue);
+        assert_eq!(image.pixel(1, 6), clear);
+
+        // Empty sixels advance without growing the image downwards
+        let image = sixel::decode(b"\"1;1;3;2#1;2;100;0;0#1@?@");
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(image.pixel(0, 0), red);
+        assert_eq!(image.pixel(1, 0), clear);
+        assert_eq!(image.pixel(2, 0), red);
+        assert_eq!(image.pixel(2, 1), clear);
+
+        // Growing band by band, down to the size limit
+        let mut data = b"#1;2;100;0;0#1".to_vec();
+        data.extend(b"~-".iter().cycle().take(2 * 1000));
+        let image = sixel::decode(&data);
+        assert_eq!((image.width, image.height), (1, 4092));
+        assert_eq!(image.pixels.len(), 4092 * 4);
+        assert_eq!(image.pixel(0, 4091), red);
+
+        // A huge raster size isn't allocated before anything is drawn
+        let image = sixel::decode(b"\"1;1;4096;4096#1@");
+        assert_eq!((image.width, image.height), (1, 1));
+    }
+
+    #[test]
+    fn parse_color_specs() {
+        let spec = Some(Rgb { r: 0xff, g: 0x80, b: 0x00 });
+
+        assert_eq!(parse_color_spec(b"rgb:ff/80/00"), spec);
+        assert_eq!(parse_color_spec(b"rgb:ffff/8080/0000"), spec);
+        assert_eq!(parse_color_spec(b"rgb:f/8/0"), Some(Rgb { r: 0xff, g: 0x88, b: 0x00 }));
+        assert_eq!(parse_color_spec(b"#ff8000"), spec);
+        assert_eq!(parse_color_spec(b"#ffff80800000"), spec);
+
+        assert_eq!(parse_color_spec(b"rgb:ff/80"), None);
+        assert_eq!(parse_color_spec(b"#ff80"), None);
+        assert_eq!(parse_color_spec(b"red"), None);
+    }
 }
@@ -3,13 +3,15 @@
 //! Alacritty reads from a config file at startup to determine various runtime
 //! parameters including font family and style, font size, etc. In the future,
 //! the config file will also hold user and platform specific keybindings.
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use ::Rgb;
-use font::Size;
+use font::{Size, Hinting, LcdFilter, RasterizerConfig};
 use serde_yaml;
 use serde::{self, Error as SerdeError};
 
@@ -31,6 +33,58 @@ pub struct Config {
     /// The standard ANSI colors to use
     #[serde(default)]
     colors: Colors,
+
+    /// Visual bell configuration
+    #[serde(default)]
+    visual_bell: VisualBell,
+
+    /// Should bold text in the first 8 colors use their bright variants
+    #[serde(default)]
+    draw_bold_text_with_bright_colors: bool,
+
+    /// Should pasting text with a newline be confirmed first
+    #[serde(default)]
+    confirm_paste_with_newline: bool,
+
+    /// Selection configuration
+    #[serde(default)]
+    selection: Selection,
+
+    /// How dim text is drawn
+    #[serde(default)]
+    dim: Dim,
+
+    /// Upper bound on the size of the grid
+    #[serde(default)]
+    grid_limit: GridLimit,
+
+    /// Cursor configuration
+    #[serde(default)]
+    cursor: Cursor,
+
+    /// Draw box drawing and block characters without the font
+    #[serde(default)]
+    builtin_box_drawing: bool,
+
+    /// Program run on the tty instead of the user's shell
+    #[serde(default)]
+    shell: Option<Shell>,
+
+    /// Directory the shell starts in
+    #[serde(default)]
+    working_directory: Option<PathBuf>,
+
+    /// Extra environment variables for the shell
+    #[serde(default)]
+    env: HashMap<String, String>,
+
+    /// Maximum number of lines kept in history
+    #[serde(default="default_scrollback_lines")]
+    scrollback_lines: usize,
+
+    /// What's drawn for characters no font has a glyph for
+    #[serde(default, deserialize_with="DeserializeFromStr::deserialize_from_str")]
+    missing_glyph: MissingGlyph,
 }
 
 /// Errors occurring during config loading
@@ -126,6 +180,23 @@ impl serde::de::Deserialize for Rgb {
             {
                 Ok(value.to_owned())
             }
+
+            // Unquoted hex colors may be read as integers
+            fn visit_u64<E>(&mut self, value: u64) -> ::std::result::Result<Self::Value, E>
+                where E: ::serde::de::Error
+            {
+                Ok(format!("0x{:06x}", value))
+            }
+
+            fn visit_i64<E>(&mut self, value: i64) -> ::std::result::Result<Self::Value, E>
+                where E: ::serde::de::Error
+            {
+                if value < 0 {
+                    return Err(E::custom("failed to parse rgb; expect 0xrrggbb"));
+                }
+
+                self.visit_u64(value as u64)
+            }
         }
 
         deserializer
@@ -137,6 +208,16 @@ impl serde::de::Deserialize for Rgb {
     }
 }
 
+/// Colors are written in the same `0xrrggbb` form they're read in
+#[cfg(feature = "snapshot")]
+impl serde::ser::Serialize for Rgb {
+    fn serialize<S>(&self, serializer: &mut S) -> ::std::result::Result<(), S::Error>
+        where S: serde::ser::Serializer
+    {
+        serializer.serialize_str(&format!("0x{:02x}{:02x}{:02x}", self.r, self.g, self.b)[..])
+    }
+}
+
 impl Rgb {
     fn from_str(s: &str) -> ::std::result::Result<Rgb, ()> {
         let mut chars = s.chars();
@@ -311,6 +392,98 @@ impl Config {
         self.render_timer
     }
 
+    /// Get visual bell config
+    #[inline]
+    pub fn visual_bell(&self) -> &VisualBell {
+        &self.visual_bell
+    }
+
+    /// Should bold text in the first 8 colors use their bright variants
+    #[inline]
+    pub fn draw_bold_text_with_bright_colors(&self) -> bool {
+        self.draw_bold_text_with_bright_colors
+    }
+
+    /// Should the selection be cleared when output changes a selected line
+    #[inline]
+    pub fn selection_clears_on_output(&self) -> bool {
+        self.selection.clears_on_output
+    }
+
+    /// Should box drawing and block characters be drawn without the font
+    #[inline]
+    pub fn builtin_box_drawing(&self) -> bool {
+        self.builtin_box_drawing
+    }
+
+    /// Get the program run on the tty instead of the user's shell, if any
+    #[inline]
+    pub fn shell(&self) -> Option<&Shell> {
+        self.shell.as_ref()
+    }
+
+    /// Get the directory the shell starts in, if not the current one
+    #[inline]
+    pub fn working_directory(&self) -> Option<&Path> {
+        self.working_directory.as_ref().map(|dir| dir.as_path())
+    }
+
+    /// Get the extra environment variables for the shell
+    ///
+    /// These are set after `TERM` and `COLORTERM`, so they may override them.
+    #[inline]
+    pub fn env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
+    /// Get the maximum number of lines kept in history
+    #[inline]
+    pub fn scrollback_lines(&self) -> usize {
+        self.scrollback_lines
+    }
+
+    /// Should pasting text with a newline be confirmed first
+    #[inline]
+    pub fn confirm_paste_with_newline(&self) -> bool {
+        self.confirm_paste_with_newline
+    }
+
+    /// Get selection config
+    #[inline]
+    pub fn selection(&self) -> &Selection {
+        &self.selection
+    }
+
+    /// Should the cursor blink unless a program picks a style
+    #[inline]
+    pub fn cursor_blink(&self) -> bool {
+        self.cursor.blink
+    }
+
+    /// Get how long the blinking cursor is shown and then hidden
+    #[inline]
+    pub fn cursor_blink_interval(&self) -> Duration {
+        Duration::from_millis(self.cursor.blink_interval_ms)
+    }
+
+    /// Get the upper bound on the size of the grid
+    #[inline]
+    pub fn grid_limit(&self) -> &GridLimit {
+        &self.grid_limit
+    }
+
+    /// How dim text is drawn
+    #[inline]
+    pub fn dim_strategy(&self) -> DimStrategy {
+        self.dim.strategy()
+    }
+
+    /// What's drawn for characters no font has a glyph for
+    #[inline]
+    pub fn missing_glyph(&self) -> MissingGlyph {
+        self.missing_glyph
+    }
+
     fn load_from<P: AsRef<Path>>(path: P) -> Result<Config> {
         let raw = Config::read_file(path)?;
         Ok(serde_yaml::from_str(&raw[..])?)
@@ -357,6 +530,276 @@ impl Dpi {
     }
 }
 
+/// Visual bell
+///
+/// The window flashes when the bell is rung; the flash fades out linearly over
+/// `duration` milliseconds.
+#[derive(Debug, Deserialize)]
+pub struct VisualBell {
+    /// Length of the flash in milliseconds
+    duration: u64,
+}
+
+impl Default for VisualBell {
+    fn default() -> VisualBell {
+        VisualBell { duration: 150 }
+    }
+}
+
+impl VisualBell {
+    /// Get how long the flash lasts
+    #[inline]
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration)
+    }
+}
+
+/// Cursor
+///
+/// Programs may still pick a cursor style of their own with DECSCUSR.
+#[derive(Debug, Deserialize)]
+pub struct Cursor {
+    /// Should the cursor blink
+    #[serde(default)]
+    blink: bool,
+
+    /// How long the blinking cursor is shown and then hidden, in milliseconds
+    #[serde(default="default_cursor_blink_interval_ms")]
+    blink_interval_ms: u64,
+}
+
+impl Default for Cursor {
+    fn default() -> Cursor {
+        Cursor { blink: false, blink_interval_ms: default_cursor_blink_interval_ms() }
+    }
+}
+
+fn default_cursor_blink_interval_ms() -> u64 {
+    500
+}
+
+/// Program run on the tty instead of the user's shell
+#[derive(Debug, Clone, Deserialize)]
+pub struct Shell {
+    /// Program to run; looked up in `PATH` unless it contains a slash
+    program: String,
+
+    /// Arguments passed to the program
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+impl Shell {
+    /// Get the program to run
+    #[inline]
+    pub fn program(&self) -> &str {
+        &self.program[..]
+    }
+
+    /// Get the arguments passed to the program
+    #[inline]
+    pub fn args(&self) -> &[String] {
+        &self.args[..]
+    }
+}
+
+/// Selection
+#[derive(Debug, Deserialize)]
+pub struct Selection {
+    /// Characters which end a word when expanding a selection, in addition to
+    /// whitespace
+    semantic_escape_chars: String,
+
+    /// Clear the selection when output changes a selected line
+    #[serde(default="default_true")]
+    clears_on_output: bool,
+}
+
+impl Default for Selection {
+    fn default() -> Selection {
+        Selection {
+            semantic_escape_chars: String::from(DEFAULT_SEMANTIC_ESCAPE_CHARS),
+            clears_on_output: true,
+        }
+    }
+}
+
+impl Selection {
+    /// Get the characters which end a word
+    #[inline]
+    pub fn semantic_escape_chars(&self) -> &str {
+        &self.semantic_escape_chars[..]
+    }
+}
+
+/// Upper bound on the size of the grid
+///
+/// The grid never grows past this, whatever size the window claims to be.
+#[derive(Debug, Deserialize)]
+pub struct GridLimit {
+    /// Most columns in the grid
+    columns: usize,
+
+    /// Most lines in the grid
+    lines: usize,
+}
+
+impl Default for GridLimit {
+    fn default() -> GridLimit {
+        GridLimit { columns: DEFAULT_MAX_COLUMNS, lines: DEFAULT_MAX_LINES }
+    }
+}
+
+impl GridLimit {
+    /// Get the most columns in the grid
+    #[inline]
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    /// Get the most lines in the grid
+    #[inline]
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+}
+
+/// Most columns in the grid when none are configured
+pub const DEFAULT_MAX_COLUMNS: usize = 2000;
+
+/// Most lines in the grid when none are configured
+pub const DEFAULT_MAX_LINES: usize = 2000;
+
+/// How dim text is drawn
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DimStrategy {
+    /// Blend the foreground toward the background, keeping this much of the
+    /// foreground
+    AlphaReduce(f32),
+
+    /// Draw bright palette colors with their normal counterparts and halve
+    /// the brightness of any other color
+    PaletteHalf,
+}
+
+impl Default for DimStrategy {
+    fn default() -> DimStrategy {
+        DimStrategy::AlphaReduce(DEFAULT_DIM_ALPHA)
+    }
+}
+
+/// Share of the foreground kept by `DimStrategy::AlphaReduce` by default
+const DEFAULT_DIM_ALPHA: f32 = 0.66;
+
+/// What's drawn for characters no font has a glyph for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MissingGlyph {
+    /// Leave the cell empty
+    Blank,
+
+    /// U+FFFD REPLACEMENT CHARACTER
+    ReplacementChar,
+
+    /// An empty box
+    Box,
+}
+
+impl MissingGlyph {
+    /// Get the character drawn in place of a missing one, if any
+    #[inline]
+    pub fn placeholder(&self) -> Option<char> {
+        match *self {
+            MissingGlyph::Blank => None,
+            MissingGlyph::ReplacementChar => Some('\u{fffd}'),
+            MissingGlyph::Box => Some('\u{25a1}'),
+        }
+    }
+}
+
+impl Default for MissingGlyph {
+    fn default() -> MissingGlyph {
+        MissingGlyph::ReplacementChar
+    }
+}
+
+impl DeserializeFromStr for MissingGlyph {
+    fn from_config_str(s: &str) -> Option<MissingGlyph> {
+        match s {
+            "blank" => Some(MissingGlyph::Blank),
+            "replacement_char" => Some(MissingGlyph::ReplacementChar),
+            "box" => Some(MissingGlyph::Box),
+            _ => None,
+        }
+    }
+
+    fn expected() -> &'static str {
+        "failed to parse missing_glyph; expect one of blank, replacement_char, box"
+    }
+}
+
+/// Kind of dimming as written in the config file
+#[derive(Debug, Copy, Clone)]
+enum DimKind {
+    Alpha,
+    PaletteHalf,
+}
+
+impl Default for DimKind {
+    fn default() -> DimKind {
+        DimKind::Alpha
+    }
+}
+
+impl DeserializeFromStr for DimKind {
+    fn from_config_str(s: &str) -> Option<DimKind> {
+        match s {
+            "alpha" => Some(DimKind::Alpha),
+            "palette_half" => Some(DimKind::PaletteHalf),
+            _ => None,
+        }
+    }
+
+    fn expected() -> &'static str {
+        "failed to parse dim strategy; expect one of alpha, palette_half"
+    }
+}
+
+fn default_dim_alpha() -> f32 {
+    DEFAULT_DIM_ALPHA
+}
+
+/// Dim text
+#[derive(Debug, Deserialize)]
+pub struct Dim {
+    /// Either `alpha` or `palette_half`
+    #[serde(default, deserialize_with="DeserializeFromStr::deserialize_from_str")]
+    strategy: DimKind,
+
+    /// Share of the foreground kept by the `alpha` strategy
+    #[serde(default="default_dim_alpha")]
+    alpha: f32,
+}
+
+impl Default for Dim {
+    fn default() -> Dim {
+        Dim { strategy: DimKind::default(), alpha: DEFAULT_DIM_ALPHA }
+    }
+}
+
+impl Dim {
+    /// Get how dim text is drawn
+    #[inline]
+    pub fn strategy(&self) -> DimStrategy {
+        match self.strategy {
+            DimKind::Alpha => DimStrategy::AlphaReduce(self.alpha),
+            DimKind::PaletteHalf => DimStrategy::PaletteHalf,
+        }
+    }
+}
+
+/// Word separators used when none are configured
+pub const DEFAULT_SEMANTIC_ESCAPE_CHARS: &'static str = ",│`|:\"' ()[]{}<>";
+
 /// Modifications to font spacing
 ///
 /// The way Alacritty calculates vertical and horizontal cell sizes may not be
@@ -416,6 +859,81 @@ impl DeserializeFromF32 for Size {
     }
 }
 
+trait DeserializeFromStr : Sized {
+    fn from_config_str(&str) -> Option<Self>;
+
+    /// Description of the accepted values for error messages
+    fn expected() -> &'static str;
+
+    fn deserialize_from_str<D>(deserializer: &mut D) -> ::std::result::Result<Self, D::Error>
+        where D: serde::de::Deserializer
+    {
+        use std::marker::PhantomData;
+
+        struct StringVisitor<__D> {
+            _marker: PhantomData<__D>,
+        }
+
+        impl<__D> ::serde::de::Visitor for StringVisitor<__D>
+            where __D: ::serde::de::Deserializer
+        {
+            type Value = String;
+
+            fn visit_str<E>(&mut self, value: &str) -> ::std::result::Result<Self::Value, E>
+                where E: ::serde::de::Error
+            {
+                Ok(value.to_owned())
+            }
+        }
+
+        deserializer
+            .deserialize_str(StringVisitor::<D>{ _marker: PhantomData })
+            .and_then(|v| {
+                Self::from_config_str(&v[..])
+                    .ok_or_else(|| D::Error::custom(Self::expected()))
+            })
+    }
+}
+
+impl DeserializeFromStr for Hinting {
+    fn from_config_str(s: &str) -> Option<Hinting> {
+        match s {
+            "none" => Some(Hinting::None),
+            "light" => Some(Hinting::Light),
+            "full" => Some(Hinting::Full),
+            _ => None,
+        }
+    }
+
+    fn expected() -> &'static str {
+        "failed to parse hinting; expect one of none, light, full"
+    }
+}
+
+impl DeserializeFromStr for LcdFilter {
+    fn from_config_str(s: &str) -> Option<LcdFilter> {
+        match s {
+            "none" => Some(LcdFilter::None),
+            "default" => Some(LcdFilter::Default),
+            "light" => Some(LcdFilter::Light),
+            "legacy" => Some(LcdFilter::Legacy),
+            _ => None,
+        }
+    }
+
+    fn expected() -> &'static str {
+        "failed to parse lcd_filter; expect one of none, default, light, legacy"
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_scrollback_lines() -> usize {
+    10_000
+}
+
 /// Font config
 ///
 /// Defaults are provided at the level of this struct per platform, but not per
@@ -442,6 +960,26 @@ pub struct Font {
 
     /// Extra spacing per character
     offset: FontOffset,
+
+    /// Glyph outline hinting
+    #[serde(default, deserialize_with="DeserializeFromStr::deserialize_from_str")]
+    hinting: Hinting,
+
+    /// Filter for subpixel rendered glyphs
+    #[serde(default, deserialize_with="DeserializeFromStr::deserialize_from_str")]
+    lcd_filter: LcdFilter,
+
+    /// Whether glyph edges are antialiased
+    #[serde(default="default_true")]
+    antialias: bool,
+
+    /// Whether antialiasing is done per LCD subpixel
+    #[serde(default="default_true")]
+    subpixel: bool,
+
+    /// Whether box drawing and block characters are stretched to fill cells
+    #[serde(default)]
+    stretch_box_drawing: bool,
 }
 
 impl Font {
@@ -484,6 +1022,23 @@ impl Font {
     pub fn offset(&self) -> &FontOffset {
         &self.offset
     }
+
+    /// Should box drawing and block characters be stretched to fill cells
+    #[inline]
+    pub fn stretch_box_drawing(&self) -> bool {
+        self.stretch_box_drawing
+    }
+
+    /// Get options for the rasterizer
+    #[inline]
+    pub fn rasterizer_config(&self) -> RasterizerConfig {
+        RasterizerConfig {
+            hinting: self.hinting,
+            lcd_filter: self.lcd_filter,
+            antialias: self.antialias,
+            subpixel: self.subpixel,
+        }
+    }
 }
 
 #[cfg(target_os = "macos")]
@@ -498,7 +1053,12 @@ impl Default for Font {
             offset: FontOffset {
                 x: 0.0,
                 y: 0.0
-            }
+            },
+            hinting: Hinting::default(),
+            lcd_filter: LcdFilter::default(),
+            antialias: true,
+            subpixel: true,
+            stretch_box_drawing: false,
         }
     }
 }
@@ -517,7 +1077,12 @@ impl Default for Font {
                 // drastic offsets for the default!
                 x: 2.0,
                 y: -7.0
-            }
+            },
+            hinting: Hinting::default(),
+            lcd_filter: LcdFilter::default(),
+            antialias: true,
+            subpixel: true,
+            stretch_box_drawing: false,
         }
     }
 }
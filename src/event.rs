@@ -5,7 +5,7 @@ use glutin;
 
 use input;
 use sync::FairMutex;
-use term::Term;
+use term::{Paste, Term};
 use util::encode_char;
 
 /// The event processor
@@ -14,6 +14,9 @@ pub struct Processor<N> {
     input_processor: input::Processor,
     terminal: Arc<FairMutex<Term>>,
     resize_tx: mpsc::Sender<(u32, u32)>,
+
+    /// A paste held back for confirmation; pasting it again sends it
+    unconfirmed_paste: Option<String>,
 }
 
 impl<N: input::Notify> Processor<N> {
@@ -31,6 +34,7 @@ impl<N: input::Notify> Processor<N> {
             terminal: terminal,
             input_processor: input::Processor::new(),
             resize_tx: resize_tx,
+            unconfirmed_paste: None,
         }
     }
 
@@ -38,6 +42,7 @@ impl<N: input::Notify> Processor<N> {
         match event {
             glutin::Event::Closed => panic!("window closed"), // TODO ...
             glutin::Event::ReceivedCharacter(c) => {
+                let accept = self.input_processor.accept_char();
                 match c {
                     // Ignore BACKSPACE and DEL. These are handled specially.
                     '\u{8}' | '\u{7f}' => (),
@@ -45,6 +50,8 @@ impl<N: input::Notify> Processor<N> {
                     '\u{f700}' | '\u{f701}' | '\u{f702}' | '\u{f703}' => (),
                     // These letters are handled in the bindings system
                     'v' => (),
+                    // Keys which triggered a binding already sent something
+                    _ if !accept => (),
                     _ => {
                         let buf = encode_char(c);
                         self.notifier.notify(buf);
@@ -58,24 +65,63 @@ impl<N: input::Notify> Processor<N> {
                 terminal.dirty = true;
             },
             glutin::Event::KeyboardInput(state, _code, key, mods) => {
-                // Acquire term lock
-                let terminal = self.terminal.lock();
-                let processor = &mut self.input_processor;
-                let notifier = &mut self.notifier;
+                {
+                    // Acquire term lock
+                    let terminal = self.terminal.lock();
+                    let processor = &mut self.input_processor;
+                    let notifier = &mut self.notifier;
 
-                processor.process_key(state, key, mods, notifier, *terminal.mode());
+                    processor.process_key(state, key, mods, notifier, *terminal.mode());
+                }
+                self.paste();
             },
             glutin::Event::MouseInput(state, button) => {
-                let terminal = self.terminal.lock();
-                let processor = &mut self.input_processor;
-                let notifier = &mut self.notifier;
+                {
+                    let terminal = self.terminal.lock();
+                    let processor = &mut self.input_processor;
+                    let notifier = &mut self.notifier;
+
+                    processor.mouse_input(state, button, notifier, *terminal.mode());
+                }
+                self.paste();
+            },
+            glutin::Event::Focused(focused) => {
+                let mut terminal = self.terminal.lock();
+                terminal.set_focused(focused);
 
-                processor.mouse_input(state, button, notifier, *terminal.mode());
+                let response = terminal.take_response();
+                if !response.is_empty() {
+                    self.notifier.notify(response);
+                }
             },
             _ => (),
         }
     }
 
+    /// Pass any text a paste binding loaded to the terminal
+    ///
+    /// A paste the terminal wants confirmed is held back; pasting the same
+    /// text again sends it.
+    fn paste(&mut self) {
+        let text = match self.input_processor.take_paste() {
+            Some(text) => text,
+            None => return,
+        };
+
+        let mut terminal = self.terminal.lock();
+        if self.unconfirmed_paste.take().map_or(false, |unconfirmed| unconfirmed == text) {
+            terminal.paste_confirmed(&text);
+        } else if terminal.paste(&text) == Paste::ConfirmationAdvised {
+            warn!("Paste contains a newline; paste again to send it");
+            self.unconfirmed_paste = Some(text);
+        }
+
+        let response = terminal.take_response();
+        if !response.is_empty() {
+            self.notifier.notify(response);
+        }
+    }
+
     /// Process at least one event and handle any additional queued events.
     pub fn process_events(&mut self, window: &glutin::Window) {
         for event in window.wait_events() {
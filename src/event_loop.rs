@@ -1,14 +1,13 @@
 //! The main event loop which performs I/O on the pseudoterminal
 use std::borrow::Cow;
-use std::collections::VecDeque;
 use std::io::{self, ErrorKind};
 use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use mio::{self, Events, PollOpt, Ready};
 use mio::unix::EventedFd;
 
-use ansi;
 use term::Term;
 use util::thread;
 use sync::FairMutex;
@@ -24,8 +23,7 @@ pub enum Msg {
 
 /// The main event!.. loop.
 ///
-/// Handles all the pty I/O and runs the pty parser which updates terminal
-/// state.
+/// Handles all the pty I/O and feeds the output to the terminal.
 pub struct EventLoop<Io> {
     poll: mio::Poll,
     pty: Io,
@@ -36,85 +34,6 @@ pub struct EventLoop<Io> {
     signal_flag: Flag,
 }
 
-/// Helper type which tracks how much of a buffer has been written.
-struct Writing {
-    source: Cow<'static, [u8]>,
-    written: usize,
-}
-
-/// All of the mutable state needed to run the event loop
-///
-/// Contains list of items to write, current write state, etc. Anything that
-/// would otherwise be mutated on the `EventLoop` goes here.
-pub struct State {
-    write_list: VecDeque<Cow<'static, [u8]>>,
-    writing: Option<Writing>,
-    parser: ansi::Processor,
-}
-
-impl Default for State {
-    fn default() -> State {
-        State {
-            write_list: VecDeque::new(),
-            parser: ansi::Processor::new(),
-            writing: None,
-        }
-    }
-}
-
-impl State {
-    #[inline]
-    fn ensure_next(&mut self) {
-        if self.writing.is_none() {
-            self.goto_next();
-        }
-    }
-
-    #[inline]
-    fn goto_next(&mut self) {
-        self.writing = self.write_list
-            .pop_front()
-            .map(|c| Writing::new(c));
-    }
-
-    #[inline]
-    fn take_current(&mut self) -> Option<Writing> {
-        self.writing.take()
-    }
-
-    #[inline]
-    fn needs_write(&self) -> bool {
-        self.writing.is_some() || !self.write_list.is_empty()
-    }
-
-    #[inline]
-    fn set_current(&mut self, new: Option<Writing>) {
-        self.writing = new;
-    }
-}
-
-impl Writing {
-    #[inline]
-    fn new(c: Cow<'static, [u8]>) -> Writing {
-        Writing { source: c, written: 0 }
-    }
-
-    #[inline]
-    fn advance(&mut self, n: usize) {
-        self.written += n;
-    }
-
-    #[inline]
-    fn remaining_bytes(&self) -> &[u8] {
-        &self.source[self.written..]
-    }
-
-    #[inline]
-    fn finished(&self) -> bool {
-        self.written >= self.source.len()
-    }
-}
-
 /// mio::Token for the event loop channel
 const CHANNEL: mio::Token = mio::Token(0);
 
@@ -122,7 +41,7 @@ const CHANNEL: mio::Token = mio::Token(0);
 const PTY: mio::Token = mio::Token(1);
 
 impl<Io> EventLoop<Io>
-    where Io: io::Read + io::Write + Send + AsRawFd + 'static
+    where Io: io::Read + Send + AsRawFd + 'static
 {
     /// Create a new event loop
     pub fn new(
@@ -148,14 +67,19 @@ impl<Io> EventLoop<Io>
     }
 
     #[inline]
-    fn channel_event(&mut self, state: &mut State) {
-        while let Ok(msg) = self.rx.try_recv() {
-            match msg {
-                Msg::Input(input) => {
-                    state.write_list.push_back(input);
+    fn channel_event(&mut self) {
+        let wants_write = {
+            // Input is left in the channel while the tty is full; `pty_write`
+            // comes back for it once there's room.
+            let mut terminal = self.terminal.lock();
+            while !terminal.tty().is_full() {
+                match self.rx.try_recv() {
+                    Ok(Msg::Input(input)) => write_pty(&mut terminal, &input),
+                    Err(_) => break,
                 }
             }
-        }
+            terminal.tty().wants_write()
+        };
 
         self.poll.reregister(
             &self.rx, CHANNEL,
@@ -163,7 +87,7 @@ impl<Io> EventLoop<Io>
             PollOpt::edge() | PollOpt::oneshot()
         ).expect("reregister channel");
 
-        if state.needs_write() {
+        if wants_write {
             self.poll.reregister(
                 &EventedFd(&self.pty.as_raw_fd()),
                 PTY,
@@ -173,19 +97,30 @@ impl<Io> EventLoop<Io>
         }
     }
 
+    /// Feed everything available on the pty to the terminal
+    ///
+    /// Returns `true` once the pty has reached EOF because the child exited.
     #[inline]
-    fn pty_read(&mut self, state: &mut State, buf: &mut [u8]) {
+    fn pty_read(&mut self, buf: &mut [u8]) -> bool {
         loop {
             match self.pty.read(&mut buf[..]) {
-                Ok(0) => break,
+                Ok(0) => return true,
                 Ok(got) => {
                     let mut terminal = self.terminal.lock();
-                    for byte in &buf[..got] {
-                        state.parser.advance(&mut *terminal, *byte);
+                    terminal.feed(&buf[..got]);
+
+                    // Send any replies the terminal produced while parsing.
+                    // A child which doesn't read its input gets no more
+                    // once the tty is full.
+                    let response = terminal.take_response();
+                    if !response.is_empty() {
+                        if terminal.tty().is_full() {
+                            warn!("Dropping {} byte reply; the pty is full", response.len());
+                        } else {
+                            write_pty(&mut terminal, &response);
+                        }
                     }
 
-                    terminal.dirty = true;
-
                     // Only wake up the event loop if it hasn't already been
                     // signaled. This is a really important optimization because
                     // waking up the event loop redundantly burns *a lot* of
@@ -197,7 +132,7 @@ impl<Io> EventLoop<Io>
                 },
                 Err(err) => {
                     match err.kind() {
-                        ErrorKind::WouldBlock => break,
+                        ErrorKind::WouldBlock => return false,
                         _ => panic!("unexpected read err: {:?}", err),
                     }
                 }
@@ -205,41 +140,69 @@ impl<Io> EventLoop<Io>
         }
     }
 
-    #[inline]
-    fn pty_write(&mut self, state: &mut State) {
-        state.ensure_next();
-
-        'write_many: while let Some(mut current) = state.take_current() {
-            'write_one: loop {
-                match self.pty.write(current.remaining_bytes()) {
-                    Ok(0) => {
-                        state.set_current(Some(current));
-                        break 'write_many;
-                    },
-                    Ok(n) => {
-                        current.advance(n);
-                        if current.finished() {
-                            state.goto_next();
-                            break 'write_one;
-                        }
-                    },
+    /// Let the terminal act on timers which have run out, waking up the main
+    /// thread if there's something new to draw
+    fn expire_timers(&mut self) {
+        let redraw = {
+            let mut terminal = self.terminal.lock();
+            let was_dirty = terminal.dirty;
+            terminal.expire_timers(Instant::now());
+            !was_dirty && terminal.dirty
+        };
+
+        if redraw && !self.signal_flag.get() {
+            self.proxy.wakeup_event_loop();
+            self.signal_flag.set(true);
+        }
+    }
+
+    /// Wait until the child has been reaped after the pty hung up
+    ///
+    /// The child may close the pty a moment before it exits, so this polls
+    /// rather than checking once. The terminal is only locked while checking.
+    fn wait_for_child(&self) {
+        loop {
+            {
+                let terminal = self.terminal.lock();
+                if terminal.tty().child_pid().is_none() {
+                    return;
+                }
+
+                match terminal.child_exited() {
+                    Ok(None) => (),
+                    Ok(Some(_)) => return,
                     Err(err) => {
-                        state.set_current(Some(current));
-                        match err.kind() {
-                            ErrorKind::WouldBlock => break 'write_many,
-                            // TODO
-                            _ => panic!("unexpected err: {:?}", err),
-                        }
-                    }
+                        error!("Waiting for the child failed: {}", err);
+                        return;
+                    },
                 }
+            }
+
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+    }
 
+    /// Send bytes the tty couldn't take earlier, now that it's writable
+    ///
+    /// Input held back in the channel while the tty was full is picked up
+    /// again.
+    #[inline]
+    fn pty_write(&mut self) {
+        let has_room = {
+            let mut terminal = self.terminal.lock();
+            if let Err(err) = terminal.tty_mut().flush() {
+                error!("Error writing to the pty: {}", err);
             }
+            !terminal.tty().is_full()
+        };
+
+        if has_room {
+            self.channel_event();
         }
     }
 
-    pub fn spawn(mut self, state: Option<State>) -> thread::JoinHandle<(EventLoop<Io>, State)> {
+    pub fn spawn(mut self) -> thread::JoinHandle<EventLoop<Io>> {
         thread::spawn_named("pty reader", move || {
-            let mut state = state.unwrap_or_else(Default::default);
             let mut buf = [0u8; 4096];
 
             let fd = self.pty.as_raw_fd();
@@ -253,29 +216,42 @@ impl<Io> EventLoop<Io>
             let mut events = Events::with_capacity(1024);
 
             'event_loop: loop {
-                self.poll.poll(&mut events, None).expect("poll ok");
+                // Wake up in time for the terminal's next timer
+                let now = Instant::now();
+                let timeout = self.terminal.lock().next_deadline(now).map(|deadline| {
+                    if deadline > now { deadline - now } else { Duration::from_millis(0) }
+                });
+                self.poll.poll(&mut events, timeout).expect("poll ok");
+                self.expire_timers();
 
                 for event in events.iter() {
                     match event.token() {
-                        CHANNEL => self.channel_event(&mut state),
+                        CHANNEL => self.channel_event(),
                         PTY => {
                             let kind = event.kind();
+                            let mut eof = false;
 
                             if kind.is_readable() {
-                                self.pty_read(&mut state, &mut buf);
+                                eof = self.pty_read(&mut buf);
                             }
 
-                            if kind.is_writable() {
-                                self.pty_write(&mut state);
+                            if kind.is_writable() && !eof {
+                                self.pty_write();
                             }
 
-                            if kind.is_hup() {
+                            if kind.is_hup() || eof {
+                                // Reap the child before waking up the main
+                                // thread so it notices the exit. Nothing is
+                                // left to end a synchronized update.
+                                self.terminal.lock().end_sync_update();
+                                self.wait_for_child();
+                                self.proxy.wakeup_event_loop();
                                 break 'event_loop;
                             }
 
                             // Figure out pty interest
                             let mut interest = Ready::readable();
-                            if state.needs_write() {
+                            if self.terminal.lock().tty().wants_write() {
                                 interest.insert(Ready::writable());
                             }
 
@@ -292,7 +268,17 @@ impl<Io> EventLoop<Io>
             self.poll.deregister(&self.rx).expect("deregister channel");
             self.poll.deregister(&fd).expect("deregister pty");
 
-            (self, state)
+            self
         })
     }
 }
+
+/// Write `bytes` to the terminal's tty without blocking
+///
+/// Whatever the tty can't take yet stays queued on it until the pty is
+/// writable again.
+fn write_pty(terminal: &mut Term, bytes: &[u8]) {
+    if let Err(err) = terminal.tty_mut().write(bytes) {
+        error!("Error writing to the pty: {}", err);
+    }
+}
@@ -14,19 +14,23 @@
 
 //! A generic 2d grid implementation optimized for use in a terminal.
 //!
-//! The current implementation uses a vector of vectors to store cell data.
-//! Reimplementing the store as a single contiguous vector may be desirable in
-//! the future. Rotation and indexing would need to be reconsidered at that
-//! time; rotation currently reorganize Vecs in the lines Vec, and indexing with
-//! ranges is currently supported.
+//! The current implementation uses a vector of vectors to store cell data. The
+//! outer vector is a ring; the oldest line is at `zero` rather than at index 0.
+//! Scrolling the whole grid only moves `zero` and never touches the rows
+//! themselves. Scrolling part of the grid swaps rows in place.
+//!
+//! Lines scrolled off the top of the screen are kept as scrollback history, up
+//! to a configurable limit. `Line` always indexes the screen; the whole buffer,
+//! history included, is addressed with `Point<usize>` where line 0 is the
+//! oldest line of history.
 
 use std::borrow::ToOwned;
-use std::cmp::Ordering;
-use std::iter::IntoIterator;
+use std::cmp::{self, Ordering};
+use std::iter::{Chain, IntoIterator};
 use std::ops::{Deref, DerefMut, Range, RangeTo, RangeFrom, RangeFull, Index, IndexMut};
 use std::slice::{self, Iter, IterMut};
 
-use index::{self, Cursor};
+use index::{self, Cursor, Point};
 
 /// Represents the terminal display contents
 #[derive(Clone, Debug)]
@@ -35,12 +39,24 @@ pub struct Grid<T> {
     /// columns in that row.
     raw: Vec<Row<T>>,
 
+    /// Index in `raw` of the oldest line
+    zero: usize,
+
+    /// Number of history lines stored above the screen
+    history: usize,
+
+    /// Maximum number of history lines
+    max_history: usize,
+
+    /// Number of lines the view is scrolled back into history
+    display_offset: usize,
+
     /// Number of columns
     cols: index::Column,
 
-    /// Number of lines.
+    /// Number of lines on the screen
     ///
-    /// Invariant: lines is equivalent to raw.len()
+    /// Invariant: lines + history is equivalent to raw.len()
     lines: index::Line,
 }
 
@@ -53,6 +69,10 @@ impl<T: Clone> Grid<T> {
 
         Grid {
             raw: raw,
+            zero: 0,
+            history: 0,
+            max_history: 0,
+            display_offset: 0,
             cols: cols,
             lines: lines,
         }
@@ -64,6 +84,9 @@ impl<T: Clone> Grid<T> {
             return;
         }
 
+        self.normalize();
+        self.display_offset = 0;
+
         match self.lines.cmp(&lines) {
             Ordering::Less => self.grow_lines(lines, template),
             Ordering::Greater => self.shrink_lines(lines),
@@ -86,24 +109,232 @@ impl<T: Clone> Grid<T> {
     }
 
     fn grow_cols(&mut self, cols: index::Column, template: &T) {
-        for row in self.lines_mut() {
+        for row in &mut self.raw {
             row.grow(cols, template);
         }
 
         self.cols = cols;
     }
 
+    /// Rotate the lines in `region.start..region.end + positions` up by
+    /// `positions`
+    ///
+    /// When the region is the whole screen, lines scrolled off the top move
+    /// into history and the lines exposed at the bottom have unspecified
+    /// contents. Rotating the whole grid is O(1).
+    #[inline]
+    pub fn scroll_up(&mut self, region: Range<index::Line>, positions: index::Line) {
+        if region.start == index::Line(0) && region.end + positions == self.lines {
+            for _ in 0..*positions {
+                self.push_history();
+            }
+            return;
+        }
+
+        for line in region {
+            let src = line;
+            let dst = line + positions;
+            self.swap_lines(src, dst);
+        }
+    }
+
+    /// Move the top line of the screen into history
+    ///
+    /// A view scrolled back into history keeps showing the same lines, until
+    /// they're dropped from the top of history.
+    fn push_history(&mut self) {
+        if self.history < self.max_history {
+            // History is still growing, so the new bottom line can be appended
+            // once the rows are back in order; `scroll_down` may have rotated
+            // them.
+            self.normalize();
+            let row = self.raw[self.raw.len() - 1].clone();
+            self.raw.push(row);
+            self.history += 1;
+        } else {
+            self.zero = (self.zero + 1) % self.raw.len();
+        }
+
+        if self.display_offset != 0 {
+            self.display_offset = cmp::min(self.display_offset + 1, self.history);
+        }
+    }
+
+    /// Get the cells on screen which differ in `other`
+    ///
+    /// Cells are returned with their values from `other`, so applying them to
+    /// this grid makes its screen match. When `other` is larger, every cell
+    /// outside this grid is included.
+    pub fn diff(&self, other: &Grid<T>) -> Vec<(index::Line, index::Column, T)>
+        where T: PartialEq
+    {
+        let mut changes = Vec::new();
+
+        for (line, row) in other.lines().enumerate() {
+            let line = index::Line(line);
+            for (col, cell) in row.cells().enumerate() {
+                let col = index::Column(col);
+                let same = line < self.lines && col < self.cols && self[line][col] == *cell;
+                if !same {
+                    changes.push((line, col, cell.clone()));
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// Set the maximum number of history lines, dropping the oldest lines if
+    /// there are more than `limit`
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.normalize();
+
+        if self.history > limit {
+            let excess = self.history - limit;
+            self.raw.drain(..excess);
+            self.history = limit;
+        }
+
+        self.max_history = limit;
+        self.display_offset = cmp::min(self.display_offset, self.history);
+    }
 }
 
 impl<T> Grid<T> {
+    /// Iterate over the lines of the screen from top to bottom
     #[inline]
-    pub fn lines(&self) -> Iter<Row<T>> {
-        self.raw.iter()
+    pub fn lines(&self) -> Chain<Iter<Row<T>>, Iter<Row<T>>> {
+        let (first, second) = self.ring_ranges(self.history, *self.lines);
+        self.raw[first].iter().chain(self.raw[second].iter())
     }
 
+    /// Iterate mutably over the lines of the screen from top to bottom
     #[inline]
-    pub fn lines_mut(&mut self) -> IterMut<Row<T>> {
-        self.raw.iter_mut()
+    pub fn lines_mut(&mut self) -> Chain<IterMut<Row<T>>, IterMut<Row<T>>> {
+        let (first, second) = self.ring_ranges(self.history, *self.lines);
+        let (head, tail) = self.raw.split_at_mut(first.start);
+        tail[..first.end - first.start].iter_mut().chain(head[second].iter_mut())
+    }
+
+    /// Iterate over the lines in view from top to bottom
+    ///
+    /// Same as `lines` unless the view is scrolled back into history.
+    #[inline]
+    pub fn display_lines(&self) -> Chain<Iter<Row<T>>, Iter<Row<T>>> {
+        let (first, second) = self.ring_ranges(self.history - self.display_offset, *self.lines);
+        self.raw[first].iter().chain(self.raw[second].iter())
+    }
+
+    /// Ranges of `raw` holding `len` lines starting `start` lines below the
+    /// oldest line
+    ///
+    /// The second range is empty unless the lines wrap around the end of `raw`.
+    #[inline]
+    fn ring_ranges(&self, start: usize, len: usize) -> (Range<usize>, Range<usize>) {
+        let start = (self.zero + start) % self.raw.len();
+        let end = start + len;
+        if end > self.raw.len() {
+            (start..self.raw.len(), 0..(end - self.raw.len()))
+        } else {
+            (start..end, 0..0)
+        }
+    }
+
+    /// Number of history lines stored above the screen
+    #[inline]
+    pub fn history_size(&self) -> usize {
+        self.history
+    }
+
+    /// Drop every history line, leaving the screen as it is
+    pub fn clear_history(&mut self) {
+        self.normalize();
+        self.raw.drain(..self.history);
+        self.history = 0;
+        self.display_offset = 0;
+    }
+
+    /// Number of lines in the buffer, history included
+    #[inline]
+    pub fn total_lines(&self) -> usize {
+        self.raw.len()
+    }
+
+    /// Number of lines the view is scrolled back into history
+    #[inline]
+    pub fn display_offset(&self) -> usize {
+        self.display_offset
+    }
+
+    /// Scroll the view `lines` lines back into history, or towards the bottom
+    /// for negative values
+    pub fn scroll_display(&mut self, lines: isize) {
+        let offset = self.display_offset as isize + lines;
+        self.display_offset = cmp::max(0, cmp::min(offset, self.history as isize)) as usize;
+    }
+
+    /// Convert a point in view to a point in the buffer
+    #[inline]
+    pub fn visible_to_absolute(&self, point: Point) -> Point<usize> {
+        Point::new(self.history - self.display_offset + *point.line, point.col)
+    }
+
+    /// Convert a point in the buffer to a point in view
+    ///
+    /// Returns `None` when the line isn't in view.
+    #[inline]
+    pub fn absolute_to_visible(&self, point: Point<usize>) -> Option<Point> {
+        let top = self.history - self.display_offset;
+        if point.line < top || point.line >= top + *self.lines {
+            return None;
+        }
+
+        Some(Point::new(index::Line(point.line - top), point.col))
+    }
+
+    /// Get a line of the buffer
+    ///
+    /// # Panics
+    ///
+    /// Panics if the line is outside the buffer
+    #[inline]
+    pub fn buffer_line(&self, line: usize) -> &Row<T> {
+        assert!(line < self.raw.len(), "buffer line out of bounds; len={}, line={}",
+                self.raw.len(), line);
+        &self.raw[(self.zero + line) % self.raw.len()]
+    }
+
+    /// Get the index in `raw` of a line
+    ///
+    /// # Panics
+    ///
+    /// Panics if the line is outside the grid
+    #[inline]
+    fn raw_index(&self, line: index::Line) -> usize {
+        use std::intrinsics::unlikely;
+
+        unsafe {
+            if unlikely(line >= self.lines) {
+                panic!("line out of bounds; len={}, line={}", self.raw.len(), line);
+            }
+        }
+
+        let index = self.zero + self.history + *line;
+        if index >= self.raw.len() {
+            index - self.raw.len()
+        } else {
+            index
+        }
+    }
+
+    /// Rotate the rows so the top line is stored first
+    fn normalize(&mut self) {
+        if self.zero != 0 {
+            let mut raw = self.raw.split_off(self.zero);
+            raw.extend(self.raw.drain(..));
+            self.raw = raw;
+            self.zero = 0;
+        }
     }
 
     #[inline]
@@ -116,8 +347,19 @@ impl<T> Grid<T> {
         self.cols
     }
 
+    /// Rotate the lines in `region.start - positions..region.end` down by
+    /// `positions`
+    ///
+    /// Rotating the whole grid is O(1) when there's no history; otherwise the
+    /// rotation would pull history back onto the screen.
     #[inline]
     pub fn scroll_down(&mut self, region: Range<index::Line>, positions: index::Line) {
+        if self.history == 0 && region.start == positions && region.end == self.lines {
+            let len = self.raw.len();
+            self.zero = (self.zero + len - *positions % len) % len;
+            return;
+        }
+
         for line in region.rev() {
             let src = line;
             let dst = line - positions;
@@ -126,23 +368,41 @@ impl<T> Grid<T> {
     }
 
     #[inline]
-    pub fn scroll_up(&mut self, region: Range<index::Line>, positions: index::Line) {
-        for line in region {
-            let src = line;
-            let dst = line + positions;
-            self.swap_lines(src, dst);
+    pub fn contains(&self, cursor: &Cursor) -> bool {
+        self.contains_point(cursor.line, cursor.col)
+    }
+
+    /// Check whether `line` and `col` are on the screen
+    #[inline]
+    pub fn contains_point(&self, line: index::Line, col: index::Column) -> bool {
+        self.lines > line && self.cols > col
+    }
+
+    /// Get the cell at `line` and `col`, or `None` when it's off the screen
+    #[inline]
+    pub fn get(&self, line: index::Line, col: index::Column) -> Option<&T> {
+        if self.contains_point(line, col) {
+            Some(&self[line][col])
+        } else {
+            None
         }
     }
 
+    /// Get the cell at `line` and `col` mutably, or `None` when it's off the
+    /// screen
     #[inline]
-    pub fn contains(&self, cursor: &Cursor) -> bool {
-        self.lines > cursor.line && self.cols > cursor.col
+    pub fn get_mut(&mut self, line: index::Line, col: index::Column) -> Option<&mut T> {
+        if self.contains_point(line, col) {
+            Some(&mut self[line][col])
+        } else {
+            None
+        }
     }
 
     /// Swap two lines in the grid
     ///
-    /// This could have used slice::swap internally, but we are able to have
-    /// better error messages by doing the bounds checking ourselves.
+    /// Bounds are checked here rather than in `raw_index` so that the error
+    /// message says which argument was out of bounds.
     #[inline]
     pub fn swap_lines(&mut self, src: index::Line, dst: index::Line) {
         use std::intrinsics::unlikely;
@@ -157,22 +417,83 @@ impl<T> Grid<T> {
             if unlikely(dst >= self.lines) {
                 panic!("swap_lines dst out of bounds; len={}, dst={}", self.raw.len(), dst);
             }
+        }
 
-            let src: *mut _ = self.raw.get_unchecked_mut(src.0);
-            let dst: *mut _ = self.raw.get_unchecked_mut(dst.0);
+        let src = self.raw_index(src);
+        let dst = self.raw_index(dst);
+        self.raw.swap(src, dst);
+    }
 
-            ::std::ptr::swap(src, dst);
+    /// Set every cell of the screen lines in `lines` to `template`
+    pub fn reset_lines<R>(&mut self, lines: R, template: &T)
+        where R: LineRange,
+              T: Clone + Send + Sync
+    {
+        let lines = lines.line_range(self.num_lines());
+        self.for_each_mut(lines.clone(), |c| *c = template.clone());
+
+        for line in lines {
+            let index = self.raw_index(line);
+            self.raw[index].clear = true;
         }
     }
 
-    #[inline]
-    pub fn clear<F: Fn(&mut T)>(&mut self, func: F) {
-        let region = index::Line(0)..self.num_lines();
-        self.clear_region(region, func);
+    /// Set every cell of the screen line `line` to `template`
+    ///
+    /// Cheaper than `reset_lines` for a single line since the row is filled
+    /// in one pass, without going through `for_each_mut`.
+    pub fn reset_line(&mut self, line: index::Line, template: &T)
+        where T: Clone
+    {
+        let index = self.raw_index(line);
+        let row = &mut self.raw[index];
+        for c in &mut row.inner {
+            c.clone_from(template);
+        }
+
+        row.clear = true;
     }
 
+    /// Get the screen lines which may hold something other than their reset
+    /// value
+    ///
+    /// A line is left out only when it was reset with `reset_lines` or
+    /// created, and not written since.
+    pub fn occupied_lines(&self) -> Vec<index::Line> {
+        self.lines()
+            .enumerate()
+            .filter(|&(_, row)| !row.clear)
+            .map(|(line, _)| index::Line(line))
+            .collect()
+    }
+
+    /// Call `func` on every cell of the screen lines in `lines`
+    ///
+    /// Bulk updates of the screen such as clearing go through here. With the
+    /// `rayon` feature, large regions are split across threads by row, so
+    /// `func` must only touch the cell it's given.
+    pub fn for_each_mut<R, F>(&mut self, lines: R, func: F)
+        where R: LineRange,
+              T: Send,
+              F: Fn(&mut T) + Sync
+    {
+        let lines = lines.line_range(self.num_lines());
+        if lines.start >= lines.end {
+            return;
+        }
+
+        let start = self.history + *lines.start;
+        let (first, second) = self.ring_ranges(start, *(lines.end - lines.start));
+        let (head, tail) = self.raw.split_at_mut(first.start);
+        for_each_row(&mut tail[..first.end - first.start], &func);
+        for_each_row(&mut head[second], &func);
+    }
+
+    /// Remove lines from the bottom of the grid
+    ///
+    /// The grid must have been normalized.
     fn shrink_lines(&mut self, lines: index::Line) {
-        while index::Line(self.raw.len()) != lines {
+        while self.raw.len() != *lines + self.history {
             self.raw.pop();
         }
 
@@ -180,7 +501,7 @@ impl<T> Grid<T> {
     }
 
     fn shrink_cols(&mut self, cols: index::Column) {
-        for row in self.lines_mut() {
+        for row in &mut self.raw {
             row.shrink(cols);
         }
 
@@ -193,14 +514,16 @@ impl<T> Index<index::Line> for Grid<T> {
 
     #[inline]
     fn index<'a>(&'a self, index: index::Line) -> &'a Row<T> {
-        &self.raw[index.0]
+        let index = self.raw_index(index);
+        &self.raw[index]
     }
 }
 
 impl<T> IndexMut<index::Line> for Grid<T> {
     #[inline]
     fn index_mut<'a>(&'a mut self, index: index::Line) -> &'a mut Row<T> {
-        &mut self.raw[index.0]
+        let index = self.raw_index(index);
+        &mut self.raw[index]
     }
 }
 
@@ -209,24 +532,33 @@ impl<'cursor, T> Index<&'cursor Cursor> for Grid<T> {
 
     #[inline]
     fn index<'a, 'b>(&'a self, cursor: &'b Cursor) -> &'a T {
-        &self.raw[cursor.line.0][cursor.col]
+        &self[cursor.line][cursor.col]
     }
 }
 
 impl<'cursor, T> IndexMut<&'cursor Cursor> for Grid<T> {
     #[inline]
     fn index_mut<'a, 'b>(&'a mut self, cursor: &'b Cursor) -> &'a mut T {
-        &mut self.raw[cursor.line.0][cursor.col]
+        &mut self[cursor.line][cursor.col]
     }
 }
 
 /// A row in the grid
 #[derive(Clone, Debug)]
-pub struct Row<T>(Vec<T>);
+pub struct Row<T> {
+    inner: Vec<T>,
+
+    /// Every cell was reset to the same value and nothing has been written
+    /// since. Any mutable access to the cells unsets this.
+    clear: bool,
+}
 
 impl<T: Clone> Row<T> {
     pub fn new(columns: index::Column, template: &T) -> Row<T> {
-        Row(vec![template.to_owned(); *columns])
+        Row {
+            inner: vec![template.to_owned(); *columns],
+            clear: true,
+        }
     }
 
     pub fn grow(&mut self, cols: index::Column, template: &T) {
@@ -245,12 +577,28 @@ impl<T> Row<T> {
 
     #[inline]
     pub fn cells(&self) -> Iter<T> {
-        self.0.iter()
+        self.inner.iter()
     }
 
     #[inline]
     pub fn cells_mut(&mut self) -> IterMut<T> {
-        self.0.iter_mut()
+        self.clear = false;
+        self.inner.iter_mut()
+    }
+
+    /// Check whether every cell in the row equals `template`
+    ///
+    /// This is constant time for rows which haven't been written since they
+    /// were last reset.
+    #[inline]
+    pub fn is_clear(&self, template: &T) -> bool
+        where T: PartialEq
+    {
+        if self.clear {
+            self.inner.first().map_or(true, |cell| cell == template)
+        } else {
+            self.inner.iter().all(|cell| cell == template)
+        }
     }
 }
 
@@ -279,14 +627,15 @@ impl<T> Deref for Row<T> {
 
     #[inline]
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.inner
     }
 }
 
 impl<T> DerefMut for Row<T> {
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        self.clear = false;
+        &mut self.inner
     }
 }
 
@@ -295,14 +644,15 @@ impl<T> Index<index::Column> for Row<T> {
 
     #[inline]
     fn index<'a>(&'a self, index: index::Column) -> &'a T {
-        &self.0[index.0]
+        &self.inner[index.0]
     }
 }
 
 impl<T> IndexMut<index::Column> for Row<T> {
     #[inline]
     fn index_mut<'a>(&'a mut self, index: index::Column) -> &'a mut T {
-        &mut self.0[index.0]
+        self.clear = false;
+        &mut self.inner[index.0]
     }
 }
 
@@ -313,14 +663,15 @@ macro_rules! row_index_range {
 
             #[inline]
             fn index<'a>(&'a self, index: $range) -> &'a [T] {
-                &self.0[index]
+                &self.inner[index]
             }
         }
 
         impl<T> IndexMut<$range> for Row<T> {
             #[inline]
             fn index_mut<'a>(&'a mut self, index: $range) -> &'a mut [T] {
-                &mut self.0[index]
+                self.clear = false;
+                &mut self.inner[index]
             }
         }
     }
@@ -332,135 +683,146 @@ row_index_range!(RangeFrom<usize>);
 row_index_range!(RangeFull);
 
 // -------------------------------------------------------------------------------------------------
-// Row ranges for Grid
+// Column ranges for Row
 // -------------------------------------------------------------------------------------------------
 
-impl<T> Index<Range<index::Line>> for Grid<T> {
-    type Output = [Row<T>];
+impl<T> Index<Range<index::Column>> for Row<T> {
+    type Output = [T];
 
     #[inline]
-    fn index(&self, index: Range<index::Line>) -> &[Row<T>] {
-        &self.raw[(index.start.0)..(index.end.0)]
+    fn index(&self, index: Range<index::Column>) -> &[T] {
+        &self.inner[(index.start.0)..(index.end.0)]
     }
 }
 
-impl<T> IndexMut<Range<index::Line>> for Grid<T> {
+impl<T> IndexMut<Range<index::Column>> for Row<T> {
     #[inline]
-    fn index_mut(&mut self, index: Range<index::Line>) -> &mut [Row<T>] {
-        &mut self.raw[(index.start.0)..(index.end.0)]
+    fn index_mut(&mut self, index: Range<index::Column>) -> &mut [T] {
+        self.clear = false;
+        &mut self.inner[(index.start.0)..(index.end.0)]
     }
 }
 
-impl<T> Index<RangeTo<index::Line>> for Grid<T> {
-    type Output = [Row<T>];
+impl<T> Index<RangeTo<index::Column>> for Row<T> {
+    type Output = [T];
 
     #[inline]
-    fn index(&self, index: RangeTo<index::Line>) -> &[Row<T>] {
-        &self.raw[..(index.end.0)]
+    fn index(&self, index: RangeTo<index::Column>) -> &[T] {
+        &self.inner[..(index.end.0)]
     }
 }
 
-impl<T> IndexMut<RangeTo<index::Line>> for Grid<T> {
+impl<T> IndexMut<RangeTo<index::Column>> for Row<T> {
     #[inline]
-    fn index_mut(&mut self, index: RangeTo<index::Line>) -> &mut [Row<T>] {
-        &mut self.raw[..(index.end.0)]
+    fn index_mut(&mut self, index: RangeTo<index::Column>) -> &mut [T] {
+        self.clear = false;
+        &mut self.inner[..(index.end.0)]
     }
 }
 
-impl<T> Index<RangeFrom<index::Line>> for Grid<T> {
-    type Output = [Row<T>];
+impl<T> Index<RangeFrom<index::Column>> for Row<T> {
+    type Output = [T];
 
     #[inline]
-    fn index(&self, index: RangeFrom<index::Line>) -> &[Row<T>] {
-        &self.raw[(index.start.0)..]
+    fn index(&self, index: RangeFrom<index::Column>) -> &[T] {
+        &self.inner[(index.start.0)..]
     }
 }
 
-impl<T> IndexMut<RangeFrom<index::Line>> for Grid<T> {
+impl<T> IndexMut<RangeFrom<index::Column>> for Row<T> {
     #[inline]
-    fn index_mut(&mut self, index: RangeFrom<index::Line>) -> &mut [Row<T>] {
-        &mut self.raw[(index.start.0)..]
+    fn index_mut(&mut self, index: RangeFrom<index::Column>) -> &mut [T] {
+        self.clear = false;
+        &mut self.inner[(index.start.0)..]
     }
 }
 
-// -------------------------------------------------------------------------------------------------
-// Column ranges for Row
-// -------------------------------------------------------------------------------------------------
-
-impl<T> Index<Range<index::Column>> for Row<T> {
-    type Output = [T];
-
-    #[inline]
-    fn index(&self, index: Range<index::Column>) -> &[T] {
-        &self.0[(index.start.0)..(index.end.0)]
+/// Regions with at least this many cells are updated in parallel
+#[cfg(feature = "rayon")]
+const PARALLEL_THRESHOLD: usize = 1 << 16;
+
+/// Call `func` on every cell of `rows`
+#[cfg(feature = "rayon")]
+fn for_each_row<T: Send, F: Fn(&mut T) + Sync>(rows: &mut [Row<T>], func: &F) {
+    let cells = rows.first().map_or(0, |row| row.len()) * rows.len();
+    if cells < PARALLEL_THRESHOLD {
+        for_each_row_serial(rows, func);
+    } else {
+        for_each_row_parallel(rows, func);
     }
 }
 
-impl<T> IndexMut<Range<index::Column>> for Row<T> {
-    #[inline]
-    fn index_mut(&mut self, index: Range<index::Column>) -> &mut [T] {
-        &mut self.0[(index.start.0)..(index.end.0)]
-    }
+/// Call `func` on every cell of `rows`
+#[cfg(not(feature = "rayon"))]
+#[inline]
+fn for_each_row<T: Send, F: Fn(&mut T) + Sync>(rows: &mut [Row<T>], func: &F) {
+    for_each_row_serial(rows, func);
 }
 
-impl<T> Index<RangeTo<index::Column>> for Row<T> {
-    type Output = [T];
-
-    #[inline]
-    fn index(&self, index: RangeTo<index::Column>) -> &[T] {
-        &self.0[..(index.end.0)]
+#[inline]
+fn for_each_row_serial<T, F: Fn(&mut T)>(rows: &mut [Row<T>], func: &F) {
+    for row in rows {
+        for cell in row.iter_mut() {
+            func(cell);
+        }
     }
 }
 
-impl<T> IndexMut<RangeTo<index::Column>> for Row<T> {
-    #[inline]
-    fn index_mut(&mut self, index: RangeTo<index::Column>) -> &mut [T] {
-        &mut self.0[..(index.end.0)]
-    }
+/// Call `func` on every cell of `rows`, handing rows out to rayon's thread
+/// pool
+#[cfg(feature = "rayon")]
+fn for_each_row_parallel<T: Send, F: Fn(&mut T) + Sync>(rows: &mut [Row<T>], func: &F) {
+    use rayon::prelude::*;
+
+    rows.par_iter_mut().for_each(|row| {
+        for cell in row.iter_mut() {
+            func(cell);
+        }
+    });
 }
 
-impl<T> Index<RangeFrom<index::Column>> for Row<T> {
-    type Output = [T];
+/// A range of screen lines
+pub trait LineRange {
+    /// Get the range as bounded lines on a screen of `num_lines` lines
+    fn line_range(self, num_lines: index::Line) -> Range<index::Line>;
+}
 
+impl LineRange for Range<index::Line> {
     #[inline]
-    fn index(&self, index: RangeFrom<index::Column>) -> &[T] {
-        &self.0[(index.start.0)..]
+    fn line_range(self, _num_lines: index::Line) -> Range<index::Line> {
+        self
     }
 }
 
-impl<T> IndexMut<RangeFrom<index::Column>> for Row<T> {
+impl LineRange for RangeTo<index::Line> {
     #[inline]
-    fn index_mut(&mut self, index: RangeFrom<index::Column>) -> &mut [T] {
-        &mut self.0[(index.start.0)..]
+    fn line_range(self, _num_lines: index::Line) -> Range<index::Line> {
+        index::Line(0)..self.end
     }
 }
 
-pub trait ClearRegion<R, T> {
-    fn clear_region<F: Fn(&mut T)>(&mut self, region: R, func: F);
+impl LineRange for RangeFrom<index::Line> {
+    #[inline]
+    fn line_range(self, num_lines: index::Line) -> Range<index::Line> {
+        self.start..num_lines
+    }
 }
 
-macro_rules! clear_region_impl {
-    ($range:ty) => {
-        impl<T> ClearRegion<$range, T> for Grid<T> {
-            fn clear_region<F: Fn(&mut T)>(&mut self, region: $range, func: F) {
-                for row in self[region].iter_mut() {
-                    for cell in row {
-                        func(cell);
-                    }
-                }
-            }
-        }
+impl LineRange for RangeFull {
+    #[inline]
+    fn line_range(self, num_lines: index::Line) -> Range<index::Line> {
+        index::Line(0)..num_lines
     }
 }
 
-clear_region_impl!(Range<index::Line>);
-clear_region_impl!(RangeTo<index::Line>);
-clear_region_impl!(RangeFrom<index::Line>);
-
 #[cfg(test)]
 mod tests {
+    use std::cmp;
+    use std::ops::Range;
+    use test::{Bencher, black_box};
+
     use super::Grid;
-    use index::{Line, Column};
+    use index::{Line, Column, Point};
     #[test]
     fn grid_swap_lines_ok() {
         let mut grid = Grid::new(Line(10), Column(1), &0);
@@ -583,4 +945,371 @@ mod tests {
             assert_eq!(grid[Line(i)][Column(0)], other[Line(i)][Column(0)]);
         }
     }
+
+    /// Scroll by swapping lines one at a time, as the grid did before rotating
+    ///
+    /// Scrolling the whole screen rotates instead, like the grid; swapping
+    /// leaves the vacated lines in a different order.
+    fn reference_scroll(lines: &mut Vec<usize>, region: Range<Line>, positions: Line, up: bool) {
+        let len = lines.len();
+        if up && *region.start == 0 && *region.end + *positions == len {
+            let top = lines.drain(..*positions).collect::<Vec<_>>();
+            lines.extend(top);
+        } else if !up && region.start == positions && *region.end == len {
+            let bottom = lines.split_off(len - *positions);
+            let rest = ::std::mem::replace(lines, bottom);
+            lines.extend(rest);
+        } else if up {
+            for line in region {
+                lines.swap(*line, *(line + positions));
+            }
+        } else {
+            for line in region.rev() {
+                lines.swap(*line, *(line - positions));
+            }
+        }
+    }
+
+    fn grid_lines(grid: &Grid<usize>) -> Vec<usize> {
+        grid.lines().map(|row| row[Column(0)]).collect()
+    }
+
+    // Rotating scrolls and partial region scrolls must match swapping line by
+    // line, including after the grid has already been rotated.
+    #[test]
+    fn scroll_matches_reference() {
+        let scrolls = [
+            (Line(0)..Line(9), Line(1), true),
+            (Line(0)..Line(7), Line(3), true),
+            (Line(2)..Line(10), Line(2), false),
+            (Line(1)..Line(10), Line(1), false),
+            (Line(1)..Line(6), Line(2), true),
+            (Line(4)..Line(8), Line(1), false),
+            (Line(0)..Line(1), Line(9), true),
+            (Line(9)..Line(10), Line(9), false),
+            (Line(3)..Line(5), Line(1), true),
+        ];
+
+        let mut grid = Grid::new(Line(10), Column(1), &0);
+        for i in 0..10 {
+            grid[Line(i)][Column(0)] = i;
+        }
+        let mut expected = (0..10).collect::<Vec<_>>();
+
+        for _ in 0..3 {
+            for &(ref region, positions, up) in &scrolls {
+                if up {
+                    grid.scroll_up(region.clone(), positions);
+                } else {
+                    grid.scroll_down(region.clone(), positions);
+                }
+                reference_scroll(&mut expected, region.clone(), positions, up);
+
+                assert_eq!(grid_lines(&grid), expected);
+                for i in 0..10 {
+                    assert_eq!(grid[Line(i)][Column(0)], expected[i]);
+                }
+            }
+        }
+    }
+
+    // Resizing a rotated grid keeps lines in order
+    #[test]
+    fn resize_after_rotation() {
+        let mut grid = Grid::new(Line(5), Column(1), &0);
+        for i in 0..5 {
+            grid[Line(i)][Column(0)] = i;
+        }
+
+        grid.scroll_up(Line(0)..Line(3), Line(2));
+        grid.resize(Line(4), Column(2), &9);
+
+        assert_eq!(grid_lines(&grid), vec![2, 3, 4, 0]);
+        assert_eq!(grid[Line(0)][Column(1)], 9);
+    }
+
+    /// Grid with `history` lines of numbered history above numbered lines
+    fn grid_with_history(lines: usize, history: usize) -> Grid<usize> {
+        let mut grid = Grid::new(Line(lines), Column(1), &0);
+        grid.set_history_limit(history);
+        for i in 0..(lines + history) {
+            if i >= lines {
+                grid.scroll_up(Line(0)..Line(lines - 1), Line(1));
+            }
+            let line = cmp::min(i, lines - 1);
+            grid[Line(line)][Column(0)] = i;
+        }
+        grid
+    }
+
+    #[test]
+    fn scroll_up_after_rotating_down() {
+        let mut grid = Grid::new(Line(4), Column(1), &0);
+        grid.set_history_limit(10);
+        for i in 0..4 {
+            grid[Line(i)][Column(0)] = i;
+        }
+
+        // With no history yet, this rotates the whole grid
+        grid.scroll_down(Line(1)..Line(4), Line(1));
+        grid[Line(0)][Column(0)] = 9;
+        assert_eq!(grid_lines(&grid), vec![9, 0, 1, 2]);
+
+        grid.scroll_up(Line(0)..Line(3), Line(1));
+        assert_eq!(grid.history_size(), 1);
+        assert_eq!(grid.buffer_line(0)[Column(0)], 9);
+        assert_eq!(grid_lines(&grid)[..3].to_vec(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn scrolled_lines_move_into_history() {
+        let grid = grid_with_history(3, 4);
+
+        assert_eq!(grid.history_size(), 4);
+        assert_eq!(grid_lines(&grid), vec![4, 5, 6]);
+        let buffer = (0..grid.total_lines())
+            .map(|line| grid.buffer_line(line)[Column(0)])
+            .collect::<Vec<_>>();
+        assert_eq!(buffer, (0..7).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn history_drops_oldest_lines_when_full() {
+        let mut grid = grid_with_history(3, 2);
+        grid.scroll_up(Line(0)..Line(2), Line(1));
+        grid[Line(2)][Column(0)] = 5;
+
+        assert_eq!(grid.history_size(), 2);
+        assert_eq!(grid.buffer_line(0)[Column(0)], 1);
+        assert_eq!(grid_lines(&grid), vec![3, 4, 5]);
+
+        grid.set_history_limit(1);
+        assert_eq!(grid.buffer_line(0)[Column(0)], 2);
+        assert_eq!(grid_lines(&grid), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn point_round_trip_at_display_offsets() {
+        let mut grid = grid_with_history(4, 6);
+
+        for &offset in &[0, 1, 3, 6] {
+            grid.scroll_display(-10);
+            grid.scroll_display(offset);
+            assert_eq!(grid.display_offset(), offset as usize);
+
+            for line in 0..4 {
+                for col in 0..2 {
+                    let point = Point::new(Line(line), Column(col));
+                    let absolute = grid.visible_to_absolute(point);
+
+                    assert_eq!(absolute.line, 6 - offset as usize + line);
+                    assert_eq!(grid.buffer_line(absolute.line)[Column(0)], absolute.line);
+                    assert_eq!(grid.absolute_to_visible(absolute), Some(point));
+                }
+            }
+
+            let above = 6 - offset as usize;
+            if above > 0 {
+                assert_eq!(grid.absolute_to_visible(Point::new(above - 1, Column(0))), None);
+            }
+            assert_eq!(grid.absolute_to_visible(Point::new(above + 4, Column(0))), None);
+        }
+    }
+
+    #[test]
+    fn display_offset_is_clamped_to_history() {
+        let mut grid = grid_with_history(2, 3);
+
+        grid.scroll_display(10);
+        assert_eq!(grid.display_offset(), 3);
+        assert_eq!(grid.display_lines().map(|row| row[Column(0)]).collect::<Vec<_>>(), vec![0, 1]);
+
+        grid.scroll_display(-1);
+        assert_eq!(grid.display_lines().map(|row| row[Column(0)]).collect::<Vec<_>>(), vec![1, 2]);
+
+        grid.scroll_display(-10);
+        assert_eq!(grid.display_offset(), 0);
+    }
+
+    #[test]
+    fn scrolled_back_view_stays_put() {
+        let mut grid = grid_with_history(2, 3);
+        grid.set_history_limit(5);
+        grid.scroll_display(2);
+        assert_eq!(grid.display_lines().map(|row| row[Column(0)]).collect::<Vec<_>>(), vec![1, 2]);
+
+        grid.scroll_up(Line(0)..Line(1), Line(1));
+        assert_eq!(grid.display_offset(), 3);
+        assert_eq!(grid.display_lines().map(|row| row[Column(0)]).collect::<Vec<_>>(), vec![1, 2]);
+
+        // Once history is full the view moves with the oldest line
+        for _ in 0..3 {
+            grid.scroll_up(Line(0)..Line(1), Line(1));
+        }
+        assert_eq!(grid.display_offset(), 5);
+        assert_eq!(grid.display_lines().map(|row| row[Column(0)]).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn diff_returns_changed_cells() {
+        let before = Grid::new(Line(4), Column(5), &0);
+        let mut after = before.clone();
+        after[Line(0)][Column(0)] = 1;
+        after[Line(2)][Column(3)] = 2;
+        after[Line(3)][Column(4)] = 3;
+        after[Line(1)][Column(1)] = 0;
+
+        assert_eq!(before.diff(&after), vec![
+            (Line(0), Column(0), 1),
+            (Line(2), Column(3), 2),
+            (Line(3), Column(4), 3),
+        ]);
+        assert!(after.diff(&after).is_empty());
+
+        // Applying the diff catches up
+        let mut applied = before.clone();
+        for (line, col, cell) in before.diff(&after) {
+            applied[line][col] = cell;
+        }
+        assert!(applied.diff(&after).is_empty());
+    }
+
+    #[test]
+    fn diff_includes_cells_outside_smaller_grid() {
+        let small = Grid::new(Line(1), Column(2), &0);
+        let large = Grid::new(Line(2), Column(2), &0);
+
+        assert_eq!(small.diff(&large), vec![(Line(1), Column(0), 0), (Line(1), Column(1), 0)]);
+    }
+
+    #[test]
+    fn for_each_mut_touches_only_range() {
+        let mut grid = Grid::new(Line(5), Column(3), &0);
+        grid.for_each_mut(Line(1)..Line(3), |c| *c += 1);
+
+        for (line, row) in grid.lines().enumerate() {
+            let expected = if line == 1 || line == 2 { 1 } else { 0 };
+            assert!(row.cells().all(|&c| c == expected), "line {}: {:?}", line, row);
+        }
+
+        grid.for_each_mut(Line(4).., |c| *c = 7);
+        grid.for_each_mut(..Line(1), |c| *c = 8);
+        assert_eq!(grid[Line(0)][Column(2)], 8);
+        assert_eq!(grid[Line(3)][Column(2)], 0);
+        assert_eq!(grid[Line(4)][Column(0)], 7);
+    }
+
+    #[test]
+    fn occupied_lines_after_reset() {
+        let mut grid = Grid::new(Line(4), Column(3), &0);
+        assert!(grid.occupied_lines().is_empty());
+
+        grid[Line(1)][Column(1)] = 1;
+        grid[Line(3)][Column(0)] = 1;
+        assert_eq!(grid.occupied_lines(), vec![Line(1), Line(3)]);
+
+        grid.reset_lines(.., &0);
+        grid[Line(2)][Column(2)] = 1;
+        assert_eq!(grid.occupied_lines(), vec![Line(2)]);
+
+        assert!(grid[Line(0)].is_clear(&0));
+        assert!(!grid[Line(0)].is_clear(&1));
+        assert!(!grid[Line(2)].is_clear(&0));
+
+        // Rows written with the same value are still found to be clear
+        grid[Line(2)][Column(2)] = 0;
+        assert!(grid[Line(2)].is_clear(&0));
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn parallel_rows_match_serial() {
+        use super::{for_each_row_serial, for_each_row_parallel};
+
+        let mut serial = Grid::new(Line(300), Column(400), &0usize);
+        for (i, row) in serial.lines_mut().enumerate() {
+            for (j, cell) in row.cells_mut().enumerate() {
+                *cell = i * 400 + j;
+            }
+        }
+
+        let mut parallel = serial.clone();
+        for_each_row_serial(&mut serial.raw[..], &|c: &mut usize| *c = *c * 3 + 1);
+        for_each_row_parallel(&mut parallel.raw[..], &|c: &mut usize| *c = *c * 3 + 1);
+
+        assert!(serial.diff(&parallel).is_empty());
+    }
+
+    #[test]
+    fn checked_access_at_edges() {
+        let mut grid = Grid::new(Line(3), Column(5), &0);
+        grid[Line(2)][Column(4)] = 1;
+
+        assert!(grid.contains_point(Line(0), Column(0)));
+        assert!(grid.contains_point(Line(2), Column(4)));
+        assert!(!grid.contains_point(Line(3), Column(0)));
+        assert!(!grid.contains_point(Line(0), Column(5)));
+
+        assert_eq!(grid.get(Line(2), Column(4)), Some(&1));
+        assert_eq!(grid.get(Line(2), Column(5)), None);
+        assert_eq!(grid.get(Line(3), Column(4)), None);
+
+        *grid.get_mut(Line(0), Column(0)).unwrap() = 2;
+        assert_eq!(grid[Line(0)][Column(0)], 2);
+        assert!(grid.get_mut(Line(3), Column(5)).is_none());
+    }
+
+    #[test]
+    fn reset_line_fills_row() {
+        let mut grid = Grid::new(Line(3), Column(5), &0);
+        for line in Line(0)..Line(3) {
+            for col in Column(0)..Column(5) {
+                grid[line][col] = 1;
+            }
+        }
+
+        grid.reset_line(Line(1), &7);
+        assert!(grid[Line(1)].cells().all(|c| *c == 7));
+        assert!(grid[Line(1)].is_clear(&7));
+        assert!(grid[Line(0)].cells().all(|c| *c == 1));
+        assert!(grid[Line(2)].cells().all(|c| *c == 1));
+        assert_eq!(grid.occupied_lines(), vec![Line(0), Line(2)]);
+    }
+
+    #[bench]
+    fn bench_reset_line(b: &mut Bencher) {
+        let mut grid = Grid::new(Line(100), Column(400), &0u32);
+        b.iter(|| {
+            grid.reset_line(Line(50), &0);
+            black_box(&grid);
+        });
+    }
+
+    #[bench]
+    fn bench_clear_large(b: &mut Bencher) {
+        // About an 8K display with a tiny font
+        let mut grid = Grid::new(Line(540), Column(1920), &0u32);
+        b.iter(|| {
+            grid.reset_lines(.., &0);
+            black_box(&grid);
+        });
+    }
+
+    #[bench]
+    fn bench_scroll_up_full(b: &mut Bencher) {
+        let mut grid = Grid::new(Line(100), Column(200), &0);
+        b.iter(|| {
+            grid.scroll_up(Line(0)..Line(99), Line(1));
+            black_box(&grid);
+        });
+    }
+
+    #[bench]
+    fn bench_scroll_up_region(b: &mut Bencher) {
+        let mut grid = Grid::new(Line(100), Column(200), &0);
+        b.iter(|| {
+            grid.scroll_up(Line(1)..Line(98), Line(1));
+            black_box(&grid);
+        });
+    }
 }
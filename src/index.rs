@@ -22,15 +22,44 @@ use std::ops::{self, Deref, Add};
 
 /// Index in the grid using row, column notation
 #[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "snapshot", derive(Serialize, Deserialize))]
 pub struct Cursor {
     pub line: Line,
     pub col: Column,
 }
 
+/// Direction of travel along a line
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    Left,
+    Right,
+}
+
+/// A location in the grid
+///
+/// By default `line` is a visible `Line` counted from the top of the screen.
+/// `Point<usize>` addresses the whole buffer instead, where line 0 is the
+/// oldest line of scrollback.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Point<L = Line> {
+    pub line: L,
+    pub col: Column,
+}
+
+impl<L> Point<L> {
+    pub fn new(line: L, col: Column) -> Point<L> {
+        Point {
+            line: line,
+            col: col,
+        }
+    }
+}
+
 /// A line
 ///
 /// Newtype to avoid passing values incorrectly
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Ord, PartialOrd)]
+#[cfg_attr(feature = "snapshot", derive(Serialize, Deserialize))]
 pub struct Line(pub usize);
 
 impl fmt::Display for Line {
@@ -43,6 +72,7 @@ impl fmt::Display for Line {
 ///
 /// Newtype to avoid passing values incorrectly
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Ord, PartialOrd)]
+#[cfg_attr(feature = "snapshot", derive(Serialize, Deserialize))]
 pub struct Column(pub usize);
 
 impl fmt::Display for Column {
@@ -40,7 +40,15 @@ use util::encode_char;
 ///
 /// TODO also need terminal state when processing input
 #[derive(Default)]
-pub struct Processor;
+pub struct Processor {
+    /// The last key press was handled by a binding, so the character it
+    /// produces must not be sent as well
+    suppress_char: bool,
+
+    /// Clipboard contents loaded by a paste binding, waiting to be passed to
+    /// `Term::paste`
+    paste: Option<String>,
+}
 
 /// Types that are notified of escape sequences from the input::Processor.
 pub trait Notify {
@@ -127,6 +135,18 @@ static DOWN_BINDINGS: &'static [Binding] = &[
     Binding { mods: mods::ANY,     action: Action::Esc("\x1bOB"),    mode: mode::APP_CURSOR, notmode: mode::NONE },
 ];
 
+/// Bindings for the Home key
+static HOME_BINDINGS: &'static [Binding] = &[
+    Binding { mods: mods::ANY, action: Action::Esc("\x1b[H"), mode: mode::ANY,        notmode: mode::APP_CURSOR },
+    Binding { mods: mods::ANY, action: Action::Esc("\x1bOH"), mode: mode::APP_CURSOR, notmode: mode::NONE },
+];
+
+/// Bindings for the End key
+static END_BINDINGS: &'static [Binding] = &[
+    Binding { mods: mods::ANY, action: Action::Esc("\x1b[F"), mode: mode::ANY,        notmode: mode::APP_CURSOR },
+    Binding { mods: mods::ANY, action: Action::Esc("\x1bOF"), mode: mode::APP_CURSOR, notmode: mode::NONE },
+];
+
 /// Bindings for the F1 key
 static F1_BINDINGS: &'static [Binding] = &[
     Binding { mods: mods::ANY, action: Action::Esc("\x1bOP"), mode: mode::ANY, notmode: mode::NONE },
@@ -221,6 +241,40 @@ static MOUSE_MIDDLE_BINDINGS: &'static [Binding] = &[];
 static MOUSE_LEFT_BINDINGS: &'static [Binding] = &[];
 static MOUSE_RIGHT_BINDINGS: &'static [Binding] = &[];
 
+/// Generate bindings for a key on the numeric keypad
+///
+/// Keypad keys only have bindings in application keypad mode; otherwise they
+/// produce the same characters as the main keyboard.
+macro_rules! keypad_bindings {
+    ($name:ident, $esc:expr) => {
+        static $name: &'static [Binding] = &[
+            Binding { mods: mods::ANY, action: Action::Esc($esc), mode: mode::APP_KEYPAD, notmode: mode::NONE },
+        ];
+    }
+}
+
+keypad_bindings!(NUMPAD0_BINDINGS, "\x1bOp");
+keypad_bindings!(NUMPAD1_BINDINGS, "\x1bOq");
+keypad_bindings!(NUMPAD2_BINDINGS, "\x1bOr");
+keypad_bindings!(NUMPAD3_BINDINGS, "\x1bOs");
+keypad_bindings!(NUMPAD4_BINDINGS, "\x1bOt");
+keypad_bindings!(NUMPAD5_BINDINGS, "\x1bOu");
+keypad_bindings!(NUMPAD6_BINDINGS, "\x1bOv");
+keypad_bindings!(NUMPAD7_BINDINGS, "\x1bOw");
+keypad_bindings!(NUMPAD8_BINDINGS, "\x1bOx");
+keypad_bindings!(NUMPAD9_BINDINGS, "\x1bOy");
+keypad_bindings!(NUMPAD_MULTIPLY_BINDINGS, "\x1bOj");
+keypad_bindings!(NUMPAD_ADD_BINDINGS, "\x1bOk");
+keypad_bindings!(NUMPAD_COMMA_BINDINGS, "\x1bOl");
+keypad_bindings!(NUMPAD_SUBTRACT_BINDINGS, "\x1bOm");
+keypad_bindings!(NUMPAD_DECIMAL_BINDINGS, "\x1bOn");
+keypad_bindings!(NUMPAD_DIVIDE_BINDINGS, "\x1bOo");
+keypad_bindings!(NUMPAD_ENTER_BINDINGS, "\x1bOM");
+keypad_bindings!(NUMPAD_EQUALS_BINDINGS, "\x1bOX");
+
+/// Keys which are known to have no bindings
+static NO_BINDINGS: &'static [Binding] = &[];
+
 /// Bindings for the Backspace key
 static BACKSPACE_BINDINGS: &'static [Binding] = &[
     Binding { mods: mods::ANY, action: Action::Esc("\x7f"), mode: mode::ANY, notmode: mode::NONE },
@@ -238,11 +292,126 @@ static DELETE_BINDINGS: &'static [Binding] = &[
 //     appcursor = DECCKM (application cursor mode);
 //          crlf = LNM    (Linefeed/new line); wtf is this
 
+/// Get the bindings for a key
+///
+/// Returns `None` for keys which aren't handled at all.
+fn key_bindings(key: VirtualKeyCode) -> Option<&'static [Binding]> {
+    let bindings = match key {
+        // Arrows
+        VirtualKeyCode::Left => LEFT_BINDINGS,
+        VirtualKeyCode::Up => UP_BINDINGS,
+        VirtualKeyCode::Down => DOWN_BINDINGS,
+        VirtualKeyCode::Right => RIGHT_BINDINGS,
+        VirtualKeyCode::Home => HOME_BINDINGS,
+        VirtualKeyCode::End => END_BINDINGS,
+        // Function keys
+        VirtualKeyCode::F1 => F1_BINDINGS,
+        VirtualKeyCode::F2 => F2_BINDINGS,
+        VirtualKeyCode::F3 => F3_BINDINGS,
+        VirtualKeyCode::F4 => F4_BINDINGS,
+        VirtualKeyCode::F5 => F5_BINDINGS,
+        VirtualKeyCode::F6 => F6_BINDINGS,
+        VirtualKeyCode::F7 => F7_BINDINGS,
+        VirtualKeyCode::F8 => F8_BINDINGS,
+        VirtualKeyCode::F9 => F9_BINDINGS,
+        VirtualKeyCode::F10 => F10_BINDINGS,
+        VirtualKeyCode::F11 => F11_BINDINGS,
+        VirtualKeyCode::F12 => F12_BINDINGS,
+        VirtualKeyCode::Back => BACKSPACE_BINDINGS,
+        VirtualKeyCode::Delete => DELETE_BINDINGS,
+        VirtualKeyCode::H => H_BINDINGS,
+        VirtualKeyCode::V => V_BINDINGS,
+        // Keypad
+        VirtualKeyCode::Numpad0 => NUMPAD0_BINDINGS,
+        VirtualKeyCode::Numpad1 => NUMPAD1_BINDINGS,
+        VirtualKeyCode::Numpad2 => NUMPAD2_BINDINGS,
+        VirtualKeyCode::Numpad3 => NUMPAD3_BINDINGS,
+        VirtualKeyCode::Numpad4 => NUMPAD4_BINDINGS,
+        VirtualKeyCode::Numpad5 => NUMPAD5_BINDINGS,
+        VirtualKeyCode::Numpad6 => NUMPAD6_BINDINGS,
+        VirtualKeyCode::Numpad7 => NUMPAD7_BINDINGS,
+        VirtualKeyCode::Numpad8 => NUMPAD8_BINDINGS,
+        VirtualKeyCode::Numpad9 => NUMPAD9_BINDINGS,
+        VirtualKeyCode::Multiply => NUMPAD_MULTIPLY_BINDINGS,
+        VirtualKeyCode::Add => NUMPAD_ADD_BINDINGS,
+        VirtualKeyCode::NumpadComma => NUMPAD_COMMA_BINDINGS,
+        VirtualKeyCode::Subtract => NUMPAD_SUBTRACT_BINDINGS,
+        VirtualKeyCode::Decimal => NUMPAD_DECIMAL_BINDINGS,
+        VirtualKeyCode::Divide => NUMPAD_DIVIDE_BINDINGS,
+        VirtualKeyCode::NumpadEnter => NUMPAD_ENTER_BINDINGS,
+        VirtualKeyCode::NumpadEquals => NUMPAD_EQUALS_BINDINGS,
+        // Mode keys ignored now
+        VirtualKeyCode::LAlt | VirtualKeyCode::RAlt | VirtualKeyCode::LShift |
+        VirtualKeyCode::RShift | VirtualKeyCode::LControl | VirtualKeyCode::RControl |
+        VirtualKeyCode::LWin | VirtualKeyCode::RWin => NO_BINDINGS,
+        // All of the alphanumeric keys get passed through here as well, but there's no work
+        // to be done for them.
+        VirtualKeyCode::A | VirtualKeyCode::B | VirtualKeyCode::C | VirtualKeyCode::D |
+        VirtualKeyCode::E | VirtualKeyCode::F | VirtualKeyCode::G |
+        VirtualKeyCode::I | VirtualKeyCode::J | VirtualKeyCode::K | VirtualKeyCode::L |
+        VirtualKeyCode::M | VirtualKeyCode::N | VirtualKeyCode::O | VirtualKeyCode::P |
+        VirtualKeyCode::Q | VirtualKeyCode::R | VirtualKeyCode::S | VirtualKeyCode::T |
+        VirtualKeyCode::U | VirtualKeyCode::W | VirtualKeyCode::X |
+        VirtualKeyCode::Y | VirtualKeyCode::Z => NO_BINDINGS,
+        VirtualKeyCode::Key1 | VirtualKeyCode::Key2 | VirtualKeyCode::Key3 |
+        VirtualKeyCode::Key4 | VirtualKeyCode::Key5 | VirtualKeyCode::Key6 |
+        VirtualKeyCode::Key7 | VirtualKeyCode::Key8 | VirtualKeyCode::Key9 |
+        VirtualKeyCode::Key0 => NO_BINDINGS,
+        _ => return None,
+    };
+
+    Some(bindings)
+}
+
+/// Find the first binding matching the terminal mode and modifiers
+fn find_binding(bindings: &[Binding], mode: TermMode, mods: Mods) -> Option<&Binding> {
+    bindings.iter().find(|binding| {
+        // TermMode positive
+        (binding.mode.is_all() || mode.intersects(binding.mode)) &&
+            // TermMode negative
+            (binding.notmode.is_empty() || !mode.intersects(binding.notmode)) &&
+            // Modifier keys
+            (binding.mods.is_all() || mods == binding.mods)
+    })
+}
+
+/// Get the bytes a key press sends to the pty in the given mode
+///
+/// Arrow keys depend on `APP_CURSOR` and the keypad on `APP_KEYPAD`. Returns
+/// an empty buffer for keys without a binding; any text they produce arrives
+/// as a character instead. Paste bindings are not encoded either.
+pub fn encode_key(key: VirtualKeyCode, mods: Mods, mode: TermMode) -> Vec<u8> {
+    let binding = key_bindings(key).and_then(|bindings| find_binding(bindings, mode, mods));
+
+    match binding.map(|binding| &binding.action) {
+        Some(&Action::Esc(s)) => s.as_bytes().to_vec(),
+        Some(&Action::Char(c)) => encode_char(c),
+        Some(&Action::Paste) | None => Vec::new(),
+    }
+}
+
 impl Processor {
     pub fn new() -> Processor {
         Default::default()
     }
 
+    /// Check whether a received character should be sent to the pty
+    ///
+    /// Characters produced by a key press which triggered a binding are
+    /// dropped; the binding already sent what the key means.
+    pub fn accept_char(&mut self) -> bool {
+        !::std::mem::replace(&mut self.suppress_char, false)
+    }
+
+    /// Take the text a paste binding loaded, if any
+    ///
+    /// Pastes aren't sent through the notifier; the terminal decides whether
+    /// they're written.
+    #[inline]
+    pub fn take_paste(&mut self) -> Option<String> {
+        self.paste.take()
+    }
+
     pub fn mouse_input<N: Notify>(
         &mut self,
         state: ElementState,
@@ -278,100 +447,59 @@ impl Processor {
                 return;
             }
 
-            let bindings = match key {
-                // Arrows
-                VirtualKeyCode::Left => LEFT_BINDINGS,
-                VirtualKeyCode::Up => UP_BINDINGS,
-                VirtualKeyCode::Down => DOWN_BINDINGS,
-                VirtualKeyCode::Right => RIGHT_BINDINGS,
-                // Function keys
-                VirtualKeyCode::F1 => F1_BINDINGS,
-                VirtualKeyCode::F2 => F2_BINDINGS,
-                VirtualKeyCode::F3 => F3_BINDINGS,
-                VirtualKeyCode::F4 => F4_BINDINGS,
-                VirtualKeyCode::F5 => F5_BINDINGS,
-                VirtualKeyCode::F6 => F6_BINDINGS,
-                VirtualKeyCode::F7 => F7_BINDINGS,
-                VirtualKeyCode::F8 => F8_BINDINGS,
-                VirtualKeyCode::F9 => F9_BINDINGS,
-                VirtualKeyCode::F10 => F10_BINDINGS,
-                VirtualKeyCode::F11 => F11_BINDINGS,
-                VirtualKeyCode::F12 => F12_BINDINGS,
-                VirtualKeyCode::Back => BACKSPACE_BINDINGS,
-                VirtualKeyCode::Delete => DELETE_BINDINGS,
-                VirtualKeyCode::H => H_BINDINGS,
-                VirtualKeyCode::V => V_BINDINGS,
-                // Mode keys ignored now
-                VirtualKeyCode::LAlt | VirtualKeyCode::RAlt | VirtualKeyCode::LShift |
-                VirtualKeyCode::RShift | VirtualKeyCode::LControl | VirtualKeyCode::RControl |
-                VirtualKeyCode::LWin | VirtualKeyCode::RWin => return,
-                // All of the alphanumeric keys get passed through here as well, but there's no work
-                // to be done for them.
-                VirtualKeyCode::A | VirtualKeyCode::B | VirtualKeyCode::C | VirtualKeyCode::D |
-                VirtualKeyCode::E | VirtualKeyCode::F | VirtualKeyCode::G |
-                VirtualKeyCode::I | VirtualKeyCode::J | VirtualKeyCode::K | VirtualKeyCode::L |
-                VirtualKeyCode::M | VirtualKeyCode::N | VirtualKeyCode::O | VirtualKeyCode::P |
-                VirtualKeyCode::Q | VirtualKeyCode::R | VirtualKeyCode::S | VirtualKeyCode::T |
-                VirtualKeyCode::U | VirtualKeyCode::W | VirtualKeyCode::X |
-                VirtualKeyCode::Y | VirtualKeyCode::Z => return,
-                VirtualKeyCode::Key1 | VirtualKeyCode::Key2 | VirtualKeyCode::Key3 |
-                VirtualKeyCode::Key4 | VirtualKeyCode::Key5 | VirtualKeyCode::Key6 |
-                VirtualKeyCode::Key7 | VirtualKeyCode::Key8 | VirtualKeyCode::Key9 |
-                VirtualKeyCode::Key0 => return,
+            let bindings = match key_bindings(key) {
+                Some(bindings) => bindings,
                 // Log something by default
-                _ => {
-                    println!("Unhandled key: {:?}; state: {:?}; mods: {:?}",
-                             key, state, mods);
+                None => {
+                    debug!("Unhandled key: {:?}; state: {:?}; mods: {:?}",
+                           key, state, mods);
+                    self.suppress_char = false;
                     return;
                 },
             };
 
-            self.process_bindings(bindings, mode, notifier, mods);
+            self.suppress_char = self.process_bindings(bindings, mode, notifier, mods);
         }
     }
 
-    fn process_bindings<N>(&self,
+    /// Run the first binding matching `mode` and `mods`
+    ///
+    /// Returns whether a binding was run.
+    fn process_bindings<N>(&mut self,
                            bindings: &[Binding],
                            mode: TermMode,
                            notifier: &mut N,
-                           mods: Mods)
+                           mods: Mods) -> bool
         where N: Notify
     {
-        // Check each binding
-        for binding in bindings {
-            // TermMode positive
-            if binding.mode.is_all() || mode.intersects(binding.mode) {
-                // TermMode negative
-                if binding.notmode.is_empty() || !mode.intersects(binding.notmode) {
-                    // Modifier keys
-                    if binding.mods.is_all() || mods == binding.mods {
-                        // everything matches; run the binding action
-                        match binding.action {
-                            Action::Esc(s) => notifier.notify(s.as_bytes()),
-                            Action::Paste => {
-                                println!("paste request");
-                                let clip = Clipboard::new().expect("get clipboard");
-                                clip.load_selection()
-                                    .map(|contents| {
-                                        println!("got contents");
-                                        notifier.notify(contents.into_bytes())
-                                    })
-                                    .unwrap_or_else(|err| {
-                                        err_println!("Error getting clipboard contents: {}", err);
-                                    });
-
-                                println!("ok");
-                            },
-                            Action::Char(c) => {
-                                notifier.notify(encode_char(c));
-                            }
-                        }
-
-                        break;
-                    }
-                }
+        let binding = match find_binding(bindings, mode, mods) {
+            Some(binding) => binding,
+            None => return false,
+        };
+
+        // everything matches; run the binding action
+        match binding.action {
+            Action::Esc(s) => notifier.notify(s.as_bytes()),
+            Action::Paste => {
+                trace!("paste request");
+                let clip = Clipboard::new().expect("get clipboard");
+                clip.load_selection()
+                    .map(|contents| {
+                        trace!("got contents");
+                        self.paste = Some(contents);
+                    })
+                    .unwrap_or_else(|err| {
+                        warn!("Error getting clipboard contents: {}", err);
+                    });
+
+                trace!("ok");
+            },
+            Action::Char(c) => {
+                notifier.notify(encode_char(c));
             }
         }
+
+        true
     }
 }
 
@@ -379,13 +507,14 @@ impl Processor {
 mod tests {
     use std::borrow::Cow;
 
-    use glutin::mods;
+    use glutin::{mods, VirtualKeyCode};
 
     use term::mode;
 
     use super::Action;
     use super::Processor;
     use super::Binding;
+    use super::encode_key;
 
     /// Receiver that keeps a copy of any strings it is notified with
     #[derive(Default)]
@@ -411,7 +540,7 @@ mod tests {
             fn $name() {
                 let bindings = &[$binding];
 
-                let processor = Processor::new();
+                let mut processor = Processor::new();
                 let mut receiver = Receiver::default();
 
                 processor.process_bindings(bindings, $mode, &mut receiver, $mods);
@@ -496,4 +625,38 @@ mod tests {
     fn print_v_bindings() {
         println!("{:#?}", super::V_BINDINGS);
     }
+
+    #[test]
+    fn encode_arrow_keys() {
+        let keys = [
+            (VirtualKeyCode::Up, 'A'),
+            (VirtualKeyCode::Down, 'B'),
+            (VirtualKeyCode::Right, 'C'),
+            (VirtualKeyCode::Left, 'D'),
+        ];
+
+        for &(key, c) in &keys {
+            assert_eq!(encode_key(key, mods::NONE, mode::NONE), format!("\x1b[{}", c).into_bytes());
+            assert_eq!(encode_key(key, mods::NONE, mode::APP_CURSOR),
+                       format!("\x1bO{}", c).into_bytes());
+            assert_eq!(encode_key(key, mods::SHIFT, mode::APP_CURSOR),
+                       format!("\x1b[1;2{}", c).into_bytes());
+        }
+    }
+
+    #[test]
+    fn encode_keypad_keys() {
+        assert_eq!(encode_key(VirtualKeyCode::Numpad1, mods::NONE, mode::NONE), b"");
+        assert_eq!(encode_key(VirtualKeyCode::Numpad1, mods::NONE, mode::APP_KEYPAD), b"\x1bOq");
+        assert_eq!(encode_key(VirtualKeyCode::NumpadEnter, mods::NONE, mode::APP_KEYPAD),
+                   b"\x1bOM");
+
+        // Application cursor mode doesn't change the keypad
+        assert_eq!(encode_key(VirtualKeyCode::Numpad1, mods::NONE, mode::APP_CURSOR), b"");
+    }
+
+    #[test]
+    fn encode_unbound_key() {
+        assert_eq!(encode_key(VirtualKeyCode::A, mods::NONE, mode::NONE), b"");
+    }
 }
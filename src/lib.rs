@@ -0,0 +1,107 @@
+// Copyright 2016 Joe Wilm, The Alacritty Project Contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//! Alacritty - The GPU Enhanced Terminal
+//!
+//! The terminal model, parser, renderer and event handling live here; the
+//! `alacritty` binary wires them up to a window.
+#![feature(question_mark)]
+#![feature(range_contains)]
+#![feature(inclusive_range_syntax)]
+#![feature(drop_types_in_const)]
+#![feature(unicode)]
+#![feature(step_trait)]
+#![feature(core_intrinsics)]
+#![cfg_attr(test, feature(test, set_stdio))]
+#![allow(stable_features)] // lying about question_mark because 1.14.0 isn't released!
+
+#![feature(proc_macro)]
+
+#[macro_use]
+extern crate serde_derive;
+
+extern crate cgmath;
+extern crate copypasta;
+extern crate errno;
+extern crate font;
+extern crate glutin;
+extern crate libc;
+extern crate mio;
+extern crate notify;
+extern crate parking_lot;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+extern crate serde;
+extern crate serde_yaml;
+extern crate unicode_width;
+extern crate vte;
+
+#[macro_use]
+extern crate bitflags;
+
+#[macro_use]
+extern crate log;
+
+#[cfg(test)]
+extern crate test;
+
+#[macro_use]
+mod macros;
+
+pub mod ansi;
+pub mod config;
+pub mod event;
+pub mod event_loop;
+pub mod grid;
+pub mod index;
+pub mod input;
+pub mod logging;
+pub mod meter;
+pub mod renderer;
+pub mod sync;
+pub mod term;
+pub mod tty;
+pub mod util;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Clone)]
+pub struct Flag(Arc<AtomicBool>);
+impl Flag {
+    pub fn new(initial_value: bool) -> Flag {
+        Flag(Arc::new(AtomicBool::new(initial_value)))
+    }
+
+    #[inline]
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub fn set(&self, value: bool) {
+        self.0.store(value, Ordering::Release)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+pub mod gl {
+    include!(concat!(env!("OUT_DIR"), "/gl_bindings.rs"));
+}
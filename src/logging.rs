@@ -0,0 +1,57 @@
+// Copyright 2016 Joe Wilm, The Alacritty Project Contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//! Logging for Alacritty
+//!
+//! Records are written to stderr. The maximum level defaults to `warn` and may
+//! be overridden with the `ALACRITTY_LOG` environment variable, eg.
+//! `ALACRITTY_LOG=trace`.
+use std::env;
+use std::io::{self, Write};
+
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+
+/// Environment variable holding the maximum log level
+const LEVEL_VAR: &'static str = "ALACRITTY_LOG";
+
+struct Logger {
+    level: LogLevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(&mut io::stderr(), "[{}] {}", record.level(), record.args());
+        }
+    }
+}
+
+/// Install the stderr logger
+///
+/// Must be called once before anything is logged; records emitted earlier are
+/// discarded.
+pub fn initialize() -> Result<(), SetLoggerError> {
+    let level = env::var(LEVEL_VAR).ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LogLevelFilter::Warn);
+
+    log::set_logger(|max_log_level| {
+        max_log_level.set(level);
+        Box::new(Logger { level: level })
+    })
+}
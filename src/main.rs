@@ -13,88 +13,33 @@
 // limitations under the License.
 //
 //! Alacritty - The GPU Enhanced Terminal
-#![feature(question_mark)]
-#![feature(range_contains)]
-#![feature(inclusive_range_syntax)]
 #![feature(drop_types_in_const)]
-#![feature(unicode)]
-#![feature(step_trait)]
-#![feature(core_intrinsics)]
-#![allow(stable_features)] // lying about question_mark because 1.14.0 isn't released!
-
-#![feature(proc_macro)]
 
 #[macro_use]
-extern crate serde_derive;
-
-extern crate cgmath;
-extern crate copypasta;
-extern crate errno;
+extern crate alacritty;
 extern crate font;
 extern crate glutin;
-extern crate libc;
-extern crate mio;
-extern crate notify;
 extern crate parking_lot;
-extern crate serde;
-extern crate serde_yaml;
-extern crate vte;
 
 #[macro_use]
-extern crate bitflags;
-
-#[macro_use]
-mod macros;
-
-mod event;
-mod event_loop;
-mod index;
-mod input;
-mod meter;
-mod renderer;
-mod sync;
-mod term;
-mod tty;
-mod util;
-pub mod ansi;
-pub mod config;
-pub mod grid;
+extern crate log;
 
 use std::sync::{mpsc, Arc};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 use parking_lot::{MutexGuard};
 
-use event_loop::EventLoop;
-
-use config::Config;
-use meter::Meter;
-use renderer::{QuadRenderer, GlyphCache};
-use sync::FairMutex;
-use term::Term;
-use tty::process_should_exit;
+use alacritty::{config, event, gl, input, logging, Flag, Rgb};
+use alacritty::event_loop::EventLoop;
+use alacritty::config::Config;
+use alacritty::meter::Meter;
+use alacritty::renderer::{QuadRenderer, GlyphCache};
+use alacritty::sync::FairMutex;
+use alacritty::term::Term;
 
 /// Channel used by resize handling on mac
 static mut resize_sender: Option<mpsc::Sender<(u32, u32)>> = None;
 
-#[derive(Clone)]
-pub struct Flag(Arc<AtomicBool>);
-impl Flag {
-    pub fn new(initial_value: bool) -> Flag {
-        Flag(Arc::new(AtomicBool::new(initial_value)))
-    }
-
-    #[inline]
-    pub fn get(&self) -> bool {
-        self.0.load(Ordering::Acquire)
-    }
-
-    #[inline]
-    pub fn set(&self, value: bool) {
-        self.0.store(value, Ordering::Release)
-    }
-}
-
 /// Resize handling for Mac
 fn window_resize_handler(width: u32, height: u32) {
     unsafe {
@@ -104,18 +49,9 @@ fn window_resize_handler(width: u32, height: u32) {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
-pub struct Rgb {
-    r: u8,
-    g: u8,
-    b: u8,
-}
-
-mod gl {
-    include!(concat!(env!("OUT_DIR"), "/gl_bindings.rs"));
-}
-
 fn main() {
+    logging::initialize().expect("logger is only initialized once");
+
     // Load configuration
     let config = match Config::load() {
         Err(err) => match err {
@@ -142,7 +78,7 @@ fn main() {
     let (width, height) = window.get_inner_size_pixels().unwrap();
     let dpr = window.hidpi_factor();
 
-    println!("device_pixel_ratio: {}", dpr);
+    debug!("device_pixel_ratio: {}", dpr);
 
     let _ = unsafe { window.make_current() };
     unsafe {
@@ -152,35 +88,36 @@ fn main() {
         gl::Enable(gl::MULTISAMPLE);
     }
 
-    let rasterizer = font::Rasterizer::new(dpi.x(), dpi.y(), dpr);
+    let rasterizer = font::Rasterizer::new(dpi.x(), dpi.y(), font.rasterizer_config());
 
     // Create renderer
     let mut renderer = QuadRenderer::new(width, height);
 
     // Initialize glyph cache
     let glyph_cache = {
-        println!("Initializing glyph cache");
-        let init_start = ::std::time::Instant::now();
+        debug!("Initializing glyph cache");
+        let init_start = Instant::now();
 
         let cache = renderer.with_loader(|mut api| {
-            GlyphCache::new(rasterizer, &config, &mut api)
+            GlyphCache::new(rasterizer, &config, dpr, &mut api)
         });
 
         let stop = init_start.elapsed();
         let stop_f = stop.as_secs() as f64 + stop.subsec_nanos() as f64 / 1_000_000_000f64;
-        println!("Finished initializing glyph cache in {}", stop_f);
+        info!("Finished initializing glyph cache in {}", stop_f);
 
         cache
     };
 
-    let metrics = glyph_cache.font_metrics();
-    let cell_width = (metrics.average_advance + font.offset().x() as f64) as u32;
-    let cell_height = (metrics.line_height + font.offset().y() as f64) as u32;
+    let (cell_width, cell_height) = glyph_cache.cell_size();
+    let cell_width = (cell_width + font.offset().x()) as u32;
+    let cell_height = (cell_height + font.offset().y()) as u32;
 
-    println!("Cell Size: ({} x {})", cell_width, cell_height);
+    debug!("Cell Size: ({} x {})", cell_width, cell_height);
 
     let terminal = Term::new(
         &config,
+        glyph_cache.font_keys(),
         width as f32,
         height as f32,
         cell_width as f32,
@@ -207,7 +144,7 @@ fn main() {
     );
 
     let loop_tx = event_loop.channel();
-    let event_loop_handle = event_loop.spawn(None);
+    let event_loop_handle = event_loop.spawn();
 
     // Wraps a renderer and gives simple draw() api.
     let mut display = Display::new(
@@ -234,18 +171,31 @@ fn main() {
         // Maybe draw the terminal
         let terminal = terminal.lock();
         signal_flag.set(false);
+
+        // Check before drawing; the draw consumes the lock
+        let exited = terminal.child_exited();
         if terminal.dirty {
             display.draw(terminal);
         }
 
-        if process_should_exit() {
-            break;
+        match exited {
+            Ok(Some(status)) => {
+                if status != 0 {
+                    warn!("child exited with status {}", status);
+                }
+                break;
+            },
+            Ok(None) => (),
+            Err(err) => {
+                error!("Waiting for the child failed: {}", err);
+                break;
+            },
         }
     }
 
     // shutdown
     event_loop_handle.join().ok();
-    println!("Goodbye");
+    debug!("Goodbye");
 }
 
 struct Display {
@@ -288,8 +238,6 @@ impl Display {
     ///
     /// This call may block if vsync is enabled
     pub fn draw(&mut self, mut terminal: MutexGuard<Term>) {
-        terminal.dirty = false;
-
         // Resize events new_size and are handled outside the poll_events
         // iterator. This has the effect of coalescing multiple resize
         // events into one.
@@ -323,7 +271,9 @@ impl Display {
                 self.renderer.with_api(&size_info, |mut api| {
                     // Draw the grid
                     let bg = terminal.bg;
-                    api.render_grid(&bg, &terminal.render_grid(), glyph_cache);
+                    let font_keys = *terminal.font_keys();
+                    let grid = terminal.render_grid(Instant::now());
+                    api.render_grid(&bg, &grid, &font_keys, glyph_cache);
                 });
             }
 
@@ -338,6 +288,9 @@ impl Display {
             }
         }
 
+        // Everything changed so far is in this frame, including a resize
+        terminal.clear_dirty();
+
         // Unlock the terminal mutex
         drop(terminal);
         self.window.swap_buffers().unwrap();
@@ -22,14 +22,13 @@ use std::sync::Arc;
 use std::sync::atomic::{Ordering, AtomicBool};
 
 use cgmath;
-use font::{self, Rasterizer, RasterizedGlyph, FontDesc, GlyphKey, FontKey};
+use font::{self, Rasterize, Rasterizer, RasterizedGlyph, FontDesc, GlyphKey, PixelFormat};
 use gl::types::*;
 use gl;
 use notify::{Watcher as WatcherApi, RecommendedWatcher as Watcher, op};
 
-use config::Config;
-use grid::Grid;
-use term::{self, cell, Cell};
+use config::{Config, MissingGlyph};
+use term::{self, cell, Cell, FontKeys, RenderGrid};
 
 use super::Rgb;
 
@@ -90,25 +89,36 @@ pub struct GlyphCache {
     /// Rasterizer for loading new glyphs
     rasterizer: Rasterizer,
 
-    /// regular font
-    font_key: FontKey,
-
-    /// italic font
-    italic_key: FontKey,
-
-    /// bold font
-    bold_key: FontKey,
+    /// regular, bold and italic fonts
+    font_keys: FontKeys,
 
     /// font size
     font_size: font::Size,
+
+    /// What's drawn for characters the fonts have no glyph for
+    missing_glyph: MissingGlyph,
+
+    /// Whether box drawing characters are drawn without the font
+    builtin_box_drawing: bool,
+
+    /// Whether box drawing characters are stretched to fill the cell
+    stretch_box_drawing: bool,
+
+    /// Size of a cell in pixels, including the configured offset
+    cell_size: (i32, i32),
 }
 
 impl GlyphCache {
-    pub fn new<L>(mut rasterizer: Rasterizer, config: &Config, loader: &mut L) -> GlyphCache
+    pub fn new<L>(
+        mut rasterizer: Rasterizer,
+        config: &Config,
+        device_pixel_ratio: f32,
+        loader: &mut L
+    ) -> GlyphCache
         where L: LoadGlyph
     {
         let font = config.font();
-        let size = font.size();
+        let size = font::Size::with_dpi(font.size().as_f32_pts(), device_pixel_ratio);
 
         // Load regular font
         let regular_desc = FontDesc::new(font.family(), font.style());
@@ -121,7 +131,7 @@ impl GlyphCache {
         let bold = if bold_desc == regular_desc {
             regular.clone()
         } else {
-            rasterizer.load_font(&bold_desc, size).unwrap_or_else(|| regular.clone())
+            rasterizer.load_font(&bold_desc, size).unwrap_or_else(|_| regular.clone())
         };
 
         // Load italic font
@@ -132,16 +142,35 @@ impl GlyphCache {
             regular.clone()
         } else {
             rasterizer.load_font(&italic_desc, size)
-                      .unwrap_or_else(|| regular.clone())
+                      .unwrap_or_else(|_| regular.clone())
         };
 
+        // Load bold italic font; fall back to plain bold when the family has
+        // no such variant.
+        let bold_italic_style = format!("{} {}", bold_style, italic_style);
+        let bold_italic_desc = FontDesc::new(font.family(), &bold_italic_style[..]);
+
+        let bold_italic = rasterizer.load_font(&bold_italic_desc, size)
+                                    .unwrap_or_else(|_| bold.clone());
+
+        let (cell_width, cell_height) = rasterizer.cell_size(regular, size);
+        let cell_size = ((cell_width + font.offset().x()) as i32,
+                         (cell_height + font.offset().y()) as i32);
+
         let mut cache = GlyphCache {
             cache: HashMap::new(),
             rasterizer: rasterizer,
-            font_size: font.size(),
-            font_key: regular.clone(),
-            bold_key: bold.clone(),
-            italic_key: italic.clone(),
+            font_size: size,
+            missing_glyph: config.missing_glyph(),
+            builtin_box_drawing: config.builtin_box_drawing(),
+            stretch_box_drawing: font.stretch_box_drawing(),
+            cell_size: cell_size,
+            font_keys: FontKeys {
+                regular: regular,
+                bold: bold,
+                italic: italic,
+                bold_italic: bold_italic,
+            },
         };
 
         macro_rules! load_glyphs_for_font {
@@ -150,7 +179,7 @@ impl GlyphCache {
                     cache.load_and_cache_glyph(GlyphKey {
                         font_key: $font,
                         c: i as char,
-                        size: font.size()
+                        size: size
                     }, loader);
                 }
             }
@@ -159,18 +188,70 @@ impl GlyphCache {
         load_glyphs_for_font!(regular);
         load_glyphs_for_font!(bold);
         load_glyphs_for_font!(italic);
+        load_glyphs_for_font!(bold_italic);
 
         cache
     }
 
-    pub fn font_metrics(&self) -> font::Metrics {
-        self.rasterizer.metrics(self.font_key, self.font_size)
+    /// Get the size of a terminal cell in pixels, before any configured offset
+    pub fn cell_size(&self) -> (f32, f32) {
+        self.rasterizer.cell_size(self.font_keys.regular, self.font_size)
+    }
+
+    /// Get the keys of the fonts loaded for regular, bold and italic text
+    #[inline]
+    pub fn font_keys(&self) -> FontKeys {
+        self.font_keys
     }
 
     fn load_and_cache_glyph<L>(&mut self, glyph_key: GlyphKey, loader: &mut L)
         where L: LoadGlyph
     {
-        let rasterized = self.rasterizer.get_glyph(&glyph_key);
+        if self.builtin_box_drawing && font::is_box_drawing(glyph_key.c) {
+            let metrics = self.rasterizer.metrics(glyph_key.font_key, glyph_key.size);
+            let (width, height) = self.cell_size;
+            let descent = metrics.descent.round() as i32;
+            let rasterized = font::box_drawing::rasterize(glyph_key.c, width, height, descent);
+            if let Some(rasterized) = rasterized {
+                let glyph = loader.load_glyph(&rasterized);
+                self.cache.insert(glyph_key, glyph);
+                return;
+            }
+        }
+
+        let rasterized = self.rasterizer.get_glyph(&glyph_key)
+            .or_else(|err| {
+                error!("{}", err);
+
+                // The placeholder is cached under the missing character's key;
+                // the cell itself keeps its character.
+                match placeholder_key(&glyph_key, self.missing_glyph) {
+                    Some(key) => self.rasterizer.get_glyph(&key),
+                    None => Err(err),
+                }
+            })
+            .unwrap_or_else(|_| {
+                // Cache an empty glyph so the character isn't rasterized again
+                RasterizedGlyph {
+                    c: glyph_key.c,
+                    width: 0,
+                    height: 0,
+                    top: 0,
+                    left: 0,
+                    advance: (0, 0),
+                    format: PixelFormat::Rgb,
+                    buf: Vec::new(),
+                }
+            });
+
+        let rasterized = if self.stretch_box_drawing && font::is_box_drawing(glyph_key.c) {
+            let metrics = self.rasterizer.metrics(glyph_key.font_key, glyph_key.size);
+            let (width, height) = self.cell_size;
+            rasterized.stretch_to_cell(&metrics, width, height)
+        } else {
+            rasterized
+        };
+
         let glyph = loader.load_glyph(&rasterized);
         self.cache.insert(glyph_key, glyph);
     }
@@ -192,6 +273,15 @@ impl GlyphCache {
     }
 }
 
+/// Get the key of the glyph drawn in place of the missing one at `key`
+///
+/// Returns `None` when nothing should be drawn, including when the
+/// placeholder is the missing character itself.
+fn placeholder_key(key: &GlyphKey, missing_glyph: MissingGlyph) -> Option<GlyphKey> {
+    missing_glyph.placeholder()
+        .and_then(|c| if c == key.c { None } else { Some(GlyphKey { c: c, ..*key }) })
+}
+
 #[derive(Debug)]
 struct InstanceData {
     // coords
@@ -267,12 +357,13 @@ impl Batch {
         }
     }
 
-    pub fn add_item(&mut self, row: f32, col: f32, cell: &Cell, glyph: &Glyph) {
+    pub fn add_item(&mut self, row: f32, col: f32, colors: (Rgb, Rgb), glyph: &Glyph) {
         if self.is_empty() {
             self.tex = glyph.tex_id;
         }
 
-        let mut instance = InstanceData {
+        let (fg, bg) = colors;
+        let instance = InstanceData {
             col: col,
             row: row,
 
@@ -286,25 +377,15 @@ impl Batch {
             uv_width: glyph.uv_width,
             uv_height: glyph.uv_height,
 
-            r: cell.fg.r as f32,
-            g: cell.fg.g as f32,
-            b: cell.fg.b as f32,
+            r: fg.r as f32,
+            g: fg.g as f32,
+            b: fg.b as f32,
 
-            bg_r: cell.bg.r as f32,
-            bg_g: cell.bg.g as f32,
-            bg_b: cell.bg.b as f32,
+            bg_r: bg.r as f32,
+            bg_g: bg.g as f32,
+            bg_b: bg.b as f32,
         };
 
-        if cell.flags.contains(cell::INVERSE) {
-            instance.r = cell.bg.r as f32;
-            instance.g = cell.bg.g as f32;
-            instance.b = cell.bg.b as f32;
-
-            instance.bg_r = cell.fg.r as f32;
-            instance.bg_g = cell.fg.g as f32;
-            instance.bg_b = cell.fg.b as f32;
-        }
-
         self.instances.push(instance);
     }
 
@@ -341,6 +422,16 @@ impl Batch {
 
 /// Maximum items to be drawn in a batch.
 const BATCH_MAX: usize = 4096;
+
+/// Glyphs drawn over cells for each underline style
+const UNDERLINE_CHARS: [(cell::Flags, char); 3] = [
+    (cell::UNDERLINE, '_'),
+    (cell::DOUBLE_UNDERLINE, '\u{2017}'),
+    (cell::UNDERCURL, '\u{fe4f}'),
+];
+
+/// Glyph drawn over overlined cells; it sits at the top of the cell
+const OVERLINE_CHAR: char = '\u{203e}';
 const ATLAS_SIZE: i32 = 1024;
 
 impl QuadRenderer {
@@ -466,7 +557,7 @@ impl QuadRenderer {
                     if op.contains(op::IGNORED) {
                         if let Some(path) = path.as_ref() {
                             if let Err(err) = watcher.watch(path) {
-                                println!("failed to establish watch on {:?}: {:?}", path, err);
+                                warn!("failed to establish watch on {:?}: {:?}", path, err);
                             }
                         }
 
@@ -550,10 +641,10 @@ impl QuadRenderer {
             Err(err) => {
                 match err {
                     ShaderCreationError::Io(err) => {
-                        println!("Error reading shader file: {}", err);
+                        error!("Error reading shader file: {}", err);
                     },
                     ShaderCreationError::Compile(path, log) => {
-                        println!("Error compiling shader at {:?}", path);
+                        error!("Error compiling shader at {:?}", path);
                         io::copy(&mut log.as_bytes(), &mut io::stdout()).unwrap();
                     }
                 }
@@ -622,19 +713,13 @@ impl<'a> RenderApi<'a> {
 
         for c in s.chars() {
             let glyph_key = GlyphKey {
-                font_key: glyph_cache.font_key,
+                font_key: glyph_cache.font_keys.regular,
                 size: glyph_cache.font_size,
                 c: c
             };
 
             if let Some(glyph) = glyph_cache.get(&glyph_key, self) {
-                let cell = Cell {
-                    c: c,
-                    fg: *color,
-                    bg: *bg,
-                    flags: cell::INVERSE,
-                };
-                self.add_render_item(row, col, &cell, glyph);
+                self.add_render_item(row, col, (*bg, *color), glyph);
             }
 
             col += 1.0;
@@ -642,7 +727,7 @@ impl<'a> RenderApi<'a> {
     }
 
     #[inline]
-    fn add_render_item(&mut self, row: f32, col: f32, cell: &Cell, glyph: &Glyph) {
+    fn add_render_item(&mut self, row: f32, col: f32, colors: (Rgb, Rgb), glyph: &Glyph) {
         // Flush batch if tex changing
         if !self.batch.is_empty() {
             if self.batch.tex != glyph.tex_id {
@@ -650,7 +735,7 @@ impl<'a> RenderApi<'a> {
             }
         }
 
-        self.batch.add_item(row, col, cell, glyph);
+        self.batch.add_item(row, col, colors, glyph);
 
         // Render batch and clear if it's full
         if self.batch.full() {
@@ -658,39 +743,82 @@ impl<'a> RenderApi<'a> {
         }
     }
 
-    pub fn render_grid(&mut self, bg: &Rgb, grid: &Grid<Cell>, glyph_cache: &mut GlyphCache) {
-        for (i, line) in grid.lines().enumerate() {
-            for (j, cell) in line.cells().enumerate() {
-                // Skip empty cells
-                if cell.c == ' ' &&
-                   cell.bg == *bg &&
-                   !cell.flags.contains(cell::INVERSE)
-                {
+    pub fn render_grid(
+        &mut self,
+        bg: &Rgb,
+        grid: &RenderGrid,
+        font_keys: &FontKeys,
+        glyph_cache: &mut GlyphCache
+    ) {
+        for (i, line) in grid.display_lines().enumerate() {
+            // Skip lines of nothing but empty cells
+            if let Some(first) = line.first() {
+                if is_empty_cell(first, grid.render_colors(first).1, bg) && line.is_clear(first) {
                     continue;
                 }
+            }
+
+            for (j, cell) in line.cells().enumerate() {
+                let colors = grid.render_colors(cell);
 
-                // Get font key for cell
-                // FIXME this is super inefficient.
-                let mut font_key = glyph_cache.font_key;
-                if cell.flags.contains(cell::BOLD) {
-                    font_key = glyph_cache.bold_key;
-                } else if cell.flags.contains(cell::ITALIC) {
-                    font_key = glyph_cache.italic_key;
+                // Skip empty cells
+                if is_empty_cell(cell, colors.1, bg) {
+                    continue;
                 }
 
                 let glyph_key = GlyphKey {
-                    font_key: font_key,
+                    font_key: font_keys.get(cell.flags),
                     size: glyph_cache.font_size,
                     c: cell.c
                 };
 
                 // Add cell to batch if glyph available
                 if let Some(glyph) = glyph_cache.get(&glyph_key, self) {
-                    self.add_render_item(i as f32, j as f32, cell, glyph);
+                    self.add_render_item(i as f32, j as f32, colors, glyph);
                 }
+
+                self.render_decorations(i as f32, j as f32, cell, colors, glyph_key, glyph_cache);
             }
         }
     }
+
+    /// Draw the lines decorating a cell
+    ///
+    /// Decorations are glyphs of their own drawn over the cell's glyph.
+    fn render_decorations(
+        &mut self,
+        row: f32,
+        col: f32,
+        cell: &Cell,
+        colors: (Rgb, Rgb),
+        glyph_key: GlyphKey,
+        glyph_cache: &mut GlyphCache
+    ) {
+        let underline = UNDERLINE_CHARS.iter().find(|&&(style, _)| cell.flags.contains(style));
+        if let Some(&(_, c)) = underline {
+            let fg = cell.underline_color.unwrap_or(colors.0);
+
+            let glyph_key = GlyphKey { c: c, ..glyph_key.clone() };
+            if let Some(glyph) = glyph_cache.get(&glyph_key, self) {
+                self.add_render_item(row, col, (fg, colors.1), glyph);
+            }
+        }
+
+        if cell.flags.contains(cell::OVERLINE) {
+            let glyph_key = GlyphKey { c: OVERLINE_CHAR, ..glyph_key };
+            if let Some(glyph) = glyph_cache.get(&glyph_key, self) {
+                self.add_render_item(row, col, colors, glyph);
+            }
+        }
+    }
+}
+
+/// Check whether drawing a cell would leave the window background as it is
+#[inline]
+fn is_empty_cell(cell: &Cell, cell_bg: Rgb, bg: &Rgb) -> bool {
+    cell.c == ' ' &&
+        cell_bg == *bg &&
+        !cell.flags.intersects(cell::ALL_UNDERLINES | cell::OVERLINE)
 }
 
 impl<'a> LoadGlyph for LoaderApi<'a> {
@@ -808,7 +936,7 @@ impl ShaderProgram {
         let ortho = cgmath::ortho(0., width, 0., height, -1., 1.);
         let projection: [[f32; 4]; 4] = ortho.into();
 
-        println!("width: {}, height: {}", width, height);
+        trace!("width: {}, height: {}", width, height);
 
         unsafe {
             gl::UniformMatrix4fv(self.u_projection,
@@ -847,7 +975,7 @@ impl ShaderProgram {
             gl::GetProgramiv(program, gl::LINK_STATUS, &mut success);
 
             if success != (gl::TRUE as GLint) {
-                println!("{}", get_program_info_log(program));
+                error!("{}", get_program_info_log(program));
                 panic!("failed to link shader program");
             }
             program
@@ -1107,6 +1235,19 @@ impl Atlas {
         let height = glyph.height as i32;
         let width = glyph.width as i32;
 
+        // The atlas stores coverage per subpixel; grayscale coverage applies to
+        // all three.
+        let expanded;
+        let buf = match glyph.format {
+            PixelFormat::Rgb => &glyph.buf[..],
+            PixelFormat::Gray => {
+                expanded = glyph.buf.iter()
+                    .flat_map(|coverage| ::std::iter::repeat(*coverage).take(3))
+                    .collect::<Vec<u8>>();
+                &expanded[..]
+            },
+        };
+
         unsafe {
             gl::BindTexture(gl::TEXTURE_2D, self.id);
 
@@ -1120,7 +1261,7 @@ impl Atlas {
                 height,
                 gl::RGB,
                 gl::UNSIGNED_BYTE,
-                glyph.buf.as_ptr() as *const _
+                buf.as_ptr() as *const _
             );
 
             gl::BindTexture(gl::TEXTURE_2D, 0);
@@ -1178,3 +1319,29 @@ impl Atlas {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use font::{self, FontKey, GlyphKey};
+    use config::MissingGlyph;
+
+    use super::placeholder_key;
+
+    #[test]
+    fn placeholder_for_missing_glyph() {
+        let key = GlyphKey { c: '\u{e000}', font_key: FontKey::next(), size: font::Size::new(12.) };
+
+        let boxed = placeholder_key(&key, MissingGlyph::Box).unwrap();
+        assert_eq!(boxed.c, '\u{25a1}');
+        assert_eq!(boxed.font_key, key.font_key);
+
+        let replaced = placeholder_key(&key, MissingGlyph::ReplacementChar).unwrap();
+        assert_eq!(replaced.c, '\u{fffd}');
+
+        assert_eq!(placeholder_key(&key, MissingGlyph::Blank), None);
+
+        // A missing placeholder is left blank rather than looked up again
+        let key = GlyphKey { c: '\u{fffd}', ..key };
+        assert_eq!(placeholder_key(&key, MissingGlyph::ReplacementChar), None);
+    }
+}
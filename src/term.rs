@@ -14,14 +14,20 @@
 //
 //! Exports the `Term` type which is a high-level API for the Grid
 use std::mem;
-use std::ops::{Deref, Range};
+use std::ops::{Deref, Range, Index, IndexMut};
 use std::ptr;
-
-use ansi::{self, Attr, Handler};
-use grid::{Grid, ClearRegion};
-use index::{Cursor, Column, Line};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use ansi::{self, sixel, Attr, CharsetIndex, CursorStyle, DynamicColor, Handler, StandardCharset};
+use font::FontKey;
+use glutin::{Mods, VirtualKeyCode};
+use grid::{Grid, Row};
+use index::{Cursor, Column, Direction, Line, Point};
+use input;
 use tty;
-use config::Config;
+use config::{self, Config, DimStrategy};
+use unicode_width::UnicodeWidthChar;
 
 use ::Rgb;
 
@@ -33,28 +39,62 @@ pub struct RenderGrid<'a> {
     inner: &'a mut Grid<Cell>,
     cursor: &'a Cursor,
     mode: TermMode,
+    show_cursor: bool,
+    fg: Rgb,
+    bg: Rgb,
+    dim_strategy: DimStrategy,
+    colors: [Rgb; 16],
 }
 
 impl<'a> RenderGrid<'a> {
-    fn new<'b>(grid: &'b mut Grid<Cell>, cursor: &'b Cursor, mode: TermMode) -> RenderGrid<'b> {
-        if mode.contains(mode::SHOW_CURSOR) && grid.contains(cursor) {
-            let cell = &mut grid[cursor];
-            mem::swap(&mut cell.fg, &mut cell.bg);
+    fn new<'b>(
+        grid: &'b mut Grid<Cell>,
+        cursor: &'b Cursor,
+        mode: TermMode,
+        show_cursor: bool,
+        fg: Rgb,
+        bg: Rgb,
+        dim_strategy: DimStrategy,
+        colors: [Rgb; 16]
+    ) -> RenderGrid<'b> {
+        // The cursor is drawn inverted. Toggling INVERSE rather than swapping
+        // the colors keeps it inverted after a reverse screen swaps them.
+        let show_cursor = show_cursor && grid.contains(cursor);
+        if show_cursor {
+            grid[cursor].flags.toggle(cell::INVERSE);
         }
 
         RenderGrid {
             inner: grid,
             cursor: cursor,
             mode: mode,
+            show_cursor: show_cursor,
+            fg: fg,
+            bg: bg,
+            dim_strategy: dim_strategy,
+            colors: colors,
+        }
+    }
+
+    /// Get the foreground and background a cell is drawn with
+    ///
+    /// Dim cells have their foreground dimmed as in `Term::effective_fg`.
+    #[inline]
+    pub fn render_colors(&self, cell: &Cell) -> (Rgb, Rgb) {
+        let reverse_screen = self.mode.contains(mode::REVERSE_SCREEN);
+        let (fg, bg) = cell.render_colors(self.fg, self.bg, reverse_screen);
+        if cell.flags.contains(cell::DIM) {
+            (dim_color(fg, bg, self.dim_strategy, &self.colors), bg)
+        } else {
+            (fg, bg)
         }
     }
 }
 
 impl<'a> Drop for RenderGrid<'a> {
     fn drop(&mut self) {
-        if self.mode.contains(mode::SHOW_CURSOR) && self.inner.contains(self.cursor) {
-            let cell = &mut self.inner[self.cursor];
-            mem::swap(&mut cell.fg, &mut cell.bg);
+        if self.show_cursor {
+            self.inner[self.cursor].flags.toggle(cell::INVERSE);
         }
     }
 }
@@ -67,6 +107,79 @@ impl<'a> Deref for RenderGrid<'a> {
     }
 }
 
+/// Everything besides the grid needed to draw a frame
+///
+/// Created by `Term::render_state`. Unlike `RenderGrid`, this leaves the grid
+/// alone, so renderers drawing the cursor themselves should use this.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RenderState {
+    /// Cursor position in view, style and color; `None` when it isn't drawn
+    pub cursor: Option<(Point, CursorStyle, Rgb)>,
+
+    /// Intensity of the visual bell flash, from 0.0 to 1.0
+    pub visual_bell: f32,
+
+    /// Whether blinking text is shown
+    pub blink: bool,
+}
+
+/// Implement serde traits for a bitflags type by its bits
+///
+/// Unknown bits are dropped when deserializing.
+#[cfg(feature = "snapshot")]
+macro_rules! serde_bitflags {
+    ($ty:ident, $bits:ty, $serialize:ident) => {
+        impl ::serde::Serialize for $ty {
+            fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+                where S: ::serde::Serializer
+            {
+                serializer.$serialize(self.bits())
+            }
+        }
+
+        impl ::serde::Deserialize for $ty {
+            fn deserialize<D>(deserializer: &mut D) -> Result<$ty, D::Error>
+                where D: ::serde::Deserializer
+            {
+                let bits = try!(<$bits as ::serde::Deserialize>::deserialize(deserializer));
+                Ok($ty::from_bits_truncate(bits))
+            }
+        }
+    }
+}
+
+/// Dim the foreground `fg` drawn over `bg` with `strategy`
+///
+/// `colors` is the 16 color palette, for `DimStrategy::PaletteHalf`.
+fn dim_color(fg: Rgb, bg: Rgb, strategy: DimStrategy, colors: &[Rgb; 16]) -> Rgb {
+    match strategy {
+        DimStrategy::AlphaReduce(alpha) => {
+            let alpha = limit(alpha, 0.0, 1.0);
+            let blend = |fg: u8, bg: u8| {
+                (fg as f32 * alpha + bg as f32 * (1.0 - alpha)).round() as u8
+            };
+
+            Rgb {
+                r: blend(fg.r, bg.r),
+                g: blend(fg.g, bg.g),
+                b: blend(fg.b, bg.b),
+            }
+        },
+        DimStrategy::PaletteHalf => {
+            match colors[8..].iter().position(|&c| c == fg) {
+                Some(index) => colors[index],
+                None => Rgb { r: fg.r / 2, g: fg.g / 2, b: fg.b / 2 },
+            }
+        },
+    }
+}
+
+/// Most bytes `Term::advance` leaves for the next call
+const MAX_INCOMPLETE_LEN: usize = 64;
+
+/// Longest a synchronized update may hold back drawing
+const SYNC_UPDATE_TIMEOUT_MS: u64 = 150;
+
 /// coerce val to be between min and max
 fn limit<T: PartialOrd>(val: T, min: T, max: T) -> T {
     if val < min {
@@ -78,24 +191,55 @@ fn limit<T: PartialOrd>(val: T, min: T, max: T) -> T {
     }
 }
 
+/// Convert a duration to fractional seconds
+fn as_secs_f32(duration: Duration) -> f32 {
+    duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+/// Convert a duration to whole milliseconds
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
 pub mod cell {
     use ::Rgb;
 
     bitflags! {
-        pub flags Flags: u32 {
+        pub flags Flags: u16 {
             const INVERSE   = 0b00000001,
             const BOLD      = 0b00000010,
             const ITALIC    = 0b00000100,
             const UNDERLINE = 0b00001000,
+            const WRAPLINE  = 0b00010000,
+            const DOUBLE_UNDERLINE = 0b00100000,
+            const UNDERCURL = 0b01000000,
+            const ALL_UNDERLINES = UNDERLINE.bits | DOUBLE_UNDERLINE.bits | UNDERCURL.bits,
+            const OVERLINE  = 0b10000000,
+            const BLINK     = 0b100000000,
+            const DIM       = 0b1000000000,
+            const WIDE_CHAR = 0b10000000000,
+            const WIDE_CHAR_SPACER = 0b100000000000,
+            const PROTECTED = 0b1000000000000,
         }
     }
 
-    #[derive(Clone, Debug, Copy)]
+    #[cfg(feature = "snapshot")]
+    serde_bitflags!(Flags, u16, serialize_u16);
+
+    /// A character on the grid and how it's drawn
+    ///
+    /// Cells are copied around a lot when scrolling and clearing, so they're
+    /// kept to 16 bytes: the field order leaves no padding, and the flags fit
+    /// in a `u16`.
+    #[derive(Clone, Debug, Copy, PartialEq)]
+    #[cfg_attr(feature = "snapshot", derive(Serialize, Deserialize))]
     pub struct Cell {
         pub c: char,
         pub fg: Rgb,
         pub bg: Rgb,
         pub flags: Flags,
+        /// Color of the underline; the foreground color when `None`
+        pub underline_color: Option<Rgb>,
     }
 
     impl Cell {
@@ -105,12 +249,42 @@ pub mod cell {
                 bg: Default::default(),
                 fg: Default::default(),
                 flags: Flags::empty(),
+                underline_color: None,
+            }
+        }
+
+        /// Get the foreground and background the cell is drawn with
+        ///
+        /// With `reverse_screen` the default colors trade places, leaving any
+        /// other color alone. INVERSE then swaps the cell's foreground and
+        /// background.
+        pub fn render_colors(&self, default_fg: Rgb, default_bg: Rgb, reverse_screen: bool)
+            -> (Rgb, Rgb)
+        {
+            let mut fg = self.fg;
+            let mut bg = self.bg;
+
+            if reverse_screen {
+                if fg == default_fg {
+                    fg = default_bg;
+                }
+
+                if bg == default_bg {
+                    bg = default_fg;
+                }
+            }
+
+            if self.flags.contains(INVERSE) {
+                (bg, fg)
+            } else {
+                (fg, bg)
             }
         }
 
         #[inline]
         pub fn reset(&mut self, template: &Cell) {
-            // memcpy template to self
+            // memcpy template to self. Only `self` is written, so cells may be
+            // reset from several threads at once.
             unsafe {
                 ::std::ptr::copy_nonoverlapping(
                     template as *const Cell,
@@ -126,15 +300,23 @@ pub use self::cell::Cell;
 
 pub mod mode {
     bitflags! {
-        pub flags TermMode: u8 {
-            const SHOW_CURSOR = 0b00000001,
-            const APP_CURSOR  = 0b00000010,
-            const APP_KEYPAD  = 0b00000100,
-            const ANY         = 0b11111111,
-            const NONE        = 0b00000000,
+        pub flags TermMode: u16 {
+            const SHOW_CURSOR = 0b000000001,
+            const APP_CURSOR  = 0b000000010,
+            const APP_KEYPAD  = 0b000000100,
+            const REVERSE_WRAP = 0b000001000,
+            const FOCUS_IN_OUT = 0b000010000,
+            const REVERSE_SCREEN = 0b000100000,
+            const ORIGIN      = 0b001000000,
+            const SYNC_UPDATE = 0b010000000,
+            const ANY         = 0b011111111,
+            const NONE        = 0b000000000,
         }
     }
 
+    #[cfg(feature = "snapshot")]
+    serde_bitflags!(TermMode, u16, serialize_u16);
+
     impl Default for TermMode {
         fn default() -> TermMode {
             SHOW_CURSOR
@@ -146,6 +328,148 @@ pub use self::mode::TermMode;
 
 pub const TAB_SPACES: usize = 8;
 
+/// Version reported to programs which ask for it
+const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// Encode a `major.minor.patch` version as a number, such as 1203 for 0.12.3
+///
+/// Missing or malformed parts count as 0.
+fn version_number(version: &str) -> u32 {
+    version.split('.')
+        .take(3)
+        .map(|part| part.parse::<u32>().unwrap_or(0))
+        .chain(::std::iter::repeat(0))
+        .take(3)
+        .fold(0, |number, part| number * 100 + part)
+}
+
+/// Prefixes of URLs found by `Term::find_url_at`
+const URL_SCHEMES: [&'static str; 4] = ["http://", "https://", "file://", "mailto:"];
+
+/// Characters trimmed from the end of a URL since they're more likely
+/// punctuation around it than part of it
+const URL_TRAILING_DELIMITERS: &'static str = ".,;:!?'\")]>";
+
+/// SGR parameters for the cell flags written by `Term::dump`
+const SGR_FLAGS: [(cell::Flags, &'static str); 9] = [
+    (cell::BOLD, "1"),
+    (cell::DIM, "2"),
+    (cell::ITALIC, "3"),
+    (cell::UNDERLINE, "4"),
+    (cell::DOUBLE_UNDERLINE, "21"),
+    (cell::UNDERCURL, "4:3"),
+    (cell::BLINK, "5"),
+    (cell::INVERSE, "7"),
+    (cell::OVERLINE, "53"),
+];
+
+/// Character sets designated to the G0-G3 slots
+#[derive(Debug, Default, Copy, Clone)]
+struct Charsets([StandardCharset; 4]);
+
+impl Index<CharsetIndex> for Charsets {
+    type Output = StandardCharset;
+
+    #[inline]
+    fn index(&self, index: CharsetIndex) -> &StandardCharset {
+        &self.0[index as usize]
+    }
+}
+
+impl IndexMut<CharsetIndex> for Charsets {
+    #[inline]
+    fn index_mut(&mut self, index: CharsetIndex) -> &mut StandardCharset {
+        &mut self.0[index as usize]
+    }
+}
+
+/// Screen contents, cursor and modes of a `Term`
+///
+/// Created by `Term::snapshot` for saving and restoring sessions; scrollback
+/// isn't included.
+#[cfg(feature = "snapshot")]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Cells on screen from top to bottom
+    lines: Vec<Vec<Cell>>,
+    cursor: Cursor,
+    mode: TermMode,
+}
+
+/// Iterator over the lines of a `Term` which need to be drawn again
+///
+/// Created by `Term::dirty_lines`.
+pub struct DirtyLines<'a> {
+    dirty: &'a [bool],
+    cursor_lines: (Line, Line),
+    line: Line,
+}
+
+impl<'a> Iterator for DirtyLines<'a> {
+    type Item = Line;
+
+    fn next(&mut self) -> Option<Line> {
+        while *self.line < self.dirty.len() {
+            let line = self.line;
+            self.line += 1;
+
+            if self.dirty[*line] || line == self.cursor_lines.0 || line == self.cursor_lines.1 {
+                return Some(line);
+            }
+        }
+
+        None
+    }
+}
+
+/// Fonts used to render each combination of the bold and italic cell flags
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FontKeys {
+    pub regular: FontKey,
+    pub bold: FontKey,
+    pub italic: FontKey,
+    pub bold_italic: FontKey,
+}
+
+impl FontKeys {
+    /// Get the font for a cell with the given flags
+    #[inline]
+    pub fn get(&self, flags: cell::Flags) -> FontKey {
+        match (flags.contains(cell::BOLD), flags.contains(cell::ITALIC)) {
+            (false, false) => self.regular,
+            (true, false) => self.bold,
+            (false, true) => self.italic,
+            (true, true) => self.bold_italic,
+        }
+    }
+}
+
+/// Position, style and visibility of the cursor
+///
+/// Created by `Term::cursor_state` and restored with `Term::set_cursor_state`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CursorState {
+    /// Position on the screen
+    pub point: Point,
+
+    /// Style the cursor is drawn with
+    pub style: CursorStyle,
+
+    /// Whether the cursor is shown
+    pub visible: bool,
+}
+
+/// Outcome of `Term::paste`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Paste {
+    /// The text was queued for the pty
+    Written,
+
+    /// The text has a newline and wasn't written; once the user confirms,
+    /// write it with `paste_confirmed`
+    ConfirmationAdvised,
+}
+
 pub struct Term {
     /// The grid
     grid: Grid<Cell>,
@@ -162,8 +486,8 @@ pub struct Term {
     /// The cursor
     cursor: Cursor,
 
-    /// Alt cursor
-    alt_cursor: Cursor,
+    /// Cursor saved by DECSC
+    saved_cursor: Cursor,
 
     /// Active foreground color
     pub fg: Rgb,
@@ -192,77 +516,279 @@ pub struct Term {
     /// Text colors
     colors: [Rgb; 16],
 
+    /// Text colors the terminal was built with, restored by `reset_palette`
+    default_colors: [Rgb; 16],
+
+    /// Named color of the template foreground, if it's from `colors`
+    template_fg_color: Option<ansi::Color>,
+
+    /// Bold text in the first 8 colors uses their bright variants
+    bold_brightens: bool,
+
+    /// How dim text is drawn
+    dim_strategy: DimStrategy,
+
+    /// Fonts for bold and italic text
+    font_keys: FontKeys,
+
+    /// Time at which the visual bell flash is over
+    visual_bell_until: Instant,
+
+    /// How long the visual bell flash lasts
+    visual_bell_duration: Duration,
+
+    /// Called whenever the bell is rung
+    bell_callback: Option<Box<FnMut() + Send>>,
+
+    /// Called with each sixel image and the cell at its top left corner
+    pixel_sink: Option<Box<FnMut(Point, sixel::Image) + Send>>,
+
+    /// Time at which a synchronized update is given up on, if one is active
+    sync_update_until: Instant,
+
+    /// Whether a blinking cursor was shown as of the last `expire_timers`
+    cursor_blink_shown: bool,
+
+    /// Blinking text is shown for an interval and then hidden for one
+    blink_interval: Duration,
+
+    /// Start of the first interval blinking text is shown
+    blink_epoch: Instant,
+
+    /// Cursor style picked by the program with DECSCUSR, if any
+    cursor_style: Option<CursorStyle>,
+
+    /// Cursor style used when the program hasn't picked one
+    default_cursor_style: CursorStyle,
+
+    /// A blinking cursor is shown for an interval and then hidden for one
+    cursor_blink_interval: Duration,
+
+    /// Bytes to be written back to the pty, such as replies to queries
+    response: Vec<u8>,
+
+    /// Lines changed since the last `clear_dirty`
+    line_dirty: Vec<bool>,
+
+    /// Line the cursor was on at the last `clear_dirty`
+    drawn_cursor_line: Line,
+
+    /// Parser state carried between calls to `feed`
+    parser: ansi::Processor,
+
+    /// Character sets designated to each slot
+    charsets: Charsets,
+
+    /// Slot of the character set used for input
+    active_charset: CharsetIndex,
+
+    /// Characters which end a word when expanding a selection
+    semantic_escape_chars: String,
+
+    /// Selected cells in view, from `start` up to `end`
+    selection: Option<Range<Point>>,
+
+    /// Clear the selection when output changes a selected line
+    selection_clears_on_output: bool,
+
+    /// Jump back to the bottom of scrollback when there is new output
+    scroll_on_output: bool,
+
+    /// Whether the window has keyboard focus
+    focused: bool,
+
+    /// Whether pasting text with a newline should be confirmed first
+    confirm_paste_with_newline: bool,
+
     pub dirty: bool,
 }
 
-/// Terminal size info
-#[derive(Debug, Copy, Clone)]
-pub struct SizeInfo {
-    /// Terminal window width
-    pub width: f32,
+/// Builder for a `Term`
+///
+/// Everything except the size has a default, so a terminal useful as a plain
+/// grid model can be built without a config file or a shell. Without an
+/// explicit tty, the terminal gets one with nothing running on it.
+pub struct TermBuilder {
+    size: SizeInfo,
+    fg: Rgb,
+    bg: Rgb,
+    colors: [Rgb; 16],
+    font_keys: Option<FontKeys>,
+    visual_bell_duration: Duration,
+    blink_interval: Duration,
+    bold_brightens: bool,
+    dim_strategy: DimStrategy,
+    cursor_blink: bool,
+    cursor_blink_interval: Duration,
+    confirm_paste_with_newline: bool,
+    semantic_escape_chars: String,
+    selection_clears_on_output: bool,
+    scrollback_limit: usize,
+    tty: Option<tty::Tty>,
+}
 
-    /// Terminal window height
-    pub height: f32,
+impl TermBuilder {
+    fn new(size: SizeInfo) -> TermBuilder {
+        TermBuilder {
+            size: size,
+            fg: Rgb { r: 0xff, g: 0xff, b: 0xff },
+            bg: Rgb { r: 0x00, g: 0x00, b: 0x00 },
+            colors: [
+                Rgb { r: 0x00, g: 0x00, b: 0x00 },
+                Rgb { r: 0xcd, g: 0x00, b: 0x00 },
+                Rgb { r: 0x00, g: 0xcd, b: 0x00 },
+                Rgb { r: 0xcd, g: 0xcd, b: 0x00 },
+                Rgb { r: 0x00, g: 0x00, b: 0xee },
+                Rgb { r: 0xcd, g: 0x00, b: 0xcd },
+                Rgb { r: 0x00, g: 0xcd, b: 0xcd },
+                Rgb { r: 0xe5, g: 0xe5, b: 0xe5 },
+                Rgb { r: 0x7f, g: 0x7f, b: 0x7f },
+                Rgb { r: 0xff, g: 0x00, b: 0x00 },
+                Rgb { r: 0x00, g: 0xff, b: 0x00 },
+                Rgb { r: 0xff, g: 0xff, b: 0x00 },
+                Rgb { r: 0x5c, g: 0x5c, b: 0xff },
+                Rgb { r: 0xff, g: 0x00, b: 0xff },
+                Rgb { r: 0x00, g: 0xff, b: 0xff },
+                Rgb { r: 0xff, g: 0xff, b: 0xff },
+            ],
+            font_keys: None,
+            visual_bell_duration: Duration::from_millis(150),
+            blink_interval: Duration::from_millis(500),
+            bold_brightens: false,
+            dim_strategy: DimStrategy::default(),
+            cursor_blink: false,
+            cursor_blink_interval: Duration::from_millis(500),
+            confirm_paste_with_newline: false,
+            semantic_escape_chars: String::from(config::DEFAULT_SEMANTIC_ESCAPE_CHARS),
+            selection_clears_on_output: true,
+            scrollback_limit: 0,
+            tty: None,
+        }
+    }
 
-    /// Width of individual cell
-    pub cell_width: f32,
+    /// Set the default foreground and background and the 16 color palette
+    ///
+    /// Defaults to white on black with the xterm palette.
+    pub fn colors(mut self, fg: Rgb, bg: Rgb, colors: [Rgb; 16]) -> TermBuilder {
+        self.fg = fg;
+        self.bg = bg;
+        self.colors = colors;
+        self
+    }
 
-    /// Height of individual cell
-    pub cell_height: f32,
-}
+    /// Set the fonts used for bold and italic text
+    ///
+    /// By default every style gets the same newly allocated key.
+    pub fn font_keys(mut self, font_keys: FontKeys) -> TermBuilder {
+        self.font_keys = Some(font_keys);
+        self
+    }
 
-impl SizeInfo {
-    #[inline]
-    pub fn lines(&self) -> Line {
-        Line((self.height / self.cell_height) as usize)
+    /// Set how long the visual bell flash lasts
+    pub fn visual_bell_duration(mut self, duration: Duration) -> TermBuilder {
+        self.visual_bell_duration = duration;
+        self
     }
 
-    #[inline]
-    pub fn cols(&self) -> Column {
-        Column((self.width / self.cell_width) as usize)
+    /// Set how long blinking text is shown and then hidden
+    ///
+    /// Defaults to 500ms.
+    pub fn blink_interval(mut self, interval: Duration) -> TermBuilder {
+        self.blink_interval = interval;
+        self
     }
-}
 
-impl Term {
-    pub fn new(
-        config: &Config,
-        width: f32,
-        height: f32,
-        cell_width: f32,
-        cell_height: f32
-    ) -> Term {
-        let size = SizeInfo {
-            width: width as f32,
-            height: height as f32,
-            cell_width: cell_width as f32,
-            cell_height: cell_height as f32,
-        };
+    /// Draw bold text in the first 8 colors with their bright variants
+    pub fn draw_bold_text_with_bright_colors(mut self, enabled: bool) -> TermBuilder {
+        self.bold_brightens = enabled;
+        self
+    }
 
-        let mut template = Cell::new(' ');
-        template.flags = cell::Flags::empty();
-        template.bg = config.bg_color();
-        template.fg = config.fg_color();
+    /// Set how dim text is drawn
+    pub fn dim_strategy(mut self, strategy: DimStrategy) -> TermBuilder {
+        self.dim_strategy = strategy;
+        self
+    }
+
+    /// Blink the cursor unless the program picks a style of its own
+    pub fn cursor_blink(mut self, enabled: bool) -> TermBuilder {
+        self.cursor_blink = enabled;
+        self
+    }
+
+    /// Set how long a blinking cursor is shown and then hidden
+    ///
+    /// Defaults to 500ms.
+    pub fn cursor_blink_interval(mut self, interval: Duration) -> TermBuilder {
+        self.cursor_blink_interval = interval;
+        self
+    }
+
+    /// Advise confirming pastes which contain a newline; see `Term::paste`
+    pub fn confirm_paste_with_newline(mut self, enabled: bool) -> TermBuilder {
+        self.confirm_paste_with_newline = enabled;
+        self
+    }
+
+    /// Set the characters which end a word when expanding a selection, in
+    /// addition to whitespace
+    pub fn semantic_escape_chars<S: Into<String>>(mut self, chars: S) -> TermBuilder {
+        self.semantic_escape_chars = chars.into();
+        self
+    }
+
+    /// Clear the selection when output changes a selected line
+    ///
+    /// Enabled by default.
+    pub fn selection_clears_on_output(mut self, enabled: bool) -> TermBuilder {
+        self.selection_clears_on_output = enabled;
+        self
+    }
 
+    /// Set the maximum number of lines kept in history
+    ///
+    /// Defaults to 0, keeping no history.
+    pub fn scrollback_limit(mut self, limit: usize) -> TermBuilder {
+        self.scrollback_limit = limit;
+        self
+    }
+
+    /// Attach the terminal to a tty
+    pub fn tty(mut self, tty: tty::Tty) -> TermBuilder {
+        self.tty = Some(tty);
+        self
+    }
+
+    pub fn build(self) -> Term {
+        let size = self.size;
         let num_cols = size.cols();
         let num_lines = size.lines();
 
-        println!("num_cols, num_lines = {}, {}", num_cols, num_lines);
-
-        println!("bg: {:?}, fg: {:?}", template.bg, template.fg);
-        println!("colors: {:?}", config.color_list());
+        let tty = match self.tty {
+            Some(tty) => tty,
+            None => tty::null(*num_lines as u8, *num_cols as u8),
+        };
 
-        let grid = Grid::new(num_lines, num_cols, &Cell::new(' '));
+        let font_keys = match self.font_keys {
+            Some(font_keys) => font_keys,
+            None => {
+                let key = FontKey::next();
+                FontKeys { regular: key, bold: key, italic: key, bold_italic: key }
+            }
+        };
 
-        let tty = tty::new(*num_lines as u8, *num_cols as u8);
-        tty.resize(*num_lines as usize, *num_cols as usize, size.width as usize, size.height as usize);
+        let mut template = Cell::new(' ');
+        template.flags = cell::Flags::empty();
+        template.bg = self.bg;
+        template.fg = self.fg;
 
-        let mut tabs = (Column(0)..grid.num_cols())
-            .map(|i| (*i as usize) % TAB_SPACES == 0)
-            .collect::<Vec<bool>>();
+        let mut grid = Grid::new(num_lines, num_cols, &Cell::new(' '));
 
-        tabs[0] = false;
+        let tabs = default_tab_stops(grid.num_cols());
 
+        // The alternate screen keeps no history
         let alt = grid.clone();
+        grid.set_history_limit(self.scrollback_limit);
         let scroll_region = Line(0)..grid.num_lines();
 
         Term {
@@ -271,9 +797,9 @@ impl Term {
             alt_grid: alt,
             alt: false,
             cursor: Cursor::default(),
-            alt_cursor: Cursor::default(),
-            fg: config.fg_color(),
-            bg: config.bg_color(),
+            saved_cursor: Cursor::default(),
+            fg: self.fg,
+            bg: self.bg,
             tty: tty,
             tabs: tabs,
             mode: Default::default(),
@@ -281,583 +807,4248 @@ impl Term {
             size_info: size,
             template_cell: template,
             empty_cell: template,
-            colors: config.color_list(),
+            colors: self.colors,
+            default_colors: self.colors,
+            template_fg_color: None,
+            bold_brightens: self.bold_brightens,
+            dim_strategy: self.dim_strategy,
+            font_keys: font_keys,
+            visual_bell_until: Instant::now(),
+            visual_bell_duration: self.visual_bell_duration,
+            bell_callback: None,
+            pixel_sink: None,
+            sync_update_until: Instant::now(),
+            cursor_blink_shown: true,
+            blink_interval: self.blink_interval,
+            blink_epoch: Instant::now(),
+            cursor_style: None,
+            default_cursor_style: CursorStyle {
+                blinking: self.cursor_blink,
+                ..CursorStyle::default()
+            },
+            cursor_blink_interval: self.cursor_blink_interval,
+            response: Vec::new(),
+            line_dirty: vec![true; *num_lines],
+            drawn_cursor_line: Line(0),
+            parser: ansi::Processor::new(),
+            charsets: Charsets::default(),
+            active_charset: CharsetIndex::default(),
+            semantic_escape_chars: self.semantic_escape_chars,
+            selection: None,
+            selection_clears_on_output: self.selection_clears_on_output,
+            scroll_on_output: true,
+            focused: true,
+            confirm_paste_with_newline: self.confirm_paste_with_newline,
         }
     }
+}
 
-    pub fn render_grid<'a>(&'a mut self) -> RenderGrid<'a> {
-        RenderGrid::new(&mut self.grid, &self.cursor, self.mode)
+/// Find where an escape sequence or UTF-8 character at the end of `bytes`
+/// which isn't complete yet starts
+///
+/// Only the last `MAX_INCOMPLETE_LEN` bytes are held back. The parser keeps
+/// its own state across calls, so anything longer is processed as it comes
+/// rather than waiting on an end which may never arrive.
+fn incomplete_suffix(bytes: &[u8]) -> Option<usize> {
+    let window = bytes.len().saturating_sub(MAX_INCOMPLETE_LEN);
+    if let Some(offset) = bytes[window..].iter().rposition(|&b| b == 0x1b) {
+        let start = window + offset;
+        if !escape_is_complete(&bytes[(start + 1)..]) {
+            return Some(start);
+        }
     }
 
-    /// Resize terminal to new dimensions
-    pub fn resize(&mut self, width: f32, height: f32) {
-        let size = SizeInfo {
-            width: width,
-            height: height,
-            cell_width: self.size_info.cell_width,
-            cell_height: self.size_info.cell_height,
+    // The lead byte of a character cut short is among the last 3 bytes
+    let tail = bytes.len().saturating_sub(3);
+    for i in (tail..bytes.len()).rev() {
+        let len = match bytes[i] {
+            0x00...0x7f => return None,
+            0x80...0xbf => continue,
+            0xc0...0xdf => 2,
+            0xe0...0xef => 3,
+            _ => 4,
         };
 
-        let old_cols = self.size_info.cols();
-        let old_lines = self.size_info.lines();
-        let num_cols = size.cols();
-        let num_lines = size.lines();
+        return if i + len > bytes.len() { Some(i) } else { None };
+    }
 
-        self.size_info = size;
+    None
+}
 
-        if old_cols == num_cols && old_lines == num_lines {
-            return;
-        }
+/// Check whether the escape sequence continuing with `rest` after the ESC has
+/// its final byte
+///
+/// OSC, DCS and other strings count as complete once they've started; they
+/// can be arbitrarily long, and the parser carries them across calls.
+fn escape_is_complete(rest: &[u8]) -> bool {
+    let mut rest = rest.iter();
+    match rest.next() {
+        None => false,
+        // CSI; parameters and intermediates run up to the final byte
+        Some(&b'[') => rest.any(|&b| b >= 0x40 && b <= 0x7e),
+        Some(&b']') | Some(&b'P') | Some(&b'X') | Some(&b'^') | Some(&b'_') => true,
+        // Intermediates run up to the final byte
+        Some(&b) => b >= 0x30 || rest.any(|&b| b >= 0x30),
+    }
+}
 
-        // Scroll up to keep cursor and as much context as possible in grid. This only runs when the
-        // lines decreases.
-        self.scroll_region = Line(0)..self.grid.num_lines();
+/// Check whether `chars` continues with `prefix` at `index`
+fn starts_with_at(chars: &[char], index: usize, prefix: &str) -> bool {
+    let mut chars = chars[index..].iter();
+    prefix.chars().all(|c| chars.next() == Some(&c))
+}
 
-        // Scroll up to keep cursor in terminal
-        if self.cursor.line >= num_lines {
-            let lines = self.cursor.line - num_lines + 1;
-            self.scroll_up(lines);
-            self.cursor.line -= lines;
-        }
+/// Get the tab stops of a line `num_cols` wide, one every `TAB_SPACES`
+/// columns
+///
+/// There's one entry per column. The first column isn't a stop; the right
+/// margin, one past the last column, always acts as one.
+fn default_tab_stops(num_cols: Column) -> Vec<bool> {
+    (0..*num_cols).map(|i| i != 0 && i % TAB_SPACES == 0).collect()
+}
 
-        println!("num_cols, num_lines = {}, {}", num_cols, num_lines);
+/// Blank the cell at `col` if it's half of a double width character whose
+/// other half is gone
+///
+/// Shifting cells along a line can split a character from its spacer.
+fn repair_wide_char(row: &mut Row<Cell>, col: Column, template: &Cell) {
+    let num_cols = row.len();
+    if *col >= num_cols {
+        return;
+    }
 
-        // Resize grids to new size
-        self.grid.resize(num_lines, num_cols, &Cell::new(' '));
-        self.alt_grid.resize(num_lines, num_cols, &Cell::new(' '));
+    let orphaned = if row[col].flags.contains(cell::WIDE_CHAR) {
+        *col + 1 == num_cols || !row[col + 1].flags.contains(cell::WIDE_CHAR_SPACER)
+    } else if row[col].flags.contains(cell::WIDE_CHAR_SPACER) {
+        col == Column(0) || !row[col - 1].flags.contains(cell::WIDE_CHAR)
+    } else {
+        false
+    };
 
-        // Ensure cursor is in-bounds
-        self.cursor.line = limit(self.cursor.line, Line(0), num_lines);
-        self.cursor.col = limit(self.cursor.col, Column(0), num_cols);
+    if orphaned {
+        row[col].reset(template);
+    }
+}
 
-        // Recreate tabs list
-        self.tabs = (Column(0)..self.grid.num_cols()).map(|i| (*i as usize) % TAB_SPACES == 0)
-                                                     .collect::<Vec<bool>>();
+/// Terminal size info
+#[derive(Debug, Copy, Clone)]
+pub struct SizeInfo {
+    /// Terminal window width
+    pub width: f32,
 
-        self.tabs[0] = false;
+    /// Terminal window height
+    pub height: f32,
 
-        // Make sure bottom of terminal is clear
-        let template = self.empty_cell.clone();
-        self.grid.clear_region((self.cursor.line).., |c| c.reset(&template));
-        self.alt_grid.clear_region((self.cursor.line).., |c| c.reset(&template));
+    /// Width of individual cell
+    pub cell_width: f32,
 
-        // Reset scrolling region to new size
-        self.scroll_region = Line(0)..self.grid.num_lines();
+    /// Height of individual cell
+    pub cell_height: f32,
 
-        // Inform tty of new dimensions
-        self.tty.resize(*num_lines as _,
-                        *num_cols as _,
-                        self.size_info.width as usize,
-                        self.size_info.height as usize);
+    /// Most columns the grid may have, whatever the width
+    pub max_cols: Column,
 
-    }
+    /// Most lines the grid may have, whatever the height
+    pub max_lines: Line,
+}
 
+impl SizeInfo {
     #[inline]
-    pub fn tty(&self) -> &tty::Tty {
-        &self.tty
+    pub fn lines(&self) -> Line {
+        ::std::cmp::min(Line((self.height / self.cell_height) as usize), self.max_lines)
     }
 
     #[inline]
-    pub fn size_info(&self) -> &SizeInfo {
-        &self.size_info
+    pub fn cols(&self) -> Column {
+        ::std::cmp::min(Column((self.width / self.cell_width) as usize), self.max_cols)
     }
+}
 
-    #[inline]
-    pub fn mode(&self) -> &TermMode {
-        &self.mode
-    }
+impl Term {
+    pub fn new(
+        config: &Config,
+        font_keys: FontKeys,
+        width: f32,
+        height: f32,
+        cell_width: f32,
+        cell_height: f32
+    ) -> Term {
+        let size = SizeInfo {
+            width: width as f32,
+            height: height as f32,
+            cell_width: cell_width as f32,
+            cell_height: cell_height as f32,
+            max_cols: Column(config.grid_limit().columns()),
+            max_lines: Line(config.grid_limit().lines()),
+        };
 
-    pub fn swap_alt(&mut self) {
-        self.alt = !self.alt;
-        ::std::mem::swap(&mut self.grid, &mut self.alt_grid);
-        ::std::mem::swap(&mut self.cursor, &mut self.alt_cursor);
+        let num_cols = size.cols();
+        let num_lines = size.lines();
 
-        if self.alt {
-            let template = self.empty_cell.clone();
-            self.grid.clear(|c| c.reset(&template));
-        }
+        let options = tty::Options {
+            program: config.shell().map(|shell| {
+                (shell.program().to_owned(), shell.args().to_vec())
+            }),
+            working_directory: config.working_directory().map(|dir| dir.to_path_buf()),
+            env: config.env().clone(),
+        };
+
+        let tty = tty::new(*num_lines as u8, *num_cols as u8, &options);
+        tty.resize(num_lines, num_cols, &size);
+
+        Term::builder(size)
+            .colors(config.fg_color(), config.bg_color(), config.color_list())
+            .font_keys(font_keys)
+            .visual_bell_duration(config.visual_bell().duration())
+            .draw_bold_text_with_bright_colors(config.draw_bold_text_with_bright_colors())
+            .dim_strategy(config.dim_strategy())
+            .cursor_blink(config.cursor_blink())
+            .cursor_blink_interval(config.cursor_blink_interval())
+            .confirm_paste_with_newline(config.confirm_paste_with_newline())
+            .semantic_escape_chars(config.selection().semantic_escape_chars())
+            .selection_clears_on_output(config.selection_clears_on_output())
+            .scrollback_limit(config.scrollback_lines())
+            .tty(tty)
+            .build()
     }
 
-    /// Scroll screen down
+    /// Start building a terminal of the given size
     ///
-    /// Text moves down; clear at bottom
-    #[inline]
-    fn scroll_down_relative(&mut self, origin: Line, lines: Line) {
-        debug_println!("scroll_down: {}", lines);
-
-        // Copy of cell template; can't have it borrowed when calling clear/scroll
-        let template = self.empty_cell.clone();
-
-        // Clear `lines` lines at bottom of area
-        {
-            let end = self.scroll_region.end;
-            let start = end - lines;
-            self.grid.clear_region(start..end, |c| c.reset(&template));
-        }
+    /// Unlike `new`, this needs no `Config` and doesn't spawn a shell; see
+    /// `TermBuilder`.
+    pub fn builder(size: SizeInfo) -> TermBuilder {
+        TermBuilder::new(size)
+    }
 
-        // Scroll between origin and bottom
-        {
-            let end = self.scroll_region.end;
-            let start = origin + lines;
-            self.grid.scroll_down(start..end, lines);
-        }
+    /// Get the grid to draw at `now`, with the cursor drawn into it
+    ///
+    /// The cursor's cell is inverted on the grid itself while the
+    /// `RenderGrid` lives, without marking anything dirty. It's left alone
+    /// while the cursor is hidden or in the off phase of blinking. Drawing
+    /// doesn't clear the dirty state either; call `clear_dirty` once the frame
+    /// is presented.
+    pub fn render_grid<'a>(&'a mut self, now: Instant) -> RenderGrid<'a> {
+        let show_cursor = self.mode.contains(mode::SHOW_CURSOR) && !self.cursor_blinked_off(now);
+        RenderGrid::new(
+            &mut self.grid,
+            &self.cursor,
+            self.mode,
+            show_cursor,
+            self.fg,
+            self.bg,
+            self.dim_strategy,
+            self.colors
+        )
     }
 
-    /// Scroll screen up
+    /// Set a function to be called whenever the bell is rung
     ///
-    /// Text moves up; clear at top
-    #[inline]
-    fn scroll_up_relative(&mut self, origin: Line, lines: Line) {
-        debug_println!("scroll_up: {}", lines);
+    /// This is where embedders can play a sound or request attention from the
+    /// window manager.
+    pub fn set_bell_callback(&mut self, callback: Box<FnMut() + Send>) {
+        self.bell_callback = Some(callback);
+    }
 
-        // Copy of cell template; can't have it borrowed when calling clear/scroll
-        let template = self.empty_cell.clone();
+    /// Set a function to be called with each sixel image
+    ///
+    /// The image is handed over along with the cell at its top left corner,
+    /// which is where the cursor was. Sixel data is only decoded once this is
+    /// set.
+    pub fn set_pixel_sink(&mut self, sink: Box<FnMut(Point, sixel::Image) + Send>) {
+        self.pixel_sink = Some(sink);
+    }
 
-        // Clear `lines` lines starting from origin to origin + lines
-        {
-            let start = origin;
-            let end = start + lines;
-            self.grid.clear_region(start..end, |c| c.reset(&template));
+    /// Get the intensity of the visual bell flash at `now`
+    ///
+    /// Returns 1.0 at the moment the bell is rung, decaying linearly to 0.0
+    /// over the configured duration.
+    pub fn visual_bell_intensity(&self, now: Instant) -> f32 {
+        if now >= self.visual_bell_until {
+            return 0.0;
         }
 
-        // Scroll from origin to bottom less number of lines
-        {
-            let start = origin;
-            let end = self.scroll_region.end - lines;
-            self.grid.scroll_up(start..end, lines);
+        let duration = as_secs_f32(self.visual_bell_duration);
+        if duration == 0.0 {
+            return 0.0;
         }
-    }
-}
 
-impl ansi::TermInfo for Term {
-    #[inline]
-    fn lines(&self) -> Line {
-        self.grid.num_lines()
+        let remaining = as_secs_f32(self.visual_bell_until - now);
+        limit(remaining / duration, 0.0, 1.0)
     }
 
-    #[inline]
-    fn cols(&self) -> Column {
-        self.grid.num_cols()
+    /// Whether blinking text is shown at `now`
+    ///
+    /// Blinking text is shown for the blink interval, then hidden for the
+    /// same interval, and so on.
+    pub fn blink_phase(&self, now: Instant) -> bool {
+        self.phase(self.blink_interval, now)
     }
-}
 
-impl ansi::Handler for Term {
-    /// A character to be displayed
-    #[inline]
-    fn input(&mut self, c: char) {
-        if self.cursor.col == self.grid.num_cols() {
-            debug_println!("wrapping");
-            if (self.cursor.line + 1) >= self.scroll_region.end {
-                self.linefeed();
-            } else {
-                self.cursor.line += 1;
-            }
-            self.cursor.col = Column(0);
-        }
+    /// Whether a blinking cursor is shown at `now`
+    ///
+    /// Like `blink_phase`, but with the cursor blink interval.
+    pub fn cursor_blink_phase(&self, now: Instant) -> bool {
+        self.phase(self.cursor_blink_interval, now)
+    }
 
-        unsafe {
-            if ::std::intrinsics::unlikely(self.cursor.line == self.grid.num_lines()) {
-                panic!("cursor fell off grid");
-            }
+    fn phase(&self, interval: Duration, now: Instant) -> bool {
+        let interval = as_millis(interval);
+        if interval == 0 || now < self.blink_epoch {
+            return true;
         }
 
-        let cell = &mut self.grid[&self.cursor];
-        *cell = self.template_cell;
-        cell.c = c;
-        self.cursor.col += 1;
-    }
-
-    #[inline]
-    fn goto(&mut self, line: Line, col: Column) {
-        debug_println!("goto: line={}, col={}", line, col);
-        self.cursor.line = line;
-        self.cursor.col = col;
+        (as_millis(now - self.blink_epoch) / interval) % 2 == 0
     }
 
+    /// Get the style the cursor should be drawn with
+    ///
+    /// This is the style last picked by the program with DECSCUSR, or the
+    /// configured default.
     #[inline]
-    fn goto_line(&mut self, line: Line) {
-        debug_println!("goto_line: {}", line);
-        self.cursor.line = line;
+    pub fn cursor_style(&self) -> CursorStyle {
+        self.cursor_style.unwrap_or(self.default_cursor_style)
     }
 
-    #[inline]
-    fn goto_col(&mut self, col: Column) {
-        debug_println!("goto_col: {}", col);
-        self.cursor.col = col;
+    /// Get the position, style and visibility of the cursor
+    pub fn cursor_state(&self) -> CursorState {
+        CursorState {
+            point: Point::new(self.cursor.line, self.cursor.col),
+            style: self.cursor_style(),
+            visible: self.mode.contains(mode::SHOW_CURSOR),
+        }
     }
 
-    #[inline]
-    fn insert_blank(&mut self, count: Column) {
-        // Ensure inserting within terminal bounds
-        let count = ::std::cmp::min(count, self.size_info.cols() - self.cursor.col);
+    /// Restore the position, style and visibility of the cursor
+    ///
+    /// The position is clamped to the screen.
+    pub fn set_cursor_state(&mut self, state: CursorState) {
+        let line = self.cursor.line;
+        self.mark_dirty(line);
+
+        self.cursor.line = ::std::cmp::min(state.point.line, self.grid.num_lines() - 1);
+        self.cursor.col = ::std::cmp::min(state.point.col, self.grid.num_cols() - 1);
+        self.cursor_style = Some(state.style);
+        if state.visible {
+            self.mode.insert(mode::SHOW_CURSOR);
+        } else {
+            self.mode.remove(mode::SHOW_CURSOR);
+        }
 
-        let source = self.cursor.col;
-        let destination = self.cursor.col + count;
-        let num_cells = (self.size_info.cols() - destination).0;
+        let line = self.cursor.line;
+        self.mark_dirty(line);
+    }
 
-        let line = self.cursor.line; // borrowck
-        let line = &mut self.grid[line];
+    /// Get what to draw besides the grid at `now`
+    ///
+    /// The cursor is left out while it's hidden by the program, scrolled out
+    /// of view or in the off phase of blinking. It doesn't blink while the
+    /// window is unfocused.
+    pub fn render_state(&self, now: Instant) -> RenderState {
+        RenderState {
+            cursor: self.render_cursor(now),
+            visual_bell: self.visual_bell_intensity(now),
+            blink: self.blink_phase(now),
+        }
+    }
 
-        unsafe {
-            let src = line[source..].as_ptr();
-            let dst = line[destination..].as_mut_ptr();
+    fn render_cursor(&self, now: Instant) -> Option<(Point, CursorStyle, Rgb)> {
+        if !self.mode.contains(mode::SHOW_CURSOR) || !self.grid.contains(&self.cursor) {
+            return None;
+        }
 
-            ptr::copy(src, dst, num_cells);
+        let line = self.cursor.line + self.grid.display_offset();
+        if line >= self.grid.num_lines() {
+            return None;
         }
 
-        // Cells were just moved out towards the end of the line; fill in
-        // between source and dest with blanks.
-        let template = self.empty_cell.clone();
-        for c in &mut line[source..destination] {
-            c.reset(&template);
+        if self.cursor_blinked_off(now) {
+            return None;
         }
-    }
 
-    #[inline]
-    fn move_up(&mut self, lines: Line) {
-        debug_println!("move_up: {}", lines);
-        self.cursor.line -= lines;
-    }
+        // The cursor takes the color of the text under it, as when drawn by
+        // inverting the cell
+        let cell = &self.grid[&self.cursor];
+        let (fg, _) = cell.render_colors(self.fg, self.bg, self.mode.contains(mode::REVERSE_SCREEN));
 
-    #[inline]
-    fn move_down(&mut self, lines: Line) {
-        debug_println!("move_down: {}", lines);
-        self.cursor.line += lines;
+        Some((Point::new(line, self.cursor.col), self.cursor_style(), fg))
     }
 
-    #[inline]
-    fn move_forward(&mut self, cols: Column) {
-        debug_println!("move_forward: {}", cols);
-        self.cursor.col += cols;
+    /// Whether the cursor blinks; it doesn't while the window is unfocused
+    fn cursor_blinks(&self) -> bool {
+        self.cursor_style().blinking && self.focused && as_millis(self.cursor_blink_interval) != 0
     }
 
-    #[inline]
-    fn move_backward(&mut self, cols: Column) {
-        debug_println!("move_backward: {}", cols);
-        self.cursor.col -= cols;
+    /// Whether a blinking cursor is in its off phase at `now`
+    fn cursor_blinked_off(&self, now: Instant) -> bool {
+        self.cursor_blinks() && !self.cursor_blink_phase(now)
     }
 
-    #[inline]
-    fn identify_terminal(&mut self) {
-        err_println!("[unimplemented] identify_terminal");
-    }
+    /// Get the first time after `now` at which the cursor blinks on or off
+    fn next_cursor_blink(&self, now: Instant) -> Instant {
+        if now < self.blink_epoch {
+            return self.blink_epoch;
+        }
 
-    #[inline]
-    fn move_down_and_cr(&mut self, lines: Line) {
-        err_println!("[unimplemented] move_down_and_cr: {}", lines);
+        let interval = as_millis(self.cursor_blink_interval);
+        let elapsed = as_millis(now - self.blink_epoch);
+        self.blink_epoch + Duration::from_millis((elapsed / interval + 1) * interval)
     }
 
-    #[inline]
-    fn move_up_and_cr(&mut self, lines: Line) {
-        err_println!("[unimplemented] move_up_and_cr: {}", lines);
+    /// Whether any cell in view blinks
+    ///
+    /// When nothing blinks there is no need to redraw for the blink timer.
+    pub fn has_blinking_cells(&self) -> bool {
+        self.grid.display_lines().any(|row| {
+            row.cells().any(|c| c.flags.contains(cell::BLINK))
+        })
     }
 
+    /// Get the 16 color palette
     #[inline]
-    fn put_tab(&mut self, mut count: i64) {
-        debug_println!("put_tab: {}", count);
+    pub fn palette(&self) -> &[Rgb] {
+        &self.colors[..]
+    }
 
-        let mut col = self.cursor.col;
-        while col < self.grid.num_cols() && count != 0 {
-            count -= 1;
-            loop {
-                if col == self.grid.num_cols() || self.tabs[*col as usize] {
-                    break;
-                }
-                col += 1;
-            }
+    /// Change a color of the palette
+    ///
+    /// Text written from now on with the color uses the new value; text
+    /// already on screen keeps the old one. Indices past the palette are
+    /// ignored.
+    pub fn set_palette_color(&mut self, index: usize, rgb: Rgb) {
+        if let Some(color) = self.colors.get_mut(index) {
+            *color = rgb;
         }
 
-        self.cursor.col = col;
+        self.update_template_fg();
     }
 
-    /// Backspace `count` characters
-    #[inline]
-    fn backspace(&mut self) {
-        debug_println!("backspace");
-        self.cursor.col -= 1;
+    /// Restore the palette the terminal was built with
+    pub fn reset_palette(&mut self) {
+        self.colors = self.default_colors;
+        self.update_template_fg();
     }
 
-    /// Carriage return
+    /// Get the fonts used for bold and italic text
     #[inline]
-    fn carriage_return(&mut self) {
-        debug_println!("carriage_return");
-        self.cursor.col = Column(0);
+    pub fn font_keys(&self) -> &FontKeys {
+        &self.font_keys
     }
 
-    /// Linefeed
-    #[inline]
-    fn linefeed(&mut self) {
-        debug_println!("linefeed");
-        if self.cursor.line + 1 == self.scroll_region.end {
-            self.scroll_up(Line(1));
-        } else {
-            self.cursor.line += 1;
-        }
-    }
+    /// Set the template foreground from the named foreground color
+    ///
+    /// Bold text may be drawn with the bright variant of the color.
+    fn update_template_fg(&mut self) {
+        if let Some(color) = self.template_fg_color {
+            let mut index = color as usize;
+            if index < 8 && self.bold_brightens && self.template_cell.flags.contains(cell::BOLD) {
+                index += 8;
+            }
 
-    /// Set current position as a tabstop
-    #[inline]
-    fn bell(&mut self) {
-        debug_println!("bell");
+            self.template_cell.fg = self.colors[index];
+        }
     }
 
-    #[inline]
-    fn substitute(&mut self) {
-        err_println!("[unimplemented] substitute");
-    }
+    /// Get the color a cell's text should be drawn with
+    ///
+    /// This is the cell's foreground, dimmed according to the configured
+    /// strategy when the cell is dim.
+    pub fn effective_fg(&self, cell: &Cell) -> Rgb {
+        if !cell.flags.contains(cell::DIM) {
+            return cell.fg;
+        }
 
-    #[inline]
-    fn newline(&mut self) {
-        err_println!("[unimplemented] newline");
+        dim_color(cell.fg, cell.bg, self.dim_strategy, &self.colors)
     }
 
+    /// Get the font a cell with the given flags should be rendered with
     #[inline]
-    fn set_horizontal_tabstop(&mut self) {
-        err_println!("[unimplemented] set_horizontal_tabstop");
+    pub fn font_key_for(&self, flags: cell::Flags) -> FontKey {
+        self.font_keys.get(flags)
     }
 
-    #[inline]
-    fn scroll_up(&mut self, lines: Line) {
-        let origin = self.scroll_region.start;
-        self.scroll_up_relative(origin, lines);
+    /// Get the text of a visible line with trailing spaces trimmed
+    pub fn row_to_string(&self, line: Line) -> String {
+        let row = self.row_text(line);
+        row.trim_right_matches(' ').to_owned()
     }
 
-    #[inline]
-    fn scroll_down(&mut self, lines: Line) {
-        let origin = self.scroll_region.start;
-        self.scroll_down_relative(origin, lines);
+    /// Get the characters of a line, leaving out the spacers after double
+    /// width characters
+    fn row_text(&self, line: Line) -> String {
+        self.grid[line]
+            .cells()
+            .filter(|c| !c.flags.contains(cell::WIDE_CHAR_SPACER))
+            .map(|c| c.c)
+            .collect()
     }
 
-    #[inline]
-    fn insert_blank_lines(&mut self, lines: Line) {
-        debug_println!("insert_blank_lines: {}", lines);
-        if self.scroll_region.contains(self.cursor.line) {
-            let origin = self.cursor.line;
-            self.scroll_down_relative(origin, lines);
+    /// Get the text of the visible grid
+    ///
+    /// Each line ends with a newline. With `trim_trailing`, the spaces padding
+    /// each line to the width of the grid are dropped. Useful for debugging and
+    /// for comparing against expected output in tests.
+    pub fn grid_to_string(&self, trim_trailing: bool) -> String {
+        let mut out = String::new();
+        for line in Line(0)..self.grid.num_lines() {
+            if trim_trailing {
+                out.push_str(&self.row_to_string(line));
+            } else {
+                out.push_str(&self.row_text(line));
+            }
+            out.push('\n');
         }
+
+        out
     }
 
-    #[inline]
-    fn delete_lines(&mut self, lines: Line) {
-        debug_println!("delete_lines: {}", lines);
-        if self.scroll_region.contains(self.cursor.line) {
-            let origin = self.cursor.line;
-            self.scroll_up_relative(origin, lines);
+    /// Write the screen in view as text with SGR sequences for its colors and
+    /// attributes
+    ///
+    /// A sequence is written only where the cell differs from the one before
+    /// it, and the output ends with a reset. Cells at the end of a line which
+    /// draw nothing over the background are left out. Fed to a fresh terminal
+    /// of the same size and default colors, the output reproduces the screen,
+    /// wrapped lines included.
+    pub fn dump<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        let blank = Cell {
+            c: ' ',
+            fg: self.fg,
+            bg: self.bg,
+            flags: cell::Flags::empty(),
+            underline_color: None,
+        };
+        let mut pen = blank;
+
+        let num_lines = self.grid.num_lines();
+        for line in Line(0)..num_lines {
+            let row = self.visible_row(line);
+            let len = row.cells().rposition(|c| !self.is_blank(c)).map_or(0, |i| i + 1);
+
+            for c in row[Column(0)..Column(len)].iter() {
+                if c.flags.contains(cell::WIDE_CHAR_SPACER) {
+                    continue;
+                }
+
+                if !self.draws_alike(c, &pen) {
+                    self.write_sgr(w, c)?;
+                    pen = *c;
+                }
+
+                write!(w, "{}", c.c)?;
+            }
+
+            // Wrapped lines continue when the next character is written
+            if line + 1 != num_lines && !self.is_wrapped(line) {
+                write!(w, "\r\n")?;
+            }
         }
+
+        write!(w, "\x1b[0m")
     }
 
-    #[inline]
-    fn erase_chars(&mut self, count: Column) {
-        debug_println!("erase_chars: {}", count);
-        let start = self.cursor.col;
-        let end = start + count;
+    /// Whether a cell draws nothing over the default background
+    ///
+    /// The last cell of a wrapped line never counts as blank.
+    fn is_blank(&self, c: &Cell) -> bool {
+        c.c == ' ' && c.bg == self.bg &&
+            !c.flags.intersects(cell::INVERSE | cell::ALL_UNDERLINES | cell::OVERLINE |
+                                cell::WRAPLINE)
+    }
 
-        let row = &mut self.grid[self.cursor.line];
-        let template = self.empty_cell.clone();
-        for c in &mut row[start..end] {
-            c.reset(&template);
-        }
+    /// Whether two cells are drawn with the same colors and attributes
+    fn draws_alike(&self, a: &Cell, b: &Cell) -> bool {
+        let style = SGR_FLAGS.iter().fold(cell::Flags::empty(), |all, &(flag, _)| all | flag);
+        a.fg == b.fg && a.bg == b.bg && a.underline_color == b.underline_color &&
+            (a.flags & style) == (b.flags & style)
     }
 
-    #[inline]
-    fn delete_chars(&mut self, count: Column) {
-        // Ensure deleting within terminal bounds
-        let count = ::std::cmp::min(count, self.size_info.cols());
+    /// Write an SGR sequence setting the colors and attributes of `cell`
+    fn write_sgr<W: io::Write>(&self, w: &mut W, cell: &Cell) -> io::Result<()> {
+        write!(w, "\x1b[")?;
+        self.write_sgr_params(w, cell)?;
+        write!(w, "m")
+    }
 
-        let start = self.cursor.col;
-        let end = self.cursor.col + count;
-        let n = (self.size_info.cols() - end).0;
+    /// Write the parameters of an SGR sequence setting the colors and
+    /// attributes of `cell`, starting with a reset
+    fn write_sgr_params<W: io::Write>(&self, w: &mut W, cell: &Cell) -> io::Result<()> {
+        write!(w, "0")?;
 
-        let line = self.cursor.line; // borrowck
-        let line = &mut self.grid[line];
+        for &(flag, param) in SGR_FLAGS.iter() {
+            if cell.flags.contains(flag) {
+                write!(w, ";{}", param)?;
+            }
+        }
 
-        unsafe {
-            let src = line[end..].as_ptr();
-            let dst = line[start..].as_mut_ptr();
+        if cell.fg != self.fg {
+            write!(w, ";38;2;{};{};{}", cell.fg.r, cell.fg.g, cell.fg.b)?;
+        }
 
-            ptr::copy(src, dst, n);
+        if cell.bg != self.bg {
+            write!(w, ";48;2;{};{};{}", cell.bg.r, cell.bg.g, cell.bg.b)?;
         }
 
-        // Clear last `count` cells in line. If deleting 1 char, need to delete 1 cell.
-        let template = self.empty_cell.clone();
-        let end = self.size_info.cols() - count;
-        for c in &mut line[end..] {
-            c.reset(&template);
+        if let Some(color) = cell.underline_color {
+            write!(w, ";58;2;{};{};{}", color.r, color.g, color.b)?;
         }
-    }
 
-    #[inline]
-    fn move_backward_tabs(&mut self, count: i64) {
-        err_println!("[unimplemented] move_backward_tabs: {}", count);
+        Ok(())
     }
 
-    #[inline]
-    fn move_forward_tabs(&mut self, count: i64) {
-        err_println!("[unimplemented] move_forward_tabs: {}", count);
+    /// Get the text of the cells in view from `range.start` up to `range.end`
+    ///
+    /// Lines are separated by newlines, except where a line was wrapped onto
+    /// the next one; those are joined as written. Spacers after double width
+    /// characters are skipped. With `trim_trailing`, trailing spaces are
+    /// dropped from lines which are selected up to their end and end with a
+    /// newline; spaces selected within the final line are kept.
+    pub fn selection_text(&self, range: Range<Point>, trim_trailing: bool) -> String {
+        let num_cols = self.grid.num_cols();
+        let mut out = String::new();
+
+        let mut line = range.start.line;
+        while line <= range.end.line {
+            let start = if line == range.start.line { range.start.col } else { Column(0) };
+            let end = if line == range.end.line { range.end.col } else { num_cols };
+            let end = ::std::cmp::min(end, num_cols);
+            let wrapped = line != range.end.line && end == num_cols && self.is_wrapped(line);
+
+            let mut text = self.visible_row(line)[start..end]
+                .iter()
+                .filter(|c| !c.flags.contains(cell::WIDE_CHAR_SPACER))
+                .map(|c| c.c)
+                .collect::<String>();
+
+            if trim_trailing && end == num_cols && !wrapped {
+                let len = text.trim_right_matches(' ').len();
+                text.truncate(len);
+            }
+
+            out.push_str(&text);
+            if line != range.end.line && !wrapped {
+                out.push('\n');
+            }
+
+            line += 1;
+        }
+
+        out
     }
 
+    /// Get the selected cells in view, from `start` up to `end`
     #[inline]
-    fn save_cursor_position(&mut self) {
-        err_println!("[unimplemented] save_cursor_position");
+    pub fn selection(&self) -> Option<Range<Point>> {
+        self.selection.clone()
     }
 
-    #[inline]
-    fn restore_cursor_position(&mut self) {
-        err_println!("[unimplemented] restore_cursor_position");
+    /// Select the cells in view from `start` up to `end`, or clear the
+    /// selection
+    pub fn set_selection(&mut self, selection: Option<Range<Point>>) {
+        self.selection = selection;
+        self.dirty = true;
     }
 
-    #[inline]
-    fn clear_line(&mut self, mode: ansi::LineClearMode) {
-        debug_println!("clear_line: {:?}", mode);
-        let template = self.empty_cell.clone();
-        match mode {
-            ansi::LineClearMode::Right => {
-                let row = &mut self.grid[self.cursor.line];
-                for cell in &mut row[self.cursor.col..] {
-                    cell.reset(&template);
-                }
-            },
-            ansi::LineClearMode::Left => {
-                let row = &mut self.grid[self.cursor.line];
-                for cell in &mut row[..(self.cursor.col + 1)] {
-                    cell.reset(&template);
-                }
-            },
-            ansi::LineClearMode::All => {
-                let row = &mut self.grid[self.cursor.line];
-                for cell in &mut row[..] {
-                    cell.reset(&template);
-                }
+    /// Clear the selection if it covers `line`, whose text is about to change
+    ///
+    /// Does nothing unless the selection is configured to clear on output.
+    pub fn invalidate_selection_if_dirtied(&mut self, line: Line) {
+        self.invalidate_selection_in(line..(line + 1));
+    }
+
+    fn invalidate_selection_in(&mut self, lines: Range<Line>) {
+        if !self.selection_clears_on_output {
+            return;
+        }
+
+        let covered = match self.selection {
+            Some(ref selection) => {
+                selection.start.line < lines.end && lines.start <= selection.end.line
             },
+            None => false,
+        };
+
+        if covered {
+            self.selection = None;
+            self.dirty = true;
         }
     }
 
-    #[inline]
-    fn clear_screen(&mut self, mode: ansi::ClearMode) {
-        debug_println!("clear_screen: {:?}", mode);
-        let template = self.empty_cell.clone();
+    /// Copy the cells of the screen in `lines` and `cols`
+    ///
+    /// The rectangle is clipped to the screen, so it may come back smaller
+    /// than asked for or empty.
+    pub fn region(&self, lines: Range<Line>, cols: Range<Column>) -> Vec<Vec<Cell>> {
+        let end_line = ::std::cmp::min(lines.end, self.grid.num_lines());
+        let end_col = ::std::cmp::min(cols.end, self.grid.num_cols());
+        if lines.start >= end_line || cols.start >= end_col {
+            return Vec::new();
+        }
+
+        (lines.start..end_line).map(|line| {
+            self.grid[line][cols.start..end_col].to_vec()
+        }).collect()
+    }
+
+    /// Find the next occurrence of `needle` in the grid and scrollback
+    ///
+    /// Searching `Direction::Right` finds the first match starting at or after
+    /// `start`; `Direction::Left` finds the last match starting at or before
+    /// it. Matches may span soft-wrapped lines but not hard line breaks. The
+    /// returned range ends just past the last matched cell.
+    pub fn search(
+        &self,
+        needle: &str,
+        start: Point<usize>,
+        direction: Direction,
+        case_sensitive: bool
+    ) -> Option<Range<Point<usize>>> {
+        let fold = |c: char| {
+            if case_sensitive {
+                c
+            } else {
+                c.to_lowercase().next().unwrap_or(c)
+            }
+        };
+
+        let needle = needle.chars().map(&fold).collect::<Vec<_>>();
+        if needle.is_empty() {
+            return None;
+        }
+
+        let (text, points) = self.buffer_text();
+        let text = text.into_iter().map(&fold).collect::<Vec<_>>();
+
+        let len = needle.len();
+        let mut matches = (0..(text.len() + 1).saturating_sub(len))
+            .filter(|&i| text[i..(i + len)] == needle[..]);
+
+        let found = match direction {
+            Direction::Right => matches.find(|&i| points[i] >= start),
+            Direction::Left => matches.take_while(|&i| points[i] <= start).last(),
+        };
+
+        found.map(|i| {
+            let last = points[i + len - 1];
+            points[i]..Point::new(last.line, last.col + Column(1))
+        })
+    }
+
+    /// Capture the screen, cursor and modes
+    #[cfg(feature = "snapshot")]
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            lines: self.grid.lines().map(|row| row.to_vec()).collect(),
+            cursor: self.cursor.clone(),
+            mode: self.mode,
+        }
+    }
+
+    /// Restore a snapshot taken with `snapshot`
+    ///
+    /// A snapshot from a differently sized terminal is clipped or padded with
+    /// blank cells to fit.
+    #[cfg(feature = "snapshot")]
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        let template = self.empty_cell;
+        self.grid.reset_lines(.., &template);
+
+        let cols = self.grid.num_cols();
+        for (row, cells) in self.grid.lines_mut().zip(snapshot.lines) {
+            for (dst, src) in row.cells_mut().zip(cells) {
+                *dst = src;
+            }
+        }
+
+        self.cursor.line = ::std::cmp::min(snapshot.cursor.line, self.grid.num_lines() - Line(1));
+        self.cursor.col = ::std::cmp::min(snapshot.cursor.col, cols - Column(1));
+        self.mode = snapshot.mode;
+        self.mark_all_dirty();
+        self.dirty = true;
+    }
+
+    /// Whether a mode as numbered by set/reset mode is on
+    ///
+    /// Returns `None` for modes which aren't recognized.
+    fn mode_state(&self, private: bool, mode: u16) -> Option<bool> {
+        if !private {
+            return None;
+        }
+
         match mode {
-            ansi::ClearMode::Below => {
-                for row in &mut self.grid[self.cursor.line..] {
-                    for cell in row {
-                        cell.reset(&template);
-                    }
-                }
-            },
-            ansi::ClearMode::All => {
-                self.grid.clear(|c| c.reset(&template));
+            1 => Some(self.mode.contains(mode::APP_CURSOR)),
+            5 => Some(self.mode.contains(mode::REVERSE_SCREEN)),
+            6 => Some(self.mode.contains(mode::ORIGIN)),
+            25 => Some(self.mode.contains(mode::SHOW_CURSOR)),
+            45 => Some(self.mode.contains(mode::REVERSE_WRAP)),
+            66 => Some(self.mode.contains(mode::APP_KEYPAD)),
+            1004 => Some(self.mode.contains(mode::FOCUS_IN_OUT)),
+            2026 => Some(self.mode.contains(mode::SYNC_UPDATE)),
+            47 | 1047 | 1049 => Some(self.alt),
+            _ => None,
+        }
+    }
+
+    /// Check whether applying `attr` would leave the template cell as it is
+    ///
+    /// Only the most common attributes are checked; `false` means the
+    /// attribute needs to be applied.
+    fn attribute_is_set(&self, attr: &Attr) -> bool {
+        let template = &self.template_cell;
+        match *attr {
+            Attr::Reset => {
+                self.template_fg_color.is_none() &&
+                    template.fg == self.fg &&
+                    template.bg == self.bg &&
+                    (template.flags - cell::PROTECTED).is_empty() &&
+                    template.underline_color.is_none()
             },
-            _ => {
-                panic!("ansi::ClearMode::Above not implemented");
+            Attr::Foreground(color) => self.template_fg_color == Some(color),
+            Attr::ForegroundSpec(rgb) => self.template_fg_color.is_none() && template.fg == rgb,
+            Attr::DefaultForeground => self.template_fg_color.is_none() && template.fg == self.fg,
+            Attr::Background(color) => template.bg == self.colors[color as usize],
+            Attr::BackgroundSpec(rgb) => template.bg == rgb,
+            Attr::DefaultBackground => template.bg == self.bg,
+            Attr::Bold => template.flags.contains(cell::BOLD),
+            Attr::Italic => template.flags.contains(cell::ITALIC),
+            Attr::Reverse => template.flags.contains(cell::INVERSE),
+            _ => false,
+        }
+    }
+
+    /// Reset the cells in `cols` of `line` which aren't protected by DECSCA
+    fn selective_erase(&mut self, line: Line, cols: Range<Column>) {
+        let template = self.empty_cell.clone();
+        self.mark_dirty(line);
+        self.invalidate_selection_if_dirtied(line);
+        for c in &mut self.grid[line][cols] {
+            if !c.flags.contains(cell::PROTECTED) {
+                c.reset(&template);
+            }
+        }
+    }
+
+    /// Get the screen line for a line given to a cursor positioning sequence
+    ///
+    /// In origin mode lines count from the top of the scroll region and stop
+    /// at its bottom; otherwise they stop at the bottom of the screen.
+    fn absolute_line(&self, line: Line) -> Line {
+        let (top, bottom) = if self.mode.contains(mode::ORIGIN) {
+            (self.scroll_region.start, self.scroll_region.end - 1)
+        } else {
+            (Line(0), self.grid.num_lines() - 1)
+        };
+
+        ::std::cmp::min(Line(line.saturating_add(*top)), bottom)
+    }
+
+    /// Find the first tab stop after `col` on the cursor line
+    ///
+    /// Stops on the right half of a double width character are skipped. The
+    /// right margin counts as a stop.
+    fn next_tab_stop(&self, mut col: Column) -> Column {
+        let num_cols = self.grid.num_cols();
+        let row = &self.grid[self.cursor.line];
+        while col < num_cols {
+            col += 1;
+            if col < num_cols && self.tabs.get(*col) == Some(&true) &&
+               !row[col].flags.contains(cell::WIDE_CHAR_SPACER)
+            {
+                break;
+            }
+        }
+
+        col
+    }
+
+    /// Find the last tab stop before `col` on the cursor line
+    ///
+    /// Stops on the right half of a double width character are skipped. The
+    /// left margin counts as a stop.
+    fn prev_tab_stop(&self, mut col: Column) -> Column {
+        let row = &self.grid[self.cursor.line];
+        while col > Column(0) {
+            col -= 1;
+            if self.tabs[*col] && !row[col].flags.contains(cell::WIDE_CHAR_SPACER) {
+                break;
             }
         }
+
+        col
     }
 
+    /// Set the underline style for new text, replacing any other style
     #[inline]
-    fn clear_tabs(&mut self, mode: ansi::TabulationClearMode) {
-        err_println!("[unimplemented] clear_tabs: {:?}", mode);
+    fn set_underline(&mut self, style: cell::Flags) {
+        self.template_cell.flags.remove(cell::ALL_UNDERLINES);
+        self.template_cell.flags.insert(style);
+    }
+
+    /// Expand a point to the word around it
+    ///
+    /// Words are separated by whitespace and the configured semantic escape
+    /// characters, and continue across soft-wrapped lines. A separator expands
+    /// to just itself. The returned range ends just past the last cell.
+    pub fn semantic_range(&self, point: Point) -> Range<Point> {
+        if self.is_semantic_separator(point) {
+            return point..Point::new(point.line, point.col + Column(1));
+        }
+
+        let mut start = point;
+        while let Some(prev) = self.wrapped_left(start) {
+            if self.is_semantic_separator(prev) {
+                break;
+            }
+            start = prev;
+        }
+
+        let mut end = point;
+        while let Some(next) = self.wrapped_right(end) {
+            if self.is_semantic_separator(next) {
+                break;
+            }
+            end = next;
+        }
+
+        start..Point::new(end.line, end.col + Column(1))
+    }
+
+    /// Expand a point to the logical line it's on
+    ///
+    /// Soft-wrapped lines in view are joined, so a wrapped paragraph is one
+    /// unit. The range covers whole rows: it starts at the first column of the
+    /// first row and ends just past the last column of the last row.
+    pub fn line_range(&self, point: Point) -> Range<Point> {
+        let mut start = point.line;
+        while start > Line(0) && self.is_wrapped(start - Line(1)) {
+            start = start - Line(1);
+        }
+
+        let mut end = point.line;
+        while end + Line(1) < self.grid.num_lines() && self.is_wrapped(end) {
+            end += Line(1);
+        }
+
+        Point::new(start, Column(0))..Point::new(end, self.grid.num_cols())
+    }
+
+    /// Find a URL which covers a point
+    ///
+    /// URLs start with one of `URL_SCHEMES` and run until whitespace,
+    /// continuing across soft-wrapped lines. Returns the URL and the range of
+    /// cells it covers, ending just past the last cell.
+    pub fn find_url_at(&self, point: Point) -> Option<(String, Range<Point>)> {
+        let is_space = |point: Point| self.visible_row(point.line)[point.col].c.is_whitespace();
+
+        if is_space(point) {
+            return None;
+        }
+
+        let mut start = point;
+        while let Some(prev) = self.wrapped_left(start) {
+            if is_space(prev) {
+                break;
+            }
+            start = prev;
+        }
+
+        // Collect the run of non-whitespace cells around the point
+        let mut chars = Vec::new();
+        let mut points = Vec::new();
+        let mut next = Some(start);
+        while let Some(point) = next {
+            if is_space(point) {
+                break;
+            }
+            chars.push(self.visible_row(point.line)[point.col].c);
+            points.push(point);
+            next = self.wrapped_right(point);
+        }
+
+        let offset = points.iter().position(|p| *p == point).unwrap();
+
+        // The closest scheme at or before the point starts the URL
+        let (url_start, scheme_len) = match (0...offset).rev().filter_map(|i| {
+            URL_SCHEMES.iter()
+                .find(|scheme| starts_with_at(&chars, i, scheme))
+                .map(|scheme| (i, scheme.chars().count()))
+        }).next() {
+            Some(found) => found,
+            None => return None,
+        };
+
+        let mut url_end = chars.len();
+        while url_end > url_start + scheme_len &&
+              URL_TRAILING_DELIMITERS.contains(chars[url_end - 1])
+        {
+            url_end -= 1;
+        }
+
+        if url_end <= offset || url_end == url_start + scheme_len {
+            return None;
+        }
+
+        let url = chars[url_start..url_end].iter().cloned().collect::<String>();
+        let last = points[url_end - 1];
+        Some((url, points[url_start]..Point::new(last.line, last.col + Column(1))))
     }
 
     #[inline]
-    fn reset_state(&mut self) {
-        err_println!("[unimplemented] reset_state");
+    fn is_semantic_separator(&self, point: Point) -> bool {
+        let c = self.visible_row(point.line)[point.col].c;
+        c.is_whitespace() || self.semantic_escape_chars.contains(c)
     }
 
+    /// Get a line in view, which may be in history
     #[inline]
-    fn reverse_index(&mut self) {
-        debug_println!("reverse_index");
-        // if cursor is at the top
-        if self.cursor.line == self.scroll_region.start {
-            self.scroll_down(Line(1));
+    fn visible_row(&self, line: Line) -> &Row<Cell> {
+        let point = self.grid.visible_to_absolute(Point::new(line, Column(0)));
+        self.grid.buffer_line(point.line)
+    }
+
+    /// Whether a line in view was wrapped onto the next line, rather than
+    /// ended by a newline
+    ///
+    /// Wrapping sets `cell::WRAPLINE` on the last cell of the line.
+    #[inline]
+    pub fn is_wrapped(&self, line: Line) -> bool {
+        let last = self.grid.num_cols() - Column(1);
+        self.visible_row(line)[last].flags.contains(cell::WRAPLINE)
+    }
+
+    /// Point before `point` in view, following a soft wrap up to the previous
+    /// line
+    fn wrapped_left(&self, point: Point) -> Option<Point> {
+        if point.col > Column(0) {
+            Some(Point::new(point.line, point.col - Column(1)))
+        } else if point.line > Line(0) && self.is_wrapped(point.line - Line(1)) {
+            Some(Point::new(point.line - Line(1), self.grid.num_cols() - Column(1)))
         } else {
-            self.cursor.line -= 1;
+            None
         }
     }
 
-    /// set a terminal attribute
-    #[inline]
-    fn terminal_attribute(&mut self, attr: Attr) {
-        debug_println!("Set Attribute: {:?}", attr);
-        match attr {
-            Attr::DefaultForeground => {
-                self.template_cell.fg = self.fg;
-            },
-            Attr::DefaultBackground => {
-                self.template_cell.bg = self.bg;
-            },
-            Attr::Foreground(named_color) => {
-                self.template_cell.fg = self.colors[named_color as usize];
-            },
-            Attr::Background(named_color) => {
-                self.template_cell.bg = self.colors[named_color as usize];
-            },
-            Attr::ForegroundSpec(rgb) => {
-                self.template_cell.fg = rgb;
-            },
-            Attr::BackgroundSpec(rgb) => {
-                self.template_cell.bg = rgb;
-            },
-            Attr::Reset => {
-                self.template_cell.fg = self.fg;
-                self.template_cell.bg = self.bg;
-                self.template_cell.flags = cell::Flags::empty();
-            },
-            Attr::Reverse => self.template_cell.flags.insert(cell::INVERSE),
-            Attr::CancelReverse => self.template_cell.flags.remove(cell::INVERSE),
-            Attr::Bold => self.template_cell.flags.insert(cell::BOLD),
-            Attr::CancelBoldDim => self.template_cell.flags.remove(cell::BOLD),
-            Attr::Italic => self.template_cell.flags.insert(cell::ITALIC),
-            Attr::CancelItalic => self.template_cell.flags.remove(cell::ITALIC),
-            Attr::Underscore => self.template_cell.flags.insert(cell::UNDERLINE),
-            Attr::CancelUnderline => self.template_cell.flags.remove(cell::UNDERLINE),
-            _ => {
-                debug_println!("Term got unhandled attr: {:?}", attr);
+    /// Point after `point` in view, following a soft wrap down to the next line
+    fn wrapped_right(&self, point: Point) -> Option<Point> {
+        if point.col + Column(1) < self.grid.num_cols() {
+            Some(Point::new(point.line, point.col + Column(1)))
+        } else if point.line + Line(1) < self.grid.num_lines() && self.is_wrapped(point.line) {
+            Some(Point::new(point.line + Line(1), Column(0)))
+        } else {
+            None
+        }
+    }
+
+    /// Get every character in the buffer along with its location
+    ///
+    /// Lines which weren't soft-wrapped are followed by a newline, located
+    /// just past the end of the line.
+    fn buffer_text(&self) -> (Vec<char>, Vec<Point<usize>>) {
+        let cols = self.grid.num_cols();
+        let capacity = self.grid.total_lines() * (*cols + 1);
+        let mut text = Vec::with_capacity(capacity);
+        let mut points = Vec::with_capacity(capacity);
+
+        for line in 0..self.grid.total_lines() {
+            let row = self.grid.buffer_line(line);
+            for (col, cell) in row.cells().enumerate() {
+                text.push(cell.c);
+                points.push(Point::new(line, Column(col)));
             }
+
+            if !row[cols - Column(1)].flags.contains(cell::WRAPLINE) {
+                text.push('\n');
+                points.push(Point::new(line, cols));
+            }
+        }
+
+        (text, points)
+    }
+
+    /// Iterate over the lines which changed since the last `clear_dirty`
+    ///
+    /// The cursor's line and the line it was on at the last `clear_dirty` are
+    /// always included since the cursor is drawn as part of the grid.
+    #[inline]
+    pub fn dirty_lines(&self) -> DirtyLines {
+        DirtyLines {
+            dirty: &self.line_dirty[..],
+            cursor_lines: (self.drawn_cursor_line, self.cursor.line),
+            line: Line(0),
         }
     }
 
+    /// Mark the terminal and all lines clean; call after rendering
+    ///
+    /// This is the only place dirty state is cleared.
     #[inline]
-    fn set_mode(&mut self, mode: ansi::Mode) {
-        debug_println!("set_mode: {:?}", mode);
-        match mode {
-            ansi::Mode::SwapScreenAndSetRestoreCursor => self.swap_alt(),
-            ansi::Mode::ShowCursor => self.mode.insert(mode::SHOW_CURSOR),
-            ansi::Mode::CursorKeys => self.mode.insert(mode::APP_CURSOR),
-            _ => {
-                debug_println!(".. ignoring set_mode");
-            }
+    pub fn clear_dirty(&mut self) {
+        for dirty in &mut self.line_dirty {
+            *dirty = false;
         }
+
+        self.drawn_cursor_line = self.cursor.line;
+        self.dirty = false;
     }
 
     #[inline]
-    fn unset_mode(&mut self,mode: ansi::Mode) {
-        debug_println!("unset_mode: {:?}", mode);
-        match mode {
-            ansi::Mode::SwapScreenAndSetRestoreCursor => self.swap_alt(),
-            ansi::Mode::ShowCursor => self.mode.remove(mode::SHOW_CURSOR),
-            ansi::Mode::CursorKeys => self.mode.remove(mode::APP_CURSOR),
-            _ => {
-                debug_println!(".. ignoring unset_mode");
+    fn mark_dirty(&mut self, line: Line) {
+        if let Some(dirty) = self.line_dirty.get_mut(*line) {
+            *dirty = true;
+        }
+    }
+
+    #[inline]
+    fn mark_dirty_range(&mut self, lines: Range<Line>) {
+        let end = ::std::cmp::min(*lines.end, self.line_dirty.len());
+        for dirty in &mut self.line_dirty[*lines.start..end] {
+            *dirty = true;
+        }
+    }
+
+    #[inline]
+    fn mark_all_dirty(&mut self) {
+        for dirty in &mut self.line_dirty {
+            *dirty = true;
+        }
+    }
+
+    /// Process a chunk of output from the child program
+    ///
+    /// Parser state is kept between calls, so an escape sequence may be split
+    /// across chunks. Any input is accepted; malformed or out of range
+    /// sequences are ignored or clamped rather than panicking.
+    /// `tests/fuzz.rs` checks this against random input.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.scroll_on_output && self.grid.display_offset() != 0 {
+            self.reset_display();
+        }
+
+        // The parser needs the terminal as its handler; move it out for the
+        // duration of the chunk.
+        let mut parser = mem::replace(&mut self.parser, ansi::Processor::new());
+        for byte in bytes {
+            parser.advance(self, *byte);
+        }
+        self.parser = parser;
+
+        // An update which has run too long ends here even if output continues
+        self.expire_timers(Instant::now());
+
+        // Lines changed during a synchronized update are still marked, but
+        // aren't drawn until the update ends
+        if !self.mode.contains(mode::SYNC_UPDATE) {
+            self.dirty = true;
+        }
+    }
+
+    /// Get the next time after `now` that `expire_timers` has something to
+    /// do, if any
+    ///
+    /// Nothing else wakes the terminal when a program stops sending output,
+    /// so the event loop should call `expire_timers` at this time.
+    pub fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        if self.mode.contains(mode::SYNC_UPDATE) {
+            Some(self.sync_update_until)
+        } else if self.cursor_blinks() && self.mode.contains(mode::SHOW_CURSOR) {
+            Some(self.next_cursor_blink(now))
+        } else {
+            None
+        }
+    }
+
+    /// Act on any timers which have run out by `now`
+    ///
+    /// A synchronized update lasting longer than `SYNC_UPDATE_TIMEOUT_MS` is
+    /// ended, so a program which never ends one can't freeze the display.
+    /// When a blinking cursor turns on or off, its line is marked dirty.
+    pub fn expire_timers(&mut self, now: Instant) {
+        if self.mode.contains(mode::SYNC_UPDATE) {
+            if now >= self.sync_update_until {
+                debug!("synchronized update timed out");
+                self.end_sync_update();
+            }
+            return;
+        }
+
+        if self.cursor_blinks() {
+            let shown = self.cursor_blink_phase(now);
+            if shown != self.cursor_blink_shown {
+                self.cursor_blink_shown = shown;
+                let line = self.cursor.line;
+                self.mark_dirty(line);
+                self.dirty = true;
             }
         }
     }
 
+    /// End any synchronized update and draw everything changed during it
+    pub fn end_sync_update(&mut self) {
+        if self.mode.contains(mode::SYNC_UPDATE) {
+            self.mode.remove(mode::SYNC_UPDATE);
+            self.dirty = true;
+        }
+    }
+
+    /// Process a chunk of output, leaving a trailing partial sequence
+    ///
+    /// A short escape sequence or UTF-8 character cut short at the end of
+    /// `bytes` isn't processed. Returns the number of bytes processed; the rest
+    /// should be passed again at the start of the next call.
+    pub fn advance(&mut self, bytes: &[u8]) -> usize {
+        let consumed = incomplete_suffix(bytes).unwrap_or(bytes.len());
+        self.feed(&bytes[..consumed]);
+        consumed
+    }
+
+    /// Get the lines scrolled by linefeeds and scroll sequences
     #[inline]
-    fn set_scrolling_region(&mut self, region: Range<Line>) {
-        debug_println!("set scroll region: {:?}", region);
-        self.scroll_region = region;
+    pub fn scroll_region(&self) -> Range<Line> {
+        self.scroll_region.clone()
+    }
+
+    /// Set the lines scrolled by linefeeds and scroll sequences, as DECSTBM
+    /// does
+    ///
+    /// The end is clamped to the screen. A region of less than two lines is
+    /// ignored. Otherwise the cursor moves to the top left corner, which is
+    /// the top of the region in origin mode.
+    pub fn set_scroll_region(&mut self, region: Range<Line>) {
+        let end = ::std::cmp::min(region.end, self.grid.num_lines());
+        if region.start + 1 >= end {
+            debug!("ignoring scroll region {:?}", region);
+            return;
+        }
+
+        self.scroll_region = region.start..end;
         self.goto(Line(0), Column(0));
     }
 
+    /// Set whether new output scrolls the view back to the bottom
+    ///
+    /// This is the default. When disabled, a view scrolled back into history
+    /// keeps showing the same lines as output arrives.
     #[inline]
-    fn set_keypad_application_mode(&mut self) {
-        debug_println!("set mode::APP_KEYPAD");
-        self.mode.insert(mode::APP_KEYPAD);
+    pub fn set_scroll_on_output(&mut self, enabled: bool) {
+        self.scroll_on_output = enabled;
     }
 
+    /// Check whether pasting `text` would also press enter
     #[inline]
-    fn unset_keypad_application_mode(&mut self) {
-        debug_println!("unset mode::APP_KEYPAD");
-        self.mode.remove(mode::APP_KEYPAD);
+    pub fn paste_contains_newline(text: &str) -> bool {
+        text.contains(|c: char| c == '\n' || c == '\r')
+    }
+
+    /// Queue pasted text for the pty
+    ///
+    /// When confirming multi-line pastes is enabled and `text` has a newline,
+    /// nothing is written; the embedder should ask the user and then call
+    /// `paste_confirmed`.
+    pub fn paste(&mut self, text: &str) -> Paste {
+        if self.confirm_paste_with_newline && Term::paste_contains_newline(text) {
+            return Paste::ConfirmationAdvised;
+        }
+
+        self.paste_confirmed(text);
+        Paste::Written
+    }
+
+    /// Queue pasted text for the pty without checking it
+    #[inline]
+    pub fn paste_confirmed(&mut self, text: &str) {
+        self.response.extend_from_slice(text.as_bytes());
+    }
+
+    /// Scroll the view `lines` lines back into history, or towards the bottom
+    /// for negative values
+    pub fn scroll_display(&mut self, lines: isize) {
+        self.grid.scroll_display(lines);
+        self.mark_all_dirty();
+        self.dirty = true;
+    }
+
+    /// Scroll the view to the bottom of history
+    pub fn reset_display(&mut self) {
+        let offset = self.grid.display_offset() as isize;
+        self.scroll_display(-offset);
+    }
+
+    /// Number of lines the view is scrolled back into history
+    #[inline]
+    pub fn display_offset(&self) -> usize {
+        self.grid.display_offset()
+    }
+
+    /// Set the maximum number of lines kept in history
+    ///
+    /// When shrinking, the oldest lines beyond the new limit are dropped and a
+    /// view scrolled back further moves down to the oldest line kept. This
+    /// applies to the primary screen even while the alternate screen is shown.
+    pub fn set_scrollback_limit(&mut self, limit: usize) {
+        if self.alt {
+            self.alt_grid.set_history_limit(limit);
+        } else {
+            self.grid.set_history_limit(limit);
+            self.mark_all_dirty();
+            self.dirty = true;
+        }
+    }
+
+    /// Update whether the window has keyboard focus
+    ///
+    /// When the child program asked for focus reports, a change is reported as
+    /// `CSI I` for focus in or `CSI O` for focus out.
+    pub fn set_focused(&mut self, focused: bool) {
+        if self.focused == focused {
+            return;
+        }
+
+        self.focused = focused;
+
+        // The cursor stops or starts blinking
+        if self.cursor_style().blinking {
+            let line = self.cursor.line;
+            self.mark_dirty(line);
+            self.dirty = true;
+        }
+
+        if self.mode.contains(mode::FOCUS_IN_OUT) {
+            let report: &[u8] = if focused { b"\x1b[I" } else { b"\x1b[O" };
+            self.response.extend_from_slice(report);
+        }
+    }
+
+    /// Take any bytes which should be written back to the pty
+    ///
+    /// Replies to queries from the child program accumulate here while input is
+    /// processed; the caller is responsible for delivering them.
+    #[inline]
+    pub fn take_response(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.response, Vec::new())
+    }
+
+    /// Resize terminal to new dimensions
+    pub fn resize(&mut self, width: f32, height: f32) {
+        let size = SizeInfo {
+            width: width,
+            height: height,
+            cell_width: self.size_info.cell_width,
+            cell_height: self.size_info.cell_height,
+            max_cols: self.size_info.max_cols,
+            max_lines: self.size_info.max_lines,
+        };
+
+        let num_cols = size.cols();
+        let num_lines = size.lines();
+
+        self.size_info = size;
+        self.resize_grid(num_lines, num_cols);
+    }
+
+    /// Resize the grids to `num_lines` by `num_cols` and tell the tty
+    ///
+    /// The size in pixels must already be set.
+    fn resize_grid(&mut self, num_lines: Line, num_cols: Column) {
+        if self.grid.num_lines() == num_lines && self.grid.num_cols() == num_cols {
+            return;
+        }
+
+        // Scroll up to keep cursor and as much context as possible in grid. This only runs when the
+        // lines decreases.
+        self.scroll_region = Line(0)..self.grid.num_lines();
+
+        // Scroll up to keep cursor in terminal
+        if self.cursor.line >= num_lines {
+            let lines = self.cursor.line - num_lines + 1;
+            self.scroll_up(lines);
+            self.cursor.line -= lines;
+        }
+
+        // Resize grids to new size
+        self.grid.resize(num_lines, num_cols, &Cell::new(' '));
+        self.alt_grid.resize(num_lines, num_cols, &Cell::new(' '));
+
+        // Ensure cursor is in-bounds
+        self.cursor.line = limit(self.cursor.line, Line(0), num_lines);
+        self.cursor.col = limit(self.cursor.col, Column(0), num_cols);
+
+        // Recreate tabs list
+        self.tabs = default_tab_stops(self.grid.num_cols());
+
+        // Make sure bottom of terminal is clear
+        let template = self.empty_cell.clone();
+        self.grid.reset_lines((self.cursor.line).., &template);
+        self.alt_grid.reset_lines((self.cursor.line).., &template);
+
+        // Reset scrolling region to new size
+        self.scroll_region = Line(0)..self.grid.num_lines();
+
+        // Everything needs to be drawn again
+        self.line_dirty = vec![true; *num_lines];
+        self.drawn_cursor_line = self.cursor.line;
+
+        // Inform tty of new dimensions
+        self.tty.resize(num_lines, num_cols, &self.size_info);
+    }
+
+    /// Resize the terminal to `lines` by `cols` cells
+    ///
+    /// The cell size is kept and the size in pixels becomes what the cells
+    /// add up to. The grid gets exactly the requested size, rather than what
+    /// that pixel size works out to, which may be a cell short with
+    /// fractional cell sizes. Sizes are limited to at least one cell and at
+    /// most the grid limit.
+    pub fn resize_to_grid(&mut self, lines: Line, cols: Column) {
+        let lines = limit(lines, Line(1), self.size_info.max_lines);
+        let cols = limit(cols, Column(1), self.size_info.max_cols);
+
+        self.size_info.width = *cols as f32 * self.size_info.cell_width;
+        self.size_info.height = *lines as f32 * self.size_info.cell_height;
+        self.resize_grid(lines, cols);
+    }
+
+    #[inline]
+    pub fn tty(&self) -> &tty::Tty {
+        &self.tty
+    }
+
+    #[inline]
+    pub fn tty_mut(&mut self) -> &mut tty::Tty {
+        &mut self.tty
+    }
+
+    /// Get the bytes a key press should send to the child program
+    ///
+    /// This depends on the application cursor and keypad modes; see
+    /// `input::encode_key`.
+    #[inline]
+    pub fn encode_key(&self, key: VirtualKeyCode, mods: Mods) -> Vec<u8> {
+        input::encode_key(key, mods, self.mode)
+    }
+
+    /// Get the exit status of the child program if it has exited
+    ///
+    /// This never blocks; see `tty::Tty::try_wait`.
+    #[inline]
+    pub fn child_exited(&self) -> io::Result<Option<i32>> {
+        self.tty.try_wait()
+    }
+
+    #[inline]
+    pub fn size_info(&self) -> &SizeInfo {
+        &self.size_info
+    }
+
+    #[inline]
+    pub fn mode(&self) -> &TermMode {
+        &self.mode
+    }
+
+    /// Whether the alternate screen is shown
+    #[inline]
+    pub fn in_alt_screen(&self) -> bool {
+        self.alt
+    }
+
+    /// Switch between the primary and alternate screens
+    ///
+    /// The cursor stays where it is and neither screen is cleared. This only
+    /// toggles, so it's private; `enter_alt` and `exit_alt` do nothing when
+    /// the screen is already switched and take care of clearing.
+    fn swap_alt(&mut self) {
+        self.alt = !self.alt;
+        ::std::mem::swap(&mut self.grid, &mut self.alt_grid);
+        self.mark_all_dirty();
+        self.selection = None;
+    }
+
+    /// Switch to the alternate screen for ?47, ?1047 and ?1049
+    ///
+    /// ?1049 saves the cursor and clears the alternate screen on the way in.
+    fn enter_alt(&mut self, mode: &ansi::Mode) {
+        if self.alt {
+            return;
+        }
+
+        if *mode == ansi::Mode::SwapScreenAndSetRestoreCursor {
+            self.save_cursor_position();
+        }
+
+        self.swap_alt();
+
+        if *mode == ansi::Mode::SwapScreenAndSetRestoreCursor {
+            let template = self.empty_cell.clone();
+            self.grid.reset_lines(.., &template);
+        }
+    }
+
+    /// Switch back to the primary screen for ?47, ?1047 and ?1049
+    fn exit_alt(&mut self, mode: &ansi::Mode) {
+        if !self.alt {
+            return;
+        }
+
+        if *mode == ansi::Mode::SwapScreenAndClear {
+            let template = self.empty_cell.clone();
+            self.grid.reset_lines(.., &template);
+        }
+
+        self.swap_alt();
+
+        if *mode == ansi::Mode::SwapScreenAndSetRestoreCursor {
+            self.restore_cursor_position();
+        }
+    }
+
+    /// Scroll screen down
+    ///
+    /// Text moves down; clear at bottom
+    #[inline]
+    fn scroll_down_relative(&mut self, origin: Line, lines: Line) {
+        trace!("scroll_down: {}", lines);
+
+        // Copy of cell template; can't have it borrowed when calling clear/scroll
+        let template = self.empty_cell.clone();
+
+        // Clear `lines` lines at bottom of area
+        {
+            let end = self.scroll_region.end;
+            let start = end - lines;
+            self.grid.reset_lines(start..end, &template);
+        }
+
+        // Scroll between origin and bottom
+        {
+            let end = self.scroll_region.end;
+            let start = origin + lines;
+            self.grid.scroll_down(start..end, lines);
+        }
+
+        let end = self.scroll_region.end;
+        self.mark_dirty_range(origin..end);
+        self.invalidate_selection_in(origin..end);
+    }
+
+    /// Scroll screen up
+    ///
+    /// Text moves up; clear at top
+    #[inline]
+    fn scroll_up_relative(&mut self, origin: Line, lines: Line) {
+        trace!("scroll_up: {}", lines);
+
+        // Copy of cell template; can't have it borrowed when calling clear/scroll
+        let template = self.empty_cell.clone();
+
+        // Scroll from origin to bottom less number of lines
+        {
+            let start = origin;
+            let end = self.scroll_region.end - lines;
+            self.grid.scroll_up(start..end, lines);
+        }
+
+        // Clear the `lines` lines exposed at the bottom. This happens after
+        // scrolling so lines moved into history keep their contents.
+        {
+            let end = self.scroll_region.end;
+            let start = end - lines;
+            self.grid.reset_lines(start..end, &template);
+        }
+
+        let end = self.scroll_region.end;
+        self.mark_dirty_range(origin..end);
+        self.invalidate_selection_in(origin..end);
+    }
+}
+
+impl ansi::TermInfo for Term {
+    #[inline]
+    fn lines(&self) -> Line {
+        self.grid.num_lines()
+    }
+
+    #[inline]
+    fn cols(&self) -> Column {
+        self.grid.num_cols()
+    }
+}
+
+impl ansi::Handler for Term {
+    /// A character to be displayed
+    #[inline]
+    fn input(&mut self, c: char) {
+        let c = self.charsets[self.active_charset].map(c);
+
+        // Double width characters take up their cell and a spacer after it
+        let num_cols = self.grid.num_cols();
+        let width = if c.width() == Some(2) && num_cols >= Column(2) { 2 } else { 1 };
+
+        // A double width character which doesn't fit on the line wraps whole
+        if self.cursor.col + width > num_cols {
+            trace!("wrapping");
+            {
+                let line = self.cursor.line;
+                let col = num_cols - Column(1);
+                self.grid[line][col].flags.insert(cell::WRAPLINE);
+            }
+            if (self.cursor.line + 1) >= self.scroll_region.end {
+                self.linefeed();
+            } else {
+                self.cursor.line += 1;
+            }
+            self.cursor.col = Column(0);
+        }
+
+        unsafe {
+            if ::std::intrinsics::unlikely(self.cursor.line == self.grid.num_lines()) {
+                panic!("cursor fell off grid");
+            }
+        }
+
+        let line = self.cursor.line;
+        self.mark_dirty(line);
+        self.invalidate_selection_if_dirtied(line);
+
+        let col = self.cursor.col;
+        debug_assert!(self.grid.contains_point(line, col + (width - 1)));
+
+        let row = &mut self.grid[line];
+        row[col] = self.template_cell;
+        row[col].c = c;
+
+        if width == 2 {
+            row[col].flags.insert(cell::WIDE_CHAR);
+
+            let spacer = &mut row[col + 1];
+            *spacer = self.template_cell;
+            spacer.c = ' ';
+            spacer.flags.insert(cell::WIDE_CHAR_SPACER);
+        }
+
+        self.cursor.col += width;
+    }
+
+    #[inline]
+    fn goto(&mut self, line: Line, col: Column) {
+        trace!("goto: line={}, col={}", line, col);
+        self.cursor.line = self.absolute_line(line);
+        self.cursor.col = ::std::cmp::min(col, self.grid.num_cols() - 1);
+    }
+
+    #[inline]
+    fn goto_line(&mut self, line: Line) {
+        trace!("goto_line: {}", line);
+        self.cursor.line = self.absolute_line(line);
+    }
+
+    #[inline]
+    fn goto_col(&mut self, col: Column) {
+        trace!("goto_col: {}", col);
+        self.cursor.col = ::std::cmp::min(col, self.grid.num_cols() - 1);
+    }
+
+    #[inline]
+    fn insert_blank(&mut self, count: Column) {
+        // Ensure inserting within terminal bounds. The cursor is one past the
+        // last column after writing to it.
+        let num_cols = self.grid.num_cols();
+        let source = ::std::cmp::min(self.cursor.col, num_cols);
+        let count = ::std::cmp::min(count, num_cols - source);
+
+        let destination = source + count;
+        debug_assert!(destination <= num_cols);
+        let num_cells = (num_cols - destination).0;
+
+        let line = self.cursor.line; // borrowck
+        self.mark_dirty(line);
+        self.invalidate_selection_if_dirtied(line);
+        let line = &mut self.grid[line];
+
+        unsafe {
+            let src = line[source..].as_ptr();
+            let dst = line[destination..].as_mut_ptr();
+
+            ptr::copy(src, dst, num_cells);
+        }
+
+        // Cells were just moved out towards the end of the line; fill in
+        // between source and dest with blanks.
+        let template = self.empty_cell.clone();
+        for c in &mut line[source..destination] {
+            c.reset(&template);
+        }
+
+        // The character left of the cursor may have lost its spacer, the one
+        // pushed to the end of the line too
+        if source > Column(0) {
+            repair_wide_char(line, source - 1, &template);
+        }
+        repair_wide_char(line, destination, &template);
+        repair_wide_char(line, num_cols - 1, &template);
+    }
+
+    #[inline]
+    fn move_up(&mut self, lines: Line) {
+        trace!("move_up: {}", lines);
+        self.cursor.line = Line(self.cursor.line.saturating_sub(*lines));
+    }
+
+    #[inline]
+    fn move_down(&mut self, lines: Line) {
+        trace!("move_down: {}", lines);
+        // Stops at the bottom margin when starting within the scroll region
+        let bottom = if self.cursor.line < self.scroll_region.end {
+            self.scroll_region.end - 1
+        } else {
+            self.grid.num_lines() - 1
+        };
+
+        self.cursor.line = ::std::cmp::min(Line(self.cursor.line.saturating_add(*lines)), bottom);
+    }
+
+    #[inline]
+    fn move_forward(&mut self, cols: Column) {
+        trace!("move_forward: {}", cols);
+        let col = Column(self.cursor.col.saturating_add(*cols));
+        self.cursor.col = ::std::cmp::min(col, self.grid.num_cols() - 1);
+    }
+
+    #[inline]
+    fn move_backward(&mut self, cols: Column) {
+        trace!("move_backward: {}", cols);
+        let col = ::std::cmp::min(self.cursor.col, self.grid.num_cols() - 1);
+        self.cursor.col = Column(col.saturating_sub(*cols));
+    }
+
+    #[inline]
+    fn identify_terminal(&mut self) {
+        warn!("[unimplemented] identify_terminal");
+    }
+
+    #[inline]
+    fn move_down_and_cr(&mut self, lines: Line) {
+        warn!("[unimplemented] move_down_and_cr: {}", lines);
+    }
+
+    #[inline]
+    fn move_up_and_cr(&mut self, lines: Line) {
+        warn!("[unimplemented] move_up_and_cr: {}", lines);
+    }
+
+    #[inline]
+    fn put_tab(&mut self, mut count: i64) {
+        trace!("put_tab: {}", count);
+
+        // The right margin is the last stop, so this ends there at the latest
+        let num_cols = self.grid.num_cols();
+        let mut col = ::std::cmp::min(self.cursor.col, num_cols);
+        while col < num_cols && count > 0 {
+            count -= 1;
+            col = self.next_tab_stop(col);
+        }
+
+        self.cursor.col = col;
+    }
+
+    /// Backspace `count` characters
+    #[inline]
+    fn backspace(&mut self) {
+        trace!("backspace");
+        if self.cursor.col > Column(0) {
+            self.cursor.col -= 1;
+        } else if self.mode.contains(mode::REVERSE_WRAP) && self.cursor.line > Line(0) {
+            self.cursor.line -= 1;
+            self.cursor.col = self.grid.num_cols() - Column(1);
+        }
+    }
+
+    /// Carriage return
+    #[inline]
+    fn carriage_return(&mut self) {
+        trace!("carriage_return");
+        self.cursor.col = Column(0);
+    }
+
+    /// Linefeed
+    #[inline]
+    fn linefeed(&mut self) {
+        trace!("linefeed");
+        if self.cursor.line + 1 == self.scroll_region.end {
+            self.scroll_up(Line(1));
+        } else if self.cursor.line + 1 < self.grid.num_lines() {
+            self.cursor.line += 1;
+        }
+    }
+
+    /// Set current position as a tabstop
+    #[inline]
+    fn bell(&mut self) {
+        trace!("bell");
+        self.visual_bell_until = Instant::now() + self.visual_bell_duration;
+        self.dirty = true;
+
+        if let Some(ref mut callback) = self.bell_callback {
+            callback();
+        }
+    }
+
+    #[inline]
+    fn substitute(&mut self) {
+        warn!("[unimplemented] substitute");
+    }
+
+    #[inline]
+    fn newline(&mut self) {
+        warn!("[unimplemented] newline");
+    }
+
+    #[inline]
+    fn set_horizontal_tabstop(&mut self) {
+        warn!("[unimplemented] set_horizontal_tabstop");
+    }
+
+    #[inline]
+    fn scroll_up(&mut self, lines: Line) {
+        // Scrolling by more than the region clears it
+        let origin = self.scroll_region.start;
+        let lines = ::std::cmp::min(lines, self.scroll_region.end - origin);
+        self.scroll_up_relative(origin, lines);
+    }
+
+    #[inline]
+    fn scroll_down(&mut self, lines: Line) {
+        // Scrolling by more than the region clears it
+        let origin = self.scroll_region.start;
+        let lines = ::std::cmp::min(lines, self.scroll_region.end - origin);
+        self.scroll_down_relative(origin, lines);
+    }
+
+    /// Insert `lines` blank lines at the cursor
+    ///
+    /// Only lines between the cursor and the bottom of the scroll region are
+    /// moved; nothing happens when the cursor is outside the region.
+    #[inline]
+    fn insert_blank_lines(&mut self, lines: Line) {
+        trace!("insert_blank_lines: {}", lines);
+        if self.scroll_region.contains(self.cursor.line) {
+            let origin = self.cursor.line;
+            let lines = ::std::cmp::min(lines, self.scroll_region.end - origin);
+            self.scroll_down_relative(origin, lines);
+        } else {
+            trace!(".. cursor outside scroll region");
+        }
+    }
+
+    /// Delete `lines` lines at the cursor
+    ///
+    /// Only lines between the cursor and the bottom of the scroll region are
+    /// moved; nothing happens when the cursor is outside the region.
+    #[inline]
+    fn delete_lines(&mut self, lines: Line) {
+        trace!("delete_lines: {}", lines);
+        if self.scroll_region.contains(self.cursor.line) {
+            let origin = self.cursor.line;
+            let lines = ::std::cmp::min(lines, self.scroll_region.end - origin);
+            self.scroll_up_relative(origin, lines);
+        } else {
+            trace!(".. cursor outside scroll region");
+        }
+    }
+
+    #[inline]
+    fn erase_chars(&mut self, count: Column) {
+        trace!("erase_chars: {}", count);
+        // Erase no further than the right margin
+        let num_cols = self.grid.num_cols();
+        let start = ::std::cmp::min(self.cursor.col, num_cols);
+        let end = ::std::cmp::min(Column(start.saturating_add(*count)), num_cols);
+
+        let line = self.cursor.line;
+        debug_assert!(start == end || self.grid.contains_point(line, end - 1));
+        self.mark_dirty(line);
+        self.invalidate_selection_if_dirtied(line);
+
+        let row = &mut self.grid[line];
+        let template = self.empty_cell.clone();
+        for c in &mut row[start..end] {
+            c.reset(&template);
+        }
+    }
+
+    #[inline]
+    fn delete_chars(&mut self, count: Column) {
+        // Ensure deleting within terminal bounds. The cursor is one past the
+        // last column after writing to it.
+        let num_cols = self.grid.num_cols();
+        let start = ::std::cmp::min(self.cursor.col, num_cols);
+        let count = ::std::cmp::min(count, num_cols - start);
+
+        let end = start + count;
+        let n = (num_cols - end).0;
+
+        let line = self.cursor.line; // borrowck
+        self.mark_dirty(line);
+        self.invalidate_selection_if_dirtied(line);
+        let line = &mut self.grid[line];
+
+        unsafe {
+            let src = line[end..].as_ptr();
+            let dst = line[start..].as_mut_ptr();
+
+            ptr::copy(src, dst, n);
+        }
+
+        // Clear last `count` cells in line. If deleting 1 char, need to delete 1 cell.
+        let template = self.empty_cell.clone();
+        let end = num_cols - count;
+        for c in &mut line[end..] {
+            c.reset(&template);
+        }
+
+        // Either side of the cursor may now be half a character
+        if start > Column(0) {
+            repair_wide_char(line, start - 1, &template);
+        }
+        repair_wide_char(line, start, &template);
+    }
+
+    #[inline]
+    fn move_backward_tabs(&mut self, mut count: i64) {
+        trace!("move_backward_tabs: {}", count);
+
+        let mut col = self.cursor.col;
+        while col > Column(0) && count > 0 {
+            count -= 1;
+            col = self.prev_tab_stop(col);
+        }
+
+        self.cursor.col = col;
+    }
+
+    #[inline]
+    fn move_forward_tabs(&mut self, count: i64) {
+        self.put_tab(count);
+    }
+
+    /// Save the cursor position; attributes aren't saved
+    #[inline]
+    fn save_cursor_position(&mut self) {
+        trace!("save_cursor_position");
+        self.saved_cursor = self.cursor.clone();
+    }
+
+    #[inline]
+    fn restore_cursor_position(&mut self) {
+        trace!("restore_cursor_position");
+        let last_line = self.grid.num_lines() - Line(1);
+        let last_col = self.grid.num_cols() - Column(1);
+
+        self.cursor.line = ::std::cmp::min(self.saved_cursor.line, last_line);
+        self.cursor.col = ::std::cmp::min(self.saved_cursor.col, last_col);
+    }
+
+    #[inline]
+    fn clear_line(&mut self, mode: ansi::LineClearMode) {
+        trace!("clear_line: {:?}", mode);
+        let template = self.empty_cell.clone();
+        let line = self.cursor.line;
+        self.mark_dirty(line);
+        self.invalidate_selection_if_dirtied(line);
+
+        // The cursor is one past the last column after writing to it
+        let col = ::std::cmp::min(self.cursor.col, self.grid.num_cols() - 1);
+        debug_assert!(self.grid.contains_point(line, col));
+
+        match mode {
+            ansi::LineClearMode::Right => {
+                let row = &mut self.grid[line];
+                for cell in &mut row[col..] {
+                    cell.reset(&template);
+                }
+            },
+            ansi::LineClearMode::Left => {
+                let row = &mut self.grid[line];
+                for cell in &mut row[..(col + 1)] {
+                    cell.reset(&template);
+                }
+            },
+            ansi::LineClearMode::All => {
+                self.grid.reset_line(line, &template);
+            },
+        }
+    }
+
+    #[inline]
+    fn clear_screen(&mut self, mode: ansi::ClearMode) {
+        trace!("clear_screen: {:?}", mode);
+        let template = self.empty_cell.clone();
+        match mode {
+            ansi::ClearMode::Below => {
+                self.grid.reset_lines(self.cursor.line.., &template);
+
+                let lines = self.cursor.line..self.grid.num_lines();
+                self.mark_dirty_range(lines.clone());
+                self.invalidate_selection_in(lines);
+            },
+            ansi::ClearMode::All => {
+                self.grid.reset_lines(.., &template);
+                self.mark_all_dirty();
+                self.selection = None;
+            },
+            ansi::ClearMode::Above => {
+                let line = self.cursor.line;
+                self.grid.reset_lines(..line, &template);
+
+                let col = ::std::cmp::min(self.cursor.col, self.grid.num_cols() - 1);
+                for cell in &mut self.grid[line][..(col + 1)] {
+                    cell.reset(&template);
+                }
+
+                self.mark_dirty_range(Line(0)..(line + 1));
+                self.invalidate_selection_in(Line(0)..(line + 1));
+            },
+            ansi::ClearMode::Saved => {
+                self.grid.clear_history();
+                self.mark_all_dirty();
+            },
+        }
+    }
+
+    #[inline]
+    fn selective_clear_line(&mut self, mode: ansi::LineClearMode) {
+        trace!("selective_clear_line: {:?}", mode);
+        let line = self.cursor.line;
+        let col = ::std::cmp::min(self.cursor.col, self.grid.num_cols() - 1);
+        let cols = match mode {
+            ansi::LineClearMode::Right => col..self.grid.num_cols(),
+            ansi::LineClearMode::Left => Column(0)..(col + 1),
+            ansi::LineClearMode::All => Column(0)..self.grid.num_cols(),
+        };
+
+        self.selective_erase(line, cols);
+    }
+
+    #[inline]
+    fn selective_clear_screen(&mut self, mode: ansi::ClearMode) {
+        trace!("selective_clear_screen: {:?}", mode);
+        let num_cols = self.grid.num_cols();
+        let (line, col) = (self.cursor.line, ::std::cmp::min(self.cursor.col, num_cols - 1));
+        match mode {
+            ansi::ClearMode::Below => {
+                self.selective_erase(line, col..num_cols);
+                for line in (line + 1)..self.grid.num_lines() {
+                    self.selective_erase(line, Column(0)..num_cols);
+                }
+            },
+            ansi::ClearMode::Above => {
+                for line in Line(0)..line {
+                    self.selective_erase(line, Column(0)..num_cols);
+                }
+                self.selective_erase(line, Column(0)..(col + 1));
+            },
+            ansi::ClearMode::All => {
+                for line in Line(0)..self.grid.num_lines() {
+                    self.selective_erase(line, Column(0)..num_cols);
+                }
+            },
+            // There's no selective erase of the scrollback
+            ansi::ClearMode::Saved => (),
+        }
+    }
+
+    #[inline]
+    fn set_protected_area(&mut self, protected: bool) {
+        trace!("set_protected_area: {}", protected);
+        if protected {
+            self.template_cell.flags.insert(cell::PROTECTED);
+        } else {
+            self.template_cell.flags.remove(cell::PROTECTED);
+        }
+    }
+
+    #[inline]
+    fn clear_tabs(&mut self, mode: ansi::TabulationClearMode) {
+        warn!("[unimplemented] clear_tabs: {:?}", mode);
+    }
+
+    #[inline]
+    fn reset_state(&mut self) {
+        // Whatever else is left alone, don't leave the display frozen
+        self.end_sync_update();
+        warn!("[unimplemented] reset_state");
+    }
+
+    #[inline]
+    fn reverse_index(&mut self) {
+        trace!("reverse_index");
+        // Only scroll when the cursor is at the top of the scroll region;
+        // elsewhere this is a plain cursor up which stops at the top of the
+        // screen.
+        if self.cursor.line == self.scroll_region.start {
+            self.scroll_down(Line(1));
+        } else if self.cursor.line > Line(0) {
+            self.cursor.line -= 1;
+        }
+    }
+
+    /// set a terminal attribute
+    #[inline]
+    fn terminal_attribute(&mut self, attr: Attr) {
+        trace!("Set Attribute: {:?}", attr);
+
+        // Colored output tends to repeat the attributes already in effect
+        if self.attribute_is_set(&attr) {
+            return;
+        }
+
+        match attr {
+            Attr::DefaultForeground => {
+                self.template_fg_color = None;
+                self.template_cell.fg = self.fg;
+            },
+            Attr::DefaultBackground => {
+                self.template_cell.bg = self.bg;
+            },
+            Attr::Foreground(named_color) => {
+                self.template_fg_color = Some(named_color);
+                self.update_template_fg();
+            },
+            Attr::Background(named_color) => {
+                self.template_cell.bg = self.colors[named_color as usize];
+            },
+            Attr::ForegroundSpec(rgb) => {
+                self.template_fg_color = None;
+                self.template_cell.fg = rgb;
+            },
+            Attr::BackgroundSpec(rgb) => {
+                self.template_cell.bg = rgb;
+            },
+            Attr::Reset => {
+                self.template_fg_color = None;
+                self.template_cell.fg = self.fg;
+                self.template_cell.bg = self.bg;
+                // Protection is set with DECSCA and isn't an SGR attribute
+                self.template_cell.flags = self.template_cell.flags & cell::PROTECTED;
+                self.template_cell.underline_color = None;
+            },
+            Attr::Reverse => self.template_cell.flags.insert(cell::INVERSE),
+            Attr::CancelReverse => self.template_cell.flags.remove(cell::INVERSE),
+            Attr::Bold => {
+                self.template_cell.flags.insert(cell::BOLD);
+                self.update_template_fg();
+            },
+            Attr::Dim => self.template_cell.flags.insert(cell::DIM),
+            Attr::CancelBoldDim => {
+                self.template_cell.flags.remove(cell::BOLD | cell::DIM);
+                self.update_template_fg();
+            },
+            Attr::BlinkSlow | Attr::BlinkFast => self.template_cell.flags.insert(cell::BLINK),
+            Attr::CancelBlink => self.template_cell.flags.remove(cell::BLINK),
+            Attr::Italic => self.template_cell.flags.insert(cell::ITALIC),
+            Attr::CancelItalic => self.template_cell.flags.remove(cell::ITALIC),
+            Attr::Underscore => self.set_underline(cell::UNDERLINE),
+            Attr::DoubleUnderline => self.set_underline(cell::DOUBLE_UNDERLINE),
+            Attr::Undercurl => self.set_underline(cell::UNDERCURL),
+            Attr::CancelUnderline => self.template_cell.flags.remove(cell::ALL_UNDERLINES),
+            Attr::Overline => self.template_cell.flags.insert(cell::OVERLINE),
+            Attr::CancelOverline => self.template_cell.flags.remove(cell::OVERLINE),
+            Attr::UnderlineColor(named_color) => {
+                self.template_cell.underline_color = Some(self.colors[named_color as usize]);
+            },
+            Attr::UnderlineColorSpec(rgb) => {
+                self.template_cell.underline_color = Some(rgb);
+            },
+            Attr::DefaultUnderlineColor => {
+                self.template_cell.underline_color = None;
+            },
+            _ => {
+                trace!("Term got unhandled attr: {:?}", attr);
+            }
+        }
+    }
+
+    #[inline]
+    fn set_dynamic_color(&mut self, color: DynamicColor, rgb: Rgb) {
+        trace!("set_dynamic_color: {:?} = {:?}", color, rgb);
+        match color {
+            DynamicColor::Foreground => {
+                if self.template_cell.fg == self.fg {
+                    self.template_cell.fg = rgb;
+                }
+                self.empty_cell.fg = rgb;
+                self.fg = rgb;
+            },
+            DynamicColor::Background => {
+                if self.template_cell.bg == self.bg {
+                    self.template_cell.bg = rgb;
+                }
+                self.empty_cell.bg = rgb;
+                self.bg = rgb;
+
+                // Blank cells are drawn with the default background
+                self.mark_all_dirty();
+            },
+        }
+    }
+
+    #[inline]
+    fn report_dynamic_color(&mut self, color: DynamicColor) {
+        trace!("report_dynamic_color: {:?}", color);
+        let rgb = match color {
+            DynamicColor::Foreground => self.fg,
+            DynamicColor::Background => self.bg,
+        };
+
+        // Components are reported with 16 bits each, like xterm
+        let _ = write!(self.response, "\x1b]{};rgb:{:04x}/{:04x}/{:04x}\x1b\\",
+                       color as u8,
+                       rgb.r as u16 * 0x101,
+                       rgb.g as u16 * 0x101,
+                       rgb.b as u16 * 0x101);
+    }
+
+    #[inline]
+    fn report_mode(&mut self, private: bool, mode: u16) {
+        trace!("report_mode: private={}, mode={}", private, mode);
+        let state = match self.mode_state(private, mode) {
+            Some(true) => 1,
+            Some(false) => 2,
+            None => 0,
+        };
+
+        let prefix = if private { "?" } else { "" };
+        let _ = write!(self.response, "\x1b[{}{};{}$y", prefix, mode, state);
+    }
+
+    #[inline]
+    fn secondary_device_attributes(&mut self) {
+        trace!("secondary_device_attributes");
+        let _ = write!(self.response, "\x1b[>0;{};0c", version_number(VERSION));
+    }
+
+    #[inline]
+    fn sixel(&mut self, data: &[u8]) {
+        trace!("sixel: {} bytes", data.len());
+        let point = Point::new(self.cursor.line, self.cursor.col);
+        match self.pixel_sink {
+            Some(ref mut sink) => sink(point, sixel::decode(data)),
+            None => debug!("[unhandled] sixel image without a pixel sink"),
+        }
+    }
+
+    #[inline]
+    fn report_terminal_version(&mut self) {
+        trace!("report_terminal_version");
+        let _ = write!(self.response, "\x1bP>|alacritty {}\x1b\\", VERSION);
+    }
+
+    #[inline]
+    fn report_setting(&mut self, setting: &str) {
+        trace!("report_setting: {:?}", setting);
+        let mut value = Vec::new();
+        match setting {
+            "r" => {
+                let _ = write!(value, "{};{}r",
+                               *self.scroll_region.start + 1,
+                               *self.scroll_region.end);
+            },
+            "m" => {
+                let template = self.template_cell;
+                let _ = self.write_sgr_params(&mut value, &template);
+                value.push(b'm');
+            },
+            " q" => {
+                let style = self.cursor_style.unwrap_or(self.default_cursor_style);
+                let _ = write!(value, "{} q", style.to_primitive());
+            },
+            "\"q" => {
+                let protected = self.template_cell.flags.contains(cell::PROTECTED);
+                let _ = write!(value, "{}\"q", if protected { 1 } else { 0 });
+            },
+            _ => {
+                debug!("[unhandled] report_setting: {:?}", setting);
+                // Invalid request
+                self.response.extend_from_slice(b"\x1bP0$r\x1b\\");
+                return;
+            }
+        }
+
+        self.response.extend_from_slice(b"\x1bP1$r");
+        self.response.extend_from_slice(&value);
+        self.response.extend_from_slice(b"\x1b\\");
+    }
+
+    #[inline]
+    fn window_op(&mut self, params: &[u16]) {
+        trace!("window_op: {:?}", params);
+        match params.get(0).cloned().unwrap_or(0) {
+            // Size of the text area in pixels
+            14 => {
+                let _ = write!(self.response, "\x1b[4;{};{}t",
+                               self.size_info.height as u32,
+                               self.size_info.width as u32);
+            },
+            // Size of the text area in characters
+            18 => {
+                let _ = write!(self.response, "\x1b[8;{};{}t",
+                               *self.grid.num_lines(),
+                               *self.grid.num_cols());
+            },
+            _ => debug!("[unhandled] window_op: {:?}", params),
+        }
+    }
+
+    #[inline]
+    fn set_mode(&mut self, mode: ansi::Mode) {
+        trace!("set_mode: {:?}", mode);
+        match mode {
+            ansi::Mode::SwapScreen |
+            ansi::Mode::SwapScreenAndClear |
+            ansi::Mode::SwapScreenAndSetRestoreCursor => self.enter_alt(&mode),
+            ansi::Mode::ShowCursor => {
+                self.mode.insert(mode::SHOW_CURSOR);
+                let line = self.cursor.line;
+                self.mark_dirty(line);
+            },
+            ansi::Mode::CursorKeys => self.mode.insert(mode::APP_CURSOR),
+            ansi::Mode::ReverseScreen => {
+                self.mode.insert(mode::REVERSE_SCREEN);
+                self.mark_all_dirty();
+            },
+            ansi::Mode::Origin => {
+                self.mode.insert(mode::ORIGIN);
+                self.goto(Line(0), Column(0));
+            },
+            ansi::Mode::ReverseWraparound => self.mode.insert(mode::REVERSE_WRAP),
+            ansi::Mode::ReportFocusInOut => self.mode.insert(mode::FOCUS_IN_OUT),
+            ansi::Mode::SyncUpdate => {
+                self.mode.insert(mode::SYNC_UPDATE);
+                let timeout = Duration::from_millis(SYNC_UPDATE_TIMEOUT_MS);
+                self.sync_update_until = Instant::now() + timeout;
+            },
+            _ => {
+                trace!(".. ignoring set_mode");
+            }
+        }
+    }
+
+    #[inline]
+    fn unset_mode(&mut self,mode: ansi::Mode) {
+        trace!("unset_mode: {:?}", mode);
+        match mode {
+            ansi::Mode::SwapScreen |
+            ansi::Mode::SwapScreenAndClear |
+            ansi::Mode::SwapScreenAndSetRestoreCursor => self.exit_alt(&mode),
+            ansi::Mode::ShowCursor => {
+                self.mode.remove(mode::SHOW_CURSOR);
+                let line = self.cursor.line;
+                self.mark_dirty(line);
+            },
+            ansi::Mode::CursorKeys => self.mode.remove(mode::APP_CURSOR),
+            ansi::Mode::ReverseScreen => {
+                self.mode.remove(mode::REVERSE_SCREEN);
+                self.mark_all_dirty();
+            },
+            ansi::Mode::Origin => {
+                self.mode.remove(mode::ORIGIN);
+                self.goto(Line(0), Column(0));
+            },
+            ansi::Mode::ReverseWraparound => self.mode.remove(mode::REVERSE_WRAP),
+            ansi::Mode::ReportFocusInOut => self.mode.remove(mode::FOCUS_IN_OUT),
+            // Draw everything which changed during the update at once
+            ansi::Mode::SyncUpdate => self.end_sync_update(),
+            _ => {
+                trace!(".. ignoring unset_mode");
+            }
+        }
+    }
+
+    #[inline]
+    fn set_scrolling_region(&mut self, region: Range<Line>) {
+        trace!("set scroll region: {:?}", region);
+        self.set_scroll_region(region);
+    }
+
+    #[inline]
+    fn configure_charset(&mut self, index: CharsetIndex, charset: StandardCharset) {
+        trace!("configure_charset: {:?} = {:?}", index, charset);
+        self.charsets[index] = charset;
+    }
+
+    fn soft_reset(&mut self) {
+        trace!("soft_reset");
+        self.mode = TermMode::default();
+        self.scroll_region = Line(0)..self.grid.num_lines();
+        self.terminal_attribute(Attr::Reset);
+        self.template_cell.flags.remove(cell::PROTECTED);
+        self.saved_cursor = Cursor::default();
+        self.charsets = Charsets::default();
+        self.active_charset = CharsetIndex::default();
+        self.cursor_style = None;
+
+        // Reverse screen mode may have been unset
+        self.mark_all_dirty();
+    }
+
+    #[inline]
+    fn set_cursor_style(&mut self, style: Option<CursorStyle>) {
+        trace!("set_cursor_style: {:?}", style);
+        self.cursor_style = style;
+        let line = self.cursor.line;
+        self.mark_dirty(line);
+    }
+
+    #[inline]
+    fn set_active_charset(&mut self, index: CharsetIndex) {
+        trace!("set_active_charset: {:?}", index);
+        self.active_charset = index;
+    }
+
+    #[inline]
+    fn set_keypad_application_mode(&mut self) {
+        trace!("set mode::APP_KEYPAD");
+        self.mode.insert(mode::APP_KEYPAD);
+    }
+
+    #[inline]
+    fn unset_keypad_application_mode(&mut self) {
+        trace!("unset mode::APP_KEYPAD");
+        self.mode.remove(mode::APP_KEYPAD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ansi::Handler;
+    use index::{Line, Column, Cursor, Direction, Point};
+    use std::time::{Duration, Instant};
+    use test::Bencher;
+
+    use glutin::{mods, VirtualKeyCode};
+
+    use super::{Term, TermMode, SizeInfo, FontKeys, Cell, CursorState, Paste, cell, mode};
+    use super::dim_color;
+    use config::DimStrategy;
+    use font::FontKey;
+    use ansi::{self, CursorShape, CursorStyle, DynamicColor};
+    use ::Rgb;
+
+    /// Create a terminal of the given dimensions which isn't attached to a shell
+    fn term(lines: usize, cols: usize) -> Term {
+        let size = SizeInfo {
+            width: cols as f32,
+            height: lines as f32,
+            cell_width: 1.0,
+            cell_height: 1.0,
+            max_cols: Column(2000),
+            max_lines: Line(2000),
+        };
+
+        let font_keys = FontKeys {
+            regular: FontKey::next(),
+            bold: FontKey::next(),
+            italic: FontKey::next(),
+            bold_italic: FontKey::next(),
+        };
+
+        Term::builder(size).font_keys(font_keys).build()
+    }
+
+    #[test]
+    fn size_is_clamped() {
+        let size = SizeInfo {
+            width: 1.0e9,
+            height: 1.0e9,
+            cell_width: 1.0,
+            cell_height: 1.0,
+            max_cols: Column(300),
+            max_lines: Line(200),
+        };
+
+        assert_eq!(size.cols(), Column(300));
+        assert_eq!(size.lines(), Line(200));
+
+        let mut term = Term::builder(SizeInfo { width: 10.0, height: 5.0, ..size }).build();
+        term.resize(1.0e9, 1.0e9);
+        assert_eq!(term.grid.num_cols(), Column(300));
+        assert_eq!(term.grid.num_lines(), Line(200));
+    }
+
+    #[test]
+    fn build_without_config_or_shell() {
+        let size = SizeInfo {
+            width: 100.0,
+            height: 60.0,
+            cell_width: 10.0,
+            cell_height: 20.0,
+            max_cols: Column(2000),
+            max_lines: Line(2000),
+        };
+
+        let fg = Rgb { r: 0x11, g: 0x22, b: 0x33 };
+        let bg = Rgb { r: 0x44, g: 0x55, b: 0x66 };
+        let mut colors = [Rgb { r: 0, g: 0, b: 0 }; 16];
+        colors[1] = Rgb { r: 0xff, g: 0, b: 0 };
+
+        let mut term = Term::builder(size).colors(fg, bg, colors).build();
+        assert_eq!(term.tty().child_pid(), None);
+        assert_eq!(term.grid.num_lines(), Line(3));
+        assert_eq!(term.grid.num_cols(), Column(10));
+
+        term.input('a');
+        term.terminal_attribute(ansi::Attr::Foreground(ansi::Color::Red));
+        term.input('b');
+        term.goto(Line(2), Column(0));
+        term.input('c');
+
+        assert_eq!(term.grid_to_string(true), "ab\n\nc\n");
+        assert_eq!(term.grid[Line(0)][Column(0)].fg, fg);
+        assert_eq!(term.grid[Line(0)][Column(0)].bg, bg);
+        assert_eq!(term.grid[Line(0)][Column(1)].fg, colors[1]);
+    }
+
+    /// Captures everything printed to stdout by the current thread
+    struct Capture(::std::sync::Arc<::std::sync::Mutex<Vec<u8>>>);
+
+    impl ::std::io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn construction_prints_nothing() {
+        let output = ::std::sync::Arc::new(::std::sync::Mutex::new(Vec::new()));
+        let previous = ::std::io::set_print(Box::new(Capture(output.clone())));
+
+        let mut term = term(3, 10);
+        term.resize(20.0, 5.0);
+        term.feed(b"\x1b[?1049h\x1b[31mhello\x1b[m\x1b[?1049l");
+
+        ::std::io::set_print(previous.unwrap_or_else(|| Box::new(::std::io::stdout())));
+        assert_eq!(&output.lock().unwrap()[..], b"");
+    }
+
+    /// Write the line number into the first column of every line
+    fn number_lines(term: &mut Term) {
+        for i in 0..*term.grid.num_lines() {
+            term.goto(Line(i), Column(0));
+            term.input(::std::char::from_digit(i as u32, 36).unwrap());
+        }
+    }
+
+    #[test]
+    fn search_forward_and_backward() {
+        let mut term = term(3, 12);
+        term.feed(b"foo bar\r\nbar foo\r\nFOO");
+
+        let search = |start, direction| {
+            term.search("foo", Point::new(start, Column(0)), direction, true)
+        };
+
+        assert_eq!(search(0, Direction::Right),
+                   Some(Point::new(0, Column(0))..Point::new(0, Column(3))));
+        assert_eq!(search(1, Direction::Right),
+                   Some(Point::new(1, Column(4))..Point::new(1, Column(7))));
+        assert_eq!(search(2, Direction::Right), None);
+        assert_eq!(search(1, Direction::Left),
+                   Some(Point::new(0, Column(0))..Point::new(0, Column(3))));
+        assert_eq!(search(2, Direction::Left),
+                   Some(Point::new(1, Column(4))..Point::new(1, Column(7))));
+
+        assert_eq!(term.search("foo", Point::new(2, Column(0)), Direction::Right, false),
+                   Some(Point::new(2, Column(0))..Point::new(2, Column(3))));
+    }
+
+    #[test]
+    fn search_spans_wrapped_lines() {
+        let mut term = term(5, 5);
+        term.feed(b"hello world\r\nhel\r\nlo");
+
+        let start = Point::new(0, Column(0));
+        assert_eq!(term.search("o w", start, Direction::Right, true),
+                   Some(Point::new(0, Column(4))..Point::new(1, Column(2))));
+
+        // Lines ending in a newline don't join
+        assert_eq!(term.search("hello", Point::new(1, Column(0)), Direction::Right, true), None);
+    }
+
+    #[test]
+    fn search_includes_scrollback() {
+        let mut term = term(2, 8);
+        term.grid.set_history_limit(10);
+        term.feed(b"needle\r\na\r\nb\r\nc");
+
+        assert_eq!(term.grid.history_size(), 2);
+        let end = Point::new(term.grid.total_lines() - 1, Column(0));
+        assert_eq!(term.search("needle", end, Direction::Left, true),
+                   Some(Point::new(0, Column(0))..Point::new(0, Column(6))));
+    }
+
+    fn range(start: (usize, usize), end: (usize, usize)) -> ::std::ops::Range<Point> {
+        Point::new(Line(start.0), Column(start.1))..Point::new(Line(end.0), Column(end.1))
+    }
+
+    #[test]
+    fn semantic_range_in_middle_of_line() {
+        let mut term = term(2, 20);
+        term.feed(b"ls foo.rs (bar) x");
+
+        let at = |col| term.semantic_range(Point::new(Line(0), Column(col)));
+
+        assert_eq!(at(4), range((0, 3), (0, 9)));
+        assert_eq!(at(3), range((0, 3), (0, 9)));
+        assert_eq!(at(8), range((0, 3), (0, 9)));
+        assert_eq!(at(11), range((0, 11), (0, 14)));
+
+        // Separators select just themselves
+        assert_eq!(at(2), range((0, 2), (0, 3)));
+        assert_eq!(at(10), range((0, 10), (0, 11)));
+    }
+
+    #[test]
+    fn semantic_range_at_line_boundaries() {
+        let mut term = term(3, 6);
+        term.feed(b"ab cdefgh\r\nwxyz");
+
+        // Words run to the edges of the grid and continue across soft wraps
+        assert_eq!(term.semantic_range(Point::new(Line(0), Column(0))), range((0, 0), (0, 2)));
+        assert_eq!(term.semantic_range(Point::new(Line(0), Column(4))), range((0, 3), (1, 3)));
+        assert_eq!(term.semantic_range(Point::new(Line(1), Column(1))), range((0, 3), (1, 3)));
+
+        // but not across hard line breaks
+        assert_eq!(term.semantic_range(Point::new(Line(2), Column(3))), range((2, 0), (2, 4)));
+    }
+
+    #[test]
+    fn line_range_of_single_line() {
+        let mut term = term(3, 8);
+        term.feed(b"one\r\ntwo two\r\nthree");
+
+        assert_eq!(term.line_range(Point::new(Line(1), Column(5))), range((1, 0), (1, 8)));
+        assert_eq!(term.line_range(Point::new(Line(2), Column(7))), range((2, 0), (2, 8)));
+    }
+
+    #[test]
+    fn line_range_of_wrapped_line() {
+        let mut term = term(5, 4);
+        term.feed(b"top\r\nabcdefghij\r\nend");
+
+        let wrapped = range((1, 0), (3, 4));
+        assert_eq!(term.line_range(Point::new(Line(1), Column(0))), wrapped);
+        assert_eq!(term.line_range(Point::new(Line(2), Column(2))), wrapped);
+        assert_eq!(term.line_range(Point::new(Line(3), Column(3))), wrapped);
+
+        assert_eq!(term.line_range(Point::new(Line(0), Column(1))), range((0, 0), (0, 4)));
+        assert_eq!(term.line_range(Point::new(Line(4), Column(1))), range((4, 0), (4, 4)));
+    }
+
+    #[test]
+    fn find_url_in_middle_of_line() {
+        let mut term = term(2, 40);
+        term.feed(b"see (https://example.org/a?b=1). ok");
+
+        let url = (String::from("https://example.org/a?b=1"), range((0, 5), (0, 30)));
+        assert_eq!(term.find_url_at(Point::new(Line(0), Column(5))), Some(url.clone()));
+        assert_eq!(term.find_url_at(Point::new(Line(0), Column(20))), Some(url.clone()));
+        assert_eq!(term.find_url_at(Point::new(Line(0), Column(29))), Some(url));
+
+        // Surrounding punctuation and text isn't part of the URL
+        assert_eq!(term.find_url_at(Point::new(Line(0), Column(4))), None);
+        assert_eq!(term.find_url_at(Point::new(Line(0), Column(30))), None);
+        assert_eq!(term.find_url_at(Point::new(Line(0), Column(1))), None);
+        assert_eq!(term.find_url_at(Point::new(Line(0), Column(33))), None);
+    }
+
+    #[test]
+    fn find_url_across_wrapped_lines() {
+        let mut term = term(3, 10);
+        term.feed(b"go file:///tmp/x.txt now");
+
+        let url = Some((String::from("file:///tmp/x.txt"), range((0, 3), (1, 10))));
+        assert_eq!(term.find_url_at(Point::new(Line(0), Column(4))), url);
+        assert_eq!(term.find_url_at(Point::new(Line(1), Column(9))), url);
+        assert_eq!(term.find_url_at(Point::new(Line(2), Column(1))), None);
+    }
+
+    #[test]
+    fn find_mailto_url() {
+        let mut term = term(1, 30);
+        term.feed(b"mailto:someone@example.org");
+
+        assert_eq!(term.find_url_at(Point::new(Line(0), Column(0))).map(|url| url.0),
+                   Some(String::from("mailto:someone@example.org")));
+    }
+
+    #[test]
+    fn underline_color_is_set_and_reset() {
+        let mut term = term(1, 10);
+        term.feed(b"a\x1b[4;58;2;1;2;3mb\x1b[58;5;1mc\x1b[59md\x1b[58;5;2me\x1b[0mf");
+
+        let colors = term.grid[Line(0)].cells()
+            .take(6)
+            .map(|cell| cell.underline_color)
+            .collect::<Vec<_>>();
+
+        assert_eq!(colors, vec![
+            None,
+            Some(Rgb { r: 1, g: 2, b: 3 }),
+            Some(term.colors[1]),
+            None,
+            Some(term.colors[2]),
+            None,
+        ]);
+    }
+
+    #[test]
+    fn underline_styles_are_exclusive() {
+        let mut term = term(1, 10);
+        term.feed(b"\x1b[4ma\x1b[21mb\x1b[4:3mc\x1b[4md\x1b[24me\x1b[4:3;0mf");
+
+        let styles = term.grid[Line(0)].cells()
+            .take(6)
+            .map(|cell| cell.flags & cell::ALL_UNDERLINES)
+            .collect::<Vec<_>>();
+
+        assert_eq!(styles, vec![
+            cell::UNDERLINE,
+            cell::DOUBLE_UNDERLINE,
+            cell::UNDERCURL,
+            cell::UNDERLINE,
+            cell::Flags::empty(),
+            cell::Flags::empty(),
+        ]);
+    }
+
+    #[test]
+    fn overline_toggles() {
+        let mut term = term(1, 10);
+        term.feed(b"a\x1b[53mb\x1b[55mc\x1b[53md\x1b[me");
+
+        let overlined = term.grid[Line(0)].cells()
+            .take(5)
+            .map(|cell| cell.flags.contains(cell::OVERLINE))
+            .collect::<Vec<_>>();
+
+        assert_eq!(overlined, vec![false, true, false, true, false]);
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn snapshot_round_trip() {
+        let mut term = term(3, 10);
+        term.feed(b"\x1b[1;4;58;5;1mbold\x1b[m plain\r\n\x1b[38;2;1;2;3mcolor\x1b[?1h");
+
+        let yaml = ::serde_yaml::to_string(&term.snapshot()).unwrap();
+        let snapshot = ::serde_yaml::from_str(&yaml).unwrap();
+
+        let mut restored = self::term(3, 10);
+        restored.restore(snapshot);
+
+        assert_eq!(restored.grid_to_string(true), term.grid_to_string(true));
+        assert_eq!(restored.cursor, term.cursor);
+        assert_eq!(restored.mode, term.mode);
+        for (restored, original) in restored.grid.lines().zip(term.grid.lines()) {
+            assert_eq!(restored.to_vec(), original.to_vec());
+        }
+    }
+
+    #[test]
+    fn semantic_escape_chars_are_configurable() {
+        let size = SizeInfo {
+            width: 10.0,
+            height: 1.0,
+            cell_width: 1.0,
+            cell_height: 1.0,
+            max_cols: Column(2000),
+            max_lines: Line(2000),
+        };
+        let mut term = Term::builder(size).semantic_escape_chars("/").build();
+        term.feed(b"a/b.c d");
+
+        assert_eq!(term.semantic_range(Point::new(Line(0), Column(2))), range((0, 2), (0, 5)));
+    }
+
+    fn first_column(term: &Term) -> String {
+        term.grid.lines().map(|row| row[Column(0)].c).collect()
+    }
+
+    #[test]
+    fn insert_blank_lines_in_scroll_region() {
+        let mut term = term(10, 5);
+        number_lines(&mut term);
+
+        term.set_scrolling_region(Line(2)..Line(8));
+        term.goto(Line(4), Column(0));
+        term.insert_blank_lines(Line(2));
+
+        assert_eq!(first_column(&term), "0123  4589");
+    }
+
+    #[test]
+    fn delete_lines_in_scroll_region() {
+        let mut term = term(10, 5);
+        number_lines(&mut term);
+
+        term.set_scrolling_region(Line(2)..Line(8));
+        term.goto(Line(4), Column(0));
+        term.delete_lines(Line(2));
+
+        assert_eq!(first_column(&term), "012367  89");
+    }
+
+    #[test]
+    fn insert_and_delete_lines_clamp_to_scroll_region() {
+        let mut term = term(10, 5);
+        number_lines(&mut term);
+
+        term.set_scrolling_region(Line(2)..Line(8));
+        term.goto(Line(4), Column(0));
+        term.insert_blank_lines(Line(100));
+        assert_eq!(first_column(&term), "0123    89");
+
+        number_lines(&mut term);
+        term.goto(Line(4), Column(0));
+        term.delete_lines(Line(100));
+        assert_eq!(first_column(&term), "0123    89");
+    }
+
+    #[test]
+    fn insert_and_delete_lines_outside_scroll_region() {
+        let mut term = term(10, 5);
+        number_lines(&mut term);
+
+        term.set_scrolling_region(Line(2)..Line(8));
+        term.goto(Line(9), Column(0));
+        term.insert_blank_lines(Line(1));
+        term.delete_lines(Line(1));
+
+        assert_eq!(first_column(&term), "0123456789");
+    }
+
+    #[test]
+    fn visual_bell_decays() {
+        let mut term = term(10, 5);
+        term.bell();
+
+        let end = term.visual_bell_until;
+        let start = end - term.visual_bell_duration;
+        let half = start + term.visual_bell_duration / 2;
+
+        assert_eq!(term.visual_bell_intensity(start), 1.0);
+        assert!((term.visual_bell_intensity(half) - 0.5).abs() < 0.01);
+        assert_eq!(term.visual_bell_intensity(end), 0.0);
+        assert_eq!(term.visual_bell_intensity(end + Duration::from_millis(1)), 0.0);
+    }
+
+    #[test]
+    fn bell_calls_callback() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut term = term(10, 5);
+        let rung = Arc::new(AtomicUsize::new(0));
+        let counter = rung.clone();
+        term.set_bell_callback(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        term.bell();
+        term.bell();
+
+        assert_eq!(rung.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn sixel_goes_to_pixel_sink() {
+        use std::sync::{Arc, Mutex};
+
+        let mut term = term(5, 10);
+        let images = Arc::new(Mutex::new(Vec::new()));
+        let sink = images.clone();
+        term.set_pixel_sink(Box::new(move |point, image| {
+            sink.lock().unwrap().push((point, image));
+        }));
+
+        term.feed(b"\x1b[2;3H\x1bPq#1;2;100;0;0#1~~\x1b\\");
+
+        let images = images.lock().unwrap();
+        assert_eq!(images.len(), 1);
+        let (point, ref image) = images[0];
+        assert_eq!(point, Point::new(Line(1), Column(2)));
+        assert_eq!((image.width, image.height), (2, 6));
+        assert_eq!(image.pixel(1, 5), [0xff, 0x00, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn feed_sequence_split_across_chunks() {
+        let bytes = b"\x1b[3;4Hx\x1b]10;rgb:ff/80/00\x07";
+
+        for split in 0..bytes.len() {
+            let mut term = term(5, 10);
+            term.feed(&bytes[..split]);
+            term.feed(&bytes[split..]);
+
+            assert_eq!(term.grid[Line(2)][Column(3)].c, 'x');
+            assert_eq!(term.cursor, Cursor { line: Line(2), col: Column(4) });
+            assert_eq!(term.fg, Rgb { r: 0xff, g: 0x80, b: 0x00 });
+            assert!(term.dirty);
+        }
+    }
+
+    #[test]
+    fn encode_key_follows_cursor_keys_mode() {
+        let mut term = term(5, 10);
+        assert_eq!(term.encode_key(VirtualKeyCode::Up, mods::NONE), b"\x1b[A");
+
+        term.feed(b"\x1b[?1h");
+        assert_eq!(term.encode_key(VirtualKeyCode::Up, mods::NONE), b"\x1bOA");
+
+        term.feed(b"\x1b[?1l");
+        assert_eq!(term.encode_key(VirtualKeyCode::Up, mods::NONE), b"\x1b[A");
+    }
+
+    #[test]
+    fn backspace_stops_at_left_margin() {
+        let mut term = term(5, 10);
+        term.goto(Line(2), Column(0));
+        term.backspace();
+
+        assert_eq!(term.cursor, Cursor { line: Line(2), col: Column(0) });
+    }
+
+    #[test]
+    fn backspace_reverse_wraps() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[?45h");
+        term.goto(Line(2), Column(0));
+        term.backspace();
+
+        assert_eq!(term.cursor, Cursor { line: Line(1), col: Column(9) });
+
+        // Nowhere to go from the top left
+        term.goto(Line(0), Column(0));
+        term.backspace();
+        assert_eq!(term.cursor, Cursor { line: Line(0), col: Column(0) });
+
+        term.feed(b"\x1b[?45l");
+        term.goto(Line(2), Column(0));
+        term.backspace();
+        assert_eq!(term.cursor, Cursor { line: Line(2), col: Column(0) });
+    }
+
+    #[test]
+    fn line_drawing_charset() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b(0qx\x1b(Bqx");
+        assert_eq!(term.row_to_string(Line(0)), "─│qx");
+    }
+
+    #[test]
+    fn shift_out_to_line_drawing_charset() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b)0lq\x0elqk\x0fq");
+        assert_eq!(term.row_to_string(Line(0)), "lq┌─┐q");
+    }
+
+    #[test]
+    fn bright_color_sgr() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[91;104m");
+
+        assert_eq!(term.template_cell.fg, term.colors[9]);
+        assert_eq!(term.template_cell.bg, term.colors[12]);
+    }
+
+    #[test]
+    fn reverse_index_scrolls_only_at_region_top() {
+        let mut term = term(5, 10);
+        term.feed(b"0\r\n1\r\n2\r\n3\r\n4");
+        term.feed(b"\x1b[2;4r");
+
+        // Above the region it's a plain cursor up, stopping at the top
+        term.feed(b"\x1b[1;1H\x1bM");
+        assert_eq!(term.cursor.line, Line(0));
+        assert_eq!(term.row_to_string(Line(1)), "1");
+
+        // Inside the region the cursor moves up without scrolling
+        term.feed(b"\x1b[3;1H\x1bM");
+        assert_eq!(term.cursor.line, Line(1));
+        assert_eq!(term.row_to_string(Line(1)), "1");
+
+        // At the top of the region the region scrolls down
+        term.feed(b"\x1bM");
+        assert_eq!(term.cursor.line, Line(1));
+        assert_eq!(term.row_to_string(Line(0)), "0");
+        assert_eq!(term.row_to_string(Line(1)), "");
+        assert_eq!(term.row_to_string(Line(2)), "1");
+        assert_eq!(term.row_to_string(Line(3)), "2");
+        assert_eq!(term.row_to_string(Line(4)), "4");
+    }
+
+    #[test]
+    fn bold_brightens_printed_cells() {
+        let size = SizeInfo {
+            width: 10.0,
+            height: 5.0,
+            cell_width: 1.0,
+            cell_height: 1.0,
+            max_cols: Column(2000),
+            max_lines: Line(2000),
+        };
+
+        let mut term = Term::builder(size).build();
+        term.feed(b"\x1b[1;31ma");
+        assert_eq!(term.grid[Line(0)][Column(0)].fg, term.colors[1]);
+
+        let mut term = Term::builder(size).draw_bold_text_with_bright_colors(true).build();
+        term.feed(b"\x1b[1;31ma\x1b[22mb");
+        assert_eq!(term.grid[Line(0)][Column(0)].fg, term.colors[9]);
+        assert_eq!(term.grid[Line(0)][Column(1)].fg, term.colors[1]);
+
+        // Indexed colors above 7 are drawn as they are
+        term.feed(b"\x1b[0;1;38;5;9mc\x1b[38;5;196md");
+        assert_eq!(term.grid[Line(0)][Column(2)].fg, term.colors[9]);
+        assert_eq!(term.grid[Line(0)][Column(3)].fg, Rgb { r: 0xff, g: 0x00, b: 0x00 });
+    }
+
+    #[test]
+    fn bold_brightens_first_eight_colors() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[1;31m");
+        assert_eq!(term.template_cell.fg, term.colors[1]);
+
+        term.bold_brightens = true;
+        term.feed(b"\x1b[0;1;31m");
+        assert_eq!(term.template_cell.fg, term.colors[9]);
+
+        // Order of bold and color doesn't matter
+        term.feed(b"\x1b[0;31;1m");
+        assert_eq!(term.template_cell.fg, term.colors[9]);
+
+        term.feed(b"\x1b[22m");
+        assert_eq!(term.template_cell.fg, term.colors[1]);
+
+        // Bright colors and truecolor are left alone
+        term.feed(b"\x1b[1;92m");
+        assert_eq!(term.template_cell.fg, term.colors[10]);
+
+        let rgb = Rgb { r: 1, g: 2, b: 3 };
+        term.feed(b"\x1b[38;2;1;2;3;1m");
+        assert_eq!(term.template_cell.fg, rgb);
+    }
+
+    #[test]
+    fn region_is_clipped_to_screen() {
+        let mut term = term(3, 4);
+        term.feed(b"abcd\r\nefgh\r\nijkl");
+
+        let text = |region: Vec<Vec<Cell>>| {
+            region.iter()
+                  .map(|row| row.iter().map(|c| c.c).collect::<String>())
+                  .collect::<Vec<_>>()
+        };
+
+        let region = term.region(Line(1)..Line(3), Column(1)..Column(4));
+        assert_eq!(text(region), vec!["fgh", "jkl"]);
+
+        let region = term.region(Line(2)..Line(10), Column(2)..Column(10));
+        assert_eq!(text(region), vec!["kl"]);
+
+        assert!(term.region(Line(3)..Line(5), Column(0)..Column(2)).is_empty());
+        assert!(term.region(Line(0)..Line(2), Column(2)..Column(2)).is_empty());
+    }
+
+    #[test]
+    fn only_written_lines_are_occupied() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[2J\x1b[3;4Ha");
+
+        assert_eq!(term.grid.occupied_lines(), vec![Line(2)]);
+        assert!(term.grid[Line(0)].is_clear(&term.empty_cell));
+        assert!(!term.grid[Line(2)].is_clear(&term.empty_cell));
+    }
+
+    #[test]
+    fn cell_size() {
+        assert!(::std::mem::size_of::<Cell>() <= 16);
+
+        // The highest flag survives being stored in the cell
+        let mut cell = Cell::new('a');
+        cell.fg = Rgb { r: 0xff, g: 0xff, b: 0xff };
+        cell.flags.insert(cell::DIM | cell::INVERSE);
+        assert!(cell.flags.contains(cell::DIM));
+        assert_eq!(cell.render_colors(cell.fg, cell.bg, false), (cell.bg, cell.fg));
+    }
+
+    #[test]
+    fn render_colors() {
+        let default_fg = Rgb { r: 0xff, g: 0xff, b: 0xff };
+        let default_bg = Rgb { r: 0x00, g: 0x00, b: 0x00 };
+        let red = Rgb { r: 0xcd, g: 0x00, b: 0x00 };
+
+        let mut cell = Cell::new('a');
+        cell.fg = red;
+        cell.bg = default_bg;
+        assert_eq!(cell.render_colors(default_fg, default_bg, false), (red, default_bg));
+
+        // Inverse swaps foreground and background
+        cell.flags.insert(cell::INVERSE);
+        assert_eq!(cell.render_colors(default_fg, default_bg, false), (default_bg, red));
+
+        // Reverse screen swaps only the default colors
+        cell.flags.remove(cell::INVERSE);
+        assert_eq!(cell.render_colors(default_fg, default_bg, true), (red, default_fg));
+
+        // Both cancel out for default colors
+        cell.fg = default_fg;
+        cell.flags.insert(cell::INVERSE);
+        assert_eq!(cell.render_colors(default_fg, default_bg, true), (default_fg, default_bg));
+
+        cell.fg = red;
+        assert_eq!(cell.render_colors(default_fg, default_bg, true), (default_fg, red));
+    }
+
+    #[test]
+    fn reverse_screen_mode() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[?5h");
+        assert!(term.mode.contains(mode::REVERSE_SCREEN));
+
+        term.feed(b"\x1b[?5$p");
+        assert_eq!(term.take_response(), b"\x1b[?5;1$y".to_vec());
+
+        term.feed(b"\x1b[?5l");
+        assert!(!term.mode.contains(mode::REVERSE_SCREEN));
+    }
+
+    #[test]
+    fn dim_sgr() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[2m");
+        assert!(term.template_cell.flags.contains(cell::DIM));
+
+        term.feed(b"\x1b[1m\x1b[22m");
+        assert!(!term.template_cell.flags.intersects(cell::BOLD | cell::DIM));
+    }
+
+    fn dim_cell(fg: Rgb) -> Cell {
+        let mut cell = Cell::new('a');
+        cell.fg = fg;
+        cell.bg = Rgb { r: 0, g: 0, b: 0 };
+        cell.flags.insert(cell::DIM);
+        cell
+    }
+
+    #[test]
+    fn effective_fg_alpha_reduce() {
+        let mut term = term(5, 10);
+        term.dim_strategy = DimStrategy::AlphaReduce(0.5);
+
+        let cell = dim_cell(Rgb { r: 0xff, g: 0x80, b: 0x00 });
+        assert_eq!(term.effective_fg(&cell), Rgb { r: 0x80, g: 0x40, b: 0x00 });
+
+        let mut bright = cell;
+        bright.flags.remove(cell::DIM);
+        assert_eq!(term.effective_fg(&bright), bright.fg);
+    }
+
+    #[test]
+    fn effective_fg_palette_half() {
+        let mut term = term(5, 10);
+        term.dim_strategy = DimStrategy::PaletteHalf;
+
+        // Bright red is drawn as normal red
+        let cell = dim_cell(term.colors[9]);
+        assert_eq!(term.effective_fg(&cell), term.colors[1]);
+
+        // Anything else loses half its brightness
+        let cell = dim_cell(Rgb { r: 0xff, g: 0x80, b: 0x00 });
+        assert_eq!(term.effective_fg(&cell), Rgb { r: 0x7f, g: 0x40, b: 0x00 });
+    }
+
+    #[test]
+    fn cursor_visible_on_reverse_screen() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[2J\x1b[?5h");
+
+        let grid = term.render_grid(Instant::now());
+        let cursor = grid.render_colors(&grid[Line(0)][Column(0)]);
+        let blank = grid.render_colors(&grid[Line(0)][Column(1)]);
+        assert_eq!(cursor, (blank.1, blank.0));
+        assert!(cursor != blank);
+    }
+
+    #[test]
+    fn dim_text_is_drawn_dimmed() {
+        let mut term = term(5, 10);
+        term.dim_strategy = DimStrategy::AlphaReduce(0.5);
+        term.feed(b"a\x1b[2mb");
+
+        let (normal, dim) = {
+            let grid = term.render_grid(Instant::now());
+            (grid.render_colors(&grid[Line(0)][Column(0)]),
+             grid.render_colors(&grid[Line(0)][Column(1)]))
+        };
+        assert_eq!(dim.1, normal.1);
+        assert_eq!(dim.0, dim_color(normal.0, normal.1, DimStrategy::AlphaReduce(0.5), &term.colors));
+        assert!(dim.0 != normal.0);
+    }
+
+    #[test]
+    fn soft_reset_keeps_screen() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[?1h\x1b[?25l\x1b[?5h\x1b[2;4r\x1b[1;31m\x1b[2;2H\x1b7");
+        term.feed(b"\x1b[3;3Hab");
+
+        term.feed(b"\x1b[!p");
+        assert_eq!(term.mode, TermMode::default());
+        assert_eq!(term.scroll_region, Line(0)..Line(5));
+        assert_eq!(term.template_cell, term.empty_cell);
+        assert_eq!(term.saved_cursor, Cursor::default());
+
+        assert_eq!(term.row_to_string(Line(2)), "  ab");
+        assert_eq!(term.cursor, Cursor { line: Line(2), col: Column(4) });
+    }
+
+    #[test]
+    fn selective_erase_skips_protected_cells() {
+        let mut term = term(3, 10);
+        term.feed(b"ab\x1b[1\"qcd\x1b[0\"qef");
+        term.feed(b"\x1b[?2K");
+        assert_eq!(term.row_to_string(Line(0)), "  cd");
+        assert!(term.grid[Line(0)][Column(2)].flags.contains(cell::PROTECTED));
+
+        // SGR 0 doesn't lift protection
+        term.feed(b"\r\n\x1b[1\"q\x1b[0mgh\x1b[2\"qij\r\n\x1b[1mkl");
+        term.feed(b"\x1b[2;1H\x1b[?J");
+        assert_eq!(term.row_to_string(Line(1)), "gh");
+        assert_eq!(term.row_to_string(Line(2)), "");
+
+        term.feed(b"\x1b[?1J");
+        assert_eq!(term.row_to_string(Line(0)), "  cd");
+        assert_eq!(term.row_to_string(Line(1)), "gh");
+
+        term.selective_clear_screen(ansi::ClearMode::Saved);
+        assert_eq!(term.row_to_string(Line(0)), "  cd");
+
+        // Plain erases ignore protection
+        term.feed(b"\x1b[2J");
+        assert_eq!(term.row_to_string(Line(0)), "");
+        assert_eq!(term.row_to_string(Line(1)), "");
+    }
+
+    #[test]
+    fn advance_leaves_partial_sequence() {
+        let mut term = term(2, 10);
+        assert_eq!(term.advance(b"ab\x1b[3"), 2);
+        assert_eq!(term.row_to_string(Line(0)), "ab");
+
+        assert_eq!(term.advance(b"\x1b[31mc\x1b("), 6);
+        assert_eq!(term.grid[Line(0)][Column(2)].fg, term.colors[ansi::Color::Red as usize]);
+
+        // A character cut short waits for the rest of its bytes
+        let bytes = "\x1b(Bdé".as_bytes();
+        assert_eq!(term.advance(&bytes[..5]), 4);
+        assert_eq!(term.advance(&bytes[4..]), 2);
+        assert_eq!(term.row_to_string(Line(0)), "abcdé");
+
+        // Strings are left to the parser, however long they run
+        let mut title = b"\x1b]2;".to_vec();
+        title.extend(vec![b'x'; 4096]);
+        assert_eq!(term.advance(&title), title.len());
+        assert_eq!(term.advance(b"\x07f"), 2);
+        assert_eq!(term.row_to_string(Line(0)), "abcdéf");
+
+        // So is a CSI too long to be real
+        let mut csi = b"\x1b[".to_vec();
+        csi.extend(vec![b'1'; 100]);
+        assert_eq!(term.advance(&csi), csi.len());
+    }
+
+    #[test]
+    fn secondary_device_attributes() {
+        let mut term = term(2, 10);
+        term.feed(b"\x1b[>c");
+        let response = String::from_utf8(term.take_response()).unwrap();
+
+        assert!(response.starts_with("\x1b[>0;"));
+        assert!(response.ends_with(";0c"));
+        let version = &response[5..(response.len() - 3)];
+        assert!(version.parse::<u32>().is_ok(), "version {:?} isn't numeric", version);
+
+        assert_eq!(super::version_number("0.12.3"), 1203);
+        assert_eq!(super::version_number("1.0.0-dev"), 10000);
+    }
+
+    #[test]
+    fn report_terminal_version() {
+        let mut term = term(2, 10);
+        term.feed(b"\x1b[>0q");
+        let response = String::from_utf8(term.take_response()).unwrap();
+        assert_eq!(response, format!("\x1bP>|alacritty {}\x1b\\", env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn report_setting() {
+        let mut term = term(10, 10);
+        let request = |term: &mut Term, setting: &str| {
+            term.feed(format!("\x1bP$q{}\x1b\\", setting).as_bytes());
+            String::from_utf8(term.take_response()).unwrap()
+        };
+
+        assert_eq!(request(&mut term, "r"), "\x1bP1$r1;10r\x1b\\");
+        term.feed(b"\x1b[3;7r");
+        assert_eq!(request(&mut term, "r"), "\x1bP1$r3;7r\x1b\\");
+
+        assert_eq!(request(&mut term, "m"), "\x1bP1$r0m\x1b\\");
+        term.feed(b"\x1b[1;4;48;2;1;2;3m");
+        assert_eq!(request(&mut term, "m"), "\x1bP1$r0;1;4;48;2;1;2;3m\x1b\\");
+
+        term.feed(b"\x1b[6 q");
+        assert_eq!(request(&mut term, " q"), "\x1bP1$r6 q\x1b\\");
+
+        assert_eq!(request(&mut term, "x"), "\x1bP0$r\x1b\\");
+    }
+
+    #[test]
+    fn paste_guard() {
+        assert!(!Term::paste_contains_newline("ls -l"));
+        assert!(Term::paste_contains_newline("ls\nrm -rf"));
+        assert!(Term::paste_contains_newline("ls\r"));
+
+        let mut term = term(2, 10);
+        assert_eq!(term.paste("a\nb"), Paste::Written);
+        assert_eq!(term.take_response(), b"a\nb".to_vec());
+
+        term.confirm_paste_with_newline = true;
+        assert_eq!(term.paste("ls"), Paste::Written);
+        assert_eq!(term.take_response(), b"ls".to_vec());
+        assert_eq!(term.paste("ls\n"), Paste::ConfirmationAdvised);
+        assert!(term.take_response().is_empty());
+
+        term.paste_confirmed("ls\n");
+        assert_eq!(term.take_response(), b"ls\n".to_vec());
+    }
+
+    #[test]
+    fn horizontal_position_absolute() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[2;2H\x1b[5G");
+        assert_eq!(term.cursor, Cursor { line: Line(1), col: Column(4) });
+
+        term.feed(b"\x1b[G");
+        assert_eq!(term.cursor.col, Column(0));
+
+        term.feed(b"\x1b[99G");
+        assert_eq!(term.cursor, Cursor { line: Line(1), col: Column(9) });
+    }
+
+    #[test]
+    fn vertical_position_absolute() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[1;3H\x1b[4d");
+        assert_eq!(term.cursor, Cursor { line: Line(3), col: Column(2) });
+
+        term.feed(b"\x1b[99d");
+        assert_eq!(term.cursor.line, Line(4));
+
+        // Origin mode counts from the top of the scroll region and stops at
+        // its bottom
+        term.feed(b"\x1b[2;4r\x1b[?6h");
+        assert_eq!(term.cursor, Cursor { line: Line(1), col: Column(0) });
+        term.feed(b"\x1b[2d");
+        assert_eq!(term.cursor.line, Line(2));
+        term.feed(b"\x1b[99d");
+        assert_eq!(term.cursor.line, Line(3));
+
+        term.feed(b"\x1b[?6l\x1b[99d");
+        assert_eq!(term.cursor.line, Line(4));
+    }
+
+    #[test]
+    fn relative_position_stops_at_margins() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[3a");
+        assert_eq!(term.cursor, Cursor { line: Line(0), col: Column(3) });
+        term.feed(b"\x1b[2e");
+        assert_eq!(term.cursor, Cursor { line: Line(2), col: Column(3) });
+
+        term.feed(b"\x1b[99a\x1b[99e");
+        assert_eq!(term.cursor, Cursor { line: Line(4), col: Column(9) });
+
+        // Within the scroll region, moving down stops at its bottom
+        term.feed(b"\x1b[2;3r\x1b[2;1H\x1b[99e");
+        assert_eq!(term.cursor.line, Line(2));
+    }
+
+    #[test]
+    fn clear_line_after_writing_last_column() {
+        let mut term = term(2, 5);
+        term.feed(b"abcde");
+        assert_eq!(term.cursor.col, Column(5));
+
+        term.feed(b"\x1b[1K");
+        assert_eq!(term.row_to_string(Line(0)), "");
+
+        term.feed(b"\x1b[1;1Habcde\x1b[K\x1b[?1K");
+        assert_eq!(term.row_to_string(Line(0)), "");
+    }
+
+    #[test]
+    fn synchronized_update_defers_draw() {
+        let mut synced = term(5, 10);
+        synced.clear_dirty();
+
+        synced.feed(b"\x1b[?2026h");
+        synced.feed(b"a");
+        synced.feed(b"\r\nb");
+        assert!(!synced.dirty);
+
+        synced.feed(b"\x1b[?2026l");
+        assert!(synced.dirty);
+        assert_eq!(synced.dirty_lines().collect::<Vec<_>>(), vec![Line(0), Line(1)]);
+        assert!(!synced.mode().contains(mode::SYNC_UPDATE));
+        assert_eq!(synced.next_deadline(Instant::now()), None);
+    }
+
+    #[test]
+    fn synchronized_update_times_out() {
+        let mut synced = term(5, 10);
+        synced.clear_dirty();
+
+        synced.feed(b"\x1b[?2026ha");
+        let deadline = synced.next_deadline(Instant::now()).expect("deadline while syncing");
+        synced.expire_timers(deadline - Duration::from_millis(1));
+        assert!(!synced.dirty);
+
+        synced.expire_timers(deadline);
+        assert!(synced.dirty);
+        assert!(!synced.mode().contains(mode::SYNC_UPDATE));
+
+        // A reset ends it too
+        synced.clear_dirty();
+        synced.feed(b"\x1b[?2026h\x1bc");
+        assert!(synced.dirty);
+        assert!(!synced.mode().contains(mode::SYNC_UPDATE));
+    }
+
+    #[test]
+    fn resize_to_grid() {
+        let mut resized = term(5, 10);
+        resized.size_info.cell_width = 7.0;
+        resized.size_info.cell_height = 15.0;
+
+        resized.resize_to_grid(Line(24), Column(80));
+        assert_eq!(resized.grid.num_lines(), Line(24));
+        assert_eq!(resized.grid.num_cols(), Column(80));
+        assert_eq!((resized.size_info.width, resized.size_info.height), (560.0, 360.0));
+        assert_eq!(resized.tabs.len(), 80);
+        assert_eq!(resized.scroll_region(), Line(0)..Line(24));
+
+        // Never smaller than a cell
+        resized.resize_to_grid(Line(0), Column(0));
+        assert_eq!(resized.grid.num_lines(), Line(1));
+        assert_eq!(resized.grid.num_cols(), Column(1));
+
+        // Fractional cell sizes don't lose a cell to rounding
+        resized.size_info.cell_width = 7.3;
+        resized.size_info.cell_height = 5.01;
+        resized.resize_to_grid(Line(7), Column(9));
+        assert_eq!(resized.grid.num_lines(), Line(7));
+        assert_eq!(resized.grid.num_cols(), Column(9));
+        assert_eq!(resized.tabs.len(), 9);
+        assert_eq!(resized.scroll_region(), Line(0)..Line(7));
+    }
+
+    #[test]
+    fn set_scroll_region_validates() {
+        let mut term = term(5, 10);
+        assert_eq!(term.scroll_region(), Line(0)..Line(5));
+
+        // Clamped to the screen
+        term.set_scroll_region(Line(1)..Line(50));
+        assert_eq!(term.scroll_region(), Line(1)..Line(5));
+
+        // Inverted and single line regions are ignored
+        term.set_scroll_region(Line(4)..Line(2));
+        assert_eq!(term.scroll_region(), Line(1)..Line(5));
+        term.set_scroll_region(Line(2)..Line(3));
+        assert_eq!(term.scroll_region(), Line(1)..Line(5));
+
+        term.set_scroll_region(Line(0)..Line(2));
+        assert_eq!(term.scroll_region(), Line(0)..Line(2));
+    }
+
+    /// Whether any half of a double width character on `line` is missing its
+    /// other half
+    fn has_orphaned_wide_char(term: &Term, line: Line) -> bool {
+        let row = &term.grid[line];
+        (0..row.len()).any(|i| {
+            let col = Column(i);
+            let wide = row[col].flags.contains(cell::WIDE_CHAR);
+            let spacer = row[col].flags.contains(cell::WIDE_CHAR_SPACER);
+            let next_spacer = i + 1 < row.len() &&
+                row[col + 1].flags.contains(cell::WIDE_CHAR_SPACER);
+            let prev_wide = i > 0 && row[col - 1].flags.contains(cell::WIDE_CHAR);
+            (wide && !next_spacer) || (spacer && !prev_wide)
+        })
+    }
+
+    #[test]
+    fn insert_blank_splitting_wide_char() {
+        // Inserting at a spacer blanks both halves
+        let mut wide = term(1, 10);
+        wide.feed("a中b\x1b[3G\x1b[@".as_bytes());
+        assert!(!has_orphaned_wide_char(&wide, Line(0)));
+        assert_eq!(wide.row_to_string(Line(0)), "a   b");
+
+        // A pair pushed halfway off the end of the line is blanked
+        let mut pushed = term(1, 10);
+        pushed.feed("abcdefgh中\x1b[1G\x1b[@".as_bytes());
+        assert!(!has_orphaned_wide_char(&pushed, Line(0)));
+        assert_eq!(pushed.row_to_string(Line(0)), " abcdefgh");
+
+        // A pair moved whole is kept
+        let mut whole = term(1, 10);
+        whole.feed("a中b\x1b[2G\x1b[2@".as_bytes());
+        assert!(!has_orphaned_wide_char(&whole, Line(0)));
+        assert_eq!(whole.row_to_string(Line(0)), "a  中b");
+    }
+
+    #[test]
+    fn delete_chars_splitting_wide_char() {
+        // Deleting the character leaves its spacer behind
+        let mut wide = term(1, 10);
+        wide.feed("a中b\x1b[2G\x1b[P".as_bytes());
+        assert!(!has_orphaned_wide_char(&wide, Line(0)));
+        assert_eq!(wide.row_to_string(Line(0)), "a b");
+
+        // Deleting the spacer leaves the character without one
+        let mut spacer = term(1, 10);
+        spacer.feed("a中b\x1b[3G\x1b[P".as_bytes());
+        assert!(!has_orphaned_wide_char(&spacer, Line(0)));
+        assert_eq!(spacer.row_to_string(Line(0)), "a b");
+
+        // Deleting both halves shifts the rest as usual
+        let mut both = term(1, 10);
+        both.feed("a中b\x1b[2G\x1b[2P".as_bytes());
+        assert_eq!(both.row_to_string(Line(0)), "ab");
+    }
+
+    #[test]
+    fn wide_chars_take_two_cells() {
+        let mut term = term(2, 10);
+        term.feed("abcdefg中".as_bytes());
+        assert_eq!(term.cursor.col, Column(9));
+        assert!(term.grid[Line(0)][Column(7)].flags.contains(cell::WIDE_CHAR));
+        assert!(term.grid[Line(0)][Column(8)].flags.contains(cell::WIDE_CHAR_SPACER));
+
+        // Wraps whole when it doesn't fit at the end of the line
+        term.feed("中".as_bytes());
+        assert!(term.grid[Line(0)][Column(9)].flags.contains(cell::WRAPLINE));
+        assert_eq!(term.grid[Line(1)][Column(0)].c, '中');
+        assert_eq!(term.cursor, Cursor { line: Line(1), col: Column(2) });
+
+        // Text dumps show each character once
+        assert_eq!(term.row_to_string(Line(0)), "abcdefg中");
+        assert_eq!(term.grid_to_string(false), "abcdefg中 \n中        \n");
+    }
+
+    #[test]
+    fn tabs_skip_right_half_of_wide_char() {
+        let mut term = term(1, 20);
+        term.feed("abcdefg中\r\t".as_bytes());
+        assert_eq!(term.cursor.col, Column(16));
+
+        term.feed(b"\x1b[Z");
+        assert_eq!(term.cursor.col, Column(0));
+
+        // From a stop the tab moves on to the next one
+        term.feed(b"\x1b[1;17H\x1b[2Z\x1b[I");
+        assert_eq!(term.cursor.col, Column(16));
+
+        term.feed(b"\t");
+        assert_eq!(term.cursor.col, Column(20));
+    }
+
+    #[test]
+    fn tabs_on_uneven_width() {
+        let mut term = term(2, 13);
+        assert_eq!(term.tabs.len(), 13);
+
+        // The right margin is the stop after the last one
+        term.feed(b"\t");
+        assert_eq!(term.cursor.col, Column(8));
+        term.feed(b"\t");
+        assert_eq!(term.cursor.col, Column(13));
+        term.feed(b"\t\x1b[5I");
+        assert_eq!(term.cursor.col, Column(13));
+
+        // Output carries on on the next line
+        term.feed(b"x");
+        assert_eq!(term.cursor, Cursor { line: Line(1), col: Column(1) });
+
+        // Stops are rebuilt to match the width after a resize
+        for &cols in &[19, 5, 8] {
+            term.resize_to_grid(Line(2), Column(cols));
+            assert_eq!(term.tabs.len(), cols);
+            assert!(!term.tabs[0]);
+
+            term.feed(b"\r\t\t\t\t");
+            assert_eq!(term.cursor.col, Column(cols));
+        }
+    }
+
+    #[test]
+    fn erase_chars_stops_at_right_margin() {
+        let mut term = term(2, 10);
+        term.feed(b"0123456789\r\nabcdefghij\x1b[1;4H\x1b[99999X");
+        assert_eq!(term.row_to_string(Line(0)), "012");
+        assert_eq!(term.row_to_string(Line(1)), "abcdefghij");
+    }
+
+    #[test]
+    fn insert_blank_at_right_margin() {
+        let mut term = term(1, 10);
+        term.feed(b"0123456789\x1b[1;9H\x1b[5@");
+        assert_eq!(term.row_to_string(Line(0)), "01234567");
+
+        // The cursor is past the last column after writing to it
+        term.feed(b"\x1b[1;1H0123456789\x1b[5@");
+        assert_eq!(term.cursor.col, Column(10));
+        assert_eq!(term.row_to_string(Line(0)), "0123456789");
+
+        term.feed(b"\x1b[1;10H\x1b[1@");
+        assert_eq!(term.row_to_string(Line(0)), "012345678");
+    }
+
+    #[test]
+    fn scroll_past_region_clears_it() {
+        let mut term = term(5, 10);
+        term.feed(b"0\r\n1\r\n2\r\n3\r\n4");
+        term.feed(b"\x1b[2;4r\x1b[100S");
+
+        let screen = |term: &Term| {
+            (0..5).map(|line| term.row_to_string(Line(line))).collect::<Vec<_>>()
+        };
+        assert_eq!(screen(&term), vec!["0", "", "", "", "4"]);
+
+        term.feed(b"\x1b[1;5r\x1b[2;1H1\x1b[2;4r\x1b[100T");
+        assert_eq!(screen(&term), vec!["0", "", "", "", "4"]);
+
+        // The whole screen too
+        term.feed(b"\x1b[r\x1b[100T");
+        assert_eq!(screen(&term), vec!["", "", "", "", ""]);
+        term.feed(b"\x1b[1;1Ha\x1b[100S");
+        assert_eq!(screen(&term), vec!["", "", "", "", ""]);
+    }
+
+    #[test]
+    fn in_alt_screen_follows_mode() {
+        let mut term = term(5, 10);
+        assert!(!term.in_alt_screen());
+
+        term.set_mode(ansi::Mode::SwapScreenAndSetRestoreCursor);
+        assert!(term.in_alt_screen());
+
+        // Entering again stays on the alternate screen
+        term.feed(b"a");
+        term.set_mode(ansi::Mode::SwapScreenAndSetRestoreCursor);
+        assert!(term.in_alt_screen());
+        assert_eq!(term.row_to_string(Line(0)), "a");
+
+        term.unset_mode(ansi::Mode::SwapScreenAndSetRestoreCursor);
+        assert!(!term.in_alt_screen());
+        term.unset_mode(ansi::Mode::SwapScreenAndSetRestoreCursor);
+        assert!(!term.in_alt_screen());
+        assert_eq!(term.row_to_string(Line(0)), "");
+    }
+
+    #[test]
+    fn palette_round_trip() {
+        let mut term = term(5, 10);
+        let defaults = term.palette().to_vec();
+        assert_eq!(defaults.len(), 16);
+
+        let rgb = Rgb { r: 1, g: 2, b: 3 };
+        term.feed(b"\x1b[31m");
+        term.set_palette_color(1, rgb);
+        term.set_palette_color(16, rgb);
+        assert_eq!(term.palette()[1], rgb);
+        assert_eq!(&term.palette()[2..], &defaults[2..]);
+
+        // The current foreground follows the palette
+        assert_eq!(term.template_cell.fg, rgb);
+
+        term.reset_palette();
+        assert_eq!(term.palette(), &defaults[..]);
+        assert_eq!(term.template_cell.fg, defaults[1]);
+    }
+
+    #[test]
+    fn report_dynamic_colors() {
+        let mut term = term(10, 5);
+        term.fg = Rgb { r: 0xea, g: 0xea, b: 0xea };
+        term.bg = Rgb { r: 0x00, g: 0x2b, b: 0x36 };
+
+        term.report_dynamic_color(DynamicColor::Foreground);
+        term.report_dynamic_color(DynamicColor::Background);
+
+        assert_eq!(term.take_response(),
+                   b"\x1b]10;rgb:eaea/eaea/eaea\x1b\\\x1b]11;rgb:0000/2b2b/3636\x1b\\".to_vec());
+        assert!(term.take_response().is_empty());
+    }
+
+    #[test]
+    fn set_dynamic_colors() {
+        let mut term = term(10, 5);
+        let mut parser = ansi::Processor::new();
+
+        for byte in &b"\x1b]10;rgb:ff/80/00\x07\x1b]11;#102030\x07"[..] {
+            parser.advance(&mut term, *byte);
+        }
+
+        let fg = Rgb { r: 0xff, g: 0x80, b: 0x00 };
+        let bg = Rgb { r: 0x10, g: 0x20, b: 0x30 };
+        assert_eq!(term.fg, fg);
+        assert_eq!(term.bg, bg);
+        assert_eq!(term.template_cell.fg, fg);
+        assert_eq!(term.template_cell.bg, bg);
+        assert_eq!(term.empty_cell.bg, bg);
+    }
+
+    #[test]
+    fn sgr_reset_uses_current_default_colors() {
+        let mut term = term(2, 10);
+        let fg = Rgb { r: 0xff, g: 0x80, b: 0x00 };
+        let bg = Rgb { r: 0x10, g: 0x20, b: 0x30 };
+
+        // Defaults changed while other colors are in effect aren't picked up
+        // by the template until the next reset
+        term.feed(b"\x1b[31;41m\x1b]10;rgb:ff/80/00\x07\x1b]11;#102030\x07a");
+        assert_eq!(term.grid[Line(0)][Column(0)].bg, term.colors[ansi::Color::Red as usize]);
+
+        term.feed(b"\x1b[0mb");
+        let cell = term.grid[Line(0)][Column(1)];
+        assert_eq!(cell.fg, fg);
+        assert_eq!(cell.bg, bg);
+    }
+
+    #[test]
+    fn report_set_and_unset_private_modes() {
+        let mut term = term(5, 10);
+
+        // The cursor is shown by default
+        term.feed(b"\x1b[?25$p");
+        assert_eq!(term.take_response(), b"\x1b[?25;1$y".to_vec());
+
+        term.feed(b"\x1b[?1h\x1b[?1$p\x1b[?1l\x1b[?1$p");
+        assert_eq!(term.take_response(), b"\x1b[?1;1$y\x1b[?1;2$y".to_vec());
+
+        term.feed(b"\x1b[?9999$p\x1b[4$p");
+        assert_eq!(term.take_response(), b"\x1b[?9999;0$y\x1b[4;0$y".to_vec());
+    }
+
+    #[test]
+    fn report_window_size() {
+        let size = SizeInfo {
+            width: 800.0,
+            height: 480.0,
+            cell_width: 10.0,
+            cell_height: 20.0,
+            max_cols: Column(2000),
+            max_lines: Line(2000),
+        };
+        let mut term = Term::builder(size).build();
+
+        term.feed(b"\x1b[14t");
+        assert_eq!(term.take_response(), b"\x1b[4;480;800t".to_vec());
+
+        term.feed(b"\x1b[18t");
+        assert_eq!(term.take_response(), b"\x1b[8;24;80t".to_vec());
+
+        // Other operations are ignored
+        term.feed(b"\x1b[8;10;10t\x1b[t");
+        assert!(term.take_response().is_empty());
+    }
+
+    /// Terminal with `lines` numbered lines of output, scrolled back `offset`
+    fn scrolled_back_term(lines: usize, offset: isize) -> Term {
+        let mut term = term(5, 10);
+        term.grid.set_history_limit(100);
+        for i in 0..lines {
+            term.feed(format!("{}\r\n", i).as_bytes());
+        }
+        term.scroll_display(offset);
+        term
+    }
+
+    /// Text of a line of the view, which may be scrolled back into history
+    fn view_text(term: &Term, line: Line) -> String {
+        let row = term.visible_row(line).cells().map(|cell| cell.c).collect::<String>();
+        row.trim_right_matches(' ').to_owned()
+    }
+
+    #[test]
+    fn set_scrollback_limit_with_history() {
+        let mut term = scrolled_back_term(20, 10);
+        assert_eq!(term.grid.history_size(), 16);
+
+        // Growing keeps what's there and makes room for more
+        term.set_scrollback_limit(30);
+        assert_eq!(term.grid.history_size(), 16);
+        term.feed(b"20\r\n21\r\n");
+        assert_eq!(term.grid.history_size(), 18);
+
+        // Shrinking drops the oldest lines and pulls the view down with them
+        term.scroll_display(10);
+        assert_eq!(view_text(&term, Line(0)), "8");
+        term.set_scrollback_limit(4);
+        assert_eq!(term.grid.history_size(), 4);
+        assert_eq!(term.display_offset(), 4);
+        assert_eq!(view_text(&term, Line(0)), "14");
+
+        term.set_scrollback_limit(0);
+        assert_eq!(term.grid.history_size(), 0);
+        assert_eq!(term.display_offset(), 0);
+        assert_eq!(view_text(&term, Line(0)), "18");
+    }
+
+    #[test]
+    fn clear_saved_lines() {
+        let mut term = scrolled_back_term(20, 3);
+        let screen = (0..5).map(|line| term.row_to_string(Line(line))).collect::<Vec<_>>();
+        assert_eq!(term.grid.history_size(), 16);
+
+        term.feed(b"\x1b[3J");
+        assert_eq!(term.grid.history_size(), 0);
+        assert_eq!(term.display_offset(), 0);
+
+        for (line, text) in screen.iter().enumerate() {
+            assert_eq!(&term.row_to_string(Line(line)), text);
+        }
+    }
+
+    #[test]
+    fn output_scrolls_to_bottom() {
+        let mut term = scrolled_back_term(20, 3);
+        assert_eq!(term.display_offset(), 3);
+
+        term.feed(b"x");
+        assert_eq!(term.display_offset(), 0);
+    }
+
+    #[test]
+    fn output_keeps_view_without_scroll_on_output() {
+        let mut term = scrolled_back_term(20, 3);
+        term.set_scroll_on_output(false);
+        let top = term.grid.display_lines().next().unwrap().to_vec();
+
+        term.feed(b"x");
+        assert_eq!(term.display_offset(), 3);
+
+        // Lines pushed into history don't move the view either
+        term.feed(b"\r\nmore\r\n");
+        assert_eq!(term.grid.display_lines().next().unwrap().to_vec(), top);
+    }
+
+    #[test]
+    fn focus_changes_are_reported() {
+        let mut term = term(5, 10);
+
+        // Nothing is reported until requested
+        term.set_focused(false);
+        term.set_focused(true);
+        assert!(term.take_response().is_empty());
+
+        term.feed(b"\x1b[?1004h");
+        term.set_focused(false);
+        term.set_focused(false);
+        term.set_focused(true);
+        assert_eq!(term.take_response(), b"\x1b[O\x1b[I".to_vec());
+
+        term.feed(b"\x1b[?1004l");
+        term.set_focused(false);
+        assert!(term.take_response().is_empty());
+    }
+
+    #[test]
+    fn blink_phase_toggles_each_interval() {
+        let term = term(5, 10);
+        let epoch = term.blink_epoch;
+        let ms = Duration::from_millis;
+
+        assert!(term.blink_phase(epoch));
+        assert!(term.blink_phase(epoch + ms(499)));
+        assert!(!term.blink_phase(epoch + ms(500)));
+        assert!(!term.blink_phase(epoch + ms(999)));
+        assert!(term.blink_phase(epoch + ms(1000)));
+    }
+
+    #[test]
+    fn cursor_style_defaults_to_config() {
+        let size = SizeInfo {
+            width: 100.0,
+            height: 50.0,
+            cell_width: 10.0,
+            cell_height: 10.0,
+            max_cols: Column(2000),
+            max_lines: Line(2000),
+        };
+        let blinking = Term::builder(size).cursor_blink(true).build();
+        assert_eq!(blinking.cursor_style().blinking, true);
+
+        let mut term = term(5, 10);
+        assert!(!term.cursor_style().blinking);
+
+        // DECSCUSR overrides the default until it's reset with 0
+        term.feed(b"\x1b[1 q");
+        assert_eq!(term.cursor_style(), CursorStyle { shape: CursorShape::Block, blinking: true });
+        term.feed(b"\x1b[6 q");
+        assert_eq!(term.cursor_style(), CursorStyle { shape: CursorShape::Beam, blinking: false });
+        term.feed(b"\x1b[0 q");
+        assert_eq!(term.cursor_style(), CursorStyle::default());
+    }
+
+    #[test]
+    fn cursor_state_round_trip() {
+        let mut saved = term(5, 10);
+        saved.feed(b"\x1b[3;4H\x1b[4 q\x1b[?25l");
+        let state = saved.cursor_state();
+        assert_eq!(state, CursorState {
+            point: Point::new(Line(2), Column(3)),
+            style: CursorStyle { shape: CursorShape::Underline, blinking: false },
+            visible: false,
+        });
+
+        let mut other = term(5, 10);
+        other.set_cursor_state(state);
+        assert_eq!(other.cursor_state(), state);
+
+        // Positions off the screen are clamped
+        other.set_cursor_state(CursorState { point: Point::new(Line(50), Column(50)), ..state });
+        assert_eq!(other.cursor_state().point, Point::new(Line(4), Column(9)));
+    }
+
+    #[test]
+    fn render_state_cursor() {
+        let mut term = term(5, 10);
+        let now = Instant::now();
+        term.feed(b"ab");
+
+        let (point, style, color) = term.render_state(now).cursor.unwrap();
+        assert_eq!(point, Point::new(Line(0), Column(2)));
+        assert_eq!(style, CursorStyle::default());
+        assert_eq!(color, term.fg);
+
+        term.feed(b"\x1b[?25l");
+        assert_eq!(term.render_state(now).cursor, None);
+
+        // A blinking cursor is hidden in the off phase, unless unfocused
+        term.feed(b"\x1b[?25h\x1b[1 q");
+        let off = term.blink_epoch + Duration::from_millis(500);
+        assert_eq!(term.render_state(off).cursor, None);
+        term.set_focused(false);
+        assert!(term.render_state(off).cursor.is_some());
+    }
+
+    #[test]
+    fn blinking_cursor_is_redrawn() {
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[2J\x1b[1 q");
+        let epoch = term.blink_epoch;
+        let ms = |ms| Duration::from_millis(ms);
+
+        let inverted = |term: &mut Term, now| {
+            let grid = term.render_grid(now);
+            grid[Line(0)][Column(0)].flags.contains(cell::INVERSE)
+        };
+        assert!(inverted(&mut term, epoch));
+        assert!(!inverted(&mut term, epoch + ms(500)));
+
+        // The event loop is woken when the cursor turns off and on again
+        term.clear_dirty();
+        assert_eq!(term.next_deadline(epoch + ms(100)), Some(epoch + ms(500)));
+        term.expire_timers(epoch + ms(499));
+        assert!(!term.dirty);
+        term.expire_timers(epoch + ms(500));
+        assert!(term.dirty);
+        assert_eq!(term.dirty_lines().collect::<Vec<_>>(), vec![Line(0)]);
+        assert_eq!(term.next_deadline(epoch + ms(500)), Some(epoch + ms(1000)));
+
+        // Nothing blinks while unfocused
+        term.set_focused(false);
+        assert!(inverted(&mut term, epoch + ms(500)));
+        assert_eq!(term.next_deadline(epoch + ms(500)), None);
+    }
+
+    #[test]
+    fn has_blinking_cells() {
+        let mut term = term(5, 10);
+        term.feed(b"plain");
+        assert!(!term.has_blinking_cells());
+
+        term.feed(b"\x1b[5mblink\x1b[25m");
+        assert!(term.has_blinking_cells());
+
+        term.feed(b"\x1b[2J");
+        assert!(!term.has_blinking_cells());
+    }
+
+    /// Move the cursor around the alternate screen entered and left with the
+    /// given private mode; returns the cursor afterwards
+    fn cursor_after_alt_screen(mode: &str) -> Cursor {
+        let mut term = term(5, 10);
+        term.goto(Line(2), Column(3));
+        term.feed(format!("\x1b[?{}h", mode).as_bytes());
+        term.goto(Line(4), Column(1));
+        term.feed(format!("\x1b[?{}l", mode).as_bytes());
+        term.cursor
+    }
+
+    #[test]
+    fn alt_screen_restores_cursor_only_for_1049() {
+        assert_eq!(cursor_after_alt_screen("1049"), Cursor { line: Line(2), col: Column(3) });
+        assert_eq!(cursor_after_alt_screen("1047"), Cursor { line: Line(4), col: Column(1) });
+        assert_eq!(cursor_after_alt_screen("47"), Cursor { line: Line(4), col: Column(1) });
+    }
+
+    #[test]
+    fn alt_screen_clearing() {
+        // ?1049 clears the alternate screen when entering it
+        let mut term = term(5, 10);
+        term.feed(b"\x1b[?47hold\x1b[?47l\x1b[?1049h");
+        assert_eq!(term.row_to_string(Line(0)), "");
+        term.feed(b"\x1b[?1049l");
+
+        // ?47 leaves it alone
+        term.feed(b"\x1b[H\x1b[?47hold\x1b[?47l\x1b[?47h");
+        assert_eq!(term.row_to_string(Line(0)), "old");
+        term.feed(b"\x1b[?47l");
+
+        // ?1047 clears it when leaving
+        term.feed(b"\x1b[?1047h\x1b[Hnew\x1b[?1047l\x1b[?47h");
+        assert_eq!(term.row_to_string(Line(0)), "");
+        term.feed(b"\x1b[?47l");
+
+        // The primary screen is untouched
+        assert_eq!(term.row_to_string(Line(0)), "");
+        assert!(!term.alt);
+    }
+
+    #[test]
+    fn font_key_for_flags() {
+        let term = term(10, 5);
+        let keys = *term.font_keys();
+
+        assert_eq!(term.font_key_for(cell::Flags::empty()), keys.regular);
+        assert_eq!(term.font_key_for(cell::UNDERLINE | cell::INVERSE), keys.regular);
+        assert_eq!(term.font_key_for(cell::BOLD), keys.bold);
+        assert_eq!(term.font_key_for(cell::ITALIC), keys.italic);
+        assert_eq!(term.font_key_for(cell::BOLD | cell::ITALIC), keys.bold_italic);
+        assert_eq!(term.font_key_for(cell::BOLD | cell::ITALIC | cell::UNDERLINE),
+                   keys.bold_italic);
+    }
+
+    #[test]
+    fn grid_to_string() {
+        let mut term = term(2, 10);
+        let mut parser = ansi::Processor::new();
+
+        for byte in &b"hello\r\nworld"[..] {
+            parser.advance(&mut term, *byte);
+        }
+
+        assert_eq!(term.row_to_string(Line(0)), "hello");
+        assert_eq!(term.row_to_string(Line(1)), "world");
+        assert_eq!(term.grid_to_string(true), "hello\nworld\n");
+    }
+
+    #[test]
+    fn grid_to_string_untrimmed() {
+        let mut term = term(2, 6);
+        term.feed(b"ab\r\ncd");
+
+        assert_eq!(term.grid_to_string(true), "ab\ncd\n");
+        assert_eq!(term.grid_to_string(false), "ab    \ncd    \n");
+    }
+
+    #[test]
+    fn output_clears_selection_on_its_lines() {
+        let mut term = term(5, 10);
+        let selection = Point::new(Line(1), Column(2))..Point::new(Line(2), Column(4));
+        term.set_selection(Some(selection.clone()));
+
+        // Writing elsewhere keeps it
+        term.feed(b"\x1b[1;1Habc\x1b[4;1H\x1b[K");
+        assert_eq!(term.selection(), Some(selection.clone()));
+
+        term.feed(b"\x1b[3;9Hx");
+        assert_eq!(term.selection(), None);
+
+        // Unless disabled
+        term.selection_clears_on_output = false;
+        term.set_selection(Some(selection.clone()));
+        term.feed(b"\x1b[2;1Hx");
+        assert_eq!(term.selection(), Some(selection));
+    }
+
+    #[test]
+    fn selection_text_trims_padding() {
+        let mut term = term(3, 8);
+        term.feed(b"one  \r\ntwo\r\nthree  x");
+
+        let whole = range((0, 0), (2, 8));
+        assert_eq!(term.selection_text(whole.clone(), false), "one     \ntwo     \nthree  x");
+        assert_eq!(term.selection_text(whole, true), "one\ntwo\nthree  x");
+
+        // Spaces selected in the middle of the last line are kept
+        let partial = range((1, 1), (2, 7));
+        assert_eq!(term.selection_text(partial.clone(), true), "wo\nthree  ");
+        assert_eq!(term.selection_text(partial, false), "wo     \nthree  ");
+    }
+
+    #[test]
+    fn selection_text_joins_wrapped_lines() {
+        let mut term = term(3, 5);
+        term.feed(b"abcdefgh\r\nxy");
+        assert!(term.is_wrapped(Line(0)));
+        assert!(!term.is_wrapped(Line(1)));
+
+        // The wrapped line is joined; the newline after it is kept
+        let whole = range((0, 0), (2, 5));
+        assert_eq!(term.selection_text(whole.clone(), true), "abcdefgh\nxy");
+        assert_eq!(term.selection_text(whole, false), "abcdefgh  \nxy   ");
+
+        // Spacers after double width characters aren't copied
+        let mut wide = term(2, 5);
+        wide.feed("a中bc中".as_bytes());
+        assert_eq!(wide.selection_text(range((0, 0), (1, 5)), true), "a中bc中");
+    }
+
+    #[test]
+    fn render_and_clear_leaves_nothing_dirty() {
+        let mut drawn = term(5, 10);
+        drawn.feed(b"hello\r\nworld");
+        assert!(drawn.dirty);
+
+        {
+            let grid = drawn.render_grid(Instant::now());
+            let cursor = grid.render_colors(&grid[Line(1)][Column(5)]);
+            assert_eq!(cursor.0, grid.render_colors(&grid[Line(1)][Column(4)]).1);
+        }
+        drawn.clear_dirty();
+
+        assert!(!drawn.dirty);
+        assert!(drawn.line_dirty.iter().all(|dirty| !dirty));
+
+        // Drawing again without new output changes nothing
+        drop(drawn.render_grid(Instant::now()));
+        assert!(!drawn.dirty);
+        assert!(drawn.line_dirty.iter().all(|dirty| !dirty));
+        assert_eq!(drawn.dirty_lines().collect::<Vec<_>>(), vec![Line(1)]);
+    }
+
+    #[test]
+    fn dump_round_trips() {
+        let mut colored = term(4, 10);
+        colored.feed(b"\x1b[1;31mred\x1b[0m plain\r\n\x1b[4:3;48;2;1;2;3mcurl\x1b[7m\xe4\xb8\xad");
+        colored.feed(b"\x1b[m\r\nwraps around");
+
+        let mut out = Vec::new();
+        colored.dump(&mut out).unwrap();
+
+        // One sequence per run and a reset at the end
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.matches("\x1b[").count(), 6);
+        assert!(text.ends_with("\x1b[0m"));
+
+        let mut copy = term(4, 10);
+        copy.feed(text.as_bytes());
+        for line in 0..4 {
+            let line = Line(line);
+            assert_eq!(copy.grid[line].cells().collect::<Vec<_>>(),
+                       colored.grid[line].cells().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn typing_marks_line_dirty() {
+        let mut term = term(5, 10);
+        term.goto(Line(2), Column(0));
+        term.clear_dirty();
+        assert_eq!(term.dirty_lines().collect::<Vec<_>>(), vec![Line(2)]);
+
+        for c in "hello".chars() {
+            term.input(c);
+        }
+        assert_eq!(term.dirty_lines().collect::<Vec<_>>(), vec![Line(2)]);
+
+        // Moving the cursor dirties the line it left
+        term.goto(Line(4), Column(0));
+        assert_eq!(term.dirty_lines().collect::<Vec<_>>(), vec![Line(2), Line(4)]);
+
+        term.clear_dirty();
+        assert_eq!(term.dirty_lines().collect::<Vec<_>>(), vec![Line(4)]);
+    }
+
+    #[test]
+    fn repeated_sgr_matches_single_sgr() {
+        let mut single = Vec::new();
+        let mut repeated = Vec::new();
+        for i in 0..50 {
+            let sgr = format!("\x1b[1;3{};4{}m", i % 8, (i + 1) % 8);
+            single.extend_from_slice(format!("{}line {}\x1b[0m\r\n", sgr, i).as_bytes());
+
+            repeated.extend_from_slice(sgr.as_bytes());
+            for word in &["line ".to_owned(), i.to_string()] {
+                repeated.extend_from_slice(sgr.as_bytes());
+                repeated.extend_from_slice(word.as_bytes());
+            }
+            repeated.extend_from_slice(b"\x1b[0m\x1b[0;0m\r\n");
+        }
+
+        let mut expected = term(10, 20);
+        expected.feed(&single);
+        let mut term = term(10, 20);
+        term.feed(&repeated);
+
+        assert!(expected.grid.diff(&term.grid).is_empty());
+        assert_eq!(expected.template_cell, term.template_cell);
+    }
+
+    #[bench]
+    fn bench_feed_repeated_sgr(b: &mut Bencher) {
+        let mut term = term(50, 80);
+        let mut output = Vec::new();
+        for i in 0..1000 {
+            let sgr = format!("\x1b[38;5;{}m", i % 8);
+            for word in 0..8 {
+                output.extend_from_slice(format!("{}word{} ", sgr, word).as_bytes());
+            }
+            output.extend_from_slice(b"\r\n");
+        }
+
+        b.bytes = output.len() as u64;
+        b.iter(|| term.feed(&output));
+    }
+
+    #[bench]
+    fn bench_feed(b: &mut Bencher) {
+        let mut term = term(50, 80);
+        let mut output = Vec::new();
+        for i in 0..1000 {
+            output.extend_from_slice(
+                format!("\x1b[1;3{}mline {}\x1b[0m yyyyyyyyyyyyyyyyyyyyyyyyyyyyyy\r\n", i % 8, i)
+                    .as_bytes()
+            );
+        }
+
+        b.bytes = output.len() as u64;
+        b.iter(|| term.feed(&output));
     }
 }
@@ -14,50 +14,20 @@
 //
 //! tty related functionality
 //!
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::env;
-use std::ffi::CStr;
-use std::fs::File;
+use std::ffi::{CStr, CString};
+use std::io::{self, Read, Write};
 use std::mem;
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
 use std::ptr;
 
-use libc::{self, winsize, c_int, pid_t, WNOHANG, WIFEXITED, WEXITSTATUS, SIGCHLD};
+use libc::{self, winsize, c_int, pid_t, WNOHANG, WIFEXITED, WEXITSTATUS, WIFSIGNALED, WTERMSIG};
 
-/// Process ID of child process
-///
-/// Necessary to put this in static storage for `sigchld` to have access
-static mut PID: pid_t = 0;
-
-/// Exit flag
-///
-/// Calling exit() in the SIGCHLD handler sometimes causes opengl to deadlock,
-/// and the process hangs. Instead, this flag is set, and its status can be
-/// cheked via `process_should_exit`.
-static mut SHOULD_EXIT: bool = false;
-
-extern "C" fn sigchld(_a: c_int) {
-    let mut status: c_int = 0;
-    unsafe {
-        let p = libc::waitpid(PID, &mut status, WNOHANG);
-        if p < 0 {
-            die!("Waiting for pid {} failed: {}\n", PID, errno());
-        }
-
-        if PID != p {
-            return;
-        }
-
-        if !WIFEXITED(status) || WEXITSTATUS(status) != 0 {
-            die!("child finished with error '{}'\n", status);
-        }
-
-        SHOULD_EXIT = true;
-    }
-}
-
-pub fn process_should_exit() -> bool {
-    unsafe { SHOULD_EXIT }
-}
+use index::{Column, Line};
+use term::SizeInfo;
 
 /// Get the current value of errno
 fn errno() -> c_int {
@@ -198,8 +168,27 @@ fn get_pw_entry<'a>(buf: &'a mut [i8; 1024]) -> Passwd<'a> {
     }
 }
 
-/// Exec a shell
-fn execsh() -> ! {
+/// Exec a program with the given arguments
+fn exec(program: &CStr, args: &[CString]) -> ! {
+    let mut argv = vec![program.as_ptr()];
+    argv.extend(args.iter().map(|arg| arg.as_ptr()));
+    argv.push(ptr::null());
+
+    let res = unsafe {
+        libc::execvp(program.as_ptr(), argv.as_ptr())
+    };
+
+    if res < 0 {
+        die!("execvp failed: {}", errno());
+    }
+
+    ::std::process::exit(1);
+}
+
+/// Exec `program` with its arguments, or the user's shell if there is none
+///
+/// Runs in the child after `fork`, so `program` is converted beforehand.
+fn execsh(options: &Options, program: Option<(CString, Vec<CString>)>) -> ! {
     let mut buf = [0; 1024];
     let pw = get_pw_entry(&mut buf);
 
@@ -209,6 +198,11 @@ fn execsh() -> ! {
     env::set_var("SHELL", pw.shell);
     env::set_var("HOME", pw.dir);
     env::set_var("TERM", "xterm-256color"); // sigh
+    env::set_var("COLORTERM", "truecolor");
+
+    for (key, value) in &options.env {
+        env::set_var(key, value);
+    }
 
     unsafe {
         libc::signal(libc::SIGCHLD, libc::SIG_DFL);
@@ -219,24 +213,67 @@ fn execsh() -> ! {
         libc::signal(libc::SIGALRM, libc::SIG_DFL);
     }
 
-    // pw.shell is null terminated
-    let shell = unsafe { CStr::from_ptr(pw.shell.as_ptr() as *const _) };
+    if let Some(ref dir) = options.working_directory {
+        if let Err(err) = env::set_current_dir(dir) {
+            error!("Couldn't change to working directory {}: {}", dir.display(), err);
+        }
+    }
 
-    let argv = [shell.as_ptr(), ptr::null()];
+    match program {
+        Some((program, args)) => exec(&program, &args),
+        None => {
+            // pw.shell is null terminated
+            let shell = unsafe { CStr::from_ptr(pw.shell.as_ptr() as *const _) };
+            exec(shell, &[]);
+        }
+    }
+}
 
-    let res = unsafe {
-        libc::execvp(shell.as_ptr(), argv.as_ptr())
-    };
+/// Convert a configured string for exec, giving up if it holds a nul byte
+fn c_string(s: &str) -> CString {
+    match CString::new(s) {
+        Ok(s) => s,
+        Err(_) => die!("Can't exec {:?}; it contains a nul byte", s),
+    }
+}
 
-    if res < 0 {
-        die!("execvp failed: {}", errno());
+/// Check that the configured environment can be set, giving up if not
+fn check_env(env: &HashMap<String, String>) {
+    for (key, value) in env {
+        if key.is_empty() || key.contains('=') || key.contains('\0') || value.contains('\0') {
+            die!("Can't set environment variable {:?}={:?}", key, value);
+        }
     }
+}
 
-    ::std::process::exit(1);
+/// What to run on a new tty, and how
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Program and its arguments; the user's shell when `None`
+    pub program: Option<(String, Vec<String>)>,
+
+    /// Directory the program starts in; the current one when `None`
+    pub working_directory: Option<PathBuf>,
+
+    /// Extra environment variables, set after the defaults like `TERM`
+    pub env: HashMap<String, String>,
 }
 
-/// Create a new tty and return a handle to interact with it.
-pub fn new(rows: u8, cols: u8) -> Tty {
+/// Create a new tty running the user's shell, or the program in `options`,
+/// and return a handle to interact with it.
+pub fn new(rows: u8, cols: u8, options: &Options) -> Tty {
+    spawn(rows, cols, options)
+}
+
+/// Create a new tty running the program described by `options`
+fn spawn(rows: u8, cols: u8, options: &Options) -> Tty {
+    // Bad options are reported here rather than by a child which would exit
+    // as soon as it started
+    let program = options.program.as_ref().map(|&(ref program, ref args)| {
+        (c_string(program), args.iter().map(|arg| c_string(arg)).collect::<Vec<_>>())
+    });
+    check_env(&options.env);
+
     let (master, slave) = openpty(rows, cols);
 
     match fork() {
@@ -259,17 +296,11 @@ pub fn new(rows: u8, cols: u8) -> Tty {
                 libc::close(master);
             }
 
-            // Exec a shell!
-            execsh();
+            // Exec the program or shell
+            execsh(options, program)
         },
         Relation::Parent(pid) => {
             unsafe {
-                // Set PID for SIGCHLD handler
-                PID = pid;
-
-                // Handle SIGCHLD
-                libc::signal(SIGCHLD, sigchld as _);
-
                 // Parent doesn't need slave fd
                 libc::close(slave);
             }
@@ -280,33 +311,182 @@ pub fn new(rows: u8, cols: u8) -> Tty {
                 set_nonblocking(master);
             }
 
-            Tty { fd: master }
+            Tty {
+                fd: master,
+                pid: Some(pid),
+                exit_status: Cell::new(None),
+                pending: Vec::new(),
+                sent: 0,
+            }
         }
     }
 }
 
+/// Create a tty which isn't attached to any child process
+///
+/// A pty pair is still opened so that operations like `resize` behave as they
+/// would for a real shell; nothing is ever spawned on the slave end.
+pub fn null(rows: u8, cols: u8) -> Tty {
+    let (master, slave) = openpty(rows, cols);
+
+    unsafe {
+        libc::close(slave);
+        set_nonblocking(master);
+    }
+
+    Tty {
+        fd: master,
+        pid: None,
+        exit_status: Cell::new(None),
+        pending: Vec::new(),
+        sent: 0,
+    }
+}
+
+/// Most bytes the tty queues before it `is_full`
+pub const MAX_PENDING: usize = 1024 * 1024;
+
 pub struct Tty {
     fd: c_int,
+
+    /// Process running on the slave end, if any
+    pid: Option<pid_t>,
+
+    /// Exit status of the child once it has been reaped
+    exit_status: Cell<Option<i32>>,
+
+    /// Bytes passed to `write`, starting with any already sent
+    pending: Vec<u8>,
+
+    /// Number of bytes at the start of `pending` which the tty has taken
+    sent: usize,
 }
 
 impl Tty {
-    /// Get reader for the TTY
+    /// Get a handle for reading and writing the tty
+    ///
+    /// Reads return `Ok(0)` once the child has exited and nothing is left to
+    /// read.
+    pub fn reader(&self) -> Pty {
+        Pty { fd: self.fd }
+    }
+
+    /// Write `bytes` to the tty without blocking
     ///
-    /// XXX File is a bad abstraction here; it closes the fd on drop
-    pub fn reader(&self) -> File {
-        unsafe {
-            File::from_raw_fd(self.fd)
+    /// Whatever the tty can't take right now is queued behind any bytes
+    /// already pending; call `flush` once the fd is writable to send more.
+    /// The event loop writes all input and replies this way, and holds off
+    /// while the tty `is_full`.
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        // Drop the sent bytes once they're most of the buffer, so each byte is
+        // moved a bounded number of times
+        if self.sent > self.pending.len() / 2 {
+            self.pending.drain(..self.sent);
+            self.sent = 0;
+        }
+
+        self.pending.extend_from_slice(bytes);
+        self.flush()
+    }
+
+    /// Send as many pending bytes as the tty will take without blocking
+    pub fn flush(&mut self) -> io::Result<()> {
+        let mut pty = self.reader();
+        let mut result = Ok(());
+
+        while self.sent < self.pending.len() {
+            match pty.write(&self.pending[self.sent..]) {
+                Ok(0) => break,
+                Ok(n) => self.sent += n,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                },
+            }
+        }
+
+        if self.sent == self.pending.len() {
+            self.pending.clear();
+            self.sent = 0;
         }
+
+        result
+    }
+
+    /// Whether bytes are waiting for the fd to become writable
+    ///
+    /// The event loop should poll for writability while this is true.
+    #[inline]
+    pub fn wants_write(&self) -> bool {
+        self.sent < self.pending.len()
+    }
+
+    /// Whether `MAX_PENDING` bytes or more are waiting to be sent
+    ///
+    /// Nothing stops `write` from queueing more, but callers should wait for
+    /// the tty to take some first.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.pending.len() - self.sent >= MAX_PENDING
+    }
+
+    /// Get the process ID of the child running on the tty
+    ///
+    /// Returns `None` for a tty created with `null`.
+    #[inline]
+    pub fn child_pid(&self) -> Option<pid_t> {
+        self.pid
     }
 
-    pub fn resize(&self, rows: usize, cols: usize, px_x: usize, px_y: usize) {
-        let win = winsize {
-            ws_row: rows as libc::c_ushort,
-            ws_col: cols as libc::c_ushort,
-            ws_xpixel: px_x as libc::c_ushort,
-            ws_ypixel: px_y as libc::c_ushort,
+    /// Check whether the child has exited without blocking
+    ///
+    /// Returns the exit status once the child has exited; the child is reaped
+    /// the first time and the status is remembered for later calls. A child
+    /// killed by a signal reports 128 plus the signal number, like a shell
+    /// would. Fails if the child can't be waited for, such as when it was
+    /// already reaped elsewhere.
+    pub fn try_wait(&self) -> io::Result<Option<i32>> {
+        if let Some(status) = self.exit_status.get() {
+            return Ok(Some(status));
+        }
+
+        let pid = match self.pid {
+            Some(pid) => pid,
+            None => return Ok(None),
+        };
+
+        let mut status: c_int = 0;
+        let res = unsafe { libc::waitpid(pid, &mut status, WNOHANG) };
+
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if res != pid {
+            return Ok(None);
+        }
+
+        let code = if WIFEXITED(status) {
+            WEXITSTATUS(status)
+        } else if WIFSIGNALED(status) {
+            128 + WTERMSIG(status)
+        } else {
+            return Ok(None);
         };
 
+        self.exit_status.set(Some(code));
+        Ok(Some(code))
+    }
+
+    /// Inform the tty and the child of a new terminal size
+    ///
+    /// The grid size is given in cells alongside the size in pixels, since it
+    /// isn't always what the pixel size works out to.
+    pub fn resize(&self, lines: Line, cols: Column, size: &SizeInfo) {
+        let win = to_winsize(lines, cols, size);
+
         let res = unsafe {
             libc::ioctl(self.fd, libc::TIOCSWINSZ, &win as *const _)
         };
@@ -314,6 +494,70 @@ impl Tty {
         if res < 0 {
             die!("ioctl TIOCSWINSZ failed: {}", errno());
         }
+
+        if let Some(pid) = self.pid {
+            unsafe {
+                libc::kill(pid, libc::SIGWINCH);
+            }
+        }
+    }
+}
+
+/// Reads and writes the master end of a tty
+///
+/// The fd is borrowed from the `Tty`; dropping a `Pty` leaves it open.
+pub struct Pty {
+    fd: c_int,
+}
+
+impl Read for Pty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if res >= 0 {
+            return Ok(res as usize);
+        }
+
+        // Once every fd for the slave end is closed, Linux fails reads from the
+        // master with EIO rather than reporting EOF.
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EIO) => Ok(0),
+            _ => Err(err),
+        }
+    }
+}
+
+impl Write for Pty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = unsafe { libc::write(self.fd, buf.as_ptr() as *const _, buf.len()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(res as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Pty {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// Get the winsize for a terminal size
+///
+/// Rows and columns are counted in cells; the pixel fields are the size of
+/// the window.
+fn to_winsize(lines: Line, cols: Column, size: &SizeInfo) -> winsize {
+    winsize {
+        ws_row: *lines as libc::c_ushort,
+        ws_col: *cols as libc::c_ushort,
+        ws_xpixel: size.width as libc::c_ushort,
+        ws_ypixel: size.height as libc::c_ushort,
     }
 }
 
@@ -330,3 +574,213 @@ fn test_get_pw_entry() {
     let pw = get_pw_entry(&mut buf);
     println!("{:?}", pw);
 }
+
+#[test]
+fn test_resize_sets_winsize() {
+    let size = SizeInfo {
+        width: 800.0,
+        height: 600.0,
+        cell_width: 10.0,
+        cell_height: 20.0,
+        max_cols: ::index::Column(2000),
+        max_lines: ::index::Line(2000),
+    };
+
+    let tty = null(24, 80);
+    tty.resize(size.lines(), size.cols(), &size);
+
+    let mut win: winsize = unsafe { mem::zeroed() };
+    let res = unsafe {
+        libc::ioctl(tty.fd, libc::TIOCGWINSZ, &mut win as *mut _)
+    };
+    assert_eq!(res, 0);
+
+    assert_eq!(win.ws_row, 30);
+    assert_eq!(win.ws_col, 80);
+    assert_eq!(win.ws_xpixel, 800);
+    assert_eq!(win.ws_ypixel, 600);
+}
+
+/// Options running `program` with `args`
+#[cfg(test)]
+fn program_options(program: &str, args: &[&str]) -> Options {
+    Options {
+        program: Some((program.to_owned(), args.iter().map(|arg| arg.to_string()).collect())),
+        ..Options::default()
+    }
+}
+
+/// Read everything the child writes until it closes the tty, giving up after
+/// a few seconds
+#[cfg(test)]
+fn read_to_end(tty: &Tty) -> Vec<u8> {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let mut pty = tty.reader();
+    let mut out = Vec::new();
+    let mut buf = [0u8; 1024];
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        match pty.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => out.extend_from_slice(&buf[..n]),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(10));
+            },
+            Err(err) => panic!("unexpected read err: {:?}", err),
+        }
+    }
+
+    out
+}
+
+#[test]
+fn test_spawn_program_with_args() {
+    use term::Term;
+
+    let tty = spawn(24, 80, &program_options("/bin/echo", &["hi", "there"]));
+    let output = read_to_end(&tty);
+
+    let mut term = Term::builder(SizeInfo {
+        width: 80.,
+        height: 24.,
+        cell_width: 1.,
+        cell_height: 1.,
+        max_cols: Column(80),
+        max_lines: Line(24),
+    }).build();
+    term.feed(&output);
+
+    assert_eq!(term.row_to_string(Line(0)), "hi there");
+    assert_eq!(term.row_to_string(Line(1)), "");
+    assert_eq!(term.cursor_state().point, ::index::Point::new(Line(1), Column(0)));
+}
+
+#[test]
+fn test_spawn_with_env() {
+    let mut options = program_options("/usr/bin/env", &[]);
+    options.env.insert("TERM".into(), "alacritty".into());
+    options.env.insert("ALACRITTY_TEST".into(), "injected".into());
+
+    let tty = spawn(24, 80, &options);
+    let output = String::from_utf8(read_to_end(&tty)).unwrap();
+    let vars = output.lines().map(|line| line.trim_right()).collect::<Vec<_>>();
+    assert!(vars.contains(&"TERM=alacritty"));
+    assert!(vars.contains(&"COLORTERM=truecolor"));
+    assert!(vars.contains(&"ALACRITTY_TEST=injected"));
+}
+
+#[test]
+fn test_spawn_in_working_directory() {
+    let options = Options {
+        working_directory: Some(PathBuf::from("/")),
+        ..program_options("/bin/pwd", &[])
+    };
+
+    let tty = spawn(24, 80, &options);
+    let output = String::from_utf8(read_to_end(&tty)).unwrap();
+    assert_eq!(output, "/\r\n");
+}
+
+#[test]
+fn test_child_exit_status() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let tty = spawn(24, 80, &program_options("/bin/true", &[]));
+    assert!(tty.child_pid().is_some());
+
+    let start = Instant::now();
+    let mut status = tty.try_wait().unwrap();
+    while status.is_none() && start.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(10));
+        status = tty.try_wait().unwrap();
+    }
+
+    assert_eq!(status, Some(0));
+
+    // Status is remembered after the child is reaped
+    assert_eq!(tty.try_wait().unwrap(), Some(0));
+}
+
+#[test]
+fn test_try_wait_for_foreign_pid() {
+    // init is never our child, so waiting for it fails rather than exiting
+    let mut tty = null(24, 80);
+    tty.pid = Some(1);
+    assert!(tty.try_wait().is_err());
+}
+
+#[test]
+fn test_read_after_exit_is_eof() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let tty = spawn(24, 80, &program_options("/bin/true", &[]));
+
+    let start = Instant::now();
+    while tty.try_wait().unwrap().is_none() && start.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(tty.try_wait().unwrap(), Some(0));
+
+    let mut buf = [0u8; 16];
+    let mut pty = tty.reader();
+    assert_eq!(pty.read(&mut buf).unwrap(), 0);
+
+    // And it stays at EOF
+    assert_eq!(pty.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_write_to_full_pipe() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let (read_fd, write_fd) = (fds[0], fds[1]);
+    unsafe {
+        set_nonblocking(read_fd);
+        set_nonblocking(write_fd);
+    }
+
+    let mut tty = Tty {
+        fd: write_fd,
+        pid: None,
+        exit_status: Cell::new(None),
+        pending: Vec::new(),
+        sent: 0,
+    };
+
+    // Far more than a pipe buffer holds; this must return rather than block
+    let input = (0..2 * MAX_PENDING).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    tty.write(&input).unwrap();
+    assert!(tty.wants_write());
+    assert!(tty.is_full());
+
+    let mut output = Vec::new();
+    let mut buf = [0u8; 4096];
+    let start = Instant::now();
+    while output.len() < input.len() && start.elapsed() < Duration::from_secs(10) {
+        let res = unsafe { libc::read(read_fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if res > 0 {
+            output.extend_from_slice(&buf[..res as usize]);
+        } else {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        tty.flush().unwrap();
+    }
+
+    assert!(!tty.wants_write());
+    assert!(!tty.is_full());
+    assert!(output == input);
+    assert!(tty.pending.is_empty());
+
+    unsafe {
+        libc::close(read_fd);
+        libc::close(write_fd);
+    }
+}
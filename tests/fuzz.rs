@@ -0,0 +1,111 @@
+// Copyright 2016 Joe Wilm, The Alacritty Project Contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//! Feed random bytes to a terminal and check it stays consistent
+
+extern crate alacritty;
+#[macro_use]
+extern crate proptest;
+
+use proptest::prelude::*;
+
+use alacritty::index::{Column, Line};
+use alacritty::term::{SizeInfo, Term};
+
+fn term(lines: usize, cols: usize) -> Term {
+    let size = SizeInfo {
+        width: cols as f32,
+        height: lines as f32,
+        cell_width: 1.0,
+        cell_height: 1.0,
+        max_cols: Column(2000),
+        max_lines: Line(2000),
+    };
+
+    Term::builder(size).build()
+}
+
+/// Bytes which are more likely than random ones to form escape sequences
+fn escape_heavy_bytes() -> BoxedStrategy<Vec<u8>> {
+    let byte = prop_oneof![
+        Just(0x1b),
+        Just(b'['),
+        Just(b']'),
+        Just(b'?'),
+        Just(b';'),
+        Just(b'\n'),
+        Just(b'\r'),
+        Just(0x08),
+        0x30u8..0x3a,
+        0x40u8..0x7f,
+        any::<u8>(),
+    ];
+
+    prop::collection::vec(byte, 0..512).boxed()
+}
+
+/// Check what must hold whatever the terminal was fed
+fn check_invariants(term: &Term, lines: usize, cols: usize) {
+    let cursor = term.cursor_state().point;
+
+    // The cursor may sit one past the last column after writing to it
+    assert!(cursor.line < Line(lines), "cursor line {} off grid", cursor.line);
+    assert!(cursor.col <= Column(cols), "cursor column {} off grid", cursor.col);
+
+    let region = term.scroll_region();
+    assert!(region.start < region.end, "empty scroll region {:?}", region);
+    assert!(region.end <= Line(lines), "scroll region {:?} off grid", region);
+
+    assert_eq!(term.grid_to_string(false).lines().count(), lines);
+}
+
+proptest! {
+    #[test]
+    fn random_bytes_never_panic(ref bytes in prop::collection::vec(any::<u8>(), 0..1024)) {
+        let mut term = term(5, 10);
+        term.feed(bytes);
+        term.take_response();
+        check_invariants(&term, 5, 10);
+    }
+
+    #[test]
+    fn escape_sequences_never_panic(ref chunks in prop::collection::vec(escape_heavy_bytes(), 1..4)) {
+        let mut term = term(4, 8);
+        for chunk in chunks {
+            term.feed(chunk);
+            check_invariants(&term, 4, 8);
+        }
+    }
+}
+
+/// Sequences which used to move the cursor off the grid or overflow
+#[test]
+fn known_bad_sequences() {
+    let cases: &[&[u8]] = &[
+        b"\x1b[A\x1b[99A",
+        b"\x1b[D\x1b[99D",
+        b"abcdefghij\x1b[P\x1b[5P",
+        b"\x1b[99999999999999B\x1b[99999999999999C",
+        b"\x1b[3;1r\x1b[5;5r\x1b[2;99r",
+        b"\x1b[4;5r\x1b[1;1H\n\n\n\n\n\n",
+        b"abcdefghij\x1b[1J\x1b[?1J",
+        b"\x1b[?6h\x1b[99999999999999d",
+    ];
+
+    for bytes in cases {
+        let mut term = term(5, 10);
+        term.feed(bytes);
+        check_invariants(&term, 5, 10);
+    }
+}